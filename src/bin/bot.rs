@@ -0,0 +1,229 @@
+//! `bot`: a channel client without the TUI that answers commands, driving the network task
+//! through the same `Command` and `Update` queues the TUI uses
+
+use std::error::Error;
+
+use chrono::Local;
+use clap::Parser;
+use colorful::{Color, Colorful};
+use libp2p::PeerId;
+use log::{info, warn};
+use tochat::config::{Config, FileConfig, SessionArgs};
+use tochat::logging::init_logger;
+use tochat::message::{ChatMessage, Delivery, Kind, Quote};
+use tochat::network::relay_pick::through_relays;
+use tochat::network::secret_store::Backend;
+use tochat::network::{self, Command, Update};
+use tokio::sync::mpsc::{self, Receiver, Sender};
+
+/// Commands start with this, other messages are ignored
+const PREFIX: char = '!';
+
+const HELP: &str = "commands: !ping, !echo <text>, !time, !help";
+
+/// Join a channel without the TUI and answer `!ping`, `!echo`, `!time` and `!help`. Flags not
+/// listed here keep their `tochat channel` defaults, the `TOCHAT_*` environment variables and
+/// `~/.tochat/config.toml` still apply
+#[derive(Parser)]
+#[clap(name = "tochat bot", version)]
+struct Opt {
+    /// nickname [default: derived from the bot's peer id]
+    #[clap(long)]
+    name: Option<String>,
+
+    /// chat topic
+    #[clap(long)]
+    topic: Option<String>,
+
+    /// More topics to answer in, repeat the flag or separate topics with commas
+    #[clap(long, use_value_delimiter = true)]
+    join: Vec<String>,
+
+    /// The relay to go through, ending in its /p2p/<peer id> [default: as `tochat channel`]
+    #[clap(long)]
+    relay_address: Option<String>,
+
+    /// Shared password of the rooms, see `tochat channel --room-password`
+    #[clap(long, env = "TOCHAT_ROOM_PASSWORD")]
+    room_password: Option<String>,
+
+    /// Rendezvous namespace to find the room's peers in
+    #[clap(long)]
+    rendezvous_namespace: Option<String>,
+
+    /// Use a fresh random identity instead of the stored secret key
+    #[clap(long)]
+    ephemeral: bool,
+
+    /// Don't connect anywhere, answer commands sent by the bot itself. For trying it out
+    #[clap(long)]
+    loopback: bool,
+
+    /// Where the secret key is kept, the keychain needs a build with `--features keychain`
+    #[clap(long, arg_enum, env = "TOCHAT_SECRET_STORE", default_value = "file")]
+    secret_store: Backend,
+
+    /// Log more, -v for info, -vv for debug, -vvv for trace. Overrides the RUST_LOG default level
+    #[clap(short, long, parse(from_occurrences))]
+    verbose: u8,
+
+    /// Disable logging entirely
+    #[clap(short, long, conflicts_with = "verbose")]
+    quiet: bool,
+}
+
+impl Opt {
+    /// The `tochat channel` flags these stand for
+    fn session(&self) -> Result<SessionArgs, clap::Error> {
+        let mut flags = vec!["--no-history".to_string()];
+        let values = [
+            ("--name", &self.name),
+            ("--topic", &self.topic),
+            ("--relay-address", &self.relay_address),
+            ("--room-password", &self.room_password),
+            ("--rendezvous-namespace", &self.rendezvous_namespace),
+        ];
+        for (flag, value) in values {
+            if let Some(value) = value {
+                flags.push(format!("{}={}", flag, value));
+            }
+        }
+        if !self.join.is_empty() {
+            flags.push(format!("--join={}", self.join.join(",")));
+        }
+        flags.extend(self.ephemeral.then(|| "--ephemeral".to_string()));
+        flags.extend(self.loopback.then(|| "--loopback".to_string()));
+        SessionArgs::from_flags(flags)
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    let opt = Opt::parse();
+    init_logger(opt.verbose, opt.quiet, None);
+    if let Err(e) = run(&opt).await {
+        eprintln!("{}", format!("bot: {}", e).color(Color::LightRed));
+        std::process::exit(1);
+    }
+}
+
+async fn run(opt: &Opt) -> Result<(), Box<dyn Error>> {
+    let session = opt.session()?;
+    let mut config = Config::resolve(&session, None, None, FileConfig::load()?, opt.secret_store)?;
+    let (tx1, rx1) = mpsc::channel::<Command>(config.channel_capacity);
+    let (tx2, rx2) = mpsc::channel::<Update>(config.channel_capacity);
+    if config.loopback {
+        tokio::spawn(network::loopback::handle_msg(rx1, tx2, config.clone()));
+    } else {
+        let (key, updates) = (&config.secret()?, &tx2);
+        let (swarm, relay_listener, bandwidth) = through_relays(&mut config, |config| async move {
+            network::connection_channel::establish_connection(key, &config, updates).await
+        })
+        .await?;
+        tokio::spawn(network::connection_channel::handle_msg(swarm, rx1, tx2, config.clone(), relay_listener, bandwidth));
+    }
+    answer_all(tx1, rx2, &config).await
+}
+
+/// The answer to a message, none when it isn't a command
+fn answer(body: &str) -> Option<String> {
+    let command = body.trim().strip_prefix(PREFIX)?;
+    let (name, rest) = command.split_once(char::is_whitespace).unwrap_or((command, ""));
+    Some(match name {
+        "ping" => "pong".to_string(),
+        "echo" if !rest.trim().is_empty() => rest.trim().to_string(),
+        "echo" => "usage: !echo <text>".to_string(),
+        "time" => Local::now().format("%Y-%m-%d %H:%M:%S %:z").to_string(),
+        "help" => HELP.to_string(),
+        _ => format!("unknown command !{}, {}", name, HELP),
+    })
+}
+
+/// Answer the commands the network task hands over until it stops. Replies quote the command
+/// and go to the room it came from, privately when it was whispered
+async fn answer_all(tx1: Sender<Command>, mut rx2: Receiver<Update>, config: &Config) -> Result<(), Box<dyn Error>> {
+    println!("{} answering {} in {}", config.name, HELP, config.rooms.join(", "));
+    let mut room = 0;
+    while let Some(update) = rx2.recv().await {
+        let (to_room, message) = match update {
+            Update::Message(to_room, message) if message.kind == Kind::Remote => (to_room, message),
+            Update::System(notice) => {
+                info!("{}", notice);
+                continue;
+            }
+            Update::Delivery(id, Delivery::Failed) => {
+                warn!("Reply {} was not delivered", id);
+                continue;
+            }
+            _ => continue,
+        };
+        let body = match answer(&message.body) {
+            Some(body) => body,
+            None => continue,
+        };
+        println!("[{}] {} in {}: {}", Local::now().format("%H:%M:%S"), message.sender, config.rooms[to_room], message.body);
+        if to_room != room {
+            tx1.send(Command::Room(to_room)).await?;
+            room = to_room;
+        }
+        let mut reply = ChatMessage::local(&config.name, body);
+        let author = message.author.as_ref().and_then(|author| author.parse::<PeerId>().ok());
+        match author {
+            Some(author) if message.private => tx1.send(Command::Whisper(reply, author)).await?,
+            _ => {
+                reply.reply_to = Some(Quote::of(&message));
+                tx1.send(Command::Publish(reply)).await?;
+            }
+        }
+    }
+    Err("the network task stopped".into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn answers_commands() {
+        assert_eq!(answer("!ping").as_deref(), Some("pong"));
+        assert_eq!(answer("  !ping  ").as_deref(), Some("pong"));
+        assert_eq!(answer("!echo hello  there ").as_deref(), Some("hello  there"));
+        assert_eq!(answer("!help").as_deref(), Some(HELP));
+    }
+
+    #[test]
+    fn echo_needs_text() {
+        assert_eq!(answer("!echo").as_deref(), Some("usage: !echo <text>"));
+        assert_eq!(answer("!echo   ").as_deref(), Some("usage: !echo <text>"));
+    }
+
+    #[test]
+    fn time_is_local_with_offset() {
+        let time = answer("!time").unwrap();
+        // e.g. 2024-03-01 09:05:00 +01:00
+        assert_eq!(time.len(), 26);
+        assert!(chrono::DateTime::parse_from_str(&time, "%Y-%m-%d %H:%M:%S %:z").is_ok());
+    }
+
+    #[test]
+    fn unknown_commands_get_the_help() {
+        assert_eq!(answer("!dance now").unwrap(), format!("unknown command !dance, {}", HELP));
+    }
+
+    #[test]
+    fn ignores_other_messages() {
+        assert_eq!(answer("hello"), None);
+        assert_eq!(answer("ping!"), None);
+        assert_eq!(answer(""), None);
+    }
+
+    #[test]
+    fn passes_only_the_given_flags_on() {
+        let opt = Opt::parse_from(["bot", "--topic", "ops", "--join", "a,b", "--ephemeral"]);
+        let config = Config::resolve(&opt.session().unwrap(), None, None, FileConfig::default(), Backend::File).unwrap();
+        assert_eq!(config.rooms, ["ops", "a", "b"]);
+        assert!(config.ephemeral_secret.is_some());
+        assert!(!config.history);
+        assert!(!config.loopback);
+    }
+}
@@ -21,25 +21,29 @@
 
 use clap::Parser;
 use futures::stream::StreamExt;
+use libp2p::bandwidth::{BandwidthLogging, BandwidthSinks};
 use libp2p::core::upgrade;
 use libp2p::identify::{Identify, IdentifyConfig, IdentifyEvent};
 use libp2p::multiaddr::Protocol;
 use libp2p::ping::{Ping, PingConfig, PingEvent};
 use libp2p::relay::v2::relay::{self, Relay, Config};
-use libp2p::swarm::{SwarmEvent, SwarmBuilder};
+use libp2p::swarm::behaviour::toggle::Toggle;
+use libp2p::swarm::{AddressScore, ConnectionLimits, PendingInboundConnectionError, SwarmEvent, SwarmBuilder};
 use libp2p::tcp::{GenTcpConfig, TokioTcpTransport};
 use libp2p::{Transport, rendezvous};
 use libp2p::{identity, NetworkBehaviour, PeerId};
 use libp2p::{noise, Multiaddr};
 use std::error::Error;
-use std::net::{Ipv4Addr, Ipv6Addr};
-use std::time::Duration;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tochat::logging::init_logger;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
-    env_logger::init();
-
     let opt = Opt::parse();
+    init_logger(opt.verbose, opt.quiet, None);
     println!("opt: {:?}", opt);
 
     // Create a static known PeerId based on given secret
@@ -51,28 +55,51 @@ async fn main() -> Result<(), Box<dyn Error>> {
         .into_authentic(&local_key)
         .expect("Signing libp2p-noise static DH keypair failed.");
 
-    let transport = TokioTcpTransport::new(GenTcpConfig::default())
+    let (transport, bandwidth) = BandwidthLogging::new(TokioTcpTransport::new(GenTcpConfig::default()));
+    let transport = transport
         .upgrade(upgrade::Version::V1)
         .authenticate(noise::NoiseConfig::xx(noise_keys).into_authenticated())
         .multiplex(libp2p::yamux::YamuxConfig::default())
         .boxed();
 
+    if let Some(banner) = &opt.banner {
+        if banner.chars().count() > MAX_BANNER_LEN || banner.contains(')') {
+            return Err(format!("--banner must be at most {} characters without ')'", MAX_BANNER_LEN).into());
+        }
+    }
+
     let mut relay_config = Config::default();
     relay_config.max_circuit_duration = Duration::from_secs(60 * 60);
 
+    // a pure relay leaves discovery out entirely, its events then simply never arrive
+    let rendezvous = if opt.no_rendezvous {
+        None
+    } else {
+        if opt.rendezvous_max_ttl < RENDEZVOUS_MIN_TTL {
+            return Err(format!("--rendezvous-max-ttl must be at least {} seconds", RENDEZVOUS_MIN_TTL).into());
+        }
+        let config = rendezvous::server::Config::default()
+            .with_min_ttl(RENDEZVOUS_MIN_TTL)
+            .with_max_ttl(opt.rendezvous_max_ttl);
+        Some(rendezvous::server::Behaviour::new(config))
+    };
+
     let behaviour = Behaviour {
         relay: Relay::new(local_peer_id, relay_config),
         ping: Ping::new(PingConfig::new().with_keep_alive(true)),
-        identify: Identify::new(IdentifyConfig::new(
-            "/TODO/0.0.1".to_string(),
-            local_key.public(),
-        )),
-        rendezvous: rendezvous::server::Behaviour::new(rendezvous::server::Config::default()),
+        identify: Identify::new(
+            IdentifyConfig::new(opt.protocol_version.clone(), local_key.public())
+                .with_agent_version(agent_version(opt.banner.as_deref())),
+        ),
+        rendezvous: rendezvous.into(),
     };
 
+    let limits = connection_limits(&opt)?;
+
     let mut swarm =  SwarmBuilder::new(transport, behaviour, local_peer_id).executor(Box::new(|fut| {
         tokio::spawn(fut);
     }))
+    .connection_limits(limits)
     .build();
 
     // Listen on all interfaces
@@ -82,20 +109,114 @@ async fn main() -> Result<(), Box<dyn Error>> {
             _ => Protocol::from(Ipv4Addr::UNSPECIFIED),
         })
         .with(Protocol::Tcp(opt.port));
-    swarm.listen_on(listen_addr)?;
+    let listener = swarm.listen_on(listen_addr)?;
+    // behind NAT the relay only knows the addresses it binds to, the public one has to be
+    // declared for identify and the reservations to hand it out
+    for addr in &opt.external_address {
+        let addr = external_address(addr, local_peer_id)?;
+        swarm.add_external_address(addr.clone(), AddressScore::Infinite);
+        tracing::info!("Advertising external address {}", addr);
+        println!("External address: {}/p2p/{}", addr, local_peer_id);
+    }
+    if let Some(addr) = &opt.announce_addr {
+        let addr = announce_address(addr, local_peer_id)?;
+        swarm.add_external_address(addr.clone(), AddressScore::Infinite);
+        tracing::info!("Announcing {}", addr);
+        println!("Clients connect with --relay-address {}/p2p/{}", addr, local_peer_id);
+    }
+    let started = Instant::now();
+
+    let metrics = Arc::new(Metrics::new(bandwidth));
+    if let Some(addr) = opt.metrics_addr {
+        serve_metrics(addr, metrics.clone())?;
+        println!("Serving metrics on http://{}/metrics", addr);
+    }
+
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
+    let mut drain_deadline: Option<Instant> = None;
 
     loop {
-        match swarm.next().await.expect("Infinite Stream.") {
+        if let Some(deadline) = drain_deadline {
+            let active = metrics.active_circuits();
+            if active == 0 || Instant::now() >= deadline {
+                if active > 0 {
+                    tracing::warn!("Grace period over, dropping {} active circuits", active);
+                }
+                break;
+            }
+        }
+
+        let event = tokio::select! {
+            event = swarm.select_next_some() => event,
+            _ = &mut shutdown, if drain_deadline.is_none() => {
+                // no new connections, and so no new reservations, while circuits drain
+                swarm.remove_listener(listener);
+                tracing::info!(
+                    "Shutting down, waiting up to {:?} for {} active circuits",
+                    Duration::from_secs(opt.shutdown_grace),
+                    metrics.active_circuits()
+                );
+                drain_deadline = Some(Instant::now() + Duration::from_secs(opt.shutdown_grace));
+                continue;
+            }
+            // re-check the drain condition even when no events arrive
+            _ = tokio::time::sleep(Duration::from_millis(250)), if drain_deadline.is_some() => continue,
+        };
+        match event {
             SwarmEvent::Behaviour(Event::Relay(event)) => {
+                metrics.record(&event);
                 println!("{:?}", event)
             }
+            SwarmEvent::ConnectionEstablished { num_established, .. } => {
+                if num_established.get() == 1 {
+                    metrics.connected_peers.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+            SwarmEvent::ConnectionClosed { num_established, .. } => {
+                if num_established == 0 {
+                    metrics.connected_peers.fetch_sub(1, Ordering::Relaxed);
+                }
+            }
             SwarmEvent::NewListenAddr { address, .. } => {
                 println!("Listening on {:?}", address);
             }
+            SwarmEvent::IncomingConnectionError {
+                send_back_addr,
+                error: PendingInboundConnectionError::ConnectionLimit(limit),
+                ..
+            } => {
+                tracing::warn!("Refused connection from {}: {}", send_back_addr, limit);
+            }
+            SwarmEvent::Behaviour(Event::Identify(IdentifyEvent::Received { peer_id, info })) => {
+                tracing::info!(
+                    "Peer {} identified as {:?} speaking {}",
+                    peer_id, info.agent_version, info.protocol_version
+                );
+                if !protocol_compatible(&opt.protocol_version, &info.protocol_version) {
+                    tracing::warn!(
+                        "Peer {} advertises protocol {} which is incompatible with ours ({})",
+                        peer_id, info.protocol_version, opt.protocol_version
+                    );
+                }
+            }
+            SwarmEvent::Behaviour(Event::Rendezvous(
+                rendezvous::server::Event::PeerRegistered { peer, registration },
+            )) if !namespace_allowed(&opt.rendezvous_namespace, &registration.namespace.to_string()) => {
+                // the server has no way to refuse or drop a registration, so ban the peer: the
+                // record stays until its TTL runs out, but no one can reach the peer through us
+                // and it can't register again
+                tracing::warn!(
+                    "Peer {} registered for namespace '{}' which is not allowed, banning it",
+                    peer,
+                    registration.namespace
+                );
+                swarm.ban_peer_id(peer);
+            }
             SwarmEvent::Behaviour(Event::Rendezvous(
                 rendezvous::server::Event::PeerRegistered { peer, registration },
             )) => {
-                log::info!(
+                tracing::info!(
                     "Peer {} registered for namespace '{}'",
                     peer,
                     registration.namespace
@@ -107,7 +228,7 @@ async fn main() -> Result<(), Box<dyn Error>> {
                     registrations,
                 },
             )) => {
-                log::info!(
+                tracing::info!(
                     "Served peer {} with {} registrations",
                     enquirer,
                     registrations.len()
@@ -116,6 +237,31 @@ async fn main() -> Result<(), Box<dyn Error>> {
             _ => {}
         }
     }
+
+    println!(
+        "Relay stopped after {:?}: {} reservations accepted, {} circuits served",
+        Duration::from_secs(started.elapsed().as_secs()),
+        metrics.reservations_accepted.load(Ordering::Relaxed),
+        metrics.circuits_opened.load(Ordering::Relaxed),
+    );
+    Ok(())
+}
+
+/// Resolves on Ctrl+C, or SIGTERM on unix
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut terminate = signal(SignalKind::terminate()).expect("installing a SIGTERM handler");
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = terminate.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+    }
 }
 
 #[derive(NetworkBehaviour)]
@@ -124,7 +270,7 @@ struct Behaviour {
     relay: Relay,
     ping: Ping,
     identify: Identify,
-    rendezvous: rendezvous::server::Behaviour,
+    rendezvous: Toggle<rendezvous::server::Behaviour>,
 }
 
 #[derive(Debug)]
@@ -159,6 +305,185 @@ impl From<rendezvous::server::Event> for Event {
     }
 }
 
+/// Counters exported in Prometheus text format on `--metrics-addr`.
+struct Metrics {
+    reservations_accepted: AtomicU64,
+    reservations_denied: AtomicU64,
+    circuits_opened: AtomicU64,
+    circuits_closed: AtomicU64,
+    connected_peers: AtomicU64,
+    bandwidth: Arc<BandwidthSinks>,
+}
+
+impl Metrics {
+    fn new(bandwidth: Arc<BandwidthSinks>) -> Self {
+        Metrics {
+            reservations_accepted: AtomicU64::new(0),
+            reservations_denied: AtomicU64::new(0),
+            circuits_opened: AtomicU64::new(0),
+            circuits_closed: AtomicU64::new(0),
+            connected_peers: AtomicU64::new(0),
+            bandwidth,
+        }
+    }
+
+    fn record(&self, event: &relay::Event) {
+        let counter = match event {
+            relay::Event::ReservationReqAccepted { .. } => &self.reservations_accepted,
+            relay::Event::ReservationReqDenied { .. } => &self.reservations_denied,
+            relay::Event::CircuitReqAccepted { .. } => &self.circuits_opened,
+            relay::Event::CircuitClosed { .. } => &self.circuits_closed,
+            _ => return,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Circuits opened and not yet closed
+    fn active_circuits(&self) -> u64 {
+        let opened = self.circuits_opened.load(Ordering::Relaxed);
+        opened.saturating_sub(self.circuits_closed.load(Ordering::Relaxed))
+    }
+
+    fn render(&self) -> String {
+        let bytes = self.bandwidth.total_inbound() + self.bandwidth.total_outbound();
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        let metrics = [
+            ("tochat_relay_reservations_accepted_total", "counter", "Reservations accepted.", load(&self.reservations_accepted)),
+            ("tochat_relay_reservations_denied_total", "counter", "Reservations denied.", load(&self.reservations_denied)),
+            ("tochat_relay_circuits_opened_total", "counter", "Circuits opened.", load(&self.circuits_opened)),
+            ("tochat_relay_circuits_closed_total", "counter", "Circuits closed.", load(&self.circuits_closed)),
+            ("tochat_relay_bytes_relayed_total", "counter", "Bytes sent and received by the relay.", bytes),
+            ("tochat_relay_connected_peers", "gauge", "Peers currently connected.", load(&self.connected_peers)),
+        ];
+
+        let mut out = String::new();
+        for (name, kind, help, value) in metrics {
+            out.push_str(&format!("# HELP {} {}\n# TYPE {} {}\n{} {}\n", name, help, name, kind, name, value));
+        }
+        out
+    }
+}
+
+/// Serve `/metrics` from a background thread, the swarm loop only touches the atomics.
+fn serve_metrics(addr: SocketAddr, metrics: Arc<Metrics>) -> Result<(), Box<dyn Error>> {
+    let server = tiny_http::Server::http(addr).map_err(|e| e.to_string())?;
+    std::thread::spawn(move || {
+        for request in server.incoming_requests() {
+            let response = if request.url() == "/metrics" {
+                let content_type =
+                    tiny_http::Header::from_bytes(&b"Content-Type"[..], &b"text/plain; version=0.0.4"[..])
+                        .expect("valid header");
+                tiny_http::Response::from_string(metrics.render()).with_header(content_type)
+            } else {
+                tiny_http::Response::from_string("not found").with_status_code(404)
+            };
+            if let Err(e) = request.respond(response) {
+                tracing::warn!("Failed to answer metrics request: {:?}", e);
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Check an `--external-address`: a public TCP address, optionally ending in the relay's own
+/// `/p2p/<peer id>`, which is dropped since libp2p appends it where needed
+fn external_address(addr: &Multiaddr, local_peer_id: PeerId) -> Result<Multiaddr, String> {
+    let mut addr = addr.clone();
+    if let Some(Protocol::P2p(hash)) = addr.iter().last() {
+        if PeerId::from_multihash(hash).ok() != Some(local_peer_id) {
+            return Err(format!("--external-address {} ends in another peer id than the relay's {}", addr, local_peer_id));
+        }
+        addr.pop();
+    }
+    let mut host = false;
+    let mut tcp = false;
+    for protocol in addr.iter() {
+        match protocol {
+            Protocol::Ip4(ip) if ip.is_unspecified() || ip.is_loopback() => {
+                return Err(format!("--external-address {} is not reachable from other hosts", addr))
+            }
+            Protocol::Ip6(ip) if ip.is_unspecified() || ip.is_loopback() => {
+                return Err(format!("--external-address {} is not reachable from other hosts", addr))
+            }
+            Protocol::Ip4(_) | Protocol::Ip6(_) | Protocol::Dns(_) | Protocol::Dns4(_) | Protocol::Dns6(_) => host = true,
+            Protocol::Tcp(_) if host => tcp = true,
+            Protocol::P2pCircuit | Protocol::P2p(_) => {
+                return Err(format!("--external-address {} must be the relay's own address, not a circuit", addr))
+            }
+            _ => {}
+        }
+    }
+    if !tcp {
+        return Err(format!("--external-address {} needs a host and a TCP port, e.g. /ip4/203.0.113.7/tcp/4001", addr));
+    }
+    Ok(addr)
+}
+
+/// Check an `--announce-addr`: exactly `/dns4|dns6|dns/<host>/tcp/<port>/p2p/<relay id>`, the
+/// form a client passes as `--relay-address`. The returned address has the peer id dropped
+fn announce_address(addr: &Multiaddr, local_peer_id: PeerId) -> Result<Multiaddr, String> {
+    let protocols: Vec<Protocol> = addr.iter().collect();
+    match protocols.as_slice() {
+        [Protocol::Dns(_) | Protocol::Dns4(_) | Protocol::Dns6(_), Protocol::Tcp(_), Protocol::P2p(hash)] => {
+            if PeerId::from_multihash(*hash).ok() != Some(local_peer_id) {
+                return Err(format!("--announce-addr {} ends in another peer id than the relay's {}", addr, local_peer_id));
+            }
+            Ok(protocols[..2].iter().cloned().collect())
+        }
+        _ => Err(format!("--announce-addr {} must look like /dns4/relay.example.com/tcp/4001/p2p/{}", addr, local_peer_id)),
+    }
+}
+
+/// Longest `--banner`, it travels in every identify message
+const MAX_BANNER_LEN: usize = 200;
+
+/// The identify agent version, `tochat-relay/<version> (<os>)`, carrying the banner as a trailing
+/// `(motd: <banner>)`. Clients that don't look for it just see an agent string
+fn agent_version(banner: Option<&str>) -> String {
+    let agent = format!("tochat-relay/{} ({})", env!("CARGO_PKG_VERSION"), std::env::consts::OS);
+    match banner {
+        Some(banner) => format!("{} (motd: {})", agent, banner),
+        None => agent,
+    }
+}
+
+/// The `--max-*` connection limits, refused when one would keep every peer out
+fn connection_limits(opt: &Opt) -> Result<ConnectionLimits, String> {
+    if opt.max_connections == 0 || opt.max_pending == 0 || opt.max_connections_per_peer == 0 {
+        return Err("--max-connections, --max-pending and --max-connections-per-peer must be at least 1".to_string());
+    }
+    if opt.max_connections_per_peer > opt.max_connections {
+        return Err("--max-connections-per-peer can't be more than --max-connections".to_string());
+    }
+    Ok(ConnectionLimits::default()
+        .with_max_established(Some(opt.max_connections))
+        .with_max_pending_incoming(Some(opt.max_pending))
+        .with_max_established_per_peer(Some(opt.max_connections_per_peer)))
+}
+
+/// Shortest registration TTL the rendezvous server accepts, the libp2p default of 2 hours
+const RENDEZVOUS_MIN_TTL: u64 = 60 * 60 * 2;
+
+/// An empty allowlist allows every namespace
+fn namespace_allowed(allowed: &[String], namespace: &str) -> bool {
+    allowed.is_empty() || allowed.iter().any(|ns| ns == namespace)
+}
+
+/// Identify protocol advertised by tochat peers, kept in step with the client
+const PROTOCOL_VERSION: &str = concat!("/tochat/", env!("CARGO_PKG_VERSION"));
+
+/// Same protocol name and major version, mirrors the client's check
+fn protocol_compatible(local: &str, remote: &str) -> bool {
+    let parse = |s: &str| {
+        s.rsplit_once('/')
+            .map(|(name, version)| (name.to_string(), version.split('.').next().unwrap_or("").to_string()))
+    };
+    match (parse(local), parse(remote)) {
+        (Some(local), Some(remote)) => local == remote,
+        _ => local == remote,
+    }
+}
+
 fn generate_ed25519(secret_key_seed: u8) -> identity::Keypair {
     let mut bytes = [0u8; 32];
     bytes[0] = secret_key_seed;
@@ -182,4 +507,93 @@ struct Opt {
     /// The port used to listen on all interfaces
     #[clap(long)]
     port: u16,
+
+    /// Public address the relay is reached at, e.g. /ip4/203.0.113.7/tcp/4001 behind a NAT that
+    /// forwards the port. Advertised to clients through identify and reservations, repeat for several
+    #[clap(long)]
+    external_address: Vec<Multiaddr>,
+
+    /// Stable DNS address to announce instead of an IP that may change, as clients pass it:
+    /// /dns4/relay.example.com/tcp/4001/p2p/<this relay's peer id>. Works behind a TCP proxy
+    #[clap(long)]
+    announce_addr: Option<Multiaddr>,
+
+    /// Address to serve Prometheus metrics on, e.g. 127.0.0.1:9090. Disabled if unset
+    #[clap(long)]
+    metrics_addr: Option<SocketAddr>,
+
+    /// Short message shown to clients when they connect, e.g. announced maintenance
+    #[clap(long)]
+    banner: Option<String>,
+
+    /// Run as a pure relay without the rendezvous server for peer discovery. Direct messages
+    /// still work through it, channels can't find their peers and fail to start
+    #[clap(long)]
+    no_rendezvous: bool,
+
+    /// Longest time in seconds a rendezvous registration may live [default: 72 hours]
+    #[clap(long, default_value_t = 60 * 60 * 72)]
+    rendezvous_max_ttl: u64,
+
+    /// Namespace peers may register in, repeat for several. All namespaces are allowed if unset
+    #[clap(long)]
+    rendezvous_namespace: Vec<String>,
+
+    /// Most connections the relay keeps open at once
+    #[clap(long, default_value_t = 256)]
+    max_connections: u32,
+
+    /// Most incoming connections still being negotiated at once
+    #[clap(long, default_value_t = 64)]
+    max_pending: u32,
+
+    /// Most connections a single peer may hold
+    #[clap(long, default_value_t = 8)]
+    max_connections_per_peer: u32,
+
+    /// Seconds to wait for active circuits to close after SIGINT or SIGTERM
+    #[clap(long, default_value_t = 10)]
+    shutdown_grace: u64,
+
+    /// Override the identify protocol version advertised to peers, for testing
+    #[clap(long, default_value = PROTOCOL_VERSION)]
+    protocol_version: String,
+
+    /// Log more, -v for info, -vv for debug, -vvv for trace. Overrides the RUST_LOG default level
+    #[clap(short, long, parse(from_occurrences))]
+    verbose: u8,
+
+    /// Disable logging entirely
+    #[clap(short, long, conflicts_with = "verbose")]
+    quiet: bool,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn opt(flags: &[&str]) -> Opt {
+        let required = ["relay", "--secret-key-seed", "1", "--port", "4001"];
+        Opt::parse_from(required.iter().chain(flags))
+    }
+
+    #[test]
+    fn connection_limits_default_to_something_usable() {
+        let opt = opt(&[]);
+        assert_eq!((opt.max_connections, opt.max_pending, opt.max_connections_per_peer), (256, 64, 8));
+        assert!(connection_limits(&opt).is_ok());
+    }
+
+    #[test]
+    fn refuses_limits_that_keep_everyone_out() {
+        assert!(connection_limits(&opt(&["--max-connections", "0"])).is_err());
+        assert!(connection_limits(&opt(&["--max-pending", "0"])).is_err());
+        assert!(connection_limits(&opt(&["--max-connections-per-peer", "0"])).is_err());
+    }
+
+    #[test]
+    fn a_peer_cannot_get_more_than_the_total() {
+        assert!(connection_limits(&opt(&["--max-connections", "4", "--max-connections-per-peer", "8"])).is_err());
+        assert!(connection_limits(&opt(&["--max-connections", "8", "--max-connections-per-peer", "8"])).is_ok());
+    }
 }
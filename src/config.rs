@@ -0,0 +1,957 @@
+use std::env;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::ops::RangeInclusive;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use clap::{ArgEnum, Args, Parser};
+use libp2p::core::multiaddr::{Multiaddr, Protocol};
+use libp2p::rendezvous::Namespace;
+use libp2p::PeerId;
+use reqwest::Url;
+use tui::style::Color;
+use serde::Deserialize;
+use web3::signing::keccak256;
+
+use crate::history::{parse_time_bound, TimeBound, Window};
+use crate::network::secret_store::Backend;
+use crate::network::secure::{ephemeral_secret, generate_ed25519, get_secret, read_stdin_secret, tochat_dir, Secret};
+use crate::network::path::Thresholds;
+use crate::network::replay::MAX_REPLAY;
+use crate::network::{default_agent_version, derived_name, PROTOCOL_VERSION};
+use crate::tui::theme::{parse_name_color, NAME_COLORS};
+
+/// Relay used when neither the flags nor the config file name one
+pub const DEFAULT_RELAY_ADDRESS: &str =
+    "/ip4/1.12.76.121/tcp/4001/p2p/12D3KooWDpJ7As7BWAwRMfu1VU2WCqNjvq387JEYKDBj4kx6nXTN";
+
+/// The relays picked from when neither `--relay-address` nor `--relays` is given
+pub const BOOTSTRAP_RELAYS: &[&str] = &[DEFAULT_RELAY_ADDRESS];
+
+/// Shown when the default relay is used: it is a server run by someone else, that may be offline
+/// or gone, and peers only find each other on the same relay
+pub const DEFAULT_RELAY_NOTICE: &str = "no --relay-address given, using the built-in public relay. It is run by a third party and \
+may be offline. Run your own with `cargo build --bin relay` (see the README) and pass its address with --relay-address, \
+or set relay_address in ~/.tochat/config.toml";
+
+/// Allowed `--input-height`, also the range `+`/`-` resize the input pane in
+pub const INPUT_HEIGHT: RangeInclusive<u16> = 10..=80;
+/// Allowed `--status-height`
+pub const STATUS_HEIGHT: RangeInclusive<u16> = 5..=50;
+
+/// Where channels register and discover peers at the relay unless `--rendezvous-namespace` says
+const DEFAULT_RENDEZVOUS_NAMESPACE: &str = "rendezvous";
+
+/// Which side of a direct message sets up the connection. Both sides reserve a slot on the relay;
+/// the listener waits there to be reached and the dialer connects to it through the relay, after
+/// which both try to upgrade to a direct connection. Start the listener first
+#[derive(ArgEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mode {
+    /// Connect to the peer given by `--remote-id`
+    Dial,
+    /// Wait for the peer to dial us
+    Listen,
+}
+
+/// Which message is selected when the history has loaded
+#[derive(ArgEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StartAt {
+    /// The oldest message
+    Top,
+    /// The newest message, following new ones
+    Bottom,
+    /// The first message received after the newest one seen in the last session
+    Unread,
+}
+
+/// How the draft shows in the input box, for shared screens and recordings. What is sent is
+/// always the text typed
+#[derive(ArgEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InputEcho {
+    /// The text as typed
+    On,
+    /// A dot for each character
+    Dots,
+    /// Nothing, the box stays empty
+    Off,
+}
+
+impl InputEcho {
+    /// The next one `Ctrl+E` switches to
+    pub fn next(self) -> InputEcho {
+        match self {
+            InputEcho::On => InputEcho::Dots,
+            InputEcho::Dots => InputEcho::Off,
+            InputEcho::Off => InputEcho::On,
+        }
+    }
+}
+
+/// What Enter does while editing. Sending on Enter is quick for chat, but a multi-line draft can
+/// only be pasted; a new line on Enter lets one be written and reviewed, at the cost of a chord
+/// to send every message
+#[derive(ArgEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EnterKey {
+    /// Enter sends the draft
+    Send,
+    /// Enter starts a new line, Alt+Enter or Ctrl+S sends
+    Newline,
+}
+
+/// Flags shared by `dm`, `channel` and `config`. Flags win over environment variables,
+/// which win over `~/.tochat/config.toml`
+#[derive(Args)]
+pub struct SessionArgs {
+    /// nickname [default: derived from your peer id, e.g. quiet-otter-kx6nXT]
+    #[clap(long, env = "TOCHAT_NAME")]
+    name: Option<String>,
+
+    /// chat topic
+    #[clap(long, env = "TOCHAT_TOPIC")]
+    topic: Option<String>,
+
+    /// More topics to join in a channel, each in a tab of its own. Repeat the flag or separate
+    /// topics with commas
+    #[clap(long, use_value_delimiter = true)]
+    join: Vec<String>,
+
+    /// The listening address [default: a public relay run by a third party, better run your own `relay`]
+    #[clap(long, env = "TOCHAT_RELAY_ADDRESS", parse(try_from_str = parse_relay_address))]
+    relay_address: Option<Multiaddr>,
+
+    /// Relays to pick from when --relay-address isn't given: the fastest reachable one is used
+    /// and the others are tried in turn if it fails [default: the built-in list]
+    #[clap(long, env = "TOCHAT_RELAYS", use_value_delimiter = true, parse(try_from_str = parse_relay_address))]
+    relays: Vec<Multiaddr>,
+
+    /// How many times to dial the relay before giving up
+    #[clap(long, default_value_t = 5)]
+    dial_attempts: u32,
+
+    /// How many times to dial the relay again after losing the connection to it, e.g. when it
+    /// restarts. 0 doesn't reconnect
+    #[clap(long, default_value_t = 20)]
+    relay_reconnect_attempts: u32,
+
+    /// Override the identify protocol version advertised to peers, for testing
+    #[clap(long, default_value = PROTOCOL_VERSION)]
+    protocol_version: String,
+
+    /// Identify agent version sent to the relay and peers [default: tochat/<version> (<os>)].
+    /// Pass an empty string to send none
+    #[clap(long, env = "TOCHAT_AGENT_VERSION")]
+    agent_version: Option<String>,
+
+    /// Refuse peers whose protocol major version differs from ours instead of warning
+    #[clap(long)]
+    strict_version: bool,
+
+    /// Don't load or save the chat history of this topic
+    #[clap(long)]
+    no_history: bool,
+
+    /// Go away (leave the topic, drop the relay reservation) after this long without key input,
+    /// e.g. `15m`, seconds without a unit
+    #[clap(long, parse(try_from_str = parse_duration))]
+    idle_timeout: Option<Duration>,
+
+    /// Largest gossipsub message in bytes, longer chat messages are sent in chunks
+    #[clap(long, default_value_t = 65536)]
+    max_message_size: usize,
+
+    /// Send `:shortcodes:` as typed instead of turning them into emoji
+    #[clap(long)]
+    no_emoji: bool,
+
+    /// Show one sender and time header for consecutive messages of a sender within the same minute
+    #[clap(long)]
+    grouped: bool,
+
+    /// Show each message on one line as `HH:MM <name> body`, cut to the width of the list
+    #[clap(long)]
+    compact: bool,
+
+    /// Show a short PeerId next to each sender's name, to tell who really wrote a message
+    #[clap(long = "show-peerids")]
+    show_peer_ids: bool,
+
+    /// Show messages in the order they arrive, instead of putting one a sender published before
+    /// others that arrived first back among them
+    #[clap(long)]
+    arrival_order: bool,
+
+    /// Only load history from this time on: a duration back like `2h` or `3d`, a day like
+    /// `2022-10-01` or a time like `2022-10-01T12:00`
+    #[clap(long, parse(try_from_str = parse_time_bound))]
+    since: Option<TimeBound>,
+
+    /// Only load history up to this time, a day counts as a whole. Same forms as --since
+    #[clap(long, parse(try_from_str = parse_time_bound))]
+    until: Option<TimeBound>,
+
+    /// Only load the newest this many messages of the history
+    #[clap(long)]
+    max_messages: Option<usize>,
+
+    /// Where the message list starts after the history loaded
+    #[clap(long, arg_enum, default_value = "bottom")]
+    start_at: StartAt,
+
+    /// Mask the draft in the input box, switched with Ctrl+E while editing
+    #[clap(long, arg_enum, default_value = "on")]
+    input_echo: InputEcho,
+
+    /// What Enter does while editing: `send` the draft, or start a `newline` to compose longer
+    /// messages, sending them with Alt+Enter or Ctrl+S
+    #[clap(long, arg_enum, default_value = "send")]
+    enter: EnterKey,
+
+    /// Watch the topic without being able to send
+    #[clap(long)]
+    read_only: bool,
+
+    /// Remove messages older than this from the list and the history, e.g. `90s`, `30m`, `12h`, `7d`
+    #[clap(long, parse(try_from_str = parse_duration))]
+    message_ttl: Option<Duration>,
+
+    /// Don't connect anywhere, echo sent messages back as if from a peer. For working on the TUI
+    #[clap(long)]
+    loopback: bool,
+
+    /// How long the echo of `--loopback` takes, to simulate latency
+    #[clap(long, default_value = "0s", parse(try_from_str = parse_duration))]
+    loopback_delay: Duration,
+
+    /// Use a fresh random identity for this session instead of the stored secret key
+    #[clap(long)]
+    ephemeral: bool,
+
+    /// Read the secret key from stdin instead of the key store, piped in or typed at a prompt.
+    /// The TOCHAT_KEY environment variable does the same without the prompt. Nothing is written
+    #[clap(long, conflicts_with = "ephemeral")]
+    stdin_key: bool,
+
+    /// Messages received again within this window, e.g. after a reconnect, are shown only once
+    #[clap(long, default_value = "2m", parse(try_from_str = parse_duration))]
+    dedup_window: Duration,
+
+    /// Shared password mixed into the topic, peers without it end up on a different topic
+    #[clap(long, env = "TOCHAT_ROOM_PASSWORD")]
+    room_password: Option<String>,
+
+    /// Quit on the first `q` instead of asking for confirmation
+    #[clap(long)]
+    no_quit_confirm: bool,
+
+    /// Height of the input pane in percent of the terminal, 10 to 80
+    #[clap(long, default_value_t = 20)]
+    input_height: u16,
+
+    /// Height of the status line above the messages in percent of the rest, 5 to 50
+    #[clap(long, default_value_t = 15)]
+    status_height: u16,
+
+    /// Draw with the terminal's own 16 color palette only, no fixed RGB colors
+    #[clap(long)]
+    terminal_colors: bool,
+
+    /// Show each sender's name in a color of its own, picked by its PeerId
+    #[clap(long)]
+    color_names: bool,
+
+    /// Colors `--color-names` picks from, ANSI color names like lightcyan or #rrggbb, comma
+    /// separated. Implies `--color-names`
+    #[clap(long, use_value_delimiter = true, parse(try_from_str = parse_name_color))]
+    name_colors: Vec<Color>,
+
+    /// Leave the terminal window title alone instead of showing the unread count in it
+    #[clap(long)]
+    no_title: bool,
+
+    /// Warn in the input pane when a draft gets longer than this many characters
+    #[clap(long, default_value_t = 2000)]
+    warn_length: usize,
+
+    /// Underline words in the input that aren't in `--dictionary`
+    #[clap(long)]
+    spellcheck: bool,
+
+    /// Word list for `--spellcheck`, one word per line
+    #[clap(long, default_value = "/usr/share/dict/words")]
+    dictionary: PathBuf,
+
+    /// Commands and updates queued between the TUI and the network task. The network task waits
+    /// when the TUI falls behind, the TUI never waits and reports a command it couldn't queue
+    #[clap(long, default_value_t = 32)]
+    channel_capacity: usize,
+
+    /// Only keep connections from these peers besides the relay, others are closed and banned.
+    /// Repeat the flag or separate ids with commas. The dialed `--remote-id` is always accepted
+    #[clap(long, use_value_delimiter = true)]
+    accept_only: Vec<PeerId>,
+
+    /// In dm sessions, give up the direct path for the relay when its averaged ping exceeds this
+    #[clap(long, default_value = "500ms", parse(try_from_str = parse_duration))]
+    max_direct_rtt: Duration,
+
+    /// In dm sessions, give up the direct path for the relay when more of the last 10 pings are lost, in percent
+    #[clap(long, default_value_t = 20)]
+    max_direct_loss: u8,
+
+    /// Least time between two automatic path switches
+    #[clap(long, default_value = "5m", parse(try_from_str = parse_duration))]
+    path_cooldown: Duration,
+
+    /// In channels, ask the first peer found in each room for this many of its latest messages on
+    /// joining, and answer others asking us the same. 0 asks for none
+    #[clap(long, default_value_t = 20)]
+    replay: usize,
+
+    /// In channels, the rendezvous namespace to register and discover peers in. A relay started
+    /// with `--rendezvous-namespace` only admits the namespaces it lists
+    #[clap(long, env = "TOCHAT_RENDEZVOUS_NAMESPACE", default_value = DEFAULT_RENDEZVOUS_NAMESPACE)]
+    rendezvous_namespace: String,
+
+    /// Show our own messages again when the mesh hands them back, e.g. when the same key is
+    /// in the room twice. They are already in the list, so they're dropped by default
+    #[clap(long)]
+    show_own_echoes: bool,
+
+    /// Measure how long chat messages take to arrive, from the time the sender stamps on them,
+    /// split by direct and relayed neighbours, and add it to `/stats`. Clocks differ between
+    /// machines, so the numbers are only as good as their sync
+    #[clap(long)]
+    profile_stats: bool,
+
+    /// Rooms to mute for this session as with `/mute`, which also mutes them in later ones.
+    /// Repeat the flag or separate topics with commas
+    #[clap(long, use_value_delimiter = true)]
+    mute: Vec<String>,
+
+    /// Don't note in the list when the dm peer connects, switches path or disconnects
+    #[clap(long)]
+    no_presence: bool,
+
+    /// Ring the terminal bell with the notes about the dm peer coming and going
+    #[clap(long)]
+    presence_bell: bool,
+
+    /// POST every received message as JSON to this http(s) URL
+    #[clap(long, env = "TOCHAT_WEBHOOK_URL", parse(try_from_str = parse_http_url))]
+    webhook_url: Option<Url>,
+
+    /// Post our own messages to `--webhook-url` too
+    #[clap(long)]
+    webhook_sent: bool,
+
+    /// How long one post to `--webhook-url` may take before it is retried
+    #[clap(long, default_value = "10s", parse(try_from_str = parse_duration))]
+    webhook_timeout: Duration,
+
+    /// Upload images given to `/upload` or pasted as a path to this http(s) URL, as a multipart
+    /// form, and put the link it answers with into the draft
+    #[clap(long, env = "TOCHAT_UPLOAD_URL", parse(try_from_str = parse_http_url))]
+    upload_url: Option<Url>,
+
+    /// Bearer token sent with each upload
+    #[clap(long, env = "TOCHAT_UPLOAD_TOKEN")]
+    upload_token: Option<String>,
+
+    /// Form field `--upload-url` expects the file in
+    #[clap(long, default_value = "file")]
+    upload_field: String,
+
+    /// Publish messages written to this Unix socket as JSON lines, e.g. `{"body": "hi"}`.
+    /// Only our own user may connect
+    #[clap(long)]
+    inject_socket: Option<PathBuf>,
+}
+
+/// Parse a duration made of a number and an optional `ms`, `s`, `m`, `h` or `d` unit, seconds by default
+pub fn parse_duration(s: &str) -> Result<Duration, String> {
+    let s = s.trim();
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (number, unit) = s.split_at(split);
+    let number: u64 = number
+        .parse()
+        .map_err(|_| format!("invalid duration `{}`, expected e.g. 500ms, 90s, 30m, 12h or 7d", s))?;
+    let millis = match unit {
+        "ms" => 1,
+        "" | "s" => 1000,
+        "m" => 60 * 1000,
+        "h" => 60 * 60 * 1000,
+        "d" => 24 * 60 * 60 * 1000,
+        _ => return Err(format!("unknown unit `{}` in duration `{}`, use ms, s, m, h or d", unit, s)),
+    };
+    number
+        .checked_mul(millis)
+        .map(Duration::from_millis)
+        .ok_or_else(|| format!("duration `{}` is too long", s))
+}
+
+/// Parse a `--webhook-url` or `--upload-url`, only http and https can be posted to
+fn parse_http_url(s: &str) -> Result<Url, String> {
+    let url = Url::parse(s.trim()).map_err(|e| format!("invalid url `{}`: {}", s, e))?;
+    match url.scheme() {
+        "http" | "https" => Ok(url),
+        scheme => Err(format!("url `{}` uses {}, only http and https are supported", s, scheme)),
+    }
+}
+
+/// Parse a relay address, which has to end in the relay's `/p2p/<peer id>`: the reservation and
+/// the circuit addresses peers dial are built from it
+pub fn parse_relay_address(s: &str) -> Result<Multiaddr, String> {
+    let addr: Multiaddr = s.trim().parse().map_err(|e| format!("invalid multiaddr `{}`: {}", s, e))?;
+    match addr.iter().last() {
+        Some(Protocol::P2p(_)) => Ok(addr),
+        _ => Err(format!(
+            "relay address `{}` has no peer id, append the relay's `/p2p/<peer id>`, e.g. {}",
+            s, DEFAULT_RELAY_ADDRESS
+        )),
+    }
+}
+
+/// `~/.tochat/config.toml`, every key is optional
+#[derive(Debug, Default, Deserialize)]
+pub struct FileConfig {
+    name: Option<String>,
+    topic: Option<String>,
+    relay_address: Option<String>,
+    relays: Option<Vec<String>>,
+    remote_id: Option<String>,
+}
+
+impl FileConfig {
+    pub fn path() -> PathBuf {
+        tochat_dir().join("config.toml")
+    }
+
+    /// Read the config file, a missing file is the same as an empty one
+    pub fn load() -> Result<FileConfig, Box<dyn Error>> {
+        let path = FileConfig::path();
+        match fs::read_to_string(&path) {
+            Ok(s) => toml::from_str(&s).map_err(|e| format!("{}: {}", path.display(), e).into()),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(FileConfig::default()),
+            Err(e) => Err(format!("{}: {}", path.display(), e).into()),
+        }
+    }
+}
+
+/// The settings a chat session runs with
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub name: String,
+    pub topic: String,
+    /// The topic first, then the ones from `--join`
+    pub rooms: Vec<String>,
+    pub relay_address: Multiaddr,
+    /// The relays to pick `relay_address` from, empty when it was given
+    pub relays: Vec<Multiaddr>,
+    /// How fast the picked relay answered its probe, none unless it was picked from `relays`
+    pub relay_rtt: Option<Duration>,
+    pub remote_id: Option<PeerId>,
+    pub mode: Mode,
+    pub dial_attempts: u32,
+    /// How often peers are pinged, libp2p's default unless `tochat ping` sets it
+    pub ping_interval: Option<Duration>,
+    pub relay_reconnect_attempts: u32,
+    pub protocol_version: String,
+    pub agent_version: String,
+    pub strict_version: bool,
+    pub history: bool,
+    pub idle_timeout: Option<Duration>,
+    pub max_message_size: usize,
+    pub emoji: bool,
+    pub grouped: bool,
+    pub compact: bool,
+    pub show_peer_ids: bool,
+    pub arrival_order: bool,
+    pub read_only: bool,
+    pub message_ttl: Option<Duration>,
+    pub loopback: bool,
+    pub loopback_delay: Duration,
+    /// The throwaway secret of an `--ephemeral` session, `None` means the stored one is used
+    pub ephemeral_secret: Option<Secret>,
+    /// The secret from `--stdin-key` or `TOCHAT_KEY`, used instead of the stored one
+    pub inline_secret: Option<Secret>,
+    /// Where the secret key is read from, see `--secret-store`
+    pub secret_store: Backend,
+    pub dedup_window: Duration,
+    pub room_password: Option<Secret>,
+    pub quit_confirm: bool,
+    pub input_height: u16,
+    pub status_height: u16,
+    pub terminal_colors: bool,
+    /// Sender colors, empty without `--color-names`
+    pub color_names: Vec<Color>,
+    pub title: bool,
+    pub warn_length: usize,
+    /// The word list when `--spellcheck` is on
+    pub spellcheck: Option<PathBuf>,
+    pub channel_capacity: usize,
+    pub own_echoes: bool,
+    pub profile_stats: bool,
+    pub replay: usize,
+    pub rendezvous_namespace: String,
+    pub path_thresholds: Thresholds,
+    /// Empty accepts everyone
+    pub accept_only: Vec<PeerId>,
+    pub mute: Vec<String>,
+    pub presence: bool,
+    pub presence_bell: bool,
+    /// What part of the history is loaded
+    pub window: Window,
+    pub start_at: StartAt,
+    pub input_echo: InputEcho,
+    pub enter: EnterKey,
+    pub webhook_url: Option<Url>,
+    pub webhook_sent: bool,
+    pub webhook_timeout: Duration,
+    pub upload_url: Option<Url>,
+    pub upload_token: Option<Secret>,
+    pub upload_field: String,
+    pub inject_socket: Option<PathBuf>,
+}
+
+impl SessionArgs {
+    /// Whether stdin is taken by `--stdin-key`, the TUI reads the terminal directly then
+    pub fn key_on_stdin(&self) -> bool {
+        self.stdin_key
+    }
+
+    /// The secret given for this session instead of the stored one, read by `read_stdin` with
+    /// `--stdin-key` or `env_key` from TOCHAT_KEY
+    fn inline_secret(
+        &self,
+        env_key: Option<String>,
+        read_stdin: impl FnOnce() -> Result<String, Box<dyn Error>>,
+    ) -> Result<Option<Secret>, Box<dyn Error>> {
+        Ok(match (self.stdin_key, env_key) {
+            (true, Some(_)) => return Err("pass the key either with --stdin-key or in TOCHAT_KEY, not both".into()),
+            (true, None) => Some(Secret(read_stdin()?)),
+            (false, Some(_)) if self.ephemeral => return Err("--ephemeral ignores TOCHAT_KEY, unset one of them".into()),
+            (false, Some(key)) if !key.trim().is_empty() => Some(Secret(key.trim().to_string())),
+            (false, _) => None,
+        })
+    }
+
+    /// The session flags of a program in `src/bin` that offers only some of them, passed on as
+    /// `--flag value` arguments. The others keep their defaults and environment variables
+    pub fn from_flags(flags: Vec<String>) -> Result<SessionArgs, clap::Error> {
+        #[derive(Parser)]
+        struct Flags {
+            #[clap(flatten)]
+            session: SessionArgs,
+        }
+        let args = std::iter::once("tochat".to_string()).chain(flags);
+        Flags::try_parse_from(args).map(|flags| flags.session)
+    }
+}
+
+impl Config {
+    /// Layer flags and environment (already merged by clap) over the config file over the defaults
+    pub fn resolve(
+        args: &SessionArgs,
+        remote_id: Option<PeerId>,
+        mode: Option<Mode>,
+        file: FileConfig,
+        secret_store: Backend,
+    ) -> Result<Config, Box<dyn Error>> {
+        let missing = |key: &str| {
+            format!(
+                "no {} given, pass --{} or set `{}` in {}",
+                key,
+                key,
+                key,
+                FileConfig::path().display()
+            )
+        };
+        let ephemeral_secret = if args.ephemeral { Some(Secret(ephemeral_secret())) } else { None };
+        // left in the environment, `tochat` takes it out once the config is resolved
+        let inline_secret = args.inline_secret(env::var("TOCHAT_KEY").ok(), read_stdin_secret)?;
+        // without a name we go by one derived from our key, which has to exist by now anyway
+        let name = match args.name.clone().or(file.name) {
+            Some(name) => name,
+            None => {
+                let secret = match (&ephemeral_secret, &inline_secret) {
+                    (Some(Secret(secret)), _) | (None, Some(Secret(secret))) => secret.clone(),
+                    (None, None) => get_secret(&*secret_store.open()?)?,
+                };
+                derived_name(&PeerId::from(generate_ed25519(&secret).public()))
+            }
+        };
+        let topic = args.topic.clone().or(file.topic).ok_or_else(|| missing("topic"))?;
+        let mut rooms = vec![topic.clone()];
+        for room in args.join.iter().map(|room| room.trim()).filter(|room| !room.is_empty()) {
+            if !rooms.iter().any(|joined| joined == room) {
+                rooms.push(room.to_string());
+            }
+        }
+
+        let relays = match (&args.relay_address, &file.relay_address, file.relays) {
+            (Some(_), _, _) | (None, Some(_), _) => Vec::new(),
+            (None, None, _) if !args.relays.is_empty() => args.relays.clone(),
+            (None, None, Some(relays)) if !relays.is_empty() => relays
+                .iter()
+                .map(|addr| parse_relay_address(addr))
+                .collect::<Result<_, _>>()
+                .map_err(|e| format!("invalid relays in config file: {}", e))?,
+            (None, None, _) => BOOTSTRAP_RELAYS.iter().map(|addr| addr.parse()).collect::<Result<_, _>>()?,
+        };
+        let relay_address = match (&args.relay_address, file.relay_address) {
+            (Some(addr), _) => addr.clone(),
+            (None, Some(addr)) => parse_relay_address(&addr)
+                .map_err(|e| format!("invalid relay_address in config file: {}", e))?,
+            // until `relay_pick::rank` has probed them
+            (None, None) => relays[0].clone(),
+        };
+        let remote_id = match (remote_id, file.remote_id) {
+            (Some(id), _) => Some(id),
+            (None, Some(id)) => Some(
+                id.parse()
+                    .map_err(|e| format!("invalid remote_id in config file: {}", e))?,
+            ),
+            (None, None) => None,
+        };
+        let mode = match (mode, remote_id) {
+            (Some(Mode::Dial), None) => {
+                return Err("--mode dial needs the peer to dial, pass --remote-id".into())
+            }
+            (Some(mode), _) => mode,
+            (None, Some(_)) => Mode::Dial,
+            (None, None) => Mode::Listen,
+        };
+
+        if !INPUT_HEIGHT.contains(&args.input_height) {
+            return Err(format!("--input-height must be between {} and {}", INPUT_HEIGHT.start(), INPUT_HEIGHT.end()).into());
+        }
+        if !STATUS_HEIGHT.contains(&args.status_height) {
+            return Err(format!("--status-height must be between {} and {}", STATUS_HEIGHT.start(), STATUS_HEIGHT.end()).into());
+        }
+        if args.max_direct_loss > 100 {
+            return Err("--max-direct-loss is a percentage, at most 100".into());
+        }
+        if args.channel_capacity == 0 {
+            return Err("--channel-capacity must be at least 1".into());
+        }
+        if args.replay > MAX_REPLAY {
+            return Err(format!("--replay is at most {}", MAX_REPLAY).into());
+        }
+        if let Err(e) = Namespace::new(args.rendezvous_namespace.clone()) {
+            return Err(format!("invalid --rendezvous-namespace: {}", e).into());
+        }
+
+        Ok(Config {
+            name,
+            topic,
+            rooms,
+            relay_address,
+            relays,
+            relay_rtt: None,
+            remote_id,
+            mode,
+            dial_attempts: args.dial_attempts,
+            ping_interval: None,
+            relay_reconnect_attempts: args.relay_reconnect_attempts,
+            protocol_version: args.protocol_version.clone(),
+            agent_version: args.agent_version.clone().unwrap_or_else(default_agent_version),
+            strict_version: args.strict_version,
+            history: !args.no_history,
+            idle_timeout: args.idle_timeout,
+            max_message_size: args.max_message_size,
+            emoji: !args.no_emoji,
+            grouped: args.grouped,
+            compact: args.compact,
+            show_peer_ids: args.show_peer_ids,
+            arrival_order: args.arrival_order,
+            read_only: args.read_only,
+            message_ttl: args.message_ttl,
+            loopback: args.loopback,
+            loopback_delay: args.loopback_delay,
+            ephemeral_secret,
+            inline_secret,
+            secret_store,
+            dedup_window: args.dedup_window,
+            room_password: args.room_password.clone().map(Secret),
+            quit_confirm: !args.no_quit_confirm,
+            input_height: args.input_height,
+            status_height: args.status_height,
+            terminal_colors: args.terminal_colors,
+            color_names: match (args.color_names, args.name_colors.is_empty()) {
+                (_, false) => args.name_colors.clone(),
+                (true, true) => NAME_COLORS.to_vec(),
+                (false, true) => Vec::new(),
+            },
+            title: !args.no_title,
+            warn_length: args.warn_length,
+            spellcheck: args.spellcheck.then(|| args.dictionary.clone()),
+            channel_capacity: args.channel_capacity,
+            own_echoes: args.show_own_echoes,
+            profile_stats: args.profile_stats,
+            replay: args.replay,
+            rendezvous_namespace: args.rendezvous_namespace.clone(),
+            path_thresholds: Thresholds {
+                max_rtt: args.max_direct_rtt,
+                max_loss: args.max_direct_loss as f64 / 100.0,
+                cooldown: args.path_cooldown,
+            },
+            accept_only: args.accept_only.clone(),
+            mute: args.mute.clone(),
+            presence: !args.no_presence,
+            presence_bell: args.presence_bell,
+            window: Window { since: args.since, until: args.until, max: args.max_messages },
+            start_at: args.start_at,
+            input_echo: args.input_echo,
+            enter: args.enter,
+            webhook_url: args.webhook_url.clone(),
+            webhook_sent: args.webhook_sent,
+            webhook_timeout: args.webhook_timeout,
+            upload_url: args.upload_url.clone(),
+            upload_token: args.upload_token.clone().map(Secret),
+            upload_field: args.upload_field.clone(),
+            inject_socket: args.inject_socket.clone(),
+        })
+    }
+
+    /// Whether the session goes through the built-in relay, see `DEFAULT_RELAY_NOTICE`
+    pub fn default_relay(&self) -> bool {
+        self.relay_address.to_string() == DEFAULT_RELAY_ADDRESS
+    }
+
+    /// The gossipsub topic actually joined. With `--room-password` it is a hash of the room name and
+    /// the password, so guessing the name alone doesn't find the room. Anyone holding the password
+    /// can still read along, the messages themselves are not encrypted
+    pub fn gossip_topic(&self) -> String {
+        self.gossip_topic_of(&self.topic)
+    }
+
+    /// The gossipsub topics of all `rooms`, in the same order
+    pub fn gossip_topics(&self) -> Vec<String> {
+        self.rooms.iter().map(|room| self.gossip_topic_of(room)).collect()
+    }
+
+    fn gossip_topic_of(&self, room: &str) -> String {
+        match &self.room_password {
+            Some(Secret(password)) => {
+                let preimage = format!("tochat-room\0{}\0{}", room, password);
+                let hash: String = keccak256(preimage.as_bytes()).iter().map(|b| format!("{:02x}", b)).collect();
+                format!("tochat-room/{}", hash)
+            }
+            None => room.to_string(),
+        }
+    }
+
+    /// The secret the session's identity is derived from, see `get_secret` for the errors
+    pub fn secret(&self) -> Result<String, Box<dyn Error>> {
+        match (&self.ephemeral_secret, &self.inline_secret) {
+            (Some(Secret(secret)), _) | (None, Some(Secret(secret))) => Ok(secret.clone()),
+            (None, None) => get_secret(&*self.secret_store.open()?),
+        }
+    }
+}
+
+/// Printed by `tochat config`, the first keys can be pasted into the config file
+impl fmt::Display for Config {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "name = {:?}", self.name)?;
+        writeln!(f, "topic = {:?}", self.topic)?;
+        if self.rooms.len() > 1 {
+            writeln!(f, "# join = {:?}", &self.rooms[1..])?;
+        }
+        if self.room_password.is_some() {
+            writeln!(f, "# room_password is set, the gossipsub topic is {:?}", self.gossip_topic())?;
+        }
+        writeln!(f, "relay_address = {:?}", self.relay_address.to_string())?;
+        if self.default_relay() {
+            writeln!(f, "# the built-in public relay, run by a third party, consider running your own")?;
+        }
+        if self.relays.len() > 1 {
+            let relays: Vec<String> = self.relays.iter().map(|addr| addr.to_string()).collect();
+            writeln!(f, "# relays = {:?}, relay_address is picked from them when connecting", relays)?;
+        }
+        match &self.remote_id {
+            Some(id) => writeln!(f, "remote_id = {:?}", id.to_base58())?,
+            None => writeln!(f, "# remote_id is unset")?,
+        }
+        writeln!(f, "# mode = {:?}", self.mode)?;
+        writeln!(f, "# dial_attempts = {}", self.dial_attempts)?;
+        writeln!(f, "# relay_reconnect_attempts = {}", self.relay_reconnect_attempts)?;
+        writeln!(f, "# protocol_version = {:?}", self.protocol_version)?;
+        writeln!(f, "# agent_version = {:?}", self.agent_version)?;
+        writeln!(f, "# strict_version = {}", self.strict_version)?;
+        writeln!(f, "# history = {}", self.history)?;
+        match self.idle_timeout {
+            Some(timeout) => writeln!(f, "# idle_timeout = \"{}s\"", timeout.as_secs())?,
+            None => writeln!(f, "# idle_timeout is unset")?,
+        }
+        writeln!(f, "# max_message_size = {}", self.max_message_size)?;
+        writeln!(f, "# emoji = {}", self.emoji)?;
+        writeln!(f, "# grouped = {}", self.grouped)?;
+        writeln!(f, "# compact = {}", self.compact)?;
+        writeln!(f, "# show_peer_ids = {}", self.show_peer_ids)?;
+        writeln!(f, "# arrival_order = {}", self.arrival_order)?;
+        writeln!(f, "# read_only = {}", self.read_only)?;
+        match self.message_ttl {
+            Some(ttl) => writeln!(f, "# message_ttl = \"{}s\"", ttl.as_secs())?,
+            None => writeln!(f, "# message_ttl is unset")?,
+        }
+        writeln!(f, "# loopback = {}", self.loopback)?;
+        writeln!(f, "# ephemeral = {}", self.ephemeral_secret.is_some())?;
+        match self.inline_secret {
+            Some(_) => writeln!(f, "# the secret key is given inline, the store isn't read")?,
+            None => writeln!(f, "# secret_store = {}", self.secret_store)?,
+        }
+        writeln!(f, "# dedup_window = \"{}s\"", self.dedup_window.as_secs())?;
+        writeln!(f, "# quit_confirm = {}", self.quit_confirm)?;
+        writeln!(f, "# input_height = {}", self.input_height)?;
+        writeln!(f, "# status_height = {}", self.status_height)?;
+        writeln!(f, "# terminal_colors = {}", self.terminal_colors)?;
+        writeln!(f, "# color_names = {:?}", self.color_names)?;
+        writeln!(f, "# title = {}", self.title)?;
+        writeln!(f, "# warn_length = {}", self.warn_length)?;
+        match &self.spellcheck {
+            Some(dictionary) => writeln!(f, "# spellcheck with {}", dictionary.display())?,
+            None => writeln!(f, "# spellcheck is off")?,
+        }
+        writeln!(f, "# channel_capacity = {}", self.channel_capacity)?;
+        writeln!(f, "# own_echoes = {}", self.own_echoes)?;
+        writeln!(f, "# profile_stats = {}", self.profile_stats)?;
+        writeln!(f, "# replay = {}", self.replay)?;
+        writeln!(f, "# rendezvous_namespace = {:?}", self.rendezvous_namespace)?;
+        writeln!(f, "# max_direct_rtt = \"{}ms\"", self.path_thresholds.max_rtt.as_millis())?;
+        writeln!(f, "# max_direct_loss = {}", (self.path_thresholds.max_loss * 100.0).round())?;
+        writeln!(f, "# path_cooldown = \"{}s\"", self.path_thresholds.cooldown.as_secs())?;
+        let accept_only: Vec<String> = self.accept_only.iter().map(PeerId::to_base58).collect();
+        writeln!(f, "# accept_only = {:?}", accept_only)?;
+        writeln!(f, "# mute = {:?}", self.mute)?;
+        writeln!(f, "# presence = {}", self.presence)?;
+        writeln!(f, "# presence_bell = {}", self.presence_bell)?;
+        writeln!(f, "# since = {:?}", self.window.since)?;
+        writeln!(f, "# until = {:?}", self.window.until)?;
+        writeln!(f, "# max_messages = {:?}", self.window.max)?;
+        writeln!(f, "# start_at = {:?}", self.start_at)?;
+        writeln!(f, "# input_echo = {:?}", self.input_echo)?;
+        writeln!(f, "# enter = {:?}", self.enter)?;
+        match &self.webhook_url {
+            Some(url) => writeln!(f, "# webhook_url = \"{}\"", url)?,
+            None => writeln!(f, "# webhook_url is unset")?,
+        }
+        writeln!(f, "# webhook_sent = {}", self.webhook_sent)?;
+        writeln!(f, "# webhook_timeout = \"{}s\"", self.webhook_timeout.as_secs())?;
+        match &self.upload_url {
+            Some(url) => writeln!(f, "# upload_url = \"{}\"", url)?,
+            None => writeln!(f, "# upload_url is unset")?,
+        }
+        if self.upload_token.is_some() {
+            writeln!(f, "# upload_token is set")?;
+        }
+        writeln!(f, "# upload_field = {:?}", self.upload_field)?;
+        match &self.inject_socket {
+            Some(path) => write!(f, "# inject_socket = \"{}\"", path.display()),
+            None => write!(f, "# inject_socket is unset"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_duration_units() {
+        assert_eq!(parse_duration("500ms"), Ok(Duration::from_millis(500)));
+        assert_eq!(parse_duration("90"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration("90s"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration("30m"), Ok(Duration::from_secs(30 * 60)));
+        assert_eq!(parse_duration(" 12h "), Ok(Duration::from_secs(12 * 60 * 60)));
+        assert_eq!(parse_duration("7d"), Ok(Duration::from_secs(7 * 24 * 60 * 60)));
+        assert_eq!(parse_duration("0s"), Ok(Duration::ZERO));
+    }
+
+    #[test]
+    fn parse_duration_overflow() {
+        assert!(parse_duration("18446744073709551615d").unwrap_err().contains("too long"));
+        assert!(parse_duration("18446744073709551615s").is_err());
+        assert_eq!(parse_duration("18446744073709551615ms"), Ok(Duration::from_millis(u64::MAX)));
+        // too many digits for a number at all
+        assert!(parse_duration("99999999999999999999999ms").is_err());
+    }
+
+    #[test]
+    fn parse_duration_garbage() {
+        assert!(parse_duration("").is_err());
+        assert!(parse_duration("s").is_err());
+        assert!(parse_duration("-5s").is_err());
+        assert!(parse_duration("1.5h").is_err());
+        assert!(parse_duration("5 s").is_err());
+        assert!(parse_duration("5w").unwrap_err().contains("unknown unit"));
+    }
+
+    #[test]
+    fn ctrl_e_cycles_through_the_input_echoes() {
+        assert_eq!(InputEcho::On.next(), InputEcho::Dots);
+        assert_eq!(InputEcho::Dots.next(), InputEcho::Off);
+        assert_eq!(InputEcho::Off.next(), InputEcho::On);
+    }
+
+    fn session(flags: &[&str]) -> SessionArgs {
+        SessionArgs::from_flags(flags.iter().map(|flag| flag.to_string()).collect()).unwrap()
+    }
+
+    fn no_stdin() -> Result<String, Box<dyn Error>> {
+        panic!("stdin is only read with --stdin-key")
+    }
+
+    fn inline(secret: Option<Secret>) -> Option<String> {
+        secret.map(|Secret(secret)| secret)
+    }
+
+    #[test]
+    fn the_key_comes_from_the_environment_or_stdin() {
+        let args = session(&[]);
+        assert_eq!(inline(args.inline_secret(Some(" abc \n".to_string()), no_stdin).unwrap()), Some("abc".to_string()));
+        assert_eq!(inline(args.inline_secret(Some(" ".to_string()), no_stdin).unwrap()), None);
+        assert_eq!(inline(args.inline_secret(None, no_stdin).unwrap()), None);
+
+        let args = session(&["--stdin-key"]);
+        assert_eq!(inline(args.inline_secret(None, || Ok("typed".to_string())).unwrap()), Some("typed".to_string()));
+    }
+
+    #[test]
+    fn the_key_is_given_one_way_only() {
+        let both = session(&["--stdin-key"]).inline_secret(Some("abc".to_string()), no_stdin);
+        assert!(both.unwrap_err().to_string().contains("not both"));
+        let ephemeral = session(&["--ephemeral"]).inline_secret(Some("abc".to_string()), no_stdin);
+        assert!(ephemeral.unwrap_err().to_string().contains("--ephemeral ignores TOCHAT_KEY"));
+        assert!(SessionArgs::from_flags(vec!["--stdin-key".to_string(), "--ephemeral".to_string()]).is_err());
+    }
+
+    #[test]
+    fn an_inline_key_is_the_same_identity_as_the_stored_one() {
+        let dir = std::env::temp_dir().join(format!("tochat-inline-key-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let store = Backend::File.open_named_in(&dir, "secret").unwrap();
+        store.save(&ephemeral_secret()).unwrap();
+        let stored = get_secret(&*store).unwrap();
+
+        let mut config = Config::resolve(&session(&["--name=test", "--ephemeral"]), None, None, FileConfig::default(), Backend::File).unwrap();
+        config.ephemeral_secret = None;
+        config.inline_secret = Some(Secret(stored.clone()));
+        let identity = |secret: &String| PeerId::from(generate_ed25519(secret).public());
+        assert_eq!(identity(&config.secret().unwrap()), identity(&stored));
+    }
+
+    #[test]
+    fn relay_addresses_need_the_peer_id() {
+        assert!(parse_relay_address(DEFAULT_RELAY_ADDRESS).is_ok());
+        assert!(parse_relay_address(&format!(" {} ", DEFAULT_RELAY_ADDRESS)).is_ok());
+        assert!(parse_relay_address("/ip4/127.0.0.1/tcp/4001").unwrap_err().contains("no peer id"));
+        // a circuit through the relay is not the relay
+        let circuit = format!("{}/p2p-circuit", DEFAULT_RELAY_ADDRESS);
+        assert!(parse_relay_address(&circuit).unwrap_err().contains("no peer id"));
+        assert!(parse_relay_address("relay.example.com:4001").unwrap_err().contains("invalid multiaddr"));
+    }
+}
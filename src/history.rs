@@ -0,0 +1,347 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use chrono::{DateTime, Local, NaiveDate, NaiveDateTime, TimeZone};
+use log::warn;
+
+use crate::config::parse_duration;
+use crate::message::{ChatMessage, Delivery, Kind};
+use crate::network::secure::tochat_dir;
+
+/// Chat history of one topic, stored as JSON lines in `~/.tochat/history/<topic>.jsonl`
+pub struct History {
+    path: PathBuf,
+    /// Messages `--since` or `--max-messages` left out of the session, written back before the
+    /// ones shown so `save` doesn't drop them from the file
+    older: Vec<ChatMessage>,
+    /// Messages after `--until`, merged back in by time on `save`
+    newer: Vec<ChatMessage>,
+}
+
+/// A `--since`/`--until` bound
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TimeBound {
+    /// This long before the session starts, e.g. `2h`
+    Ago(Duration),
+    /// A whole day in local time, e.g. `2022-10-01`. Both bounds include it
+    Day(NaiveDate),
+    /// A moment, e.g. `2022-10-01T12:00` or with an offset as in RFC 3339
+    At(DateTime<Local>),
+}
+
+/// Parse a `--since`/`--until`: a duration back from now, a day or a time
+pub fn parse_time_bound(s: &str) -> Result<TimeBound, String> {
+    let s = s.trim();
+    if let Ok(day) = NaiveDate::parse_from_str(s, "%Y-%m-%d") {
+        return Ok(TimeBound::Day(day));
+    }
+    if let Ok(at) = DateTime::parse_from_rfc3339(s) {
+        return Ok(TimeBound::At(at.with_timezone(&Local)));
+    }
+    for format in ["%Y-%m-%dT%H:%M:%S", "%Y-%m-%dT%H:%M", "%Y-%m-%d %H:%M:%S", "%Y-%m-%d %H:%M"] {
+        if let Ok(at) = NaiveDateTime::parse_from_str(s, format) {
+            return local(at).map(TimeBound::At).ok_or_else(|| format!("{} doesn't exist in the local time zone", s));
+        }
+    }
+    parse_duration(s).map(TimeBound::Ago).map_err(|_| {
+        format!("invalid time `{}`, expected a duration like 2h or 3d, a day like 2022-10-01 or a time like 2022-10-01T12:00", s)
+    })
+}
+
+fn local(at: NaiveDateTime) -> Option<DateTime<Local>> {
+    Local.from_local_datetime(&at).earliest()
+}
+
+/// Which part of the history a session loads, from `--since`, `--until` and `--max-messages`
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Window {
+    pub since: Option<TimeBound>,
+    pub until: Option<TimeBound>,
+    /// Only the newest this many of the messages in the time range
+    pub max: Option<usize>,
+}
+
+impl Window {
+    /// The first moment in the window and the first after it, relative to `now`
+    fn range(&self, now: DateTime<Local>) -> (Option<DateTime<Local>>, Option<DateTime<Local>>) {
+        let start_of = |day: NaiveDate| day.and_hms_opt(0, 0, 0).and_then(local);
+        let ago = |d: Duration| chrono::Duration::from_std(d).ok().and_then(|d| now.checked_sub_signed(d));
+        let since = self.since.and_then(|bound| match bound {
+            TimeBound::Ago(d) => ago(d),
+            TimeBound::Day(day) => start_of(day),
+            TimeBound::At(at) => Some(at),
+        });
+        let end = self.until.and_then(|bound| match bound {
+            TimeBound::Ago(d) => ago(d).map(|at| at + chrono::Duration::nanoseconds(1)),
+            TimeBound::Day(day) => day.succ_opt().and_then(start_of),
+            TimeBound::At(at) => Some(at + chrono::Duration::nanoseconds(1)),
+        });
+        (since, end)
+    }
+}
+
+impl History {
+    pub fn open(topic: &str) -> io::Result<History> {
+        let dir = tochat_dir().join("history");
+        fs::create_dir_all(&dir)?;
+        Ok(History {
+            path: dir.join(format!("{}.jsonl", file_name(topic))),
+            older: Vec::new(),
+            newer: Vec::new(),
+        })
+    }
+
+    /// Read the persisted messages in `window`, which may leave none. Messages that were still
+    /// pending when tochat last exited are marked unconfirmed, lines from older files without a
+    /// delivery state load as delivered
+    pub fn load(&mut self, window: &Window) -> io::Result<Vec<ChatMessage>> {
+        let lines = match read(&self.path) {
+            Ok(lines) => lines,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+
+        let (since, end) = window.range(Local::now());
+        let mut messages = Vec::new();
+        for message in lines {
+            let mut message = message?;
+            if message.delivery == Delivery::Pending {
+                message.delivery = Delivery::Unconfirmed;
+            }
+            if since.map_or(false, |since| message.timestamp < since) {
+                self.older.push(message);
+            } else if end.map_or(false, |end| message.timestamp >= end) {
+                self.newer.push(message);
+            } else {
+                messages.push(message);
+            }
+        }
+        if let Some(max) = window.max {
+            let cut = messages.len().saturating_sub(max);
+            self.older.extend(messages.drain(..cut));
+        }
+        Ok(messages)
+    }
+
+    pub fn append(&self, message: &ChatMessage) -> io::Result<()> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        writeln!(file, "{}", serde_json::to_string(message)?)
+    }
+
+    /// Rewrite the whole file, used when the delivery state of a message changes.
+    /// This also migrates older files since every line is written with all fields
+    pub fn save(&self, messages: &[ChatMessage]) -> io::Result<()> {
+        let tmp = self.path.with_extension("jsonl.tmp");
+        {
+            let mut buf = BufWriter::new(File::create(&tmp)?);
+            // the messages after `--until` go back among the session's by time, the ones
+            // loaded come before them and the ones received since after
+            let (mut shown, mut newer) = (messages.iter().peekable(), self.newer.iter().peekable());
+            let merged = std::iter::from_fn(|| match (shown.peek(), newer.peek()) {
+                (Some(m), Some(n)) if n.timestamp < m.timestamp => newer.next(),
+                (Some(_), _) => shown.next(),
+                (None, _) => newer.next(),
+            });
+            for message in self.older.iter().chain(merged).filter(|m| m.kind != Kind::System) {
+                writeln!(buf, "{}", serde_json::to_string(message)?)?;
+            }
+            buf.flush()?;
+        }
+        fs::rename(tmp, &self.path)
+    }
+
+    /// Forget the messages left out of the session that were received before `cutoff`, for
+    /// `--message-ttl`. Whether there were any, the next `save` drops them from the file
+    pub fn expire(&mut self, cutoff: DateTime<Local>) -> bool {
+        let before = self.older.len() + self.newer.len();
+        self.older.retain(|m| m.timestamp >= cutoff);
+        self.newer.retain(|m| m.timestamp >= cutoff);
+        self.older.len() + self.newer.len() < before
+    }
+
+    /// When the newest message seen before tochat last quit was received, for `--start-at unread`.
+    /// `None` before the first session that recorded it
+    pub fn last_read(&self) -> Option<DateTime<Local>> {
+        let marker = fs::read_to_string(self.read_marker()).ok()?;
+        DateTime::parse_from_rfc3339(marker.trim()).ok().map(|at| at.with_timezone(&Local))
+    }
+
+    pub fn mark_read(&self, at: DateTime<Local>) -> io::Result<()> {
+        fs::write(self.read_marker(), at.to_rfc3339())
+    }
+
+    /// Kept next to the history as `<topic>.read`
+    fn read_marker(&self) -> PathBuf {
+        self.path.with_extension("read")
+    }
+}
+
+/// The messages of a history file one line at a time, so a large file is never held in memory.
+/// Unreadable lines are skipped with a warning
+pub fn read(path: &Path) -> io::Result<impl Iterator<Item = io::Result<ChatMessage>>> {
+    let path = path.to_path_buf();
+    let lines = BufReader::new(File::open(&path)?).lines();
+    Ok(lines.filter_map(move |line| {
+        let line = match line {
+            Ok(line) => line,
+            Err(e) => return Some(Err(e)),
+        };
+        if line.trim().is_empty() {
+            return None;
+        }
+        match serde_json::from_str::<ChatMessage>(&line) {
+            Ok(message) => Some(Ok(message)),
+            Err(e) => {
+                warn!("Skipping unreadable line in {}: {}", path.display(), e);
+                None
+            }
+        }
+    }))
+}
+
+/// The history files kept so far with the topic each one is for, as far as its file name tells,
+/// sorted by topic. Only `topic`'s file when it is given
+pub fn files(topic: Option<&str>) -> io::Result<Vec<(String, PathBuf)>> {
+    let dir = tochat_dir().join("history");
+    if let Some(topic) = topic {
+        let path = dir.join(format!("{}.jsonl", file_name(topic)));
+        return Ok(if path.exists() { vec![(topic.to_string(), path)] } else { Vec::new() });
+    }
+    let entries = match fs::read_dir(&dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    let mut files = Vec::new();
+    for entry in entries {
+        let path = entry?.path();
+        if path.extension().map_or(false, |ext| ext == "jsonl") {
+            if let Some(stem) = path.file_stem() {
+                files.push((stem.to_string_lossy().into_owned(), path));
+            }
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Topics are free text, keep them to a safe file name
+fn file_name(topic: &str) -> String {
+    topic
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(y: i32, m: u32, d: u32, h: u32, min: u32) -> DateTime<Local> {
+        local(NaiveDate::from_ymd_opt(y, m, d).unwrap().and_hms_opt(h, min, 0).unwrap()).unwrap()
+    }
+
+    /// A history in a fresh file under the temp dir holding five messages an hour apart
+    fn history(test: &str, base: DateTime<Local>) -> History {
+        let dir = std::env::temp_dir().join(format!("tochat-{}-{}", test, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let history = History { path: dir.join("room.jsonl"), older: Vec::new(), newer: Vec::new() };
+        for i in 0..5 {
+            let mut message = ChatMessage::remote("alice", format!("message {}", i));
+            message.timestamp = base + chrono::Duration::hours(i);
+            history.append(&message).unwrap();
+        }
+        history
+    }
+
+    fn bodies(messages: &[ChatMessage]) -> Vec<&str> {
+        messages.iter().map(|m| m.body.as_str()).collect()
+    }
+
+    #[test]
+    fn parses_days_times_and_durations() {
+        let day = NaiveDate::from_ymd_opt(2022, 10, 1).unwrap();
+        assert_eq!(parse_time_bound("2022-10-01"), Ok(TimeBound::Day(day)));
+        assert_eq!(parse_time_bound("2022-10-01T12:00"), Ok(TimeBound::At(at(2022, 10, 1, 12, 0))));
+        assert_eq!(parse_time_bound("2022-10-01 12:00"), Ok(TimeBound::At(at(2022, 10, 1, 12, 0))));
+        let offset = DateTime::parse_from_rfc3339("2022-10-01T12:00:00+02:00").unwrap().with_timezone(&Local);
+        assert_eq!(parse_time_bound("2022-10-01T12:00:00+02:00"), Ok(TimeBound::At(offset)));
+        assert_eq!(parse_time_bound(" 2h "), Ok(TimeBound::Ago(Duration::from_secs(2 * 60 * 60))));
+        assert!(parse_time_bound("soon").unwrap_err().starts_with("invalid time `soon`"));
+    }
+
+    #[test]
+    fn a_day_covers_the_whole_day() {
+        let day = NaiveDate::from_ymd_opt(2022, 10, 1).unwrap();
+        let window = Window { since: Some(TimeBound::Day(day)), until: Some(TimeBound::Day(day)), max: None };
+        let (since, end) = window.range(Local::now());
+        assert_eq!(since, Some(at(2022, 10, 1, 0, 0)));
+        assert_eq!(end, Some(at(2022, 10, 2, 0, 0)));
+    }
+
+    #[test]
+    fn durations_count_back_from_now() {
+        let now = Local::now();
+        let window = Window { since: Some(TimeBound::Ago(Duration::from_secs(60))), until: None, max: None };
+        assert_eq!(window.range(now), (Some(now - chrono::Duration::seconds(60)), None));
+    }
+
+    #[test]
+    fn loads_the_window_and_saves_everything_back() {
+        let base = Local::now() - chrono::Duration::hours(10);
+        let mut history = history("history-window", base);
+        let window = Window {
+            since: Some(TimeBound::At(base + chrono::Duration::hours(1))),
+            until: Some(TimeBound::At(base + chrono::Duration::hours(3))),
+            max: None,
+        };
+        let mut messages = history.load(&window).unwrap();
+        assert_eq!(bodies(&messages), ["message 1", "message 2", "message 3"]);
+
+        messages.push(ChatMessage::remote("bob", "new".to_string()));
+        history.save(&messages).unwrap();
+        let saved: Vec<ChatMessage> = read(&history.path).unwrap().map(Result::unwrap).collect();
+        assert_eq!(bodies(&saved), ["message 0", "message 1", "message 2", "message 3", "message 4", "new"]);
+    }
+
+    #[test]
+    fn max_messages_keeps_the_newest() {
+        let base = Local::now() - chrono::Duration::hours(10);
+        let mut history = history("history-max", base);
+        let messages = history.load(&Window { max: Some(2), ..Window::default() }).unwrap();
+        assert_eq!(bodies(&messages), ["message 3", "message 4"]);
+        history.save(&messages).unwrap();
+        assert_eq!(read(&history.path).unwrap().count(), 5);
+    }
+
+    #[test]
+    fn expired_messages_outside_the_window_leave_the_file() {
+        let base = Local::now() - chrono::Duration::hours(10);
+        let mut history = history("history-expire", base);
+        let window = Window {
+            since: Some(TimeBound::At(base + chrono::Duration::hours(1))),
+            until: Some(TimeBound::At(base + chrono::Duration::hours(3))),
+            max: None,
+        };
+        let messages = history.load(&window).unwrap();
+        // as `--message-ttl` does at a cutoff after message 0, left out by `--since`
+        let cutoff = base + chrono::Duration::minutes(30);
+        assert!(history.expire(cutoff));
+        assert!(!history.expire(cutoff));
+        history.save(&messages).unwrap();
+        let saved: Vec<ChatMessage> = read(&history.path).unwrap().map(Result::unwrap).collect();
+        assert_eq!(bodies(&saved), ["message 1", "message 2", "message 3", "message 4"]);
+    }
+}
@@ -0,0 +1,116 @@
+//! `--inject-socket`: a Unix socket bots and bridges write messages to, one JSON object per
+//! line, e.g. `{"body": "hi"}` or `{"body": "agreed", "reply_to": 1234}`. Each line is answered
+//! with `{"ok":true}` once the message is handed to the TUI, which publishes it as if typed, or
+//! with `{"ok":false,"error":"..."}`
+
+use std::io;
+use std::path::Path;
+
+use tokio::sync::mpsc::Sender;
+
+use crate::network::Update;
+
+/// Listen on `path` in the background, replacing a socket left over from an earlier session
+#[cfg(unix)]
+pub fn listen(path: &Path, tx2: &Sender<Update>) -> io::Result<()> {
+    use std::fs;
+    use std::os::unix::fs::{FileTypeExt, MetadataExt, PermissionsExt};
+
+    use log::{info, warn};
+    use serde::Deserialize;
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::{UnixListener, UnixStream};
+    use tokio::sync::mpsc::WeakSender;
+
+    /// Longest line taken, a longer one closes the connection
+    const MAX_LINE: usize = 256 * 1024;
+
+    #[derive(Deserialize)]
+    #[serde(deny_unknown_fields)]
+    struct Injected {
+        body: String,
+        #[serde(default)]
+        reply_to: Option<u64>,
+    }
+
+    async fn serve(stream: UnixStream, owner: u32, tx2: WeakSender<Update>) -> io::Result<()> {
+        // the socket file is private already, this also turns away root and other users
+        // that got hold of an open descriptor
+        if stream.peer_cred()?.uid() != owner {
+            warn!("Refused an inject socket connection from another user");
+            return Ok(());
+        }
+        let (reader, mut writer) = stream.into_split();
+        let mut reader = BufReader::new(reader);
+        loop {
+            let mut line = String::new();
+            let read = (&mut reader).take(MAX_LINE as u64 + 1).read_line(&mut line).await?;
+            if read == 0 {
+                return Ok(());
+            }
+            if read > MAX_LINE {
+                writer.write_all(b"{\"ok\":false,\"error\":\"line too long\"}\n").await?;
+                return Ok(());
+            }
+            if line.trim().is_empty() {
+                continue;
+            }
+            let answer = match serde_json::from_str::<Injected>(&line) {
+                Err(e) => Err(format!("invalid message: {}", e)),
+                Ok(injected) if injected.body.trim().is_empty() => Err("empty body".to_string()),
+                Ok(Injected { body, reply_to }) => match tx2.upgrade() {
+                    Some(tx2) => tx2
+                        .send(Update::Inject { body, reply_to })
+                        .await
+                        .map_err(|_| "tochat is shutting down".to_string()),
+                    None => Err("tochat is shutting down".to_string()),
+                },
+            };
+            let answer = match answer {
+                Ok(()) => serde_json::json!({ "ok": true }),
+                Err(error) => serde_json::json!({ "ok": false, "error": error }),
+            };
+            writer.write_all(format!("{}\n", answer).as_bytes()).await?;
+        }
+    }
+
+    // a socket left over from a crashed session would make bind fail, anything else is kept
+    match fs::symlink_metadata(path) {
+        Ok(meta) if meta.file_type().is_socket() => fs::remove_file(path)?,
+        Ok(_) => {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} exists and isn't a socket", path.display()),
+            ))
+        }
+        Err(_) => {}
+    }
+    let listener = UnixListener::bind(path)?;
+    fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+    let owner = fs::metadata(path)?.uid();
+    info!("Listening for injected messages on {}", path.display());
+
+    // weak, so the TUI still notices when the network task is gone
+    let tx2 = tx2.downgrade();
+    tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    let tx2 = tx2.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = serve(stream, owner, tx2).await {
+                            warn!("Inject socket connection failed: {}", e);
+                        }
+                    });
+                }
+                Err(e) => warn!("Inject socket accept failed: {}", e),
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn listen(_path: &Path, _tx2: &Sender<Update>) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "--inject-socket needs Unix domain sockets"))
+}
@@ -0,0 +1,117 @@
+//! Trust on first use: the key each name first came with, pinned in `~/.tochat/known_peers.json`
+//! so a name showing up with another key in a later session is noticed, and the safety numbers
+//! `/verify` shows to compare keys out of band
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Local};
+use libp2p::PeerId;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::network::secure::tochat_dir;
+
+/// Digits per identity in a safety number, shown in groups of five
+const DIGITS: usize = 30;
+/// Hash rounds per identity, so a key matching a given number is slow to search for
+const ROUNDS: usize = 5200;
+
+/// The key pinned for a name
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Pin {
+    /// Base58, it holds the public key
+    pub peer_id: String,
+    /// When it was first seen or last accepted
+    pub pinned_at: DateTime<Local>,
+}
+
+/// Where the pins are kept, `~/.tochat/known_peers.json`
+pub fn path() -> PathBuf {
+    tochat_dir().join("known_peers.json")
+}
+
+/// The keys pinned in `path` by name, none when the file doesn't exist yet
+pub fn load(path: &Path) -> io::Result<BTreeMap<String, Pin>> {
+    match fs::read_to_string(path) {
+        Ok(text) => serde_json::from_str(&text).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(BTreeMap::new()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Pin `peer` as the key of `name` in `path`, replacing the one pinned before and leaving the
+/// other names as they are
+pub fn pin(path: &Path, name: &str, peer: &str) -> io::Result<Pin> {
+    let mut pins = load(path)?;
+    let pin = Pin { peer_id: peer.to_string(), pinned_at: Local::now() };
+    pins.insert(name.to_string(), pin.clone());
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    fs::write(path, serde_json::to_string_pretty(&pins)?)?;
+    Ok(pin)
+}
+
+/// The 30 digits standing for one identity, from its PeerId, which holds the public key
+fn identity_digits(peer: &PeerId) -> String {
+    let key = peer.to_bytes();
+    let mut hash = Sha256::new().chain(b"tochat-safety-number-1").chain(&key).finalize();
+    for _ in 1..ROUNDS {
+        hash = Sha256::new().chain(hash).chain(&key).finalize();
+    }
+    // 5 digits from each 5 bytes, 30 bytes of the 32 are used
+    hash.chunks(5)
+        .take(DIGITS / 5)
+        .map(|chunk| {
+            let n = chunk.iter().fold(0u64, |n, b| n << 8 | *b as u64);
+            format!("{:05}", n % 100_000)
+        })
+        .collect()
+}
+
+/// The safety number of a conversation between `a` and `b`: both identities' digits, the lower
+/// first, so both sides get the same number whoever computes it. Shown in 12 groups of 5 digits
+pub fn safety_number(a: &PeerId, b: &PeerId) -> String {
+    let (mut first, mut second) = (identity_digits(a), identity_digits(b));
+    if second < first {
+        std::mem::swap(&mut first, &mut second);
+    }
+    let digits = first + &second;
+    let groups: Vec<&str> = (0..digits.len()).step_by(5).map(|i| &digits[i..i + 5]).collect();
+    groups.join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALICE: &str = "12D3KooWDpJ7As7BWAwRMfu1VU2WCqNjvq387JEYKDBj4kx6nXTN";
+    const BOB: &str = "12D3KooWD3eckifWpRn9wQpMG9R9hX3sD158z7EqHWmweQAJU5SA";
+
+    fn peer(id: &str) -> PeerId {
+        id.parse().unwrap()
+    }
+
+    #[test]
+    fn safety_number_is_symmetric() {
+        assert_eq!(safety_number(&peer(ALICE), &peer(BOB)), safety_number(&peer(BOB), &peer(ALICE)));
+    }
+
+    #[test]
+    fn safety_number_is_stable() {
+        // a change here means every user's safety numbers changed
+        assert_eq!(
+            safety_number(&peer(ALICE), &peer(BOB)),
+            "67899 49132 74794 85716 17281 48923 79774 32901 60423 47965 54737 40760"
+        );
+    }
+
+    #[test]
+    fn safety_number_differs_per_pair() {
+        let carol = PeerId::random();
+        assert_ne!(safety_number(&peer(ALICE), &peer(BOB)), safety_number(&peer(ALICE), &carol));
+    }
+}
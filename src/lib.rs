@@ -0,0 +1,15 @@
+//! The chat client behind the `tochat` binary, shared with `bot` and `relay` in `src/bin`
+
+pub mod config;
+pub mod history;
+pub mod inject;
+pub mod known_peers;
+pub mod logging;
+pub mod message;
+pub mod muted;
+pub mod network;
+pub mod search;
+pub mod transcript;
+pub mod tui;
+pub mod upload;
+pub mod webhook;
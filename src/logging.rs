@@ -0,0 +1,65 @@
+//! Logging setup shared by `tochat`, `bot` and `relay`
+
+use std::fs::OpenOptions;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use tracing_subscriber::filter::{EnvFilter, LevelFilter};
+use tracing_subscriber::fmt::format::FmtSpan;
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
+
+/// The level -v/-q ask for, none to keep the RUST_LOG default
+fn level(verbose: u8, quiet: bool) -> Option<LevelFilter> {
+    match verbose {
+        _ if quiet => Some(LevelFilter::OFF),
+        0 => None,
+        1 => Some(LevelFilter::INFO),
+        2 => Some(LevelFilter::DEBUG),
+        _ => Some(LevelFilter::TRACE),
+    }
+}
+
+/// Set up tracing from RUST_LOG, with -v/-q taking precedence over its default level. `log`
+/// records from dependencies are forwarded to it. Logs go to stderr, or appended to `log_file`
+/// for the commands drawing a TUI, which lines on stderr would run over. Without the file they're
+/// dropped
+pub fn init_logger(verbose: u8, quiet: bool, log_file: Option<PathBuf>) {
+    let mut filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("error"));
+    if let Some(level) = level(verbose, quiet) {
+        filter = filter.add_directive(level.into());
+    }
+    let (writer, ansi) = match log_file {
+        None => (BoxMakeWriter::new(std::io::stderr), true),
+        Some(path) => {
+            let file = path
+                .parent()
+                .map_or(Ok(()), std::fs::create_dir_all)
+                .and_then(|()| OpenOptions::new().create(true).append(true).open(&path));
+            match file {
+                Ok(file) => (BoxMakeWriter::new(Mutex::new(file)), false),
+                Err(_) => (BoxMakeWriter::new(std::io::sink), false),
+            }
+        }
+    };
+    // closing a span logs how long its phase took
+    tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_span_events(FmtSpan::CLOSE)
+        .with_writer(writer)
+        .with_ansi(ansi)
+        .init();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flags_pick_the_level() {
+        assert_eq!(level(0, false), None);
+        assert_eq!(level(1, false), Some(LevelFilter::INFO));
+        assert_eq!(level(2, false), Some(LevelFilter::DEBUG));
+        assert_eq!(level(5, false), Some(LevelFilter::TRACE));
+        assert_eq!(level(0, true), Some(LevelFilter::OFF));
+    }
+}
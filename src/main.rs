@@ -1,108 +1,308 @@
-mod network;
-mod tui;
-
+use chrono::NaiveDate;
 use clap::{Parser, Subcommand};
-use libp2p::core::multiaddr::Multiaddr;
-use libp2p::PeerId;
+use colorful::{Color, Colorful};
+use libp2p::{Multiaddr, PeerId};
+use tochat::config::{self, Config, FileConfig, Mode, SessionArgs};
+use tochat::network::relay_pick::{self, through_relays};
+use tochat::network::secret_store::Backend;
+use tochat::logging::init_logger;
+use tochat::{history, inject, network, search, transcript, tui};
 use tokio::sync::mpsc;
+use tracing::error;
+use std::env;
 use std::error::Error;
+use std::path::PathBuf;
+use std::time::Duration;
 
 #[derive(Parser)]
 #[clap(author, version, about, long_about = None)]
 struct Cli {
     #[clap(subcommand)]
     command: Commands,
+
+    /// Log more, -v for info, -vv for debug, -vvv for trace. Overrides the RUST_LOG default level
+    #[clap(short, long, parse(from_occurrences), global = true)]
+    verbose: u8,
+
+    /// Disable logging entirely
+    #[clap(short, long, global = true, conflicts_with = "verbose")]
+    quiet: bool,
+
+    /// Where the secret key is kept, the keychain needs a build with `--features keychain`
+    #[clap(long, arg_enum, global = true, env = "TOCHAT_SECRET_STORE", default_value = "file")]
+    secret_store: Backend,
 }
 #[derive(Subcommand)]
 enum Commands {
     /// Create a new private key
-    New,
+    New {
+        /// Also print the key as a 24 word BIP-39 mnemonic to write down
+        #[clap(long)]
+        mnemonic: bool,
+    },
     /// Import your secret key
     Import {
          /// secret key
          #[clap(long)]
          key: String,
     },
+    /// Import your secret key from a BIP-39 mnemonic
+    ImportMnemonic {
+        /// the 24 words, quoted as one argument
+        #[clap(long)]
+        phrase: String,
+    },
+    /// Replace your secret key with a new one, backing up the old one
+    Rotate {
+        /// Keep only this many of the newest backups
+        #[clap(long)]
+        keep_backups: Option<usize>,
+    },
+    /// Move your secret key to another store, e.g. from the file into the OS keychain
+    MoveSecret {
+        /// the store to move it to
+        #[clap(long, arg_enum)]
+        to: Backend,
+    },
     /// Direct Message
     DM {
-        /// nickname
-        #[clap(long)]
-        name: String,
+        #[clap(flatten)]
+        session: SessionArgs,
 
-        /// chat topic 
-        #[clap(long)]
-        topic: String,
+        /// ID of the remote peer to hole punch to.
+        #[clap(long, env = "TOCHAT_REMOTE_ID")]
+        remote_id: Option<PeerId>,
 
-        /// The listening address
-        #[clap(
-            long,
-            default_value = "/ip4/1.12.76.121/tcp/4001/p2p/12D3KooWDpJ7As7BWAwRMfu1VU2WCqNjvq387JEYKDBj4kx6nXTN"
-        )]
-        relay_address: Multiaddr,
+        /// Dial the remote peer or wait to be dialed [default: dial with --remote-id, listen without]
+        #[clap(long, arg_enum)]
+        mode: Option<Mode>,
 
-        /// ID of the remote peer to hole punch to.
+        /// Print the address your peer dials you at and exit without connecting
         #[clap(long)]
-        remote_id: Option<PeerId>,
+        print_multiaddr: bool,
     },
     /// Group Message
     Channel {
-        /// nickname
+        #[clap(flatten)]
+        session: SessionArgs,
+    },
+    /// Browse a JSON transcript from `/export` or a history file, without connecting
+    View {
+        /// the transcript file
+        file: PathBuf,
+    },
+    /// Search the saved history of every topic, without connecting
+    Search {
+        /// Words that all have to appear in a message, a quoted argument is matched as a phrase
+        #[clap(required = true)]
+        query: Vec<String>,
+
+        /// Only search this topic's history
         #[clap(long)]
-        name: String,
+        topic: Option<String>,
 
-        /// chat topic 
+        /// Only messages from this day on, YYYY-MM-DD
+        #[clap(long, parse(try_from_str = search::parse_day))]
+        since: Option<NaiveDate>,
+
+        /// Only messages up to this day, YYYY-MM-DD
+        #[clap(long, parse(try_from_str = search::parse_day))]
+        until: Option<NaiveDate>,
+
+        /// Messages shown before and after each match
+        #[clap(short = 'C', long, default_value_t = 2)]
+        context: usize,
+
+        /// Match upper and lower case exactly
         #[clap(long)]
-        topic: String,
-
-        /// The listening address
-        #[clap(
-            long,
-            default_value = "/ip4/1.12.76.121/tcp/4001/p2p/12D3KooWDpJ7As7BWAwRMfu1VU2WCqNjvq387JEYKDBj4kx6nXTN"
-        )]
-        relay_address: Multiaddr,
+        case_sensitive: bool,
     },
-}
+    /// Check that a peer can be reached: dial it through the relay, ping it a few times and exit
+    Ping {
+        /// ID of the peer to ping, which has to be listening on the relay as with `tochat dm`
+        peer: PeerId,
 
+        /// The relay the peer listens on [default: as for `tochat dm`]
+        #[clap(long, env = "TOCHAT_RELAY_ADDRESS", parse(try_from_str = config::parse_relay_address))]
+        relay_address: Option<Multiaddr>,
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn Error>> {
-    env_logger::init();
+        /// Pings to send once connected
+        #[clap(short = 'c', long, default_value_t = 3)]
+        count: usize,
 
+        /// Give up connecting after this long, e.g. `30s`, and pinging after as long again
+        #[clap(long, default_value = "30s", parse(try_from_str = config::parse_duration))]
+        timeout: Duration,
+    },
+    /// Print the configuration a session would run with
+    Config {
+        #[clap(flatten)]
+        session: SessionArgs,
+
+        /// ID of the remote peer to hole punch to.
+        #[clap(long, env = "TOCHAT_REMOTE_ID")]
+        remote_id: Option<PeerId>,
+
+        /// Dial the remote peer or wait to be dialed [default: dial with --remote-id, listen without]
+        #[clap(long, arg_enum)]
+        mode: Option<Mode>,
+    },
+}
+
+#[tokio::main]
+async fn main() {
     let cli = Cli::parse();
+    let tui = matches!(
+        cli.command,
+        Commands::DM { print_multiaddr: false, .. } | Commands::Channel { .. } | Commands::View { .. }
+    );
+    init_logger(cli.verbose, cli.quiet, tui.then(|| network::secure::tochat_dir().join("tochat.log")));
+    // the message alone, returning the error from main would print its Debug form. The log gets
+    // it too, where the TUI's own lines went
+    if let Err(e) = run(&cli).await {
+        error!("{}", e);
+        eprintln!("{}", format!("tochat: {}", e).color(Color::LightRed));
+        std::process::exit(1);
+    }
+}
 
+/// `Config::resolve`, then TOCHAT_KEY is taken out of the environment, which the config holds by
+/// then, so the programs tochat starts, like the browser for links, don't inherit the key
+fn resolve(
+    session: &SessionArgs,
+    remote_id: Option<PeerId>,
+    mode: Option<Mode>,
+    file: FileConfig,
+    secret_store: Backend,
+) -> Result<Config, Box<dyn Error>> {
+    let config = Config::resolve(session, remote_id, mode, file, secret_store);
+    env::remove_var("TOCHAT_KEY");
+    config
+}
+
+async fn run(cli: &Cli) -> Result<(), Box<dyn Error>> {
     match &cli.command {
-        Commands::New => network::secure::new_secret_key(),
-        Commands::Import { key } => network::secure::import_secret(key),
-        Commands::DM {
-            name,
-            topic,
-            relay_address,
-            remote_id,
-        } => {  
-            let key = network::secure::get_secret();
-            
-            let (tx1, rx1) = mpsc::channel::<String>(32);
-            let (tx2, rx2) = mpsc::channel::<String>(32);
-
-            let swarm = network::connection_dm::establish_connection(&key, topic, relay_address, remote_id).await;
-            tokio::spawn(network::connection_dm::handle_msg(swarm, rx1, tx2, topic.clone()));
-            tui::bootstrap(tx1, rx2, name).await.unwrap();
+        Commands::New { mnemonic } => network::secure::new_secret_key(&*cli.secret_store.open()?, *mnemonic),
+        Commands::Import { key } => network::secure::import_secret(&*cli.secret_store.open()?, key),
+        Commands::ImportMnemonic { phrase } => network::secure::import_mnemonic(&*cli.secret_store.open()?, phrase),
+        Commands::Rotate { keep_backups } => network::secure::rotate_secret(cli.secret_store, *keep_backups),
+        Commands::MoveSecret { to } => network::secure::move_secret(cli.secret_store, *to),
+        Commands::DM { session, remote_id, mode, print_multiaddr: true } => {
+            let mut config = resolve(session, *remote_id, *mode, FileConfig::load()?, cli.secret_store)?;
+            if config.relays.len() > 1 {
+                if let Some(fastest) = relay_pick::rank(&config.relays).await.into_iter().next() {
+                    config.relay_address = fastest.address;
+                }
+            }
+            let local_peer_id = PeerId::from(network::secure::generate_ed25519(&config.secret()?).public());
+            println!("{}", network::circuit_address(&config.relay_address, local_peer_id));
+            println!("# your peer dials you with --relay-address {} --remote-id {}", config.relay_address, local_peer_id);
             Ok(())
+        }
+        Commands::DM { session, remote_id, mode, .. } => {
+            tui::ensure_terminal("dm", session.key_on_stdin())?;
+            let mut config = resolve(session, *remote_id, *mode, FileConfig::load()?, cli.secret_store)?;
+            if config.rooms.len() > 1 {
+                return Err("--join only works in channels, a dm has a single topic".into());
+            }
+            let (tx1, rx1) = mpsc::channel::<network::Command>(config.channel_capacity);
+            let (tx2, rx2) = mpsc::channel::<network::Update>(config.channel_capacity);
+            if let Some(path) = &config.inject_socket {
+                inject::listen(path, &tx2).map_err(|e| format!("cannot listen on {}: {}", path.display(), e))?;
+            }
+
+            if config.loopback {
+                tokio::spawn(network::loopback::handle_msg(rx1, tx2, config.clone()));
+            } else {
+                let (key, updates) = (&config.secret()?, &tx2);
+                let (swarm, relay_listener, bandwidth, path) = through_relays(&mut config, |config| async move {
+                    network::connection_dm::establish_connection(key, &config, updates).await
+                })
+                .await?;
+                tokio::spawn(network::connection_dm::handle_msg(swarm, rx1, tx2, config.clone(), relay_listener, bandwidth, path));
+            }
+            let history = if config.history { Some(history::History::open(&config.topic)?) } else { None };
+            tui::bootstrap(tx1, rx2, &config, history).await
         },
-        Commands::Channel {
-            name,
-            topic,
-            relay_address,
-        } => {
-            let key = network::secure::get_secret();
-            
-            let (tx1, rx1) = mpsc::channel::<String>(32);
-            let (tx2, rx2) = mpsc::channel::<String>(32);
-
-            let swarm = network::connection_channel::establish_connection(&key, topic, relay_address).await;
-            tokio::spawn(network::connection_channel::handle_msg(swarm, rx1, tx2, topic.clone()));
-            tui::bootstrap(tx1, rx2, name).await.unwrap();
+        Commands::Channel { session } => {
+            tui::ensure_terminal("channel", session.key_on_stdin())?;
+            let mut config = resolve(session, None, None, FileConfig::load()?, cli.secret_store)?;
+            let (tx1, rx1) = mpsc::channel::<network::Command>(config.channel_capacity);
+            let (tx2, rx2) = mpsc::channel::<network::Update>(config.channel_capacity);
+            if let Some(path) = &config.inject_socket {
+                inject::listen(path, &tx2).map_err(|e| format!("cannot listen on {}: {}", path.display(), e))?;
+            }
+
+            if config.loopback {
+                tokio::spawn(network::loopback::handle_msg(rx1, tx2, config.clone()));
+            } else {
+                let (key, updates) = (&config.secret()?, &tx2);
+                let (swarm, relay_listener, bandwidth) = through_relays(&mut config, |config| async move {
+                    network::connection_channel::establish_connection(key, &config, updates).await
+                })
+                .await?;
+                tokio::spawn(network::connection_channel::handle_msg(swarm, rx1, tx2, config.clone(), relay_listener, bandwidth));
+            }
+            let history = if config.history { Some(history::History::open(&config.topic)?) } else { None };
+            tui::bootstrap(tx1, rx2, &config, history).await
+        }
+        Commands::Ping { peer, relay_address, count, timeout } => {
+            // a dm session with the defaults, its topic is never written to
+            let mut flags = vec!["--topic=tochat-ping".to_string(), "--no-history".to_string()];
+            flags.extend(relay_address.as_ref().map(|relay| format!("--relay-address={}", relay)));
+            let session = SessionArgs::from_flags(flags)?;
+            let mut config = resolve(&session, Some(*peer), Some(Mode::Dial), FileConfig::load()?, cli.secret_store)?;
+            config.ping_interval = Some(network::ping::PING_INTERVAL);
+            let (tx2, rx2) = mpsc::channel::<network::Update>(config.channel_capacity);
+            let reasons = network::ping::collect_reasons(rx2);
+            println!("Dialing {} through {}", peer, config.relay_address);
+            let connecting = {
+                let (key, updates) = (&config.secret()?, &tx2);
+                let connecting = through_relays(&mut config, |config| async move {
+                    network::connection_dm::establish_connection(key, &config, updates).await
+                });
+                tokio::time::timeout(*timeout, connecting).await
+            };
+            drop(tx2);
+            let (mut swarm, _, _, path) = match connecting {
+                Ok(connected) => connected?,
+                Err(_) => return Err(network::ping::unreachable(peer, *timeout, reasons.await?)),
+            };
+            println!("Connected, path: {}", path.status());
+            let deadline = tokio::time::Instant::now() + *timeout;
+            network::ping::ping(&mut swarm, *peer, *count, deadline).await
+        }
+        Commands::View { file } => {
+            tui::ensure_terminal("view", false)?;
+            let messages = transcript::load(file)?;
+            tui::view(messages).await
+        }
+        Commands::Search { query, topic, since, until, context, case_sensitive } => {
+            let search = search::Search::new(query, *case_sensitive, *since, *until, *context)?;
+            let found = search.run(topic.as_deref(), &mut std::io::stdout().lock())?;
+            eprintln!("{} matching message{}", found, if found == 1 { "" } else { "s" });
+            Ok(())
+        }
+        Commands::Config { session, remote_id, mode } => {
+            let config = resolve(session, *remote_id, *mode, FileConfig::load()?, cli.secret_store)?;
+            println!("# config file: {}", FileConfig::path().display());
+            println!("{}", config);
             Ok(())
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tochat::network::secure::Secret;
+
+    #[test]
+    fn the_key_leaves_the_environment_once_read() {
+        env::set_var("TOCHAT_KEY", " inline ");
+        let session = SessionArgs::from_flags(vec!["--name=test".to_string()]).unwrap();
+        let config = resolve(&session, None, None, FileConfig::default(), Backend::File).unwrap();
+        assert!(matches!(config.inline_secret, Some(Secret(key)) if key == "inline"));
+        assert!(env::var_os("TOCHAT_KEY").is_none());
+    }
+}
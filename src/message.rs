@@ -0,0 +1,175 @@
+use chrono::prelude::*;
+use serde::{Deserialize, Serialize};
+
+/// Where a message in the list came from
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Kind {
+    /// Typed by us
+    Local,
+    /// Received from a peer
+    Remote,
+    /// Generated by tochat itself, e.g. `/peers` output
+    System,
+}
+
+/// Delivery state of a message we sent
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Delivery {
+    /// Handed to the network task, not yet published
+    Pending,
+    /// Published to the topic mesh
+    Delivered,
+    /// Publishing failed
+    Failed,
+    /// Was still pending when tochat exited, it may or may not have gone out
+    Unconfirmed,
+}
+
+/// History files written before delivery tracking only hold messages that were shown,
+/// so a missing field is treated as delivered
+impl Default for Delivery {
+    fn default() -> Self {
+        Delivery::Delivered
+    }
+}
+
+/// Longest quoted snippet in characters, the first line of the original is cut to this
+const SNIPPET_CHARS: usize = 80;
+
+/// The message a reply answers, carrying enough of it to show when the original isn't in the list
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Quote {
+    pub id: u64,
+    pub sender: String,
+    pub snippet: String,
+}
+
+impl Quote {
+    pub fn of(message: &ChatMessage) -> Quote {
+        if message.deleted {
+            return Quote {
+                id: message.id,
+                sender: message.sender.clone(),
+                snippet: "message deleted".to_string(),
+            };
+        }
+        let line = message.body.lines().next().unwrap_or_default();
+        let mut snippet: String = line.chars().take(SNIPPET_CHARS).collect();
+        if snippet.len() < message.body.trim_end().len() {
+            snippet.push('…');
+        }
+        Quote {
+            id: message.id,
+            sender: message.sender.clone(),
+            snippet,
+        }
+    }
+}
+
+/// Where a chat message stands among those its sender published in a topic, so the ones
+/// gossipsub delivers out of order can be put back and lost ones noticed
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Seq {
+    /// Picked at random when the sender starts, numbering starts over with a new one
+    pub session: u32,
+    /// 1 for the sender's first message of the session in the topic
+    pub n: u64,
+}
+
+/// A message in the list, as shown and as persisted to the history file
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ChatMessage {
+    /// Sent along with the message so replies can refer to it, also matches delivery updates
+    #[serde(default)]
+    pub id: u64,
+    pub kind: Kind,
+    pub sender: String,
+    pub timestamp: DateTime<Local>,
+    pub body: String,
+    #[serde(default)]
+    pub delivery: Delivery,
+    /// The message this one answers
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reply_to: Option<Quote>,
+    /// PeerId that signed a received message, only it may edit or delete the message
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub author: Option<String>,
+    #[serde(default)]
+    pub edited: bool,
+    /// Tombstone left by a delete, the body is cleared
+    #[serde(default)]
+    pub deleted: bool,
+    /// A whisper, only readable by us and the other end
+    #[serde(default)]
+    pub private: bool,
+    /// Name of the peer a whisper we sent went to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub recipient: Option<String>,
+    /// Set by the network task on what we publish, missing from peers on older builds
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seq: Option<Seq>,
+    /// Sent by a peer that was in the room before we joined, see `network::replay`
+    #[serde(default)]
+    pub replayed: bool,
+}
+
+impl ChatMessage {
+    pub fn local(sender: &str, body: String) -> ChatMessage {
+        ChatMessage {
+            id: rand::random(),
+            kind: Kind::Local,
+            sender: sender.to_string(),
+            timestamp: Local::now(),
+            body,
+            delivery: Delivery::Pending,
+            reply_to: None,
+            author: None,
+            edited: false,
+            deleted: false,
+            private: false,
+            recipient: None,
+            seq: None,
+            replayed: false,
+        }
+    }
+
+    pub fn remote(sender: &str, body: String) -> ChatMessage {
+        ChatMessage {
+            id: rand::random(),
+            kind: Kind::Remote,
+            sender: sender.to_string(),
+            timestamp: Local::now(),
+            body,
+            delivery: Delivery::Delivered,
+            reply_to: None,
+            author: None,
+            edited: false,
+            deleted: false,
+            private: false,
+            recipient: None,
+            seq: None,
+            replayed: false,
+        }
+    }
+
+    pub fn system(body: String) -> ChatMessage {
+        ChatMessage {
+            id: rand::random(),
+            kind: Kind::System,
+            sender: "system".to_string(),
+            timestamp: Local::now(),
+            body,
+            delivery: Delivery::Delivered,
+            reply_to: None,
+            author: None,
+            edited: false,
+            deleted: false,
+            private: false,
+            recipient: None,
+            seq: None,
+            replayed: false,
+        }
+    }
+}
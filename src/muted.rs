@@ -0,0 +1,65 @@
+//! Rooms muted with `/mute`, kept in `~/.tochat/muted` one topic per line so a room stays muted
+//! across sessions
+
+use std::collections::BTreeSet;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::network::secure::tochat_dir;
+
+/// Where the muted topics are kept, `~/.tochat/muted`
+pub fn path() -> PathBuf {
+    tochat_dir().join("muted")
+}
+
+/// The topics muted in `path`, none when the file doesn't exist yet
+pub fn load(path: &Path) -> io::Result<BTreeSet<String>> {
+    match fs::read_to_string(path) {
+        Ok(text) => Ok(text.lines().map(str::trim).filter(|l| !l.is_empty()).map(str::to_string).collect()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(BTreeSet::new()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Mute or unmute `topic` in `path`, leaving the other topics in the file as they are
+pub fn set(path: &Path, topic: &str, muted: bool) -> io::Result<()> {
+    let mut topics = load(path)?;
+    let changed = if muted { topics.insert(topic.to_string()) } else { topics.remove(topic) };
+    if !changed {
+        return Ok(());
+    }
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let text: String = topics.iter().map(|topic| format!("{}\n", topic)).collect();
+    fs::write(path, text)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_file(test: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("tochat-{}-{}", test, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir.join("muted")
+    }
+
+    #[test]
+    fn nothing_is_muted_without_the_file() {
+        assert!(load(&temp_file("muted-none")).unwrap().is_empty());
+    }
+
+    #[test]
+    fn mutes_and_unmutes_one_topic_at_a_time() {
+        let path = temp_file("muted-set");
+        set(&path, "ops", true).unwrap();
+        set(&path, "random", true).unwrap();
+        set(&path, "ops", true).unwrap();
+        assert_eq!(load(&path).unwrap().into_iter().collect::<Vec<_>>(), ["ops", "random"]);
+        set(&path, "ops", false).unwrap();
+        assert_eq!(load(&path).unwrap().into_iter().collect::<Vec<_>>(), ["random"]);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "random\n");
+    }
+}
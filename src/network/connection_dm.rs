@@ -1,16 +1,26 @@
 use crate::network::secure::generate_ed25519;
-use chrono::prelude::*;
-
+use crate::config::{Config, Mode};
+use crate::message::{ChatMessage, Delivery};
+use crate::network::dedup::Dedup;
+use crate::network::path::{PathMonitor, Preference};
+use crate::network::event_log;
+use crate::network::health::{HealthMonitor, HEALTH_INTERVAL};
+use crate::network::relay_link::{RelayLink, RelayStatus};
+use crate::network::whisper;
+use crate::network::wire::{self, Frame, Reassembler, Sequencer, ENVELOPE_OVERHEAD};
+use crate::network::{backoff_delay, circuit_address, is_own_echo, peers_report, protocol_mismatch, publish_chat, publish_frame, relay_motd, relay_peer_id, resubscribe, Allowlist, Command, Stats, Update, SUBSCRIPTION_CHECK, SWARM_ENDED};
+use chrono::Utc;
 use futures::prelude::*;
 
 use libp2p::core::multiaddr::{Multiaddr, Protocol};
-use libp2p::core::transport::OrTransport;
+use libp2p::core::transport::{ListenerId, OrTransport};
+use libp2p::bandwidth::{BandwidthLogging, BandwidthSinks};
 use libp2p::core::upgrade;
 use libp2p::dns::TokioDnsConfig;
 use libp2p::gossipsub::{self, GossipsubEvent, IdentTopic as Topic, MessageAuthenticity};
 use libp2p::identify::{Identify, IdentifyConfig, IdentifyEvent, IdentifyInfo};
 use libp2p::noise;
-use libp2p::ping::{Ping, PingConfig, PingEvent};
+use libp2p::ping::{Ping, PingConfig, PingEvent, PingSuccess};
 use libp2p::relay::v2::client::{self, Client};
 use libp2p::swarm::{SwarmBuilder, SwarmEvent};
 use libp2p::tcp::{GenTcpConfig, TokioTcpTransport};
@@ -19,9 +29,13 @@ use libp2p::Transport;
 use libp2p::{dcutr, Swarm};
 use libp2p::{NetworkBehaviour, PeerId};
 
-use log::info;
+use tracing::field::{self, display};
+use tracing::{debug, info, info_span, instrument, warn, Instrument, Span};
 use std::convert::TryInto;
+use std::error::Error;
 use std::net::Ipv4Addr;
+use std::sync::Arc;
+use tokio::sync::mpsc::error::SendError;
 use tokio::sync::mpsc::{Receiver, Sender};
 
 #[derive(NetworkBehaviour)]
@@ -73,17 +87,48 @@ impl From<GossipsubEvent> for Event {
     }
 }
 
+impl Event {
+    /// The line for the `l` event log, for the notable events
+    fn logged(&self) -> Option<String> {
+        match self {
+            Event::Relay(event) => event_log::relay(event),
+            Event::Dcutr(event) => event_log::dcutr(event),
+            _ => None,
+        }
+    }
+}
+
+#[instrument(name = "connect", skip_all, fields(mode = ?config.mode))]
 pub async fn establish_connection(
     key: &String,
-    topic: &String,
-    relay_address: &Multiaddr,
-    remote_id: &Option<PeerId>,
-) -> Swarm<Behaviour> {
+    config: &Config,
+    tx2: &Sender<Update>,
+) -> Result<(Swarm<Behaviour>, ListenerId, Arc<BandwidthSinks>, PathMonitor), Box<dyn Error>> {
+    let topic = config.gossip_topic();
+    let relay_address = &config.relay_address;
+    let remote_id = &config.remote_id;
+    let allowlist = Allowlist::new(config);
+    // fed from here on, the connections to the peer are made before `handle_msg` runs
+    let mut path = PathMonitor::new(relay_peer_id(relay_address), *remote_id, config.path_thresholds);
+    let dial_attempts = config.dial_attempts;
+    let protocol_version = config.protocol_version.as_str();
+    let strict_version = config.strict_version;
+    let max_message_size = config.max_message_size;
+
+    if max_message_size < 2 * ENVELOPE_OVERHEAD {
+        return Err(format!("--max-message-size must be at least {} bytes", 2 * ENVELOPE_OVERHEAD).into());
+    }
+
     let local_key = generate_ed25519(key);
 
     let local_peer_id = PeerId::from(local_key.public());
     info!("Local peer id: {:?}", local_peer_id);
     println!("Local peer id: {:?}", local_peer_id);
+    if config.ephemeral_secret.is_some() {
+        let notice = format!("ephemeral identity {}, not linked to your stored key", local_peer_id);
+        info!("Using an {}", notice);
+        let _ = tx2.try_send(Update::System(notice));
+    }
 
     let (relay_transport, client) = Client::new_transport_and_behaviour(local_peer_id);
 
@@ -91,17 +136,18 @@ pub async fn establish_connection(
         .into_authentic(&local_key)
         .expect("Signing libp2p-noise static DH keypair failed.");
 
-    let transport = OrTransport::new(
+    let (transport, bandwidth) = BandwidthLogging::new(OrTransport::new(
         relay_transport,
         TokioDnsConfig::system(TokioTcpTransport::new(
             GenTcpConfig::default().port_reuse(true),
         ))
         .unwrap(),
-    )
-    .upgrade(upgrade::Version::V1)
-    .authenticate(noise::NoiseConfig::xx(noise_keys).into_authenticated())
-    .multiplex(yamux::YamuxConfig::default())
-    .boxed();
+    ));
+    let transport = transport
+        .upgrade(upgrade::Version::V1)
+        .authenticate(noise::NoiseConfig::xx(noise_keys).into_authenticated())
+        .multiplex(yamux::YamuxConfig::default())
+        .boxed();
 
     let topic = Topic::new(topic);
 
@@ -112,6 +158,7 @@ pub async fn establish_connection(
             .mesh_n_low(1)
             .mesh_n(2)
             .mesh_outbound_min(1)
+            .max_transmit_size(max_message_size)
             .build()
             .expect("Valid config");
         let mut gossip = gossipsub::Gossipsub::new(
@@ -124,11 +171,14 @@ pub async fn establish_connection(
 
         let behaviour = Behaviour {
             relay_client: client,
-            ping: Ping::new(PingConfig::new().with_keep_alive(true)),
-            identify: Identify::new(IdentifyConfig::new(
-                "/TODO/0.0.1".to_string(),
-                local_key.public(),
-            )),
+            ping: Ping::new(match config.ping_interval {
+                Some(interval) => PingConfig::new().with_keep_alive(true).with_interval(interval),
+                None => PingConfig::new().with_keep_alive(true),
+            }),
+            identify: Identify::new(
+                IdentifyConfig::new(protocol_version.to_string(), local_key.public())
+                    .with_agent_version(config.agent_version.clone()),
+            ),
             dcutr: dcutr::behaviour::Behaviour::new(),
             gossip,
         };
@@ -145,156 +195,591 @@ pub async fn establish_connection(
             Multiaddr::empty()
                 .with("0.0.0.0".parse::<Ipv4Addr>().unwrap().into())
                 .with(Protocol::Tcp(0)),
-        )
-        .unwrap();
+        )?;
 
     // Wait to listen on all interfaces.
-    let mut delay = futures_timer::Delay::new(std::time::Duration::from_secs(1)).fuse();
-    loop {
-        futures::select! {
-            event = swarm.next() => {
-                match event.unwrap() {
-                    SwarmEvent::NewListenAddr { address, .. } => {
-                        info!("Listening on {:?}", address);
+    async {
+        let mut delay = futures_timer::Delay::new(std::time::Duration::from_secs(1)).fuse();
+        loop {
+            futures::select! {
+                event = swarm.next() => {
+                    match event.ok_or(SWARM_ENDED)? {
+                        SwarmEvent::NewListenAddr { address, .. } => {
+                            info!("Listening on {:?}", address);
+                        }
+                        event => debug!("{:?}", event),
                     }
-                    event => panic!("{:?}", event),
                 }
-            }
-            _ = delay => {
-                // Likely listening on all interfaces now, thus continuing by breaking the loop.
-                break;
+                _ = delay => {
+                    // Likely listening on all interfaces now, thus continuing by breaking the loop.
+                    break;
+                }
             }
         }
+        Ok::<_, Box<dyn Error>>(())
     }
+    .instrument(info_span!("listen"))
+    .await?;
 
     // Connect to the relay server. Not for the reservation or relayed connection, but to (a) learn
     // our local public address and (b) enable a freshly started relay to learn its public address.
-    swarm.dial(relay_address.clone()).unwrap();
-
-    let mut learned_observed_addr = false;
-    let mut told_relay_observed_addr = false;
-
-    loop {
-        match swarm.next().await.unwrap() {
-            SwarmEvent::NewListenAddr { .. } => {}
-            SwarmEvent::Dialing { .. } => {}
-            SwarmEvent::ConnectionEstablished { .. } => {}
-            SwarmEvent::Behaviour(Event::Gossip(_)) => {}
-            SwarmEvent::Behaviour(Event::Ping(_)) => {}
-            SwarmEvent::Behaviour(Event::Identify(IdentifyEvent::Sent { .. })) => {
-                info!("Told relay its public address.");
-                told_relay_observed_addr = true;
-            }
-            SwarmEvent::Behaviour(Event::Identify(IdentifyEvent::Received {
-                info: IdentifyInfo { observed_addr, .. },
-                ..
-            })) => {
-                info!("Relay told us our public address: {:?}", observed_addr);
-                learned_observed_addr = true;
+    async {
+        let mut attempt = 1;
+        info!("Dialing relay {} (attempt {}/{})", relay_address, attempt, dial_attempts);
+        swarm
+            .dial(relay_address.clone())
+            .map_err(|e| format!("cannot dial relay {}: {}", relay_address, e))?;
+        loop {
+            let event = swarm.next().await.ok_or(SWARM_ENDED)?;
+            event_log::record(tx2, &event, Event::logged);
+            match event {
+                SwarmEvent::NewListenAddr { .. } => {}
+                SwarmEvent::Dialing { .. } => {}
+                // the relay is the only peer dialed so far
+                SwarmEvent::OutgoingConnectionError { error, .. } => {
+                    if attempt >= dial_attempts {
+                        return Err(format!(
+                            "could not reach relay {} after {} attempts: {}",
+                            relay_address, attempt, error
+                        )
+                        .into());
+                    }
+                    let delay = backoff_delay(attempt);
+                    warn!(
+                        "Dialing relay failed (attempt {}/{}): {}, retrying in {:?}",
+                        attempt, dial_attempts, error, delay
+                    );
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                    info!("Dialing relay {} (attempt {}/{})", relay_address, attempt, dial_attempts);
+                    swarm
+                        .dial(relay_address.clone())
+                        .map_err(|e| format!("cannot dial relay {}: {}", relay_address, e))?;
+                }
+                SwarmEvent::ConnectionEstablished { endpoint, .. } => {
+                    info!("Connected to relay via {:?}", endpoint);
+                    return Ok::<_, Box<dyn Error>>(());
+                }
+                event => debug!("{:?}", event),
             }
-            event => panic!("{:?}", event),
         }
-
-        if learned_observed_addr && told_relay_observed_addr {
-            break;
+    }
+    .instrument(info_span!("dial_relay", relay = %relay_address))
+    .await?;
+
+    // behaviours only report on a connection once it is established, so `/identify` starts here
+    let relay = relay_peer_id(relay_address);
+    async {
+        let mut learned_observed_addr = false;
+        let mut told_relay_observed_addr = false;
+        while !(learned_observed_addr && told_relay_observed_addr) {
+            let event = swarm.next().await.ok_or(SWARM_ENDED)?;
+            event_log::record(tx2, &event, Event::logged);
+            match event {
+                SwarmEvent::NewListenAddr { .. } => {}
+                SwarmEvent::ConnectionEstablished { .. } => {}
+                SwarmEvent::Behaviour(Event::Gossip(_)) => {}
+                SwarmEvent::Behaviour(Event::Ping(_)) => {}
+                SwarmEvent::Behaviour(Event::Identify(IdentifyEvent::Sent { .. })) => {
+                    info!("Told relay its public address.");
+                    told_relay_observed_addr = true;
+                }
+                SwarmEvent::Behaviour(Event::Identify(IdentifyEvent::Received {
+                    peer_id,
+                    info: IdentifyInfo { observed_addr, protocol_version: remote_version, agent_version, .. },
+                })) => {
+                    if let Some(warning) = protocol_mismatch(protocol_version, &peer_id, &remote_version) {
+                        warn!("Relay: {}", warning);
+                    }
+                    if let Some(motd) = relay_motd(&agent_version) {
+                        info!("Relay message of the day: {}", motd);
+                        let _ = tx2.try_send(Update::System(format!("relay: {}", motd)));
+                    }
+                    info!("Relay told us our public address: {:?}", observed_addr);
+                    Span::current().record("observed_addr", &display(&observed_addr));
+                    learned_observed_addr = true;
+                }
+                // either way the relay won't tell us our address
+                SwarmEvent::Behaviour(Event::Identify(IdentifyEvent::Error { peer_id, error })) if Some(peer_id) == relay => {
+                    return Err(format!("relay {} did not identify itself: {}", relay_address, error).into());
+                }
+                SwarmEvent::ConnectionClosed { peer_id, .. } if Some(peer_id) == relay => {
+                    return Err(format!("relay {} closed the connection", relay_address).into());
+                }
+                event => debug!("{:?}", event),
+            }
         }
+        Ok::<_, Box<dyn Error>>(())
     }
+    .instrument(info_span!("learn_observed_addr", observed_addr = field::Empty))
+    .await?;
 
     // request listening-connection to relay
-    swarm
-        .listen_on(relay_address.clone().with(Protocol::P2pCircuit))
-        .unwrap();
+    let relay_listener = swarm
+        .listen_on(relay_address.clone().with(Protocol::P2pCircuit))?;
 
-    // establish relay-connection with remote peer
-    if *remote_id != None {
-        swarm
-            .dial(
-                relay_address
-                    .clone()
-                    .with(Protocol::P2pCircuit)
-                    .with(Protocol::P2p(PeerId::from((*remote_id).unwrap()).into())),
-            )
-            .unwrap();
+    // establish relay-connection with remote peer, a listener waits to be dialed instead
+    match (config.mode, remote_id) {
+        (Mode::Dial, Some(remote_id)) => {
+            swarm.dial(circuit_address(relay_address, *remote_id))?;
+        }
+        (Mode::Dial, None) => unreachable!("Config::resolve requires --remote-id to dial"),
+        (Mode::Listen, _) => info!("Waiting for the remote peer to dial us"),
     }
 
     // waiting for connection to be established
 
-    let mut established = false;
-    loop {
-        match swarm.next().await.unwrap() {
-            SwarmEvent::NewListenAddr { address, .. } => {
-                info!("Listening on {:?}", address);
-            }
-            SwarmEvent::Behaviour(Event::Relay(client::Event::ReservationReqAccepted {
-                ..
-            })) => {
-                info!("Relay accepted our reservation request.");
-            }
-            SwarmEvent::Behaviour(Event::Relay(event)) => {
-                info!("{:?}", event)
-            }
-            SwarmEvent::Behaviour(Event::Dcutr(event)) => {
-                info!("{:?}", event);
-                established = true;
-            }
-            SwarmEvent::Behaviour(Event::Identify(event)) => {
-                info!("{:?}", event)
-            }
-            SwarmEvent::Behaviour(Event::Ping(_)) => {}
-            SwarmEvent::ConnectionEstablished {
-                peer_id, endpoint, ..
-            } => {
-                info!("Established connection to {:?} via {:?}", peer_id, endpoint);
-            }
-            SwarmEvent::OutgoingConnectionError { peer_id, error } => {
-                info!("Outgoing connection error to {:?}: {:?}", peer_id, error);
+    // `reserve` lasts until the relay accepts, `dcutr` from the relayed connection to the peer
+    // until the direct one replaces it, both inside `circuit`
+    let circuit = info_span!("circuit");
+    let mut reserve = Some(info_span!(parent: &circuit, "reserve"));
+    let mut dcutr: Option<Span> = None;
+    async {
+        let mut established = false;
+        while !established {
+            let event = swarm.next().await.ok_or(SWARM_ENDED)?;
+            event_log::record(tx2, &event, Event::logged);
+            match event {
+                SwarmEvent::NewListenAddr { address, .. } => {
+                    info!("Listening on {:?}", address);
+                }
+                SwarmEvent::Behaviour(Event::Relay(client::Event::ReservationReqAccepted {
+                    ..
+                })) => {
+                    let span = reserve.take().unwrap_or_else(Span::current);
+                    info!(parent: &span, "Relay accepted our reservation request.");
+                    if config.mode == Mode::Listen {
+                        // the TUI starts once the peer is connected, until then this is all there is to see
+                        let address = circuit_address(relay_address, local_peer_id);
+                        println!("Reachable at {}", address);
+                        println!("Your peer dials you with --relay-address {} --remote-id {}", relay_address, local_peer_id);
+                    }
+                }
+                SwarmEvent::Behaviour(Event::Relay(event)) => {
+                    info!("{:?}", event)
+                }
+                SwarmEvent::Behaviour(Event::Dcutr(event)) => {
+                    let span = dcutr.take().unwrap_or_else(Span::current);
+                    info!(parent: &span, "{:?}", event);
+                    established = true;
+                }
+                SwarmEvent::Behaviour(Event::Identify(IdentifyEvent::Received { peer_id, info })) => {
+                    if let Some(warning) = protocol_mismatch(protocol_version, &peer_id, &info.protocol_version) {
+                        if strict_version {
+                            return Err(warning.into());
+                        }
+                        warn!("{}", warning);
+                        let _ = tx2.try_send(Update::System(warning));
+                    }
+                }
+                SwarmEvent::Behaviour(Event::Identify(event)) => {
+                    info!("{:?}", event)
+                }
+                SwarmEvent::Behaviour(Event::Ping(_)) => {}
+                // banning closes the connection and refuses the peer's later ones
+                SwarmEvent::ConnectionEstablished { peer_id, .. } if !allowlist.admits(&peer_id) => {
+                    let notice = allowlist.turn_away(&mut swarm, peer_id);
+                    let _ = tx2.try_send(Update::System(notice));
+                }
+                SwarmEvent::ConnectionEstablished {
+                    peer_id, endpoint, ..
+                } => {
+                    info!("Established connection to {:?} via {:?}", peer_id, endpoint);
+                    if endpoint.is_relayed() && dcutr.is_none() {
+                        dcutr = Some(info_span!("dcutr", peer = %peer_id));
+                    }
+                    path.connected(&peer_id, &endpoint);
+                }
+                SwarmEvent::ConnectionClosed { peer_id, endpoint, .. } => {
+                    path.disconnected(&peer_id, &endpoint);
+                }
+                SwarmEvent::OutgoingConnectionError { peer_id, error } => {
+                    info!("Outgoing connection error to {:?}: {:?}", peer_id, error);
+                }
+                _ => {}
             }
-            _ => {}
-        }
-
-        if established {
-            break;
         }
+        Ok::<_, Box<dyn Error>>(())
     }
-    swarm
+    .instrument(circuit)
+    .await?;
+    Ok((swarm, relay_listener, bandwidth, path))
 }
 
 pub async fn handle_msg(
     mut swarm: Swarm<Behaviour>,
-    mut rx1: Receiver<String>,
-    tx2: Sender<String>,
-    topic: String,
+    mut rx1: Receiver<Command>,
+    tx2: Sender<Update>,
+    config: Config,
+    mut relay_listener: ListenerId,
+    bandwidth: Arc<BandwidthSinks>,
+    mut path: PathMonitor,
 ) {
-    loop {
-        tokio::select! {
-            // publish
-            msg = rx1.recv() => {
-                swarm.behaviour_mut()
-            .gossip
-            .publish(Topic::new(&topic), msg.unwrap().as_bytes())
-            .expect("publish error");
-            },
-            // receive
-            event = swarm.select_next_some() => {
-                match event {
-                    SwarmEvent::Behaviour(Event::Gossip(GossipsubEvent::Message{
-                        propagation_source: _,
-                        message_id: _,
-                        message,
-                    })) => {
-                        let message = String::from_utf8_lossy(&message.data);
-                        let tokens:Vec<&str> = message.split(",").collect();
-                        let content = tokens[0];
-                        let remote_name = tokens[1];
-
-                        tx2.send(format!("{} {} - {}",
-                                    remote_name,
-                                    Local::now().format("%H:%M:%S").to_string(),
-                                    content)).await.unwrap();
+    let topic = config.gossip_topic();
+    // read once already to set up the swarm, so this only fails if the file changed since.
+    // As a string, the boxed error isn't `Send` and can't be held across the await below
+    let keypair = match config.secret().map_err(|e| e.to_string()) {
+        Ok(secret) => generate_ed25519(&secret),
+        Err(e) => {
+            warn!("Cannot read the secret key: {}", e);
+            let _ = tx2.send(Update::System(format!("network stopped: {}", e))).await;
+            return;
+        }
+    };
+    let allowlist = Allowlist::new(&config);
+    let relay = relay_peer_id(&config.relay_address);
+    let mut relay_link = RelayLink::new(relay, config.relay_reconnect_attempts);
+    let Config {
+        protocol_version,
+        strict_version,
+        relay_address,
+        max_message_size,
+        read_only,
+        dedup_window,
+        own_echoes,
+        profile_stats,
+        mode,
+        ..
+    } = config;
+    let local_peer_id = *swarm.local_peer_id();
+    // runs until the TUI is gone, having closed the command queue or stopped taking updates
+    let stopped: Result<(), SendError<Update>> = async {
+        tx2.send(Update::Path(path.status())).await?;
+        if mode == Mode::Listen {
+            let address = circuit_address(&relay_address, local_peer_id);
+            tx2.send(Update::Address(address.to_string())).await?;
+        }
+        let mut reassembler = Reassembler::default();
+        let mut dedup = Dedup::new(dedup_window);
+        let mut stats = Stats::new(bandwidth, profile_stats);
+        let mut sequencer = Sequencer::default();
+        // the topics are left on purpose while away, and not checked then
+        let mut away = false;
+        let mut subscription_check = tokio::time::interval(SUBSCRIPTION_CHECK);
+        let mut health = HealthMonitor::new(relay, swarm.connected_peers().cloned());
+        let mut health_check = tokio::time::interval(HEALTH_INTERVAL);
+        loop {
+            tokio::select! {
+                _ = subscription_check.tick(), if !away => {
+                    resubscribe(&mut swarm.behaviour_mut().gossip, std::slice::from_ref(&topic));
+                }
+                _ = health_check.tick() => {
+                    let mesh = swarm.behaviour().gossip.mesh_peers(&Topic::new(&topic).hash()).count();
+                    tx2.send(Update::Health(health.snapshot(&stats.rtt, relay_link.status(), mesh))).await?;
+                }
+                _ = tokio::time::sleep_until(relay_link.deadline()), if relay_link.waiting() => {
+                    relay_link.dialing();
+                    info!("Dialing relay {} again ({})", relay_address, relay_link.status());
+                    if let Err(e) = swarm.dial(relay_address.clone()) {
+                        warn!("Cannot dial relay {}: {}", relay_address, e);
+                        relay_link.dial_failed(relay);
+                        tx2.send(Update::Relay(relay_link.status())).await?;
+                    }
+                }
+                cmd = rx1.recv() => {
+                    // the TUI closed its end of the queue on quitting
+                    let cmd = match cmd {
+                        Some(cmd) => cmd,
+                        None => return Ok(()),
+                    };
+                    match cmd {
+                        // the TUI never sends these in read-only mode, refuse anyway
+                        Command::Publish(message) if read_only => {
+                            warn!("Not publishing in read-only mode");
+                            tx2.send(Update::Delivery(message.id, Delivery::Failed)).await?;
+                        }
+                        // publish
+                        Command::Publish(mut message) => {
+                            message.seq = Some(sequencer.next(&topic));
+                            let limit = max_message_size - ENVELOPE_OVERHEAD;
+                            let published = wire::encode_chat(&message, limit)
+                                .and_then(|payloads| publish_chat(&mut swarm.behaviour_mut().gossip, &topic, payloads));
+                            let delivery = match published {
+                                Ok(()) => {
+                                    stats.sent += 1;
+                                    Delivery::Delivered
+                                }
+                                Err(e) => {
+                                    warn!("Publishing failed: {}", e);
+                                    Delivery::Failed
+                                }
+                            };
+                            tx2.send(Update::Delivery(message.id, delivery)).await?;
+                        }
+                        Command::Edit(..) | Command::Delete(_) if read_only => {
+                            warn!("Not publishing in read-only mode");
+                        }
+                        Command::Whisper(message, _) if read_only => {
+                            warn!("Not publishing in read-only mode");
+                            tx2.send(Update::Delivery(message.id, Delivery::Failed)).await?;
+                        }
+                        Command::Whisper(message, to) => {
+                            let chat = Frame::Chat {
+                                id: Some(message.id),
+                                name: message.sender.clone(),
+                                body: message.body.clone(),
+                                reply_to: None,
+                                seq: None,
+                                sent_at: Some(Utc::now().timestamp_millis()),
+                            };
+                            let published = whisper::seal(&keypair, &to, &chat.encode()).and_then(|(nonce, sealed)| {
+                                let frame = Frame::Whisper { to: to.to_base58(), nonce, sealed };
+                                publish_frame(&mut swarm.behaviour_mut().gossip, &topic, frame, max_message_size - ENVELOPE_OVERHEAD)
+                            });
+                            let delivery = match published {
+                                Ok(()) => {
+                                    stats.sent += 1;
+                                    Delivery::Delivered
+                                }
+                                Err(e) => {
+                                    warn!("Publishing a whisper failed: {}", e);
+                                    tx2.send(Update::System(format!("private message not sent: {}", e))).await?;
+                                    Delivery::Failed
+                                }
+                            };
+                            tx2.send(Update::Delivery(message.id, delivery)).await?;
+                        }
+                        Command::Edit(id, body) => {
+                            let frame = Frame::Edit { id, body };
+                            if let Err(e) = publish_frame(&mut swarm.behaviour_mut().gossip, &topic, frame, max_message_size - ENVELOPE_OVERHEAD) {
+                                warn!("Publishing an edit failed: {}", e);
+                                tx2.send(Update::System(format!("edit not sent: {}", e))).await?;
+                            }
+                        }
+                        Command::Delete(id) => {
+                            let frame = Frame::Delete { id };
+                            if let Err(e) = publish_frame(&mut swarm.behaviour_mut().gossip, &topic, frame, max_message_size - ENVELOPE_OVERHEAD) {
+                                warn!("Publishing a delete failed: {}", e);
+                                tx2.send(Update::System(format!("delete not sent: {}", e))).await?;
+                            }
+                        }
+                        Command::Away => {
+                            away = true;
+                            if let Err(e) = swarm.behaviour_mut().gossip.unsubscribe(&Topic::new(&topic)) {
+                                warn!("Leaving topic failed: {:?}", e);
+                            }
+                            swarm.remove_listener(relay_listener);
+                            health.unreserved();
+                            tx2.send(Update::System("away, left the topic and dropped the relay reservation".to_string())).await?;
+                        }
+                        Command::Resume => {
+                            away = false;
+                            if let Err(e) = swarm.behaviour_mut().gossip.subscribe(&Topic::new(&topic)) {
+                                warn!("Rejoining topic failed: {:?}", e);
+                            }
+                            match swarm.listen_on(relay_address.clone().with(Protocol::P2pCircuit)) {
+                                Ok(id) => relay_listener = id,
+                                Err(e) => warn!("Renewing the relay reservation failed: {:?}", e),
+                            }
+                            tx2.send(Update::System("back, rejoined the topic".to_string())).await?;
+                        }
+                        Command::Address => {
+                            let address = circuit_address(&relay_address, local_peer_id);
+                            let notice = format!("reachable at {}, your peer dials you with --remote-id {}", address, local_peer_id);
+                            tx2.send(Update::System(notice)).await?;
+                        }
+                        Command::Stats => {
+                            tx2.send(Update::System(stats.report())).await?;
+                        }
+                        Command::Peers => {
+                            let report = peers_report(&swarm.behaviour().gossip, &Topic::new(&topic).hash());
+                            tx2.send(Update::System(report)).await?;
+                        }
+                        Command::Path(preference) => {
+                            path.prefer(preference);
+                            let notice = match (preference, path.peer()) {
+                                (_, None) => "no peer connected yet".to_string(),
+                                (Preference::Direct, Some(_)) => match path.direct_addr() {
+                                    Some(addr) => match swarm.dial(addr.clone()) {
+                                        Ok(()) => format!("dialing {} directly", addr),
+                                        Err(e) => format!("direct dial failed: {}", e),
+                                    },
+                                    None => "no direct address known yet, waiting for a hole punch".to_string(),
+                                },
+                                (Preference::Relay, Some(_)) => use_relay(&mut swarm, &relay_address, &mut path)
+                                    .unwrap_or_else(|| "using the relay".to_string()),
+                                (Preference::Auto, Some(_)) => "choosing the path automatically".to_string(),
+                            };
+                            tx2.send(Update::System(notice)).await?;
+                            tx2.send(Update::Path(path.status())).await?;
+                        }
+                        // a dm has the one topic, there are no tabs to switch
+                        Command::Room(_) => {}
+                    }
+                },
+                // receive
+                event = swarm.select_next_some() => {
+                    event_log::record(&tx2, &event, Event::logged);
+                    match &event {
+                        SwarmEvent::ConnectionEstablished { peer_id, endpoint, .. } => health.connected(peer_id, endpoint),
+                        SwarmEvent::ConnectionClosed { peer_id, endpoint, .. } => health.disconnected(peer_id, endpoint),
+                        SwarmEvent::Behaviour(Event::Identify(IdentifyEvent::Received { peer_id, info })) => {
+                            health.identified(peer_id, &info.agent_version);
+                        }
+                        SwarmEvent::Behaviour(Event::Relay(client::Event::ReservationReqAccepted { .. })) => health.reserved(),
+                        _ => {}
+                    }
+                    match event {
+                        SwarmEvent::Behaviour(Event::Gossip(GossipsubEvent::Message{
+                            propagation_source,
+                            message,
+                            ..
+                        })) => {
+                            // keyed by sender and frame, a frame published again gets a new message id
+                            let frame = Frame::decode(&message.data)
+                                .filter(|frame| frame.key().map_or(true, |key| dedup.first_sighting(&(message.source, key))))
+                                .and_then(|frame| reassembler.push(message.source, frame));
+                            match (frame, message.source) {
+                                (Some(frame), source) if is_own_echo(&frame, source.as_ref(), &local_peer_id, own_echoes) => {
+                                    debug!("Dropping the echo of our own message");
+                                }
+                                (Some(Frame::Chat { id, name, body, reply_to, seq, sent_at }), source) => {
+                                    stats.received += 1;
+                                    stats.arrived(sent_at, health.is_direct(&propagation_source));
+                                    let mut received = ChatMessage::remote(&name, body);
+                                    received.id = id.unwrap_or(received.id);
+                                    received.reply_to = reply_to;
+                                    received.seq = seq;
+                                    received.author = source.map(|peer| peer.to_base58());
+                                    tx2.send(Update::Message(0, received)).await?;
+                                }
+                                (Some(Frame::Edit { id, body }), Some(author)) => {
+                                    tx2.send(Update::Edit { id, author, body }).await?;
+                                }
+                                (Some(Frame::Delete { id }), Some(author)) => {
+                                    tx2.send(Update::Delete { id, author }).await?;
+                                }
+                                // whispers for others are dropped unread, they couldn't be opened anyway
+                                (Some(Frame::Whisper { to, nonce, sealed }), Some(author)) if to == local_peer_id.to_base58() => {
+                                    let chat = whisper::open(&keypair, &author, &nonce, &sealed).and_then(|plain| Frame::decode(&plain));
+                                    match chat {
+                                        Some(Frame::Chat { id, name, body, sent_at, .. }) => {
+                                            stats.received += 1;
+                                            stats.arrived(sent_at, health.is_direct(&propagation_source));
+                                            let mut received = ChatMessage::remote(&name, body);
+                                            received.id = id.unwrap_or(received.id);
+                                            received.author = Some(author.to_base58());
+                                            received.private = true;
+                                            tx2.send(Update::Message(0, received)).await?;
+                                        }
+                                        _ => warn!("Could not open a private message from {}", author),
+                                    }
+                                }
+                                _ => {}
+                            }
+                        }
+                        SwarmEvent::Behaviour(Event::Identify(IdentifyEvent::Received { peer_id, info })) => {
+                            if let Some(warning) = protocol_mismatch(&protocol_version, &peer_id, &info.protocol_version) {
+                                warn!("{}", warning);
+                                if strict_version {
+                                    let _ = swarm.disconnect_peer_id(peer_id);
+                                    tx2.send(Update::System(format!("{}, disconnected", warning))).await?;
+                                } else {
+                                    tx2.send(Update::System(warning)).await?;
+                                }
+                            }
+                        }
+                        // a pong only means we answered the peer's ping
+                        SwarmEvent::Behaviour(Event::Ping(PingEvent { peer, result })) if !matches!(result, Ok(PingSuccess::Pong)) => {
+                            let rtt = match result {
+                                Ok(PingSuccess::Ping { rtt }) => Some(rtt),
+                                _ => None,
+                            };
+                            if let Some(rtt) = rtt {
+                                stats.rtt.insert(peer, rtt);
+                            }
+                            if path.ping(&peer, rtt) {
+                                if let Some(notice) = use_relay(&mut swarm, &relay_address, &mut path) {
+                                    tx2.send(Update::System(notice)).await?;
+                                }
+                                tx2.send(Update::Path(path.status())).await?;
+                            }
+                        }
+                        // banning closes the connection and refuses the peer's later ones
+                        SwarmEvent::ConnectionEstablished { peer_id, .. } if !allowlist.admits(&peer_id) => {
+                            let notice = allowlist.turn_away(&mut swarm, peer_id);
+                            tx2.send(Update::System(notice)).await?;
+                        }
+                        // the relay restarted, the reservation went with the old connection
+                        SwarmEvent::ConnectionEstablished { peer_id, .. } if relay_link.established(&peer_id) => {
+                            info!("Reconnected to relay {}", peer_id);
+                            swarm.remove_listener(relay_listener);
+                            if !away {
+                                match swarm.listen_on(relay_address.clone().with(Protocol::P2pCircuit)) {
+                                    Ok(id) => relay_listener = id,
+                                    Err(e) => warn!("Renewing the relay reservation failed: {:?}", e),
+                                }
+                                resubscribe(&mut swarm.behaviour_mut().gossip, std::slice::from_ref(&topic));
+                            }
+                            tx2.send(Update::System("reconnected to the relay".to_string())).await?;
+                            tx2.send(Update::Relay(relay_link.status())).await?;
+                        }
+                        SwarmEvent::ConnectionEstablished { peer_id, endpoint, .. } => {
+                            // a reconnect is when a lost subscription would show
+                            if !away {
+                                resubscribe(&mut swarm.behaviour_mut().gossip, std::slice::from_ref(&topic));
+                            }
+                            if path.connected(&peer_id, &endpoint) {
+                                if let Some(notice) = use_relay(&mut swarm, &relay_address, &mut path) {
+                                    tx2.send(Update::System(notice)).await?;
+                                }
+                                tx2.send(Update::Path(path.status())).await?;
+                            }
+                        }
+                        SwarmEvent::ConnectionClosed { peer_id, num_established, .. } if relay_link.closed(&peer_id, num_established) => {
+                            warn!("Lost the connection to relay {}", peer_id);
+                            health.unreserved();
+                            let notice = match relay_link.status() {
+                                RelayStatus::Lost => "lost the connection to the relay, the peer can't reach you through it",
+                                _ => "lost the connection to the relay, reconnecting",
+                            };
+                            tx2.send(Update::System(notice.to_string())).await?;
+                            tx2.send(Update::Relay(relay_link.status())).await?;
+                        }
+                        SwarmEvent::ConnectionClosed { peer_id, endpoint, .. } => {
+                            if path.disconnected(&peer_id, &endpoint) {
+                                tx2.send(Update::Path(path.status())).await?;
+                            }
+                        }
+                        SwarmEvent::OutgoingConnectionError { peer_id, error } if relay_link.dial_failed(peer_id) => {
+                            warn!("Dialing relay {} again failed: {}", relay_address, error);
+                            if relay_link.status() == RelayStatus::Lost {
+                                tx2.send(Update::System("gave up reconnecting to the relay".to_string())).await?;
+                            }
+                            tx2.send(Update::Relay(relay_link.status())).await?;
+                        }
+                        _ => {}
                     }
-                    _ => {}
                 }
             }
         }
     }
+    .await;
+    if stopped.is_err() {
+        info!("The TUI stopped taking updates, stopping the network task");
+    }
+}
+
+/// Move the conversation to the relay when `path` says so: close the connections to the peer
+/// and dial it again through a circuit. Returns the notice to show when it did
+fn use_relay(swarm: &mut Swarm<Behaviour>, relay_address: &Multiaddr, path: &mut PathMonitor) -> Option<String> {
+    let peer = path.peer()?;
+    if !path.should_use_relay() {
+        return None;
+    }
+    let notice = if path.degraded() {
+        format!("direct path degraded ({}), switching to the relay", path.status())
+    } else {
+        "switching to the relay".to_string()
+    };
+    path.switched();
+    let _ = swarm.disconnect_peer_id(peer);
+    let circuit = relay_address
+        .clone()
+        .with(Protocol::P2pCircuit)
+        .with(Protocol::P2p(peer.into()));
+    match swarm.dial(circuit) {
+        Ok(()) => Some(notice),
+        Err(e) => Some(format!("{}, but dialing through the relay failed: {}", notice, e)),
+    }
 }
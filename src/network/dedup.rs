@@ -0,0 +1,98 @@
+use std::collections::{HashMap, VecDeque};
+use std::hash::Hash;
+use std::time::{Duration, Instant};
+
+/// Upper bound on remembered ids, the oldest are forgotten first when a burst exceeds it
+const CAPACITY: usize = 4096;
+
+/// Remembers ids seen within the last `window` so a message re-delivered after a reconnect
+/// isn't shown twice
+pub struct Dedup<K> {
+    window: Duration,
+    seen: HashMap<K, Instant>,
+    order: VecDeque<(Instant, K)>,
+}
+
+impl<K: Clone + Eq + Hash> Dedup<K> {
+    pub fn new(window: Duration) -> Dedup<K> {
+        Dedup {
+            window,
+            seen: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    /// Whether `id` wasn't seen within the window, remembering it either way
+    pub fn first_sighting(&mut self, id: &K) -> bool {
+        self.first_sighting_at(id, Instant::now())
+    }
+
+    fn first_sighting_at(&mut self, id: &K, now: Instant) -> bool {
+        while let Some((at, _)) = self.order.front() {
+            if now.duration_since(*at) < self.window && self.order.len() < CAPACITY {
+                break;
+            }
+            let (at, old) = self.order.pop_front().unwrap();
+            // a re-sighting moved the id to the back, only forget it with its last entry
+            if self.seen.get(&old) == Some(&at) {
+                self.seen.remove(&old);
+            }
+        }
+
+        let first = !self.seen.contains_key(id);
+        self.seen.insert(id.clone(), now);
+        self.order.push_back((now, id.clone()));
+        first
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WINDOW: Duration = Duration::from_secs(120);
+
+    #[test]
+    fn drops_repeats_within_the_window() {
+        let mut dedup = Dedup::new(WINDOW);
+        let start = Instant::now();
+        assert!(dedup.first_sighting_at(&1, start));
+        assert!(dedup.first_sighting_at(&2, start));
+        assert!(!dedup.first_sighting_at(&1, start + WINDOW / 2));
+        assert!(!dedup.first_sighting_at(&2, start + WINDOW - Duration::from_millis(1)));
+    }
+
+    #[test]
+    fn forgets_ids_after_the_window() {
+        let mut dedup = Dedup::new(WINDOW);
+        let start = Instant::now();
+        assert!(dedup.first_sighting_at(&1, start));
+        assert!(dedup.first_sighting_at(&1, start + WINDOW));
+        assert_eq!(dedup.seen.len(), 1);
+        assert_eq!(dedup.order.len(), 1);
+    }
+
+    #[test]
+    fn a_repeat_extends_the_window() {
+        let mut dedup = Dedup::new(WINDOW);
+        let start = Instant::now();
+        assert!(dedup.first_sighting_at(&1, start));
+        assert!(!dedup.first_sighting_at(&1, start + WINDOW / 2));
+        // the first entry expired, the repeat still holds the id
+        assert!(!dedup.first_sighting_at(&1, start + WINDOW + WINDOW / 4));
+    }
+
+    #[test]
+    fn evicts_the_oldest_beyond_capacity() {
+        let mut dedup = Dedup::new(WINDOW);
+        let start = Instant::now();
+        for id in 0..CAPACITY {
+            assert!(dedup.first_sighting_at(&id, start));
+        }
+        assert!(dedup.first_sighting_at(&CAPACITY, start));
+        assert!(dedup.order.len() <= CAPACITY);
+        // the newest are still known, the first one went to make room
+        assert!(!dedup.first_sighting_at(&CAPACITY, start));
+        assert!(dedup.first_sighting_at(&0, start));
+    }
+}
@@ -0,0 +1,99 @@
+//! The lines of the `l` event log: the swarm events that tell how the connection came about or
+//! broke, sent to the TUI as `Update::Event` with the time they happened
+
+use std::fmt;
+
+use chrono::Local;
+use libp2p::dcutr;
+use libp2p::relay::v2::client;
+use libp2p::rendezvous;
+use libp2p::swarm::SwarmEvent;
+use tokio::sync::mpsc::Sender;
+
+use crate::network::{short_peer_id, Update};
+
+/// Log `event` if it is one of the notable ones, `behaviour` picks the notable events of the
+/// session's own behaviours
+pub fn record<B, E: fmt::Debug>(tx2: &Sender<Update>, event: &SwarmEvent<B, E>, behaviour: impl FnOnce(&B) -> Option<String>) {
+    let line = match event {
+        SwarmEvent::Dialing(peer) => Some(format!("dialing {}", short_peer_id(peer))),
+        SwarmEvent::ConnectionEstablished { peer_id, endpoint, .. } => Some(format!(
+            "connected to {} {}",
+            short_peer_id(peer_id),
+            if endpoint.is_relayed() { "through the relay" } else { "directly" }
+        )),
+        SwarmEvent::ConnectionClosed { peer_id, cause: Some(cause), .. } => {
+            Some(format!("connection to {} closed: {:?}", short_peer_id(peer_id), cause))
+        }
+        SwarmEvent::ConnectionClosed { peer_id, cause: None, .. } => {
+            Some(format!("connection to {} closed", short_peer_id(peer_id)))
+        }
+        SwarmEvent::OutgoingConnectionError { peer_id, error } => Some(match peer_id {
+            Some(peer) => format!("dialing {} failed: {}", short_peer_id(peer), error),
+            None => format!("dialing failed: {}", error),
+        }),
+        SwarmEvent::IncomingConnectionError { send_back_addr, error, .. } => {
+            Some(format!("incoming connection from {} failed: {}", send_back_addr, error))
+        }
+        SwarmEvent::ListenerClosed { addresses, reason: Err(e), .. } => {
+            Some(format!("stopped listening on {:?}: {}", addresses, e))
+        }
+        SwarmEvent::ListenerError { error, .. } => Some(format!("listener error: {}", error)),
+        SwarmEvent::Behaviour(event) => behaviour(event),
+        _ => None,
+    };
+    if let Some(line) = line {
+        let _ = tx2.try_send(Update::Event(Local::now(), line));
+    }
+}
+
+pub fn relay(event: &client::Event) -> Option<String> {
+    Some(match event {
+        client::Event::ReservationReqAccepted { renewal: false, .. } => "relay accepted the reservation".to_string(),
+        client::Event::ReservationReqAccepted { renewal: true, .. } => "relay renewed the reservation".to_string(),
+        client::Event::ReservationReqFailed { error, .. } => format!("reservation failed: {:?}", error),
+        client::Event::OutboundCircuitEstablished { relay_peer_id, .. } => {
+            format!("circuit through {} open", short_peer_id(relay_peer_id))
+        }
+        client::Event::OutboundCircuitReqFailed { error, .. } => format!("circuit request failed: {:?}", error),
+        client::Event::InboundCircuitEstablished { src_peer_id, .. } => {
+            format!("{} reached us through the relay", short_peer_id(src_peer_id))
+        }
+        client::Event::InboundCircuitReqFailed { error, .. } => format!("incoming circuit failed: {:?}", error),
+        client::Event::InboundCircuitReqDenied { src_peer_id } => {
+            format!("denied a circuit from {}", short_peer_id(src_peer_id))
+        }
+        client::Event::InboundCircuitReqDenyFailed { src_peer_id, error } => {
+            format!("denying a circuit from {} failed: {:?}", short_peer_id(src_peer_id), error)
+        }
+    })
+}
+
+pub fn dcutr(event: &dcutr::behaviour::Event) -> Option<String> {
+    use dcutr::behaviour::Event;
+    Some(match event {
+        Event::InitiatedDirectConnectionUpgrade { remote_peer_id, .. } => {
+            format!("hole punching to {}", short_peer_id(remote_peer_id))
+        }
+        Event::RemoteInitiatedDirectConnectionUpgrade { remote_peer_id, .. } => {
+            format!("{} is hole punching to us", short_peer_id(remote_peer_id))
+        }
+        Event::DirectConnectionUpgradeSucceeded { remote_peer_id } => {
+            format!("hole punched, direct connection to {}", short_peer_id(remote_peer_id))
+        }
+        Event::DirectConnectionUpgradeFailed { remote_peer_id, error } => {
+            format!("hole punching to {} failed: {:?}", short_peer_id(remote_peer_id), error)
+        }
+    })
+}
+
+pub fn rendezvous(event: &rendezvous::client::Event) -> Option<String> {
+    match event {
+        rendezvous::client::Event::Registered { namespace, ttl, .. } => {
+            Some(format!("registered in {} for {}s", namespace, ttl))
+        }
+        rendezvous::client::Event::RegisterFailed(error) => Some(format!("rendezvous registration failed: {:?}", error)),
+        rendezvous::client::Event::DiscoverFailed { error, .. } => Some(format!("discovery failed: {:?}", error)),
+        _ => None,
+    }
+}
@@ -0,0 +1,119 @@
+//! What the health sidebar shows, gathered by the network task from the ping, identify, relay
+//! and gossipsub events it already handles and sent to the TUI every `HEALTH_INTERVAL`
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use libp2p::core::ConnectedPoint;
+use libp2p::PeerId;
+
+use crate::network::relay_link::RelayStatus;
+
+/// How often `Update::Health` is sent
+pub const HEALTH_INTERVAL: Duration = Duration::from_secs(2);
+/// How long a relay keeps a reservation, the libp2p default. The relay doesn't tell its
+/// clients, so the expiry shown is an estimate from when it was last accepted
+pub const RESERVATION_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// A snapshot of the connections, for `Update::Health`
+#[derive(Clone, Debug)]
+pub struct Health {
+    pub relay: Option<PeerId>,
+    /// The agent version the relay identified with
+    pub relay_agent: Option<String>,
+    pub relay_rtt: Option<Duration>,
+    pub relay_status: RelayStatus,
+    /// When the reservation was last accepted, none while there is no reservation
+    pub reserved_at: Option<Instant>,
+    pub peers: Vec<PeerHealth>,
+    /// Peers in the gossipsub mesh of the topic messages are published to
+    pub mesh: usize,
+}
+
+#[derive(Clone, Debug)]
+pub struct PeerHealth {
+    pub peer: PeerId,
+    pub rtt: Option<Duration>,
+    /// Whether one of the connections is direct rather than through a relay circuit
+    pub direct: bool,
+}
+
+/// Keeps what `Health` needs that the connection loop doesn't track already
+pub struct HealthMonitor {
+    relay: Option<PeerId>,
+    relay_agent: Option<String>,
+    reserved_at: Option<Instant>,
+    /// Open direct and relayed connections per peer
+    links: HashMap<PeerId, (usize, usize)>,
+}
+
+impl HealthMonitor {
+    /// The reservation asked for while connecting counts as held, the relay has usually
+    /// accepted it by the time the connection loop starts. The peers `connected` by then were
+    /// reached through circuits, a hole punched later shows up as a new connection
+    pub fn new(relay: Option<PeerId>, connected: impl IntoIterator<Item = PeerId>) -> HealthMonitor {
+        let links = connected.into_iter().filter(|peer| Some(*peer) != relay).map(|peer| (peer, (0, 1))).collect();
+        HealthMonitor { relay, relay_agent: None, reserved_at: Some(Instant::now()), links }
+    }
+
+    pub fn connected(&mut self, peer: &PeerId, endpoint: &ConnectedPoint) {
+        if Some(*peer) == self.relay {
+            return;
+        }
+        let (direct, relayed) = self.links.entry(*peer).or_default();
+        if endpoint.is_relayed() {
+            *relayed += 1;
+        } else {
+            *direct += 1;
+        }
+    }
+
+    pub fn disconnected(&mut self, peer: &PeerId, endpoint: &ConnectedPoint) {
+        if let Some((direct, relayed)) = self.links.get_mut(peer) {
+            let count = if endpoint.is_relayed() { relayed } else { direct };
+            *count = count.saturating_sub(1);
+            if *direct == 0 && *relayed == 0 {
+                self.links.remove(peer);
+            }
+        }
+    }
+
+    /// Whether we have a direct connection to `peer`, not only circuits through the relay
+    pub fn is_direct(&self, peer: &PeerId) -> bool {
+        self.links.get(peer).map_or(false, |(direct, _)| *direct > 0)
+    }
+
+    pub fn identified(&mut self, peer: &PeerId, agent_version: &str) {
+        if Some(*peer) == self.relay {
+            self.relay_agent = Some(agent_version.to_string());
+        }
+    }
+
+    /// The relay accepted a reservation or renewed it
+    pub fn reserved(&mut self) {
+        self.reserved_at = Some(Instant::now());
+    }
+
+    /// The reservation was dropped or went with the connection to the relay
+    pub fn unreserved(&mut self) {
+        self.reserved_at = None;
+    }
+
+    pub fn snapshot(&self, rtt: &HashMap<PeerId, Duration>, relay_status: RelayStatus, mesh: usize) -> Health {
+        let mut peers: Vec<PeerHealth> = self
+            .links
+            .iter()
+            .map(|(peer, (direct, _))| PeerHealth { peer: *peer, rtt: rtt.get(peer).copied(), direct: *direct > 0 })
+            .collect();
+        peers.sort_by_key(|peer| peer.peer.to_base58());
+        Health {
+            relay: self.relay,
+            relay_agent: self.relay_agent.clone(),
+            relay_rtt: self.relay.and_then(|relay| rtt.get(&relay).copied()),
+            relay_status,
+            reserved_at: self.reserved_at,
+            peers,
+            mesh,
+        }
+    }
+}
@@ -0,0 +1,172 @@
+//! `--profile-stats`: how long chat messages take to arrive, from the send time the sender puts
+//! in each frame to our clock, kept apart for messages from direct and from relayed neighbours.
+//! The two clocks are not synchronized, so a skew between them shifts every sample of that
+//! sender; comparing the paths or settings against each other stays meaningful
+
+use std::collections::VecDeque;
+
+use chrono::Utc;
+
+/// Samples kept per path, the oldest are dropped
+const MAX_SAMPLES: usize = 1000;
+
+/// Milliseconds, a sample further off either way has a clock a day out or a made up send time
+/// and says nothing about the path
+const MAX_LATENCY: i64 = 24 * 60 * 60 * 1000;
+
+/// Milliseconds, the fastest, mean, 95th percentile and slowest of `count` samples
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Summary {
+    pub count: usize,
+    pub min: i64,
+    pub avg: i64,
+    pub p95: i64,
+    pub max: i64,
+}
+
+/// Summarize `samples`, none when there are none. The percentile is the nearest rank one: the
+/// smallest sample at least 95% of them are at or below
+pub fn summarize(samples: impl IntoIterator<Item = i64>) -> Option<Summary> {
+    let mut sorted: Vec<i64> = samples.into_iter().collect();
+    if sorted.is_empty() {
+        return None;
+    }
+    sorted.sort_unstable();
+    let count = sorted.len();
+    let rank = (count * 95 + 99) / 100;
+    Some(Summary {
+        count,
+        min: sorted[0],
+        // samples from `record` can't overflow this, but any i64 can be summarized
+        avg: (sorted.iter().map(|&sample| sample as i128).sum::<i128>() / count as i128) as i64,
+        p95: sorted[rank - 1],
+        max: sorted[count - 1],
+    })
+}
+
+#[derive(Default)]
+pub struct LatencyProfile {
+    direct: VecDeque<i64>,
+    relayed: VecDeque<i64>,
+    /// Samples that arrived before they were sent by our clock, the sender's runs ahead
+    ahead: u64,
+}
+
+impl LatencyProfile {
+    /// A message sent at `sent_at`, unix milliseconds on the sender's clock, arrived now from a
+    /// neighbour we're connected to directly or through the relay. `sent_at` comes from the
+    /// peer, a time more than `MAX_LATENCY` off is dropped
+    pub fn record(&mut self, sent_at: i64, direct: bool) {
+        let latency = match Utc::now().timestamp_millis().checked_sub(sent_at) {
+            Some(latency) if (-MAX_LATENCY..=MAX_LATENCY).contains(&latency) => latency,
+            _ => return,
+        };
+        if latency < 0 {
+            self.ahead += 1;
+        }
+        let samples = if direct { &mut self.direct } else { &mut self.relayed };
+        if samples.len() == MAX_SAMPLES {
+            samples.pop_front();
+        }
+        samples.push_back(latency);
+    }
+
+    pub fn report(&self) -> String {
+        let line = |path: &str, samples: &VecDeque<i64>| match summarize(samples.iter().copied()) {
+            Some(s) => format!(
+                "{} {} msgs min {}ms avg {}ms p95 {}ms max {}ms",
+                path, s.count, s.min, s.avg, s.p95, s.max
+            ),
+            None => format!("{} no messages yet", path),
+        };
+        let mut report = format!("latency {}; {}", line("direct", &self.direct), line("relayed", &self.relayed));
+        if self.ahead > 0 {
+            report.push_str(&format!(
+                "; {} arrived before they were sent, a sender's clock is ahead of ours and skews these numbers",
+                self.ahead
+            ));
+        } else {
+            report.push_str("; the senders' clocks may differ from ours, compare the numbers rather than trust them");
+        }
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nothing_to_summarize() {
+        assert_eq!(summarize(Vec::new()), None);
+    }
+
+    #[test]
+    fn one_sample_is_every_statistic() {
+        assert_eq!(summarize([42]), Some(Summary { count: 1, min: 42, avg: 42, p95: 42, max: 42 }));
+    }
+
+    #[test]
+    fn the_percentile_is_the_nearest_rank() {
+        // 1..=100 in any order, the 95th smallest is 95
+        let summary = summarize((1..=100).rev()).unwrap();
+        assert_eq!(summary, Summary { count: 100, min: 1, avg: 50, p95: 95, max: 100 });
+        // of 10 samples the rank rounds up to the slowest
+        assert_eq!(summarize(1..=10).unwrap().p95, 10);
+        // of 20 it's the 19th
+        assert_eq!(summarize(1..=20).unwrap().p95, 19);
+    }
+
+    #[test]
+    fn a_skewed_clock_gives_negative_samples() {
+        let summary = summarize([-30, 10, 20]).unwrap();
+        assert_eq!((summary.min, summary.avg, summary.max), (-30, 0, 20));
+    }
+
+    #[test]
+    fn sums_without_overflowing() {
+        assert_eq!(summarize([i64::MAX, i64::MAX, i64::MAX]).unwrap().avg, i64::MAX);
+        assert_eq!(summarize([i64::MIN, i64::MIN]).unwrap().avg, i64::MIN);
+    }
+
+    #[test]
+    fn drops_hostile_send_times() {
+        let mut profile = LatencyProfile::default();
+        let now = Utc::now().timestamp_millis();
+        for sent_at in [i64::MIN, i64::MAX, 0, now - 2 * MAX_LATENCY, now + 2 * MAX_LATENCY] {
+            profile.record(sent_at, true);
+            profile.record(sent_at, false);
+        }
+        assert!(profile.direct.is_empty());
+        assert!(profile.relayed.is_empty());
+        assert_eq!(profile.ahead, 0);
+
+        profile.record(now - 1000, true);
+        assert_eq!(profile.direct.len(), 1);
+    }
+
+    #[test]
+    fn reports_both_paths_and_the_skew() {
+        let mut profile = LatencyProfile::default();
+        assert_eq!(
+            profile.report(),
+            "latency direct no messages yet; relayed no messages yet; \
+             the senders' clocks may differ from ours, compare the numbers rather than trust them"
+        );
+        // sent a minute from now by our clock
+        profile.record(Utc::now().timestamp_millis() + 60_000, false);
+        let report = profile.report();
+        assert!(report.starts_with("latency direct no messages yet; relayed 1 msgs min -"), "{}", report);
+        assert!(report.ends_with("; 1 arrived before they were sent, a sender's clock is ahead of ours and skews these numbers"));
+    }
+
+    #[test]
+    fn keeps_the_newest_samples() {
+        let mut profile = LatencyProfile::default();
+        for _ in 0..MAX_SAMPLES + 5 {
+            profile.record(Utc::now().timestamp_millis(), true);
+        }
+        assert_eq!(profile.direct.len(), MAX_SAMPLES);
+        assert!(profile.relayed.is_empty());
+    }
+}
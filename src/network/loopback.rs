@@ -0,0 +1,62 @@
+use crate::config::Config;
+use crate::message::{ChatMessage, Delivery};
+use crate::network::{Command, Update};
+
+use std::time::Duration;
+use tokio::sync::mpsc::{Receiver, Sender};
+
+/// Name the echoed messages appear under
+const ECHO_NAME: &str = "echo";
+
+/// Stands in for the network task with `--loopback`: every published message is marked
+/// delivered and comes back from `echo` after `--loopback-delay`, no libp2p involved
+pub async fn handle_msg(mut rx1: Receiver<Command>, tx2: Sender<Update>, config: Config) {
+    let delay = config.loopback_delay;
+    // echoes go back to the tab they were sent from
+    let mut room = 0;
+    tx2.send(Update::System("loopback mode, messages are echoed back locally".to_string()))
+        .await
+        .unwrap();
+    while let Some(cmd) = rx1.recv().await {
+        match cmd {
+            Command::Publish(message) | Command::Whisper(message, _) if config.read_only => {
+                tx2.send(Update::Delivery(message.id, Delivery::Failed)).await.unwrap();
+            }
+            Command::Publish(message) | Command::Whisper(message, _) => {
+                tx2.send(Update::Delivery(message.id, Delivery::Delivered)).await.unwrap();
+                let tx2 = tx2.clone();
+                // echo in the background so a slow echo doesn't hold up later commands
+                tokio::spawn(async move {
+                    if delay > Duration::ZERO {
+                        tokio::time::sleep(delay).await;
+                    }
+                    let mut echo = ChatMessage::remote(ECHO_NAME, message.body);
+                    echo.reply_to = message.reply_to;
+                    echo.private = message.private;
+                    let _ = tx2.send(Update::Message(room, echo)).await;
+                });
+            }
+            // the echo isn't ours to change, edits and deletes stay local
+            Command::Edit(..) | Command::Delete(_) => {}
+            Command::Room(index) => room = index,
+            Command::Path(_) => {
+                tx2.send(Update::System("loopback mode, no network paths".to_string())).await.unwrap();
+            }
+            Command::Address => {
+                tx2.send(Update::System("loopback mode, not reachable".to_string())).await.unwrap();
+            }
+            Command::Stats => {
+                tx2.send(Update::System("loopback mode, no network traffic".to_string())).await.unwrap();
+            }
+            Command::Peers => {
+                tx2.send(Update::System("loopback mode, no peers".to_string())).await.unwrap();
+            }
+            Command::Away => {
+                tx2.send(Update::System("away".to_string())).await.unwrap();
+            }
+            Command::Resume => {
+                tx2.send(Update::System("back".to_string())).await.unwrap();
+            }
+        }
+    }
+}
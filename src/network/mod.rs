@@ -1,3 +1,550 @@
 pub mod connection_dm;
 pub mod connection_channel;
-pub mod secure;
\ No newline at end of file
+pub mod dedup;
+pub mod event_log;
+pub mod health;
+pub mod latency;
+pub mod loopback;
+pub mod path;
+pub mod ping;
+pub mod relay_link;
+pub mod relay_pick;
+pub mod replay;
+pub mod secret_store;
+pub mod secure;
+pub mod whisper;
+pub mod wire;
+
+use crate::config::Config;
+use crate::message::{ChatMessage, Delivery};
+use health::Health;
+use latency::LatencyProfile;
+use path::{PathStatus, Preference};
+use relay_link::RelayStatus;
+use wire::Frame;
+use libp2p::bandwidth::BandwidthSinks;
+use libp2p::core::multiaddr::{Multiaddr, Protocol};
+use libp2p::gossipsub::{Gossipsub, IdentTopic, TopicHash};
+use chrono::{DateTime, Local};
+use libp2p::swarm::NetworkBehaviour;
+use libp2p::{PeerId, Swarm};
+use log::{info, warn};
+use rand::Rng;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use trust_dns_resolver::TokioAsyncResolver;
+
+/// Identify protocol advertised by tochat peers, bumped with the crate version
+pub const PROTOCOL_VERSION: &str = concat!("/tochat/", env!("CARGO_PKG_VERSION"));
+
+/// Identify agent version sent unless `--agent-version` overrides it, e.g. `tochat/0.1.0 (linux)`,
+/// so relays and peers can log what connected
+pub fn default_agent_version() -> String {
+    format!("tochat/{} ({})", env!("CARGO_PKG_VERSION"), std::env::consts::OS)
+}
+
+/// Requests sent from the TUI to the network task
+#[derive(Debug)]
+pub enum Command {
+    /// Publish one of our messages to the topic, answered with `Update::Delivery`
+    Publish(ChatMessage),
+    /// Replace the body of one of our published messages
+    Edit(u64, String),
+    /// Delete one of our published messages
+    Delete(u64),
+    /// Publish one of our messages sealed for a single peer, answered with `Update::Delivery`
+    Whisper(ChatMessage, PeerId),
+    /// Choose how to reach the peer of a dm session, answered with `Update::Path`
+    Path(Preference),
+    /// Publish, edit and delete in the room with this index of `Config::rooms` from now on,
+    /// sent when the user switches tabs
+    Room(usize),
+    /// Report the connected peers and the topic mesh, publishes nothing
+    Peers,
+    /// Report the circuit address peers reach us at, for `/address`
+    Address,
+    /// Report traffic, message counts and round trip times
+    Stats,
+    /// Leave the topic and drop the relay reservation after `--idle-timeout`
+    Away,
+    /// Undo `Away` once the user is active again
+    Resume,
+}
+
+/// Updates sent from the network task to the TUI
+#[derive(Debug)]
+pub enum Update {
+    /// A message received from a peer in the room with this index of `Config::rooms`,
+    /// always 0 outside channels
+    Message(usize, ChatMessage),
+    /// Messages a peer already in the room with this index sent us on joining, oldest first
+    Replay(usize, Vec<ChatMessage>),
+    /// A notice for the message list
+    System(String),
+    /// The outcome of publishing the message with this id
+    Delivery(u64, Delivery),
+    /// `author` replaced the body of the message with this id
+    Edit { id: u64, author: PeerId, body: String },
+    /// `author` deleted the message with this id
+    Delete { id: u64, author: PeerId },
+    /// The path to the peer of a dm session or its quality changed
+    Path(PathStatus),
+    /// Connections, round trips and the reservation, sent every `HEALTH_INTERVAL` for the sidebar
+    Health(Health),
+    /// The connection to the relay was lost or came back, shown in the status line while it is down
+    Relay(RelayStatus),
+    /// A line for the `l` event log, with when it happened
+    Event(DateTime<Local>, String),
+    /// The circuit address a listening dm session is reached at, shown in the status line
+    Address(String),
+    /// A message written to `--inject-socket`, published as if typed. `reply_to` is the id of
+    /// a message in the list to answer
+    Inject { body: String, reply_to: Option<u64> },
+}
+
+/// Where a peer listening on the relay is dialed: `<relay>/p2p-circuit/p2p/<peer>`
+pub fn circuit_address(relay_address: &Multiaddr, peer: PeerId) -> Multiaddr {
+    relay_address.clone().with(Protocol::P2pCircuit).with(Protocol::P2p(peer.into()))
+}
+
+/// The relay's own id, the `/p2p/` part `parse_relay_address` requires
+pub fn relay_peer_id(relay_address: &Multiaddr) -> Option<PeerId> {
+    match relay_address.iter().last() {
+        Some(Protocol::P2p(hash)) => PeerId::from_multihash(hash).ok(),
+        _ => None,
+    }
+}
+
+/// A chat message of ours handed back by the mesh, e.g. with the same key in the room twice.
+/// It's already in the list since we sent it, so it's dropped unless `--show-own-echoes` is set
+pub fn is_own_echo(frame: &Frame, source: Option<&PeerId>, local_peer_id: &PeerId, own_echoes: bool) -> bool {
+    !own_echoes && matches!(frame, Frame::Chat { .. }) && source == Some(local_peer_id)
+}
+
+/// The `--accept-only` allowlist, the relay and a dialed `--remote-id` always get through
+pub struct Allowlist {
+    peers: Vec<PeerId>,
+}
+
+impl Allowlist {
+    pub fn new(config: &Config) -> Allowlist {
+        let mut peers = config.accept_only.clone();
+        if !peers.is_empty() {
+            peers.extend(relay_peer_id(&config.relay_address));
+            peers.extend(config.remote_id);
+        }
+        Allowlist { peers }
+    }
+
+    pub fn admits(&self, peer: &PeerId) -> bool {
+        self.peers.is_empty() || self.peers.contains(peer)
+    }
+
+    /// Ban a `peer` not admitted, which closes its connections and refuses its later ones.
+    /// Returns the notice to show
+    pub fn turn_away<B: NetworkBehaviour>(&self, swarm: &mut Swarm<B>, peer: PeerId) -> String {
+        swarm.ban_peer_id(peer);
+        warn!("Rejected connection from {}, not in --accept-only", peer);
+        format!("rejected connection from {}", peer)
+    }
+}
+
+/// The message of the day a relay started with `--banner` puts in its identify agent version
+pub fn relay_motd(agent_version: &str) -> Option<&str> {
+    let (_, rest) = agent_version.split_once(" (motd: ")?;
+    rest.strip_suffix(')').filter(|motd| !motd.is_empty())
+}
+
+/// Abbreviate a PeerId to its first and last characters, e.g. `12D3Ko…kx6nXTN`
+pub fn short_peer_id(peer_id: &PeerId) -> String {
+    let s = peer_id.to_base58();
+    if s.len() <= 14 {
+        return s;
+    }
+    format!("{}…{}", &s[..6], &s[s.len() - 6..])
+}
+
+const ADJECTIVES: &[&str] = &[
+    "amber", "bold", "brave", "calm", "clever", "crisp", "eager", "fancy", "gentle", "glad", "grand",
+    "happy", "jolly", "keen", "kind", "lively", "lucky", "merry", "mighty", "noble", "plucky", "proud",
+    "quick", "quiet", "rapid", "shy", "silent", "sunny", "swift", "tidy", "witty", "zesty",
+];
+const ANIMALS: &[&str] = &[
+    "badger", "bat", "bear", "beaver", "bison", "crane", "crow", "deer", "dolphin", "eagle", "falcon",
+    "ferret", "fox", "gecko", "heron", "ibex", "koala", "lemur", "lynx", "marten", "moose", "newt",
+    "otter", "owl", "panda", "puffin", "raven", "seal", "stoat", "tiger", "walrus", "wolf",
+];
+
+/// Readable name for a peer without `--name`, e.g. `quiet-otter-kx6nXT`. The words are picked from
+/// the key hash at the end of the PeerId and the suffix is its last base58 characters, so the name
+/// is stable per key and different keys practically never share one
+pub fn derived_name(peer_id: &PeerId) -> String {
+    let bytes = peer_id.to_bytes();
+    let n = bytes.len();
+    let s = peer_id.to_base58();
+    format!(
+        "{}-{}-{}",
+        ADJECTIVES[bytes[n - 1] as usize % ADJECTIVES.len()],
+        ANIMALS[bytes[n - 2] as usize % ANIMALS.len()],
+        &s[s.len() - 6..]
+    )
+}
+
+/// Counters behind `/stats`, kept by the network task
+pub struct Stats {
+    started: Instant,
+    pub sent: u64,
+    pub received: u64,
+    /// Last ping round trip per peer
+    pub rtt: HashMap<PeerId, Duration>,
+    bandwidth: Arc<BandwidthSinks>,
+    /// With `--profile-stats`
+    latency: Option<LatencyProfile>,
+}
+
+impl Stats {
+    pub fn new(bandwidth: Arc<BandwidthSinks>, profile: bool) -> Stats {
+        Stats {
+            started: Instant::now(),
+            sent: 0,
+            received: 0,
+            rtt: HashMap::new(),
+            bandwidth,
+            latency: profile.then(LatencyProfile::default),
+        }
+    }
+
+    /// A chat message stamped `sent_at` by its sender arrived from a `direct` neighbour or one
+    /// behind the relay, counted with `--profile-stats`
+    pub fn arrived(&mut self, sent_at: Option<i64>, direct: bool) {
+        if let (Some(latency), Some(sent_at)) = (&mut self.latency, sent_at) {
+            latency.record(sent_at, direct);
+        }
+    }
+
+    pub fn report(&self) -> String {
+        let rtt = if self.rtt.is_empty() {
+            "no round trips measured yet".to_string()
+        } else {
+            let rtts: Vec<String> = self
+                .rtt
+                .iter()
+                .map(|(peer, rtt)| format!("{} {}ms", short_peer_id(peer), rtt.as_millis()))
+                .collect();
+            format!("rtt {}", rtts.join(", "))
+        };
+        let report = format!(
+            "up {:?}, {} messages sent, {} received, {} sent, {} received, {}",
+            Duration::from_secs(self.started.elapsed().as_secs()),
+            self.sent,
+            self.received,
+            format_bytes(self.bandwidth.total_outbound()),
+            format_bytes(self.bandwidth.total_inbound()),
+            rtt
+        );
+        match &self.latency {
+            Some(latency) => format!("{}, {}", report, latency.report()),
+            None => report,
+        }
+    }
+}
+
+fn format_bytes(bytes: u64) -> String {
+    match bytes {
+        b if b >= 1 << 20 => format!("{:.1} MiB", b as f64 / (1 << 20) as f64),
+        b if b >= 1 << 10 => format!("{:.1} KiB", b as f64 / (1 << 10) as f64),
+        b => format!("{} B", b),
+    }
+}
+
+/// Publish an edit or delete, which unlike chat messages are never split into chunks
+pub fn publish_frame(gossip: &mut Gossipsub, topic: &str, frame: Frame, limit: usize) -> Result<(), String> {
+    let data = frame.encode();
+    if data.len() > limit {
+        return Err(format!("{} bytes, at most {} fit in one message", data.len(), limit));
+    }
+    gossip
+        .publish(IdentTopic::new(topic), data)
+        .map(|_| ())
+        .map_err(|e| format!("{:?}", e))
+}
+
+/// Publish the payloads of one chat message from `wire::encode_chat`. A split message is only
+/// started when a peer is subscribed to `topic`, so the usual failure, no one to send to, leaves
+/// no stray chunks behind; a later chunk failing anyway leaves receivers to drop the rest
+pub fn publish_chat(gossip: &mut Gossipsub, topic: &str, payloads: Vec<Vec<u8>>) -> Result<(), String> {
+    let hash = IdentTopic::new(topic).hash();
+    if payloads.len() > 1 && !gossip.all_peers().any(|(_, topics)| topics.contains(&&hash)) {
+        return Err(format!("no peer is subscribed to '{}', not sending any of {} chunks", topic, payloads.len()));
+    }
+    for (index, data) in payloads.iter().enumerate() {
+        gossip.publish(IdentTopic::new(topic), data.clone()).map_err(|e| {
+            if index == 0 {
+                format!("{:?}", e)
+            } else {
+                format!("{:?} after {} of {} chunks", e, index, payloads.len())
+            }
+        })?;
+    }
+    Ok(())
+}
+
+/// Summarize the gossipsub state for `/peers`
+pub fn peers_report(gossip: &Gossipsub, topic: &TopicHash) -> String {
+    let peers: Vec<String> = gossip.all_peers().map(|(p, _)| short_peer_id(p)).collect();
+    let mesh = gossip.mesh_peers(topic).count();
+    if peers.is_empty() {
+        format!("no connected peers, mesh size for '{}' is {}", topic, mesh)
+    } else {
+        format!(
+            "{} connected peers ({}), mesh size for '{}' is {}",
+            peers.len(),
+            peers.join(", "),
+            topic,
+            mesh
+        )
+    }
+}
+
+/// Why `establish_connection` gave up when the swarm stopped yielding events, which it only
+/// does once it is dropped
+pub const SWARM_ENDED: &str = "the network stopped while connecting";
+
+/// How often `handle_msg` checks that gossipsub still holds the subscriptions it should
+pub const SUBSCRIPTION_CHECK: Duration = Duration::from_secs(30);
+
+/// Subscribe again to each of `topics` gossipsub has lost, so a session can't stay connected but
+/// deaf after a reconnect. Not for while away, when the topics were left on purpose
+pub fn resubscribe(gossip: &mut Gossipsub, topics: &[String]) {
+    let subscribed: Vec<TopicHash> = gossip.topics().cloned().collect();
+    for topic in topics {
+        let topic = IdentTopic::new(topic);
+        if subscribed.contains(&topic.hash()) {
+            continue;
+        }
+        match gossip.subscribe(&topic) {
+            Ok(_) => warn!("Lost the subscription to topic {}, subscribed again", topic),
+            Err(e) => warn!("Subscribing again to topic {} failed: {:?}", topic, e),
+        }
+    }
+}
+
+/// Delay before retrying a failed dial: exponential backoff from 500ms capped at 30s,
+/// plus up to 50% random jitter so peers restarting together don't retry in lockstep
+pub fn backoff_delay(attempt: u32) -> Duration {
+    let base = 500u64.saturating_mul(1 << attempt.saturating_sub(1).min(6)).min(30_000);
+    let jitter = rand::thread_rng().gen_range(0..=base / 2);
+    Duration::from_millis(base + jitter)
+}
+
+/// Split `/tochat/1.2.3` into its protocol name and major version
+fn parse_protocol_version(s: &str) -> Option<(&str, &str)> {
+    let (name, version) = s.rsplit_once('/')?;
+    let major = version.split('.').next()?;
+    Some((name, major))
+}
+
+/// Peers are compatible when they speak the same protocol with the same major version
+pub fn protocol_compatible(local: &str, remote: &str) -> bool {
+    match (parse_protocol_version(local), parse_protocol_version(remote)) {
+        (Some(local), Some(remote)) => local == remote,
+        _ => local == remote,
+    }
+}
+
+/// Warning to show when `peer_id` advertises a protocol we may not understand
+pub fn protocol_mismatch(local: &str, peer_id: &PeerId, remote: &str) -> Option<String> {
+    if protocol_compatible(local, remote) {
+        return None;
+    }
+    Some(format!(
+        "peer {} runs {} which is incompatible with our {}",
+        short_peer_id(peer_id),
+        remote,
+        local
+    ))
+}
+
+/// Resolve the host of a `/dns`, `/dns4`, `/dns6` or `/dnsaddr` relay address before dialing,
+/// so a typo or an unreachable name server is reported plainly instead of as a dial error.
+/// The dial itself still goes through the transport's `DnsConfig`
+pub async fn check_relay_dns(relay_address: &Multiaddr) -> Result<(), String> {
+    let resolver = || {
+        TokioAsyncResolver::tokio_from_system_conf()
+            .map_err(|e| format!("cannot read the system DNS configuration: {}", e))
+    };
+    for protocol in relay_address.iter() {
+        let (host, resolved) = match protocol {
+            Protocol::Dns(host) => (host.to_string(), resolver()?.lookup_ip(host.as_ref()).await.map(|r| r.iter().count())),
+            Protocol::Dns4(host) => (host.to_string(), resolver()?.ipv4_lookup(host.as_ref()).await.map(|r| r.iter().count())),
+            Protocol::Dns6(host) => (host.to_string(), resolver()?.ipv6_lookup(host.as_ref()).await.map(|r| r.iter().count())),
+            Protocol::Dnsaddr(host) => {
+                let name = format!("_dnsaddr.{}", host);
+                (name.clone(), resolver()?.txt_lookup(name).await.map(|r| r.iter().count()))
+            }
+            _ => continue,
+        };
+        match resolved {
+            Ok(n) if n > 0 => info!("Resolved relay host {} to {} records", host, n),
+            Ok(_) => return Err(format!("could not resolve {}: no records", host)),
+            Err(e) => return Err(format!("could not resolve {}: {}", host, e)),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{FileConfig, SessionArgs};
+    use crate::network::secret_store::Backend;
+
+    fn allowlist(accept_only: &[PeerId], relay: &PeerId, remote_id: Option<PeerId>) -> Allowlist {
+        let mut flags = vec![
+            "--name=test".to_string(),
+            "--ephemeral".to_string(),
+            format!("--relay-address=/ip4/127.0.0.1/tcp/4001/p2p/{}", relay),
+        ];
+        if !accept_only.is_empty() {
+            let ids: Vec<String> = accept_only.iter().map(PeerId::to_base58).collect();
+            flags.push(format!("--accept-only={}", ids.join(",")));
+        }
+        let args = SessionArgs::from_flags(flags).unwrap();
+        let config = Config::resolve(&args, remote_id, None, FileConfig::default(), Backend::File).unwrap();
+        Allowlist::new(&config)
+    }
+
+    #[test]
+    fn an_empty_allowlist_admits_everyone() {
+        let allowlist = allowlist(&[], &PeerId::random(), None);
+        assert!(allowlist.admits(&PeerId::random()));
+    }
+
+    #[test]
+    fn admits_only_listed_peers() {
+        let (friend, stranger) = (PeerId::random(), PeerId::random());
+        let allowlist = allowlist(&[friend], &PeerId::random(), None);
+        assert!(allowlist.admits(&friend));
+        assert!(!allowlist.admits(&stranger));
+    }
+
+    /// A swarm on the in-memory transport that keeps its connections open
+    fn memory_swarm() -> Swarm<libp2p::ping::Ping> {
+        use libp2p::core::transport::MemoryTransport;
+        use libp2p::core::upgrade;
+        use libp2p::ping::{Ping, PingConfig};
+        use libp2p::plaintext::PlainText2Config;
+        use libp2p::swarm::SwarmBuilder;
+        use libp2p::{identity, yamux, Transport};
+
+        let key = identity::Keypair::generate_ed25519();
+        let peer_id = PeerId::from(key.public());
+        let transport = MemoryTransport::default()
+            .upgrade(upgrade::Version::V1)
+            .authenticate(PlainText2Config { local_public_key: key.public() })
+            .multiplex(yamux::YamuxConfig::default())
+            .boxed();
+        SwarmBuilder::new(transport, Ping::new(PingConfig::new().with_keep_alive(true)), peer_id)
+            .executor(Box::new(|fut| {
+                tokio::spawn(fut);
+            }))
+            .build()
+    }
+
+    #[tokio::test]
+    async fn closes_connections_from_peers_not_on_the_allowlist() {
+        use futures::StreamExt;
+        use libp2p::swarm::SwarmEvent;
+
+        let (mut guarded, mut stranger) = (memory_swarm(), memory_swarm());
+        let stranger_id = *stranger.local_peer_id();
+        let address = Multiaddr::from(Protocol::Memory(rand::random::<u64>() | 1));
+        guarded.listen_on(address.clone()).unwrap();
+        stranger.dial(address.clone()).unwrap();
+        let allowlist = allowlist(&[PeerId::random()], &PeerId::random(), None);
+
+        let (mut closed, mut refused) = (false, false);
+        let run = async {
+            while !refused {
+                tokio::select! {
+                    event = guarded.select_next_some() => match event {
+                        // as the `ConnectionEstablished` arms of the connection modules do
+                        SwarmEvent::ConnectionEstablished { peer_id, .. } if !allowlist.admits(&peer_id) => {
+                            allowlist.turn_away(&mut guarded, peer_id);
+                        }
+                        SwarmEvent::ConnectionEstablished { peer_id, .. } => panic!("admitted {}", peer_id),
+                        SwarmEvent::ConnectionClosed { peer_id, .. } => {
+                            assert_eq!(peer_id, stranger_id);
+                            closed = true;
+                            // coming back doesn't help
+                            stranger.dial(address.clone()).unwrap();
+                        }
+                        SwarmEvent::BannedPeer { peer_id, .. } => {
+                            assert_eq!(peer_id, stranger_id);
+                            refused = true;
+                        }
+                        _ => {}
+                    },
+                    _ = stranger.select_next_some() => {}
+                }
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(10), run).await.expect("the stranger was not turned away");
+        assert!(closed);
+        assert!(!guarded.is_connected(&stranger_id));
+    }
+
+    #[test]
+    fn always_admits_the_relay_and_the_dialed_peer() {
+        let (relay, remote) = (PeerId::random(), PeerId::random());
+        let allowlist = allowlist(&[PeerId::random()], &relay, Some(remote));
+        assert!(allowlist.admits(&relay));
+        assert!(allowlist.admits(&remote));
+        assert!(!allowlist.admits(&PeerId::random()));
+    }
+
+    fn chat() -> Frame {
+        Frame::Chat { id: Some(1), name: "me".to_string(), body: "hi".to_string(), reply_to: None, seq: None, sent_at: None }
+    }
+
+    #[test]
+    fn drops_only_our_own_chat_echoes() {
+        let (me, alice) = (PeerId::random(), PeerId::random());
+        assert!(is_own_echo(&chat(), Some(&me), &me, false));
+        assert!(!is_own_echo(&chat(), Some(&me), &me, true));
+        assert!(!is_own_echo(&chat(), Some(&alice), &me, false));
+        assert!(!is_own_echo(&chat(), None, &me, false));
+        assert!(!is_own_echo(&Frame::Delete { id: 1 }, Some(&me), &me, false));
+    }
+
+    #[test]
+    fn subscribes_again_to_lost_topics() {
+        use libp2p::gossipsub::{GossipsubConfig, MessageAuthenticity};
+        let keypair = libp2p::identity::Keypair::generate_ed25519();
+        let mut gossip = Gossipsub::new(MessageAuthenticity::Signed(keypair), GossipsubConfig::default()).unwrap();
+        let topics = ["a".to_string(), "b".to_string()];
+        gossip.subscribe(&IdentTopic::new("a")).unwrap();
+        resubscribe(&mut gossip, &topics);
+        let mut subscribed: Vec<String> = gossip.topics().map(|topic| topic.to_string()).collect();
+        subscribed.sort();
+        assert_eq!(subscribed, topics);
+
+        gossip.unsubscribe(&IdentTopic::new("b")).unwrap();
+        resubscribe(&mut gossip, &topics);
+        assert_eq!(gossip.topics().count(), 2);
+    }
+
+    #[tokio::test]
+    async fn addresses_without_a_name_need_no_lookup() {
+        let address: Multiaddr = "/ip4/127.0.0.1/tcp/4001".parse().unwrap();
+        assert_eq!(check_relay_dns(&address).await, Ok(()));
+    }
+
+    #[tokio::test]
+    async fn reports_a_name_that_does_not_resolve() {
+        // .invalid never resolves, with or without a network
+        let address: Multiaddr = "/dns4/relay.invalid/tcp/4001".parse().unwrap();
+        assert!(check_relay_dns(&address).await.is_err());
+    }
+}
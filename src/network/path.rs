@@ -0,0 +1,241 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use libp2p::core::multiaddr::{Multiaddr, Protocol};
+use libp2p::core::ConnectedPoint;
+use libp2p::PeerId;
+
+/// Ping outcomes the loss rate is computed over
+const WINDOW: usize = 10;
+/// Pings needed before the direct path can count as degraded
+const MIN_SAMPLES: usize = 3;
+/// Segments of the quality bar
+pub const QUALITY_BARS: u8 = 5;
+
+/// How traffic to the peer of a dm session travels
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Path {
+    /// Hole punched by dcutr
+    Direct,
+    /// Through a circuit on the relay
+    Relayed,
+}
+
+/// The path chosen with `/direct`, `/relay` or `/auto`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Preference {
+    /// Direct while it is healthy, the relay when it degrades
+    Auto,
+    Direct,
+    Relay,
+}
+
+/// When the direct path counts as degraded, from `--max-direct-rtt` and friends
+#[derive(Clone, Copy, Debug)]
+pub struct Thresholds {
+    pub max_rtt: Duration,
+    /// Share of lost pings, 0 to 1
+    pub max_loss: f64,
+    /// Least time between two automatic switches, so a flapping path doesn't bounce
+    pub cooldown: Duration,
+}
+
+/// What the status area shows
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PathStatus {
+    pub path: Option<Path>,
+    /// From 0 to `QUALITY_BARS`, `None` before the first ping
+    pub quality: Option<u8>,
+    pub preference: Preference,
+}
+
+impl fmt::Display for PathStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.path {
+            Some(Path::Direct) => write!(f, "direct")?,
+            Some(Path::Relayed) => write!(f, "relay")?,
+            None => write!(f, "no path")?,
+        }
+        if let Some(quality) = self.quality {
+            let bars: String = (0..QUALITY_BARS).map(|i| if i < quality { '▮' } else { '▯' }).collect();
+            write!(f, " {}", bars)?;
+        }
+        if self.preference != Preference::Auto {
+            write!(f, " (forced)")?;
+        }
+        Ok(())
+    }
+}
+
+/// Tracks the connections to the peer of a dm session and how its pings fare
+pub struct PathMonitor {
+    relay: Option<PeerId>,
+    peer: Option<PeerId>,
+    direct: usize,
+    relayed: usize,
+    /// Where the last direct connection went, for `/direct`
+    direct_addr: Option<Multiaddr>,
+    /// Smoothed round trip
+    rtt: Option<Duration>,
+    /// Recent pings, `true` for a lost one
+    losses: VecDeque<bool>,
+    preference: Preference,
+    thresholds: Thresholds,
+    last_switch: Option<Instant>,
+}
+
+impl PathMonitor {
+    /// `relay` is ignored when counting connections, `peer` is learned from the first other
+    /// connection when we listen and don't know who will dial
+    pub fn new(relay: Option<PeerId>, peer: Option<PeerId>, thresholds: Thresholds) -> PathMonitor {
+        PathMonitor {
+            relay,
+            peer,
+            direct: 0,
+            relayed: 0,
+            direct_addr: None,
+            rtt: None,
+            losses: VecDeque::new(),
+            preference: Preference::Auto,
+            thresholds,
+            last_switch: None,
+        }
+    }
+
+    pub fn peer(&self) -> Option<PeerId> {
+        self.peer
+    }
+
+    fn is_peer(&mut self, peer: &PeerId) -> bool {
+        if Some(*peer) == self.relay {
+            return false;
+        }
+        *self.peer.get_or_insert(*peer) == *peer
+    }
+
+    /// Count a new connection, returns whether it was to the peer
+    pub fn connected(&mut self, peer: &PeerId, endpoint: &ConnectedPoint) -> bool {
+        if !self.is_peer(peer) {
+            return false;
+        }
+        let addr = endpoint.get_remote_address();
+        if is_relayed(addr) {
+            self.relayed += 1;
+        } else {
+            self.direct += 1;
+            self.direct_addr = Some(addr.clone());
+        }
+        true
+    }
+
+    /// Count a closed connection, returns whether it was to the peer
+    pub fn disconnected(&mut self, peer: &PeerId, endpoint: &ConnectedPoint) -> bool {
+        if !self.is_peer(peer) {
+            return false;
+        }
+        let count = if is_relayed(endpoint.get_remote_address()) { &mut self.relayed } else { &mut self.direct };
+        *count = count.saturating_sub(1);
+        true
+    }
+
+    /// Record a ping to `peer`, `None` for a lost one. Returns whether it was to the peer
+    pub fn ping(&mut self, peer: &PeerId, rtt: Option<Duration>) -> bool {
+        if !self.is_peer(peer) {
+            return false;
+        }
+        if let Some(rtt) = rtt {
+            // exponential moving average, a single slow ping doesn't trip the threshold
+            self.rtt = Some(self.rtt.map_or(rtt, |avg| (avg * 3 + rtt) / 4));
+        }
+        self.losses.push_back(rtt.is_none());
+        if self.losses.len() > WINDOW {
+            self.losses.pop_front();
+        }
+        true
+    }
+
+    pub fn path(&self) -> Option<Path> {
+        match (self.direct, self.relayed) {
+            (0, 0) => None,
+            (0, _) => Some(Path::Relayed),
+            _ => Some(Path::Direct),
+        }
+    }
+
+    fn loss(&self) -> f64 {
+        if self.losses.is_empty() {
+            return 0.0;
+        }
+        self.losses.iter().filter(|lost| **lost).count() as f64 / self.losses.len() as f64
+    }
+
+    /// Whether the current path is slower or lossier than the thresholds allow
+    pub fn degraded(&self) -> bool {
+        self.losses.len() >= MIN_SAMPLES
+            && (self.loss() > self.thresholds.max_loss || self.rtt.map_or(false, |rtt| rtt > self.thresholds.max_rtt))
+    }
+
+    fn quality(&self) -> Option<u8> {
+        let rtt = self.rtt?;
+        let by_rtt = match rtt.as_millis() {
+            0..=49 => 5,
+            50..=99 => 4,
+            100..=199 => 3,
+            _ if rtt <= self.thresholds.max_rtt => 2,
+            _ => 1,
+        };
+        let by_loss = QUALITY_BARS - (self.loss() * QUALITY_BARS as f64).ceil() as u8;
+        Some(by_rtt.min(by_loss))
+    }
+
+    pub fn status(&self) -> PathStatus {
+        PathStatus {
+            path: self.path(),
+            quality: self.quality(),
+            preference: self.preference,
+        }
+    }
+
+    /// Whether the direct path should be given up for the relay now: it degraded while on
+    /// `Auto` and the last switch is older than the cooldown, or `/relay` forces the relay
+    pub fn should_use_relay(&self) -> bool {
+        if self.path() != Some(Path::Direct) {
+            return false;
+        }
+        let cooled_down = self.last_switch.map_or(true, |at| at.elapsed() >= self.thresholds.cooldown);
+        match self.preference {
+            Preference::Auto => self.degraded() && cooled_down,
+            // a new hole punch while forced to the relay is undone, but not in a tight loop
+            Preference::Relay => cooled_down,
+            Preference::Direct => false,
+        }
+    }
+
+    /// Apply `/direct`, `/relay` or `/auto` right away, regardless of the cooldown
+    pub fn prefer(&mut self, preference: Preference) {
+        self.preference = preference;
+        self.last_switch = None;
+    }
+
+    /// The path was switched, the ping history belongs to the old one
+    pub fn switched(&mut self) {
+        self.last_switch = Some(Instant::now());
+        self.rtt = None;
+        self.losses.clear();
+    }
+
+    /// Where `/direct` dials, the address of the last direct connection
+    pub fn direct_addr(&self) -> Option<Multiaddr> {
+        let peer = self.peer?;
+        let addr = self.direct_addr.clone()?;
+        Some(match addr.iter().last() {
+            Some(Protocol::P2p(_)) => addr,
+            _ => addr.with(Protocol::P2p(peer.into())),
+        })
+    }
+}
+
+fn is_relayed(addr: &Multiaddr) -> bool {
+    addr.iter().any(|p| p == Protocol::P2pCircuit)
+}
@@ -0,0 +1,106 @@
+//! `tochat ping`: checks that a peer can be reached before starting a chat with it. The peer is
+//! dialed through the relay like in a dm session, then pinged a few times over the connection
+
+use std::collections::VecDeque;
+use std::error::Error;
+use std::time::Duration;
+
+use futures::StreamExt;
+use libp2p::ping::{PingEvent, PingSuccess};
+use libp2p::swarm::SwarmEvent;
+use libp2p::{PeerId, Swarm};
+use tokio::sync::mpsc::Receiver;
+use tokio::task::JoinHandle;
+use tokio::time::{timeout_at, Instant};
+
+use crate::network::connection_dm::{Behaviour, Event};
+use crate::network::latency::summarize;
+use crate::network::{short_peer_id, Update};
+
+/// How often the peer is pinged, libp2p's default of 15s would make a check take a minute
+pub const PING_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Event log lines kept to explain a failed connection
+const REASONS: usize = 3;
+
+/// Keep the last event log lines of the connection, they are the reason it failed if it does.
+/// The task ends with them once every sender of `rx2` is dropped
+pub fn collect_reasons(mut rx2: Receiver<Update>) -> JoinHandle<VecDeque<String>> {
+    tokio::spawn(async move {
+        let mut lines = VecDeque::with_capacity(REASONS);
+        while let Some(update) = rx2.recv().await {
+            let line = match update {
+                Update::Event(_, line) => line,
+                Update::System(notice) => notice,
+                _ => continue,
+            };
+            if lines.len() == REASONS {
+                lines.pop_front();
+            }
+            lines.push_back(line);
+        }
+        lines
+    })
+}
+
+/// The error for a peer not reached within `wait`, with what happened last
+pub fn unreachable(peer: &PeerId, wait: Duration, reasons: VecDeque<String>) -> Box<dyn Error> {
+    let mut message = format!("could not reach {} within {}s", peer, wait.as_secs());
+    if reasons.is_empty() {
+        message.push_str(", the relay answered but the peer never did; is it online with a reservation on this relay?");
+    } else {
+        message.push_str(", last events:");
+        for reason in reasons {
+            message.push_str(&format!("\n  {}", reason));
+        }
+    }
+    message.into()
+}
+
+/// Ping `peer` over the connection `swarm` has to it until `count` pings were answered or lost,
+/// or until `deadline`, printing each round trip and then a summary
+pub async fn ping(swarm: &mut Swarm<Behaviour>, peer: PeerId, count: usize, deadline: Instant) -> Result<(), Box<dyn Error>> {
+    let mut rtts = Vec::new();
+    let mut lost = 0;
+    while rtts.len() + lost < count {
+        let event = match timeout_at(deadline, swarm.next()).await {
+            Ok(Some(event)) => event,
+            Ok(None) | Err(_) => break,
+        };
+        match event {
+            SwarmEvent::Behaviour(Event::Ping(PingEvent { peer: from, result })) if from == peer => match result {
+                Ok(PingSuccess::Ping { rtt }) => {
+                    println!("reply from {}: {} ms", short_peer_id(&peer), rtt.as_millis());
+                    rtts.push(rtt);
+                }
+                Ok(PingSuccess::Pong) => {}
+                Err(e) => {
+                    println!("no reply from {}: {}", short_peer_id(&peer), e);
+                    lost += 1;
+                }
+            },
+            SwarmEvent::ConnectionClosed { peer_id, num_established: 0, cause, .. } if peer_id == peer => {
+                return Err(match cause {
+                    Some(cause) => format!("the connection to {} closed: {}", peer, cause).into(),
+                    None => format!("{} closed the connection", peer).into(),
+                });
+            }
+            _ => {}
+        }
+    }
+
+    let sent = rtts.len() + lost;
+    let summary = summarize(rtts.iter().map(|rtt| rtt.as_millis() as i64));
+    match summary {
+        Some(s) => println!(
+            "{} pings, {} answered, {} lost; rtt min {} ms, avg {} ms, p95 {} ms, max {} ms",
+            sent, s.count, lost, s.min, s.avg, s.p95, s.max
+        ),
+        None => println!("{} pings, none answered", sent),
+    }
+    match summary {
+        Some(_) => Ok(()),
+        None if sent == 0 => Err(format!("connected to {} but no ping went through before the timeout", peer).into()),
+        None => Err(format!("connected to {} but it answered none of {} pings", peer, sent).into()),
+    }
+}
@@ -0,0 +1,170 @@
+//! Noticing a relay restart: the connection to the relay's own PeerId closes while peers may
+//! still be connected directly, so the reservation is gone without anything failing yet
+
+use std::fmt;
+
+use libp2p::PeerId;
+use tokio::time::{Duration, Instant};
+
+use crate::network::backoff_delay;
+
+/// The connection to the relay as the status line shows it
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RelayStatus {
+    Connected,
+    /// Dialing it again, this is the attempt number
+    Reconnecting(u32),
+    /// `--relay-reconnect-attempts` ran out or is 0
+    Lost,
+}
+
+impl fmt::Display for RelayStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RelayStatus::Connected => write!(f, "relay connected"),
+            RelayStatus::Reconnecting(attempt) => write!(f, "reconnecting to relay… (attempt {})", attempt),
+            RelayStatus::Lost => write!(f, "relay lost"),
+        }
+    }
+}
+
+/// Follows the connections to the relay and when to dial it again after losing the last one
+pub struct RelayLink {
+    relay: Option<PeerId>,
+    max_attempts: u32,
+    status: RelayStatus,
+    /// When the next dial is due while reconnecting
+    retry_at: Option<Instant>,
+}
+
+impl RelayLink {
+    /// `relay` is the id at the end of the relay address, without one nothing is tracked
+    pub fn new(relay: Option<PeerId>, max_attempts: u32) -> RelayLink {
+        RelayLink { relay, max_attempts, status: RelayStatus::Connected, retry_at: None }
+    }
+
+    pub fn status(&self) -> RelayStatus {
+        self.status
+    }
+
+    pub fn is_relay(&self, peer: &PeerId) -> bool {
+        Some(*peer) == self.relay
+    }
+
+    /// A connection to `peer` closed and `remaining` are left. Returns whether it was the last
+    /// one to the relay, the status changed then
+    pub fn closed(&mut self, peer: &PeerId, remaining: u32) -> bool {
+        if !self.is_relay(peer) || remaining > 0 || self.status != RelayStatus::Connected {
+            return false;
+        }
+        self.schedule(1);
+        true
+    }
+
+    /// A connection to `peer` is up. Returns whether it brought the relay back, the
+    /// reservation has to be renewed then
+    pub fn established(&mut self, peer: &PeerId) -> bool {
+        if !self.is_relay(peer) || self.status == RelayStatus::Connected {
+            return false;
+        }
+        self.status = RelayStatus::Connected;
+        self.retry_at = None;
+        true
+    }
+
+    /// Dialing the relay failed, the next attempt is scheduled with backoff. Returns whether
+    /// the status changed
+    pub fn dial_failed(&mut self, peer: Option<PeerId>) -> bool {
+        match (peer, self.status) {
+            (Some(peer), RelayStatus::Reconnecting(attempt)) if self.is_relay(&peer) && self.retry_at.is_none() => {
+                self.schedule(attempt + 1);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// When the next dial is due, far off when none is so a disabled `select!` branch still
+    /// has a deadline to hold
+    pub fn deadline(&self) -> Instant {
+        self.retry_at.unwrap_or_else(|| Instant::now() + Duration::from_secs(3600))
+    }
+
+    pub fn waiting(&self) -> bool {
+        self.retry_at.is_some()
+    }
+
+    /// The deadline passed and the relay is dialed now, its outcome is `established` or
+    /// `dial_failed`
+    pub fn dialing(&mut self) {
+        self.retry_at = None;
+    }
+
+    fn schedule(&mut self, attempt: u32) {
+        if attempt > self.max_attempts {
+            self.status = RelayStatus::Lost;
+            self.retry_at = None;
+            return;
+        }
+        self.status = RelayStatus::Reconnecting(attempt);
+        // the first one waits too, a restarting relay needs a moment to listen again
+        self.retry_at = Some(Instant::now() + backoff_delay(attempt));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn losing_the_last_relay_connection_schedules_a_dial() {
+        let relay = PeerId::random();
+        let mut link = RelayLink::new(Some(relay), 3);
+        assert!(!link.closed(&PeerId::random(), 0));
+        assert!(!link.closed(&relay, 1));
+        assert!(link.closed(&relay, 0));
+        assert_eq!(link.status(), RelayStatus::Reconnecting(1));
+        assert!(link.waiting());
+        // the status already changed
+        assert!(!link.closed(&relay, 0));
+    }
+
+    #[test]
+    fn failed_dials_back_off_until_the_attempts_run_out() {
+        let relay = PeerId::random();
+        let mut link = RelayLink::new(Some(relay), 2);
+        link.closed(&relay, 0);
+        // not dialed yet, so nothing could have failed
+        assert!(!link.dial_failed(Some(relay)));
+        link.dialing();
+        assert!(!link.dial_failed(Some(PeerId::random())));
+        assert!(link.dial_failed(Some(relay)));
+        assert_eq!(link.status(), RelayStatus::Reconnecting(2));
+        link.dialing();
+        assert!(link.dial_failed(Some(relay)));
+        assert_eq!(link.status(), RelayStatus::Lost);
+        assert!(!link.waiting());
+    }
+
+    #[test]
+    fn the_relay_coming_back_renews_the_reservation() {
+        let relay = PeerId::random();
+        let mut link = RelayLink::new(Some(relay), 3);
+        assert!(!link.established(&relay));
+        link.closed(&relay, 0);
+        link.dialing();
+        assert!(!link.established(&PeerId::random()));
+        assert!(link.established(&relay));
+        assert_eq!(link.status(), RelayStatus::Connected);
+        assert!(!link.waiting());
+    }
+
+    #[test]
+    fn without_attempts_the_relay_is_lost_at_once() {
+        let relay = PeerId::random();
+        let mut link = RelayLink::new(Some(relay), 0);
+        assert!(link.closed(&relay, 0));
+        assert_eq!(link.status(), RelayStatus::Lost);
+        assert_eq!(RelayStatus::Reconnecting(2).to_string(), "reconnecting to relay… (attempt 2)");
+    }
+}
@@ -0,0 +1,154 @@
+//! Picking a relay from a list when none is given with `--relay-address`: each one is probed
+//! with a TCP connect, the fastest reachable is tried first and the others follow in case it
+//! fails. The relay that last worked is kept in `~/.tochat/last_relay` and preferred on a tie
+//! or when no probe gets through
+
+use std::error::Error;
+use std::fs;
+use std::future::Future;
+use std::io;
+use std::path::PathBuf;
+
+use colorful::{Color, Colorful};
+use futures::future::join_all;
+use libp2p::core::multiaddr::{Multiaddr, Protocol};
+use log::{info, warn};
+use tokio::net::TcpStream;
+use tokio::time::{timeout, Duration, Instant};
+
+use crate::config::{Config, DEFAULT_RELAY_NOTICE};
+use crate::network::check_relay_dns;
+use crate::network::secure::tochat_dir;
+
+/// How long a relay gets to accept the probe before it counts as unreachable
+const PROBE_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// A relay to try and how fast it answered the probe, none when it didn't
+#[derive(Clone, Debug)]
+pub struct Candidate {
+    pub address: Multiaddr,
+    pub rtt: Option<Duration>,
+}
+
+fn path() -> PathBuf {
+    tochat_dir().join("last_relay")
+}
+
+/// The relay the last session got a connection through, if any
+pub fn last_good() -> Option<Multiaddr> {
+    fs::read_to_string(path()).ok()?.trim().parse().ok()
+}
+
+/// Remember `address` as the relay that worked
+pub fn save_last_good(address: &Multiaddr) -> io::Result<()> {
+    fs::create_dir_all(tochat_dir())?;
+    fs::write(path(), format!("{}\n", address))
+}
+
+/// `host:port` of the TCP transport in `address`, the part a probe connects to
+pub fn socket_address(address: &Multiaddr) -> Option<String> {
+    let mut host = None;
+    for protocol in address.iter() {
+        match protocol {
+            Protocol::Ip4(ip) => host = Some(ip.to_string()),
+            Protocol::Ip6(ip) => host = Some(format!("[{}]", ip)),
+            Protocol::Dns(name) | Protocol::Dns4(name) | Protocol::Dns6(name) => host = Some(name.to_string()),
+            Protocol::Tcp(port) => return host.map(|host| format!("{}:{}", host, port)),
+            _ => {}
+        }
+    }
+    None
+}
+
+/// The time a TCP connection to the relay takes to open, none when it can't be reached
+async fn probe(address: &Multiaddr) -> Option<Duration> {
+    let target = socket_address(address)?;
+    let start = Instant::now();
+    match timeout(PROBE_TIMEOUT, TcpStream::connect(&target)).await {
+        Ok(Ok(_)) => Some(start.elapsed()),
+        Ok(Err(e)) => {
+            warn!("Relay {} is unreachable: {}", address, e);
+            None
+        }
+        Err(_) => {
+            warn!("Relay {} didn't answer within {:?}", address, PROBE_TIMEOUT);
+            None
+        }
+    }
+}
+
+/// Probe all `relays` at once and order them to be tried: the reachable ones by latency, then
+/// the others as listed. The last good relay goes first among equals
+pub async fn rank(relays: &[Multiaddr]) -> Vec<Candidate> {
+    let last = last_good();
+    let rtts = join_all(relays.iter().map(probe)).await;
+    let mut candidates: Vec<Candidate> = relays
+        .iter()
+        .zip(rtts)
+        .map(|(address, rtt)| Candidate { address: address.clone(), rtt })
+        .collect();
+    // stable, so unreachable relays keep the listed order
+    candidates.sort_by_key(|candidate| (candidate.rtt.is_none(), candidate.rtt, Some(&candidate.address) != last.as_ref()));
+    for candidate in &candidates {
+        match candidate.rtt {
+            Some(rtt) => info!("Relay {} answered in {} ms", candidate.address, rtt.as_millis()),
+            None => info!("Relay {} kept as a fallback", candidate.address),
+        }
+    }
+    candidates
+}
+
+/// Tell users of the built-in relay, before connecting, that it is someone else's server
+fn warn_default_relay(config: &Config) {
+    if config.default_relay() {
+        eprintln!("{}", DEFAULT_RELAY_NOTICE.color(Color::LightRed));
+    }
+}
+
+/// Failing to reach the built-in relay says why it may be down and what to do instead
+fn relay_hint(config: &Config, e: Box<dyn Error>) -> Box<dyn Error> {
+    if config.default_relay() {
+        format!("{}\n{}", e, DEFAULT_RELAY_NOTICE).into()
+    } else {
+        e
+    }
+}
+
+/// Connect through the session's relay, or through the relays picked from `--relays` in turn,
+/// fastest first, until one works. `config.relay_address` is left at the relay used
+pub async fn through_relays<T, F, Fut>(config: &mut Config, connect: F) -> Result<T, Box<dyn Error>>
+where
+    F: Fn(Config) -> Fut,
+    Fut: Future<Output = Result<T, Box<dyn Error>>>,
+{
+    let candidates = if config.relays.len() > 1 {
+        rank(&config.relays).await
+    } else {
+        vec![Candidate { address: config.relay_address.clone(), rtt: None }]
+    };
+    let mut candidates = candidates.into_iter().peekable();
+    while let Some(candidate) = candidates.next() {
+        config.relay_address = candidate.address;
+        config.relay_rtt = candidate.rtt;
+        warn_default_relay(config);
+        let connected = match check_relay_dns(&config.relay_address).await {
+            Ok(()) => connect(config.clone()).await,
+            Err(e) => Err(e.into()),
+        };
+        match connected {
+            Ok(connected) => {
+                if !config.relays.is_empty() {
+                    if let Err(e) = save_last_good(&config.relay_address) {
+                        eprintln!("cannot remember relay {}: {}", config.relay_address, e);
+                    }
+                }
+                return Ok(connected);
+            }
+            Err(e) if candidates.peek().is_some() => {
+                eprintln!("cannot connect through relay {}: {}, trying the next one", config.relay_address, e)
+            }
+            Err(e) => return Err(relay_hint(config, e)),
+        }
+    }
+    Err("no relay to connect through".into())
+}
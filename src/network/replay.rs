@@ -0,0 +1,381 @@
+//! Catching up on a room: a peer that joins asks one already there for the last messages it
+//! saw, over a request-response protocol of its own next to gossipsub
+
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use chrono::{DateTime, Local};
+use futures::prelude::*;
+use libp2p::core::upgrade::{read_length_prefixed, write_length_prefixed, ProtocolName};
+use libp2p::request_response::{RequestId, RequestResponseCodec};
+use libp2p::PeerId;
+use serde::{Deserialize, Serialize};
+
+use crate::message::{ChatMessage, Quote};
+
+/// Most messages kept per room, and sent in one answer
+pub const MAX_REPLAY: usize = 50;
+/// Largest request read, one only names a topic and a count
+const MAX_REQUEST_BYTES: usize = 1024;
+/// Largest answer read or written, the oldest messages are left out to stay under it
+const MAX_RESPONSE_BYTES: usize = 256 * 1024;
+/// A peer asking again sooner gets an empty answer
+const REQUEST_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone)]
+pub struct ReplayProtocol;
+
+impl ProtocolName for ReplayProtocol {
+    fn protocol_name(&self) -> &[u8] {
+        b"/tochat/replay/1"
+    }
+}
+
+/// Ask for the last `count` messages of a gossipsub topic
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ReplayRequest {
+    pub topic: String,
+    pub count: usize,
+}
+
+/// The messages asked for, oldest first
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct ReplayResponse {
+    pub messages: Vec<Replayed>,
+}
+
+/// A chat message as the answering peer saw it, received at `timestamp`
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Replayed {
+    pub id: u64,
+    pub name: String,
+    pub body: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reply_to: Option<Quote>,
+    pub timestamp: DateTime<Local>,
+    /// Only used by the answering peer to apply edits and deletes, never sent
+    #[serde(skip)]
+    author: Option<PeerId>,
+}
+
+impl Replayed {
+    /// The message for the list. The answering peer vouches for it, not its author, so it
+    /// gets no author and can't be edited or deleted
+    pub fn into_message(self) -> ChatMessage {
+        let mut message = ChatMessage::remote(&self.name, self.body);
+        message.id = self.id;
+        message.reply_to = self.reply_to;
+        message.timestamp = self.timestamp;
+        message.replayed = true;
+        message
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct ReplayCodec;
+
+#[async_trait]
+impl RequestResponseCodec for ReplayCodec {
+    type Protocol = ReplayProtocol;
+    type Request = ReplayRequest;
+    type Response = ReplayResponse;
+
+    async fn read_request<T>(&mut self, _: &ReplayProtocol, io: &mut T) -> io::Result<ReplayRequest>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        let data = read_length_prefixed(io, MAX_REQUEST_BYTES).await?;
+        Ok(serde_json::from_slice(&data)?)
+    }
+
+    async fn read_response<T>(&mut self, _: &ReplayProtocol, io: &mut T) -> io::Result<ReplayResponse>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        let data = read_length_prefixed(io, MAX_RESPONSE_BYTES).await?;
+        Ok(serde_json::from_slice(&data)?)
+    }
+
+    async fn write_request<T>(&mut self, _: &ReplayProtocol, io: &mut T, request: ReplayRequest) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_length_prefixed(io, serde_json::to_vec(&request)?).await?;
+        io.close().await
+    }
+
+    async fn write_response<T>(&mut self, _: &ReplayProtocol, io: &mut T, response: ReplayResponse) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_length_prefixed(io, serde_json::to_vec(&response)?).await?;
+        io.close().await
+    }
+}
+
+/// Both ends of the protocol for one session: the recent messages of each room to answer
+/// with, and which rooms still wait for an answer of their own
+pub struct Replay {
+    topics: Vec<String>,
+    recent: Vec<VecDeque<Replayed>>,
+    /// When each peer was last answered about each room, for the rate limit. A joining peer
+    /// asks about all its rooms at once
+    answered: HashMap<(PeerId, usize), Instant>,
+    /// Messages to ask for per room, 0 with `--replay 0`
+    count: usize,
+    /// Rooms that got their answer
+    caught_up: Vec<bool>,
+    /// Requests in flight and the room each is for
+    pending: HashMap<RequestId, usize>,
+}
+
+impl Replay {
+    pub fn new(topics: &[String], count: usize) -> Replay {
+        Replay {
+            topics: topics.to_vec(),
+            recent: vec![VecDeque::new(); topics.len()],
+            answered: HashMap::new(),
+            count: count.min(MAX_REPLAY),
+            caught_up: vec![count == 0; topics.len()],
+            pending: HashMap::new(),
+        }
+    }
+
+    /// Keep a chat message shown in `room`, received or our own, to answer with later
+    pub fn remember(&mut self, room: usize, message: &ChatMessage, author: Option<PeerId>) {
+        let recent = match self.recent.get_mut(room) {
+            Some(recent) => recent,
+            None => return,
+        };
+        if recent.len() == MAX_REPLAY {
+            recent.pop_front();
+        }
+        recent.push_back(Replayed {
+            id: message.id,
+            name: message.sender.clone(),
+            body: message.body.clone(),
+            reply_to: message.reply_to.clone(),
+            timestamp: message.timestamp,
+            author,
+        });
+    }
+
+    /// Apply an edit the way the TUI does, only from the message's author
+    pub fn edit(&mut self, id: u64, author: PeerId, body: &str) {
+        for m in self.recent.iter_mut().flatten() {
+            if m.id == id && m.author == Some(author) {
+                m.body = body.to_string();
+            }
+        }
+    }
+
+    /// Forget a deleted message, so it isn't handed to peers joining later
+    pub fn delete(&mut self, id: u64, author: PeerId) {
+        for recent in &mut self.recent {
+            recent.retain(|m| !(m.id == id && m.author == Some(author)));
+        }
+    }
+
+    /// What to answer `peer` with: nothing for a topic we aren't in or when it asked about the
+    /// topic within `REQUEST_INTERVAL`, otherwise the newest messages that fit
+    pub fn answer(&mut self, peer: PeerId, request: &ReplayRequest) -> ReplayResponse {
+        let room = match self.topics.iter().position(|topic| *topic == request.topic) {
+            Some(room) => room,
+            None => return ReplayResponse::default(),
+        };
+        let now = Instant::now();
+        self.answered.retain(|_, at| now.duration_since(*at) < REQUEST_INTERVAL);
+        if self.answered.contains_key(&(peer, room)) {
+            return ReplayResponse::default();
+        }
+        self.answered.insert((peer, room), now);
+        ReplayResponse { messages: newest(self.recent[room].iter().cloned(), request.count.min(MAX_REPLAY)) }
+    }
+
+    /// The requests to send to a newly connected peer, one per room still waiting for an answer
+    pub fn requests(&self) -> Vec<(usize, ReplayRequest)> {
+        if !self.pending.is_empty() {
+            return Vec::new();
+        }
+        (0..self.topics.len())
+            .filter(|room| !self.caught_up[*room])
+            .map(|room| (room, ReplayRequest { topic: self.topics[room].clone(), count: self.count }))
+            .collect()
+    }
+
+    pub fn sent(&mut self, id: RequestId, room: usize) {
+        self.pending.insert(id, room);
+    }
+
+    /// The room an answer is for, which then counts as caught up. The messages are cut to what
+    /// was asked for and would fit in our own answer, a peer could send more
+    pub fn received(&mut self, id: RequestId, response: ReplayResponse) -> Option<(usize, Vec<ChatMessage>)> {
+        let room = self.pending.remove(&id)?;
+        let messages: Vec<ChatMessage> =
+            newest(response.messages, self.count).into_iter().map(Replayed::into_message).collect();
+        // an empty answer may just mean the peer joined after us, ask the next one
+        if !messages.is_empty() {
+            self.caught_up[room] = true;
+        }
+        Some((room, messages))
+    }
+
+    /// A request that got no answer, the room is asked again from the next peer that connects
+    pub fn failed(&mut self, id: RequestId) {
+        self.pending.remove(&id);
+    }
+}
+
+/// The newest of `messages`, oldest first, at most `count` and as many as fit in
+/// `MAX_RESPONSE_BYTES` with room left for the JSON around them
+fn newest<I>(messages: I, count: usize) -> Vec<Replayed>
+where
+    I: IntoIterator<Item = Replayed>,
+    I::IntoIter: DoubleEndedIterator,
+{
+    let mut size = 64usize;
+    let mut kept = Vec::new();
+    for m in messages.into_iter().rev().take(count) {
+        size = size.saturating_add(serde_json::to_vec(&m).map_or(usize::MAX, |json| json.len() + 1));
+        if size > MAX_RESPONSE_BYTES {
+            break;
+        }
+        kept.push(m);
+    }
+    kept.reverse();
+    kept
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::Kind;
+
+    fn replayed(id: u64, body: String) -> Replayed {
+        Replayed { id, name: "alice".to_string(), body, reply_to: None, timestamp: Local::now(), author: None }
+    }
+
+    fn ids(messages: &[Replayed]) -> Vec<u64> {
+        messages.iter().map(|m| m.id).collect()
+    }
+
+    fn request(count: usize) -> ReplayRequest {
+        ReplayRequest { topic: "room".to_string(), count }
+    }
+
+    /// A replay of "room" holding messages 0..count, sent by `author`
+    fn holding(count: u64, author: PeerId) -> Replay {
+        let mut replay = Replay::new(&["room".to_string()], MAX_REPLAY);
+        for id in 0..count {
+            let mut message = ChatMessage::remote("alice", format!("message {}", id));
+            message.id = id;
+            replay.remember(0, &message, Some(author));
+        }
+        replay
+    }
+
+    #[test]
+    fn answers_with_the_newest_messages_asked_for() {
+        let mut replay = holding(10, PeerId::random());
+        let response = replay.answer(PeerId::random(), &request(3));
+        assert_eq!(ids(&response.messages), [7, 8, 9]);
+    }
+
+    #[test]
+    fn keeps_and_answers_at_most_max_replay() {
+        let mut replay = holding(MAX_REPLAY as u64 + 10, PeerId::random());
+        let response = replay.answer(PeerId::random(), &request(usize::MAX));
+        assert_eq!(response.messages.len(), MAX_REPLAY);
+        assert_eq!(response.messages.last().unwrap().id, MAX_REPLAY as u64 + 9);
+    }
+
+    #[test]
+    fn answers_a_peer_once_per_interval() {
+        let mut replay = holding(5, PeerId::random());
+        let peer = PeerId::random();
+        assert_eq!(replay.answer(peer, &request(5)).messages.len(), 5);
+        assert!(replay.answer(peer, &request(5)).messages.is_empty());
+        assert_eq!(replay.answer(PeerId::random(), &request(5)).messages.len(), 5);
+    }
+
+    #[test]
+    fn answers_a_peer_about_each_room() {
+        let rooms = ["room".to_string(), "other".to_string()];
+        let mut replay = Replay::new(&rooms, MAX_REPLAY);
+        for (room, body) in ["in room", "in other"].into_iter().enumerate() {
+            replay.remember(room, &ChatMessage::remote("alice", body.to_string()), None);
+        }
+        let peer = PeerId::random();
+        // as `ask_replay` sends them, one request per room to the same peer
+        let answers: Vec<Vec<String>> = Replay::new(&rooms, MAX_REPLAY)
+            .requests()
+            .into_iter()
+            .map(|(_, request)| replay.answer(peer, &request).messages.into_iter().map(|m| m.body).collect())
+            .collect();
+        assert_eq!(answers, [["in room"], ["in other"]]);
+        let again = ReplayRequest { topic: "other".to_string(), count: 5 };
+        assert!(replay.answer(peer, &again).messages.is_empty());
+    }
+
+    #[test]
+    fn answers_nothing_for_other_topics() {
+        let mut replay = holding(5, PeerId::random());
+        let request = ReplayRequest { topic: "elsewhere".to_string(), count: 5 };
+        assert!(replay.answer(PeerId::random(), &request).messages.is_empty());
+    }
+
+    #[test]
+    fn cuts_what_was_received_to_the_count() {
+        let messages: Vec<Replayed> = (0..20).map(|id| replayed(id, "hi".to_string())).collect();
+        assert_eq!(ids(&newest(messages, 3)), [17, 18, 19]);
+    }
+
+    #[test]
+    fn cuts_what_was_received_to_the_size() {
+        let big = "x".repeat(20 * 1024);
+        let messages: Vec<Replayed> = (0..MAX_REPLAY as u64).map(|id| replayed(id, big.clone())).collect();
+        let kept = newest(messages, MAX_REPLAY);
+        assert!(!kept.is_empty() && kept.len() < MAX_REPLAY);
+        assert_eq!(kept.last().unwrap().id, MAX_REPLAY as u64 - 1);
+        let size: usize = kept.iter().map(|m| serde_json::to_vec(m).unwrap().len() + 1).sum();
+        assert!(size <= MAX_RESPONSE_BYTES);
+    }
+
+    #[test]
+    fn ignores_edits_and_deletes_from_others() {
+        let (author, forger) = (PeerId::random(), PeerId::random());
+        let mut replay = holding(2, author);
+        replay.edit(0, forger, "forged");
+        replay.delete(1, forger);
+        let response = replay.answer(PeerId::random(), &request(2));
+        assert_eq!(ids(&response.messages), [0, 1]);
+        assert_eq!(response.messages[0].body, "message 0");
+
+        replay.edit(0, author, "fixed");
+        replay.delete(1, author);
+        let response = replay.answer(PeerId::random(), &request(2));
+        assert_eq!(ids(&response.messages), [0]);
+        assert_eq!(response.messages[0].body, "fixed");
+    }
+
+    #[test]
+    fn replayed_messages_get_no_author() {
+        // a peer claiming someone else's message, which would let them edit or delete it
+        let json = serde_json::json!({
+            "messages": [{
+                "id": 1,
+                "name": "alice",
+                "body": "hi",
+                "timestamp": Local::now(),
+                "author": PeerId::random().to_base58(),
+            }]
+        });
+        let response: ReplayResponse = serde_json::from_value(json).unwrap();
+        let message = response.messages.into_iter().next().unwrap().into_message();
+        assert_eq!(message.author, None);
+        assert_eq!(message.kind, Kind::Remote);
+        assert!(message.replayed);
+    }
+}
@@ -0,0 +1,318 @@
+//! Where the secret key lives: `~/.tochat/secret.json` by default, or the OS keychain (macOS
+//! Keychain, Secret Service, Windows Credential Manager) with `--secret-store keychain` on builds
+//! with the `keychain` feature
+
+use std::error::Error;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+use clap::ArgEnum;
+use fs2::FileExt;
+use log::warn;
+
+use crate::network::secure::{corrupted, io_context, tochat_dir};
+
+/// Holds one secret under a name, `secret` for the key in use
+pub trait SecretStore {
+    /// Store `secret`, replacing what was there
+    fn save(&self, secret: &str) -> Result<(), Box<dyn Error>>;
+    /// The stored secret, none when nothing was stored yet
+    fn load(&self) -> Result<Option<String>, Box<dyn Error>>;
+    fn exists(&self) -> Result<bool, Box<dyn Error>> {
+        Ok(self.load()?.is_some())
+    }
+    fn delete(&self) -> Result<(), Box<dyn Error>>;
+    /// Where the secret is, for messages
+    fn describe(&self) -> String;
+}
+
+/// `--secret-store`
+#[derive(ArgEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Backend {
+    /// A JSON file in ~/.tochat
+    File,
+    /// The OS keychain
+    Keychain,
+}
+
+/// As `--secret-store` and TOCHAT_SECRET_STORE take it
+impl fmt::Display for Backend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Backend::File => "file",
+            Backend::Keychain => "keychain",
+        })
+    }
+}
+
+impl Backend {
+    /// The store of the key in use
+    pub fn open(self) -> Result<Box<dyn SecretStore>, Box<dyn Error>> {
+        self.open_named("secret")
+    }
+
+    /// The store of another secret kept next to it, e.g. a backup
+    pub fn open_named(self, name: &str) -> Result<Box<dyn SecretStore>, Box<dyn Error>> {
+        self.open_named_in(&tochat_dir(), name)
+    }
+
+    /// Like `open_named`, with the file store in `dir` rather than `~/.tochat`
+    pub fn open_named_in(self, dir: &Path, name: &str) -> Result<Box<dyn SecretStore>, Box<dyn Error>> {
+        match self {
+            Backend::File => Ok(Box::new(FileStore::in_dir(dir, name))),
+            #[cfg(feature = "keychain")]
+            Backend::Keychain => Ok(Box::new(keychain::KeychainStore::new(name))),
+            #[cfg(not(feature = "keychain"))]
+            Backend::Keychain => {
+                Err("this tochat was built without keychain support, rebuild it with `--features keychain`".into())
+            }
+        }
+    }
+}
+
+/// A secret as a JSON string in `~/.tochat/<name>.json`, the format `tochat new` always wrote
+pub struct FileStore {
+    path: PathBuf,
+}
+
+impl FileStore {
+    pub fn new(name: &str) -> FileStore {
+        FileStore::in_dir(&tochat_dir(), name)
+    }
+
+    /// `<dir>/<name>.json`
+    pub fn in_dir(dir: &Path, name: &str) -> FileStore {
+        FileStore { path: dir.join(format!("{}.json", name)) }
+    }
+
+    fn dir(&self) -> &Path {
+        self.path.parent().unwrap_or_else(|| Path::new("."))
+    }
+}
+
+impl FileStore {
+    /// An exclusive advisory lock on `<name>.lock`, held by writers until it is dropped so two
+    /// tochat processes don't write the same secret at once. Readers don't take it, they only
+    /// ever see a complete file thanks to the rename
+    fn lock(&self) -> Result<File, Box<dyn Error>> {
+        let path = self.path.with_extension("lock");
+        let lock = OpenOptions::new().create(true).write(true).open(&path).map_err(io_context("write to", &path))?;
+        if lock.try_lock_exclusive().is_err() {
+            warn!("Waiting for another tochat writing {}", self.path.display());
+            lock.lock_exclusive().map_err(io_context("lock", &path))?;
+        }
+        Ok(lock)
+    }
+}
+
+impl SecretStore for FileStore {
+    /// Written to a temporary file next to it, flushed to disk and renamed over the old one, so
+    /// an interruption leaves either the old or the new secret in place. A temporary file an
+    /// interrupted write left behind is never read, and the next write replaces it. On unix only
+    /// the owner can read the file
+    fn save(&self, secret: &str) -> Result<(), Box<dyn Error>> {
+        let dir = self.dir();
+        fs::create_dir_all(dir).map_err(io_context("create", dir))?;
+        let _lock = self.lock()?;
+        let tmp_path = self.path.with_extension("json.tmp");
+        // created afresh, so the mode applies and a leftover can't be a link to elsewhere
+        if let Err(e) = fs::remove_file(&tmp_path) {
+            if e.kind() != io::ErrorKind::NotFound {
+                return Err(io_context("replace", &tmp_path)(e));
+            }
+        }
+        let mut options = OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let mut tmp = options.open(&tmp_path).map_err(io_context("write to", &tmp_path))?;
+        serde_json::to_writer_pretty(&mut tmp, secret)
+            .map_err(io::Error::from)
+            .and_then(|()| tmp.flush())
+            .and_then(|()| tmp.sync_all())
+            .map_err(io_context("write to", &tmp_path))?;
+        fs::rename(&tmp_path, &self.path).map_err(io_context("replace", &self.path))?;
+        // the rename itself is only durable once the directory is
+        if let Ok(dir) = File::open(dir) {
+            let _ = dir.sync_all();
+        }
+        Ok(())
+    }
+
+    fn load(&self) -> Result<Option<String>, Box<dyn Error>> {
+        let file = match File::open(&self.path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(format!("cannot read your secret key file {}: {}", self.path.display(), e).into()),
+        };
+        match serde_json::from_reader::<_, String>(BufReader::new(file)) {
+            Ok(secret) => Ok(Some(secret)),
+            Err(e) => Err(corrupted(&self.describe(), &e.to_string())),
+        }
+    }
+
+    fn exists(&self) -> Result<bool, Box<dyn Error>> {
+        Ok(self.path.exists())
+    }
+
+    fn delete(&self) -> Result<(), Box<dyn Error>> {
+        let _lock = self.lock()?;
+        match fs::remove_file(&self.path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(io_context("delete", &self.path)(e)),
+            _ => Ok(()),
+        }
+    }
+
+    fn describe(&self) -> String {
+        self.path.display().to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A store in a fresh directory under the temp dir
+    fn store(test: &str) -> FileStore {
+        let dir = std::env::temp_dir().join(format!("tochat-{}-{}", test, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        FileStore::in_dir(&dir, "secret")
+    }
+
+    #[test]
+    fn save_load_exists_delete() {
+        let store = store("file-store");
+        assert_eq!(store.load().unwrap(), None);
+        assert!(!store.exists().unwrap());
+        store.save("first").unwrap();
+        store.save("second").unwrap();
+        assert!(store.exists().unwrap());
+        assert_eq!(store.load().unwrap().as_deref(), Some("second"));
+        store.delete().unwrap();
+        assert!(!store.exists().unwrap());
+        assert_eq!(store.load().unwrap(), None);
+        // deleting twice is fine
+        store.delete().unwrap();
+    }
+
+    #[test]
+    fn the_file_backend_keeps_named_secrets_apart() {
+        let dir = std::env::temp_dir().join(format!("tochat-backend-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let secret = Backend::File.open_named_in(&dir, "secret").unwrap();
+        let backup = Backend::File.open_named_in(&dir, "backup").unwrap();
+        secret.save("current").unwrap();
+        backup.save("old").unwrap();
+        assert_eq!(FileStore::in_dir(&dir, "secret").load().unwrap().as_deref(), Some("current"));
+        assert_eq!(backup.load().unwrap().as_deref(), Some("old"));
+        assert!(backup.describe().contains("backup.json"));
+    }
+
+    #[cfg(not(feature = "keychain"))]
+    #[test]
+    fn the_keychain_needs_the_feature() {
+        let error = Backend::Keychain.open().err().unwrap();
+        assert!(error.to_string().contains("--features keychain"));
+    }
+
+    #[test]
+    fn backends_display_as_the_flag_takes_them() {
+        for backend in [Backend::File, Backend::Keychain] {
+            assert_eq!(Backend::from_str(&backend.to_string(), false), Ok(backend));
+        }
+    }
+
+    #[test]
+    fn rejects_a_corrupted_file() {
+        let store = store("file-store-corrupted");
+        store.save("secret").unwrap();
+        fs::write(&store.path, "not json").unwrap();
+        assert!(store.load().is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn only_the_owner_reads_the_secret() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let store = store("file-store-mode");
+        store.save("secret").unwrap();
+        let mode = fs::metadata(&store.path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+
+    #[test]
+    fn a_failed_save_names_the_directory() {
+        // a file where the directory should be fails for root too, unlike a read-only directory
+        let parent = std::env::temp_dir().join(format!("tochat-file-store-blocked-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&parent);
+        let _ = fs::remove_file(&parent);
+        fs::write(&parent, "").unwrap();
+        let store = FileStore::in_dir(&parent.join("dir"), "secret");
+        let error = store.save("secret").unwrap_err();
+        assert!(error.to_string().starts_with(&format!("cannot create {}: ", parent.join("dir").display())));
+        fs::remove_file(&parent).unwrap();
+    }
+
+    #[test]
+    fn writers_take_the_lock_in_turn() {
+        let store = store("file-store-lock");
+        fs::create_dir_all(store.dir()).unwrap();
+        let held = store.lock().unwrap();
+        let other = OpenOptions::new().write(true).open(store.path.with_extension("lock")).unwrap();
+        assert!(other.try_lock_exclusive().is_err());
+        drop(held);
+        assert!(other.try_lock_exclusive().is_ok());
+    }
+}
+
+#[cfg(feature = "keychain")]
+mod keychain {
+    use std::error::Error;
+
+    use super::SecretStore;
+
+    /// Entries are filed under this service, with the secret's name as the user
+    const SERVICE: &str = "tochat";
+
+    pub struct KeychainStore {
+        name: String,
+    }
+
+    impl KeychainStore {
+        pub fn new(name: &str) -> KeychainStore {
+            KeychainStore { name: name.to_string() }
+        }
+
+        fn entry(&self) -> keyring::Entry {
+            keyring::Entry::new(SERVICE, &self.name)
+        }
+    }
+
+    impl SecretStore for KeychainStore {
+        fn save(&self, secret: &str) -> Result<(), Box<dyn Error>> {
+            Ok(self.entry().set_password(secret)?)
+        }
+
+        fn load(&self) -> Result<Option<String>, Box<dyn Error>> {
+            match self.entry().get_password() {
+                Ok(secret) => Ok(Some(secret)),
+                Err(keyring::Error::NoEntry) => Ok(None),
+                Err(e) => Err(format!("cannot read {} from the keychain: {}", self.describe(), e).into()),
+            }
+        }
+
+        fn delete(&self) -> Result<(), Box<dyn Error>> {
+            match self.entry().delete_password() {
+                Ok(()) | Err(keyring::Error::NoEntry) => Ok(()),
+                Err(e) => Err(e.into()),
+            }
+        }
+
+        fn describe(&self) -> String {
+            format!("the keychain entry {}/{}", SERVICE, self.name)
+        }
+    }
+}
@@ -1,77 +1,381 @@
 use std::error::Error;
-use std::fs::OpenOptions;
-use std::io::BufReader;
-use std::io::BufWriter;
+use std::fmt;
+use std::fs;
 use std::env;
+use std::io::{self, Write};
 use std::path::Path;
+use std::path::PathBuf;
+use bip39::Mnemonic;
+use chrono::Local;
 use colorful::Colorful;
 use colorful::Color;
+use crossterm::event::{self, Event, KeyCode, KeyModifiers};
+use crossterm::terminal::{disable_raw_mode, enable_raw_mode};
+use crossterm::tty::IsTty;
 use libp2p::identity;
 use libp2p::identity::Keypair;
 use libp2p::identity::ed25519::SecretKey;
+use libp2p::PeerId;
 use rand::rngs::OsRng;
 use web3::signing::keccak256;
 
-pub fn new_secret_key() -> Result<(), Box<dyn Error>> {
+use crate::network::secret_store::{Backend, SecretStore};
+
+/// The `~/.tochat` directory holding the secret key and chat history, under the working
+/// directory when neither `HOME` nor `HOMEPATH` is set
+pub fn tochat_dir() -> PathBuf {
+    let home_path = env::var_os("HOME").or_else(|| env::var_os("HOMEPATH")).unwrap_or_default();
+    Path::new(&home_path).join(".tochat")
+}
+
+/// Turns an IO error of `operation` on `path` into one saying both, e.g. `cannot create
+/// /home/alice/.tochat: permission denied`, for the file operations behind the secret key
+pub fn io_context<'a>(operation: &'a str, path: &'a Path) -> impl FnOnce(io::Error) -> Box<dyn Error> + 'a {
+    move |e| {
+        let reason = match e.kind() {
+            io::ErrorKind::PermissionDenied => "permission denied".to_string(),
+            _ => e.to_string(),
+        };
+        format!("cannot {} {}: {}", operation, path.display(), reason).into()
+    }
+}
+
+pub fn new_secret_key(store: &dyn SecretStore, mnemonic: bool) -> Result<(), Box<dyn Error>> {
     let secret_key = secp256k1::SecretKey::new(&mut OsRng);
-    println!("{}", "The secret_key is saved in $HOME/.tochat. Please keep it safe.\n".color(Color::LightRed));
     let s = format!("{}", secret_key.display_secret());
+    store.save(&s)?;
+    println!("{}", format!("The secret_key is saved in {}. Please keep it safe.\n", store.describe()).color(Color::LightRed));
     println!("{}", s);
+    if mnemonic {
+        let words = Mnemonic::from_entropy(&secret_key.secret_bytes())?;
+        println!("\nThe same key as a mnemonic, `tochat import-mnemonic` restores it:\n{}", words);
+    }
+    Ok(())
+}
 
-    let home_path = match env::var("HOME") {
-        Ok(path) => path,
-        Err(_) => env::var("HOMEPATH").unwrap(),
-    };
+/// A random secret in the same format as `new_secret_key`, used for one session and never saved
+pub fn ephemeral_secret() -> String {
+    let secret_key = secp256k1::SecretKey::new(&mut OsRng);
+    format!("{}", secret_key.display_secret())
+}
 
-    let tochat_path = Path::new("/.tochat").to_string_lossy();
-    std::fs::create_dir_all(format!("{}{}", home_path, tochat_path))?;
+/// A secret the session holds, like the key from `--stdin-key` or `TOCHAT_KEY`, kept out of
+/// `Debug` output so it can't end up in a log
+#[derive(Clone)]
+pub struct Secret(pub String);
 
-    let secret_path = Path::new("/.tochat/secret.json").to_string_lossy();
-    let buf = BufWriter::new(
-        OpenOptions::new()
-        .write(true)
-        .create(true)
-        .open(format!("{}{}", home_path, secret_path))?);
-    serde_json::to_writer_pretty(buf, &s).unwrap();
-    Ok(())
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Secret(..)")
+    }
+}
+
+/// Read the secret for `--stdin-key`: a line piped in, or typed at a prompt without echo
+pub fn read_stdin_secret() -> Result<String, Box<dyn Error>> {
+    let mut secret = String::new();
+    if io::stdin().is_tty() {
+        eprint!("secret key: ");
+        io::stderr().flush()?;
+        enable_raw_mode()?;
+        let read = read_hidden(&mut secret);
+        disable_raw_mode()?;
+        eprintln!();
+        read?;
+    } else {
+        io::stdin().read_line(&mut secret)?;
+    }
+    let secret = secret.trim().to_string();
+    if secret.is_empty() {
+        return Err("--stdin-key got no key".into());
+    }
+    Ok(secret)
+}
+
+fn read_hidden(secret: &mut String) -> Result<(), Box<dyn Error>> {
+    loop {
+        if let Event::Key(key) = event::read()? {
+            match key.code {
+                KeyCode::Enter => return Ok(()),
+                KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                    return Err("interrupted".into())
+                }
+                KeyCode::Char(c) => secret.push(c),
+                KeyCode::Backspace => {
+                    secret.pop();
+                }
+                _ => {}
+            }
+        }
+    }
 }
 
 pub fn generate_ed25519(key: &String) -> identity::Keypair {
     let mut hash = keccak256(key.as_bytes());
-    let secret_key = SecretKey::from_bytes(&mut hash).unwrap();
+    // any 32 bytes make an ed25519 secret, only a wrong length is refused
+    let secret_key = SecretKey::from_bytes(&mut hash).expect("keccak256 yields 32 bytes");
     Keypair::Ed25519(secret_key.into())
 }
 
-pub fn get_secret() -> String {
-    let home_path = match env::var("HOME") {
-        Ok(path) => path,
-        Err(_) => env::var("HOMEPATH").unwrap(),
-    };
+/// Read the stored secret, failing with what to do about it when there is none or the
+/// store doesn't hold a string as `tochat new` and `tochat import` write it
+pub fn get_secret(store: &dyn SecretStore) -> Result<String, Box<dyn Error>> {
+    match store.load()? {
+        Some(secret) if !secret.trim().is_empty() => Ok(secret),
+        Some(_) => Err(corrupted(&store.describe(), "the key is empty")),
+        None => Err("no secret key yet, create one with `tochat new` or import one with `tochat import`".into()),
+    }
+}
+
+pub fn corrupted(place: &str, reason: &str) -> Box<dyn Error> {
+    format!(
+        "your secret key in {} appears corrupted ({}); re-import your key with `tochat import` \
+         or `tochat import-mnemonic`, or create a new one with `tochat new`",
+        place,
+        reason
+    )
+    .into()
+}
 
-    let secret_path = Path::new("/.tochat/secret.json").to_string_lossy();
-    let buf = BufReader::new(
-        OpenOptions::new()
-        .read(true)
-        .open(format!("{}{}", home_path, secret_path))
-        .expect("Please create or import a secret key"));
-    serde_json::from_reader(buf).unwrap()
+pub fn import_secret(store: &dyn SecretStore, key: &str) -> Result<(), Box<dyn Error>> {
+    store.save(key)?;
+    println!("The secret_key is saved in {}.", store.describe());
+    Ok(())
+}
+
+/// Store the secret encoded by a 24 word BIP-39 mnemonic, as printed by `tochat new --mnemonic`
+pub fn import_mnemonic(store: &dyn SecretStore, phrase: &str) -> Result<(), Box<dyn Error>> {
+    let words = phrase.split_whitespace().count();
+    if words != 24 {
+        return Err(format!(
+            "expected a 24 word mnemonic as printed by `tochat new --mnemonic`, got {} words",
+            words
+        )
+        .into());
+    }
+    // checks the words against the list and the checksum
+    let mnemonic = Mnemonic::parse_normalized(&phrase.to_lowercase())
+        .map_err(|e| format!("invalid mnemonic: {}", e))?;
+    let key: String = mnemonic.to_entropy().iter().map(|b| format!("{:02x}", b)).collect();
+    import_secret(store, &key)
 }
 
-pub fn import_secret(key: &String) -> Result<(), Box<dyn Error>> {
-    let home_path = match env::var("HOME") {
-        Ok(path) => path,
-        Err(_) => env::var("HOMEPATH").unwrap(),
+/// Replace the stored secret with a new one. The old secret is copied to a timestamped backup
+/// in the same store first, and the store replaces the secret in one step, so an interruption
+/// leaves either the old or the new key in place. Only the newest `keep_backups` backups are kept
+/// if given, which needs the file store since a keychain can't be listed
+pub fn rotate_secret(backend: Backend, keep_backups: Option<usize>) -> Result<(), Box<dyn Error>> {
+    rotate_secret_in(backend, &tochat_dir(), keep_backups)
+}
+
+/// `rotate_secret` with the file store in `dir`
+fn rotate_secret_in(backend: Backend, dir: &Path, keep_backups: Option<usize>) -> Result<(), Box<dyn Error>> {
+    if keep_backups.is_some() && backend != Backend::File {
+        return Err("--keep-backups only prunes backups in the file store".into());
+    }
+    let store = backend.open_named_in(dir, "secret")?;
+    let old = match store.load()? {
+        Some(old) => old,
+        None => return Err("no secret key to rotate, create one with `tochat new`".into()),
     };
 
-    let tochat_path = Path::new("/.tochat").to_string_lossy();
-    std::fs::create_dir_all(format!("{}{}", home_path, tochat_path))?;
+    let backup = backend.open_named_in(dir, &format!("secret.{}", Local::now().format("%Y%m%dT%H%M%S%.3f")))?;
+    backup.save(&old)?;
+
+    let new = ephemeral_secret();
+    store.save(&new)?;
 
-    let secret_path = Path::new("/.tochat/secret.json").to_string_lossy();
-    let buf = BufWriter::new(
-        OpenOptions::new()
-        .write(true)
-        .create(true)
-        .open(format!("{}{}", home_path, secret_path))?);
-    serde_json::to_writer_pretty(buf, key).unwrap();
+    let old_id = PeerId::from(generate_ed25519(&old).public());
+    let new_id = PeerId::from(generate_ed25519(&new).public());
+    println!("{}", format!("The new secret_key is saved in {}. Please keep it safe.\n", store.describe()).color(Color::LightRed));
+    println!("Old peer id: {}", old_id);
+    println!("New peer id: {}", new_id);
+    println!("Old key backed up to {}", backup.describe());
+
+    if let Some(keep) = keep_backups {
+        prune_backups(dir, keep)?;
+    }
+    Ok(())
+}
+
+/// Move the secret key from one store to another, e.g. into the keychain. It is read back from
+/// the new store before the old copy is deleted
+pub fn move_secret(from: Backend, to: Backend) -> Result<(), Box<dyn Error>> {
+    if from == to {
+        return Err(format!("the key is already in the {} store, pass the other one with --to", from).into());
+    }
+    let (source, target) = (from.open()?, to.open()?);
+    if target.exists()? {
+        return Err(format!("{} already holds a key, refusing to overwrite it", target.describe()).into());
+    }
+    let secret = get_secret(&*source)?;
+    target.save(&secret)?;
+    if target.load()?.as_deref() != Some(secret.as_str()) {
+        return Err(format!("{} didn't keep the key, {} is left as it was", target.describe(), source.describe()).into());
+    }
+    source.delete()?;
+    println!("The secret_key moved from {} to {}.", source.describe(), target.describe());
+    println!("Pass --secret-store {} or set TOCHAT_SECRET_STORE from now on.", to);
+    Ok(())
+}
+
+/// Delete all but the newest `keep` secret backups, their timestamped names sort by age
+fn prune_backups(dir: &Path, keep: usize) -> Result<(), Box<dyn Error>> {
+    let mut backups: Vec<PathBuf> = fs::read_dir(dir)
+        .map_err(io_context("list", dir))?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| {
+            let name = path.file_name().and_then(|name| name.to_str()).unwrap_or_default();
+            name.starts_with("secret.") && name.ends_with(".json") && name != "secret.json"
+        })
+        .collect();
+    backups.sort();
+    let excess = backups.len().saturating_sub(keep);
+    for path in &backups[..excess] {
+        fs::remove_file(path).map_err(io_context("remove", path))?;
+        println!("Removed old backup {}", path.display());
+    }
     Ok(())
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::secret_store::FileStore;
+
+    /// A fresh directory under the temp dir
+    fn temp_dir(test: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("tochat-{}-{}", test, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn backups(dir: &Path) -> Vec<String> {
+        let mut names: Vec<String> = fs::read_dir(dir)
+            .unwrap()
+            .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+            .filter(|name| name.starts_with("secret.") && name.ends_with(".json") && name != "secret.json")
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn mnemonic_round_trip() {
+        let dir = temp_dir("mnemonic");
+        let store = FileStore::in_dir(&dir, "secret");
+        let key = ephemeral_secret();
+        let words = Mnemonic::from_entropy(&hex::decode(&key).unwrap()).unwrap().to_string();
+        // as typed back, in any case and spacing
+        import_mnemonic(&store, &format!("  {} ", words.to_uppercase().replace(' ', "   "))).unwrap();
+        let imported = get_secret(&store).unwrap();
+        assert_eq!(imported, key);
+        assert_eq!(Mnemonic::from_entropy(&hex::decode(&imported).unwrap()).unwrap().to_string(), words);
+    }
+
+    #[test]
+    fn mnemonic_with_a_bad_checksum_is_rejected() {
+        let dir = temp_dir("mnemonic-checksum");
+        let store = FileStore::in_dir(&dir, "secret");
+        // all zero entropy ends with "art", not "abandon"
+        let words = vec!["abandon"; 24].join(" ");
+        assert!(import_mnemonic(&store, &words).is_err());
+        assert!(!store.exists().unwrap());
+    }
+
+    #[test]
+    fn mnemonic_with_the_wrong_word_count_is_rejected() {
+        let dir = temp_dir("mnemonic-count");
+        let store = FileStore::in_dir(&dir, "secret");
+        let twelve = Mnemonic::from_entropy(&[0u8; 16]).unwrap().to_string();
+        let error = import_mnemonic(&store, &twelve).unwrap_err();
+        assert!(error.to_string().contains("got 12 words"));
+        assert!(!store.exists().unwrap());
+    }
+
+    #[test]
+    fn a_missing_key_says_how_to_make_one() {
+        let store = FileStore::in_dir(&temp_dir("missing"), "secret");
+        assert!(get_secret(&store).unwrap_err().to_string().contains("tochat new"));
+    }
+
+    #[test]
+    fn an_empty_or_garbled_key_is_reported_as_corrupted() {
+        let dir = temp_dir("corrupted-key");
+        let store = FileStore::in_dir(&dir, "secret");
+        store.save("  ").unwrap();
+        assert!(get_secret(&store).unwrap_err().to_string().contains("appears corrupted (the key is empty)"));
+        fs::write(dir.join("secret.json"), "{not json").unwrap();
+        assert!(get_secret(&store).unwrap_err().to_string().contains("appears corrupted"));
+    }
+
+    #[test]
+    fn the_same_secret_gives_the_same_identity() {
+        let key = ephemeral_secret();
+        assert_eq!(PeerId::from(generate_ed25519(&key).public()), PeerId::from(generate_ed25519(&key).public()));
+    }
+
+    #[test]
+    fn moving_to_the_same_store_is_refused() {
+        let error = move_secret(Backend::File, Backend::File).unwrap_err();
+        assert!(error.to_string().contains("already in the file store"));
+    }
+
+    #[test]
+    fn io_errors_say_what_failed_and_where() {
+        let path = Path::new("/home/alice/.tochat");
+        let denied = io_context("create", path)(io::Error::from(io::ErrorKind::PermissionDenied));
+        assert_eq!(denied.to_string(), "cannot create /home/alice/.tochat: permission denied");
+        let other = io_context("delete", path)(io::Error::new(io::ErrorKind::Other, "disk on fire"));
+        assert_eq!(other.to_string(), "cannot delete /home/alice/.tochat: disk on fire");
+    }
+
+    #[test]
+    fn pruning_a_missing_directory_names_it() {
+        let dir = temp_dir("prune-missing");
+        let error = prune_backups(&dir, 1).unwrap_err();
+        assert!(error.to_string().starts_with(&format!("cannot list {}: ", dir.display())));
+    }
+
+    #[test]
+    fn rotation_keeps_the_newest_backups() {
+        let dir = temp_dir("rotate");
+        let store = FileStore::in_dir(&dir, "secret");
+        store.save(&ephemeral_secret()).unwrap();
+        let mut keys = vec![get_secret(&store).unwrap()];
+        for _ in 0..4 {
+            // backups are named to the millisecond
+            std::thread::sleep(std::time::Duration::from_millis(5));
+            rotate_secret_in(Backend::File, &dir, Some(2)).unwrap();
+            keys.push(get_secret(&store).unwrap());
+        }
+
+        let kept = backups(&dir);
+        assert_eq!(kept.len(), 2);
+        // the two keys before the one in use, oldest first
+        let backed_up: Vec<String> = kept
+            .iter()
+            .map(|name| get_secret(&FileStore::in_dir(&dir, name.trim_end_matches(".json"))).unwrap())
+            .collect();
+        assert_eq!(backed_up, keys[2..4]);
+    }
+
+    #[test]
+    fn the_rotated_key_loads() {
+        let dir = temp_dir("rotate-load");
+        let store = FileStore::in_dir(&dir, "secret");
+        store.save(&ephemeral_secret()).unwrap();
+        let old = get_secret(&store).unwrap();
+        rotate_secret_in(Backend::File, &dir, None).unwrap();
+        let new = get_secret(&store).unwrap();
+        assert_ne!(new, old);
+        assert_eq!(new.len(), 64);
+        assert_ne!(PeerId::from(generate_ed25519(&new).public()), PeerId::from(generate_ed25519(&old).public()));
+        assert_eq!(backups(&dir).len(), 1);
+    }
+
+    #[test]
+    fn rotating_without_a_key_fails() {
+        let dir = temp_dir("rotate-none");
+        assert!(rotate_secret_in(Backend::File, &dir, None).is_err());
+    }
+}
@@ -0,0 +1,117 @@
+//! Whispers are sealed with a key both ends derive from their ed25519 identities converted to
+//! x25519: our secret and the other peer's public key, which ed25519 PeerIds carry inline.
+//! Only the two of them can compute it, the rest of the room sees an opaque frame
+
+use chacha20poly1305::aead::{Aead, NewAead};
+use chacha20poly1305::{Key, XChaCha20Poly1305, XNonce};
+use curve25519_dalek::edwards::CompressedEdwardsY;
+use curve25519_dalek::montgomery::MontgomeryPoint;
+use curve25519_dalek::scalar::Scalar;
+use libp2p::identity::{Keypair, PublicKey};
+use libp2p::PeerId;
+use sha2::{Digest, Sha512};
+use web3::signing::keccak256;
+
+/// Encrypt `plaintext` for `to`, returns the hex nonce and ciphertext
+pub fn seal(keypair: &Keypair, to: &PeerId, plaintext: &[u8]) -> Result<(String, String), String> {
+    let cipher = cipher(keypair, to)?;
+    let nonce: [u8; 24] = rand::random();
+    let sealed = cipher
+        .encrypt(XNonce::from_slice(&nonce), plaintext)
+        .map_err(|_| "encryption failed".to_string())?;
+    Ok((hex::encode(nonce), hex::encode(sealed)))
+}
+
+/// Decrypt a whisper `from` sent us, `None` when it wasn't sealed for us or was tampered with
+pub fn open(keypair: &Keypair, from: &PeerId, nonce: &str, sealed: &str) -> Option<Vec<u8>> {
+    let cipher = cipher(keypair, from).ok()?;
+    let nonce = hex::decode(nonce).ok().filter(|n| n.len() == 24)?;
+    cipher.decrypt(XNonce::from_slice(&nonce), hex::decode(sealed).ok()?.as_ref()).ok()
+}
+
+fn cipher(keypair: &Keypair, peer: &PeerId) -> Result<XChaCha20Poly1305, String> {
+    let secret = match keypair {
+        Keypair::Ed25519(keypair) => keypair.secret(),
+        _ => return Err("whispers need an ed25519 identity".to_string()),
+    };
+    // the x25519 scalar is the clamped first half of the hashed seed, as ed25519 signing uses it
+    let hash = Sha512::digest(secret.as_ref());
+    let mut scalar = [0u8; 32];
+    scalar.copy_from_slice(&hash[..32]);
+    scalar[0] &= 248;
+    scalar[31] &= 127;
+    scalar[31] |= 64;
+
+    let shared = Scalar::from_bits(scalar) * public_montgomery(peer)?;
+    let mut preimage = b"tochat-whisper\0".to_vec();
+    preimage.extend_from_slice(shared.as_bytes());
+    Ok(XChaCha20Poly1305::new(Key::from_slice(&keccak256(&preimage))))
+}
+
+/// The x25519 form of the ed25519 key inlined in `peer`
+fn public_montgomery(peer: &PeerId) -> Result<MontgomeryPoint, String> {
+    let multihash = peer.as_ref();
+    // the identity multihash, its digest is the protobuf encoded public key
+    if multihash.code() != 0 {
+        return Err(format!("{} doesn't carry its public key", peer));
+    }
+    match PublicKey::from_protobuf_encoding(multihash.digest()) {
+        Ok(PublicKey::Ed25519(public)) => CompressedEdwardsY(public.encode())
+            .decompress()
+            .map(|point| point.to_montgomery())
+            .ok_or_else(|| format!("{} has an invalid public key", peer)),
+        _ => Err(format!("{} isn't an ed25519 peer id", peer)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer() -> (Keypair, PeerId) {
+        let keypair = Keypair::generate_ed25519();
+        let id = PeerId::from(keypair.public());
+        (keypair, id)
+    }
+
+    #[test]
+    fn seal_then_open() {
+        let (alice, alice_id) = peer();
+        let (bob, bob_id) = peer();
+        let (nonce, sealed) = seal(&alice, &bob_id, b"psst").unwrap();
+        assert_eq!(open(&bob, &alice_id, &nonce, &sealed).as_deref(), Some(&b"psst"[..]));
+    }
+
+    #[test]
+    fn only_the_recipient_opens() {
+        let (alice, alice_id) = peer();
+        let (_, bob_id) = peer();
+        let (eve, _) = peer();
+        let (nonce, sealed) = seal(&alice, &bob_id, b"psst").unwrap();
+        assert_eq!(open(&eve, &alice_id, &nonce, &sealed), None);
+    }
+
+    #[test]
+    fn tampering_is_rejected() {
+        let (alice, alice_id) = peer();
+        let (bob, bob_id) = peer();
+        let (nonce, sealed) = seal(&alice, &bob_id, b"psst").unwrap();
+        let flip = |hex_string: &str| {
+            let mut bytes = hex::decode(hex_string).unwrap();
+            bytes[0] ^= 1;
+            hex::encode(bytes)
+        };
+        assert_eq!(open(&bob, &alice_id, &nonce, &flip(&sealed)), None);
+        assert_eq!(open(&bob, &alice_id, &flip(&nonce), &sealed), None);
+        assert_eq!(open(&bob, &alice_id, &nonce[2..], &sealed), None);
+    }
+
+    #[test]
+    fn the_wrong_author_fails() {
+        let (alice, _) = peer();
+        let (bob, bob_id) = peer();
+        let (_, mallory_id) = peer();
+        let (nonce, sealed) = seal(&alice, &bob_id, b"psst").unwrap();
+        assert_eq!(open(&bob, &mallory_id, &nonce, &sealed), None);
+    }
+}
@@ -0,0 +1,479 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::time::{Duration, Instant};
+
+use chrono::Utc;
+use libp2p::PeerId;
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::message::{ChatMessage, Quote, Seq};
+
+/// Chunks of a message that never completes are dropped after this long
+const REASSEMBLY_TIMEOUT: Duration = Duration::from_secs(30);
+/// Upper bound on chunks per message, larger announcements are ignored
+const MAX_CHUNKS: u32 = 1024;
+/// Incomplete messages kept per sender, starting another drops their oldest so a peer can't
+/// fill our memory with chunks it never finishes
+const MAX_PARTIALS_PER_SOURCE: usize = 8;
+/// Room left in every gossipsub message for its protobuf envelope (signature, key, topic, seqno)
+pub const ENVELOPE_OVERHEAD: usize = 512;
+
+/// What tochat peers publish on the topic, encoded as JSON
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Frame {
+    /// A chat message, `id` is missing from peers on older builds
+    Chat {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<u64>,
+        name: String,
+        body: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        reply_to: Option<Quote>,
+        /// Missing from peers on older builds and inside whispers
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        seq: Option<Seq>,
+        /// When the sender published it, unix milliseconds on their clock, missing from peers on
+        /// older builds
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        sent_at: Option<i64>,
+    },
+    /// Part `index` of chat message `id` split into `count` parts to stay under the size limit
+    Chunk {
+        id: u64,
+        index: u32,
+        count: u32,
+        name: String,
+        part: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        reply_to: Option<Quote>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        seq: Option<Seq>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        sent_at: Option<i64>,
+    },
+    /// Replaces the body of chat message `id`, only honoured from the peer that sent it
+    Edit { id: u64, body: String },
+    /// Tombstones chat message `id`, only honoured from the peer that sent it
+    Delete { id: u64 },
+    /// A `Chat` frame encrypted for the peer `to`, see `whisper`
+    Whisper { to: String, nonce: String, sealed: String },
+}
+
+/// What tells a frame apart from others of its sender, so one delivered again, e.g. after a
+/// reconnect or published twice, is noticed
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum FrameKey {
+    Chat(u64),
+    Chunk { id: u64, index: u32 },
+    /// A message can be edited several times, each new body is another edit
+    Edit { id: u64, body: u64 },
+    Delete(u64),
+    Whisper(String),
+}
+
+impl Frame {
+    /// The key of this frame, none for chat messages from peers on older builds, which carry
+    /// no id
+    pub fn key(&self) -> Option<FrameKey> {
+        Some(match self {
+            Frame::Chat { id, .. } => FrameKey::Chat((*id)?),
+            Frame::Chunk { id, index, .. } => FrameKey::Chunk { id: *id, index: *index },
+            Frame::Edit { id, body } => {
+                let mut hasher = DefaultHasher::new();
+                body.hash(&mut hasher);
+                FrameKey::Edit { id: *id, body: hasher.finish() }
+            }
+            Frame::Delete { id } => FrameKey::Delete(*id),
+            Frame::Whisper { nonce, .. } => FrameKey::Whisper(nonce.clone()),
+        })
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        serde_json::to_vec(self).expect("frames always serialize")
+    }
+
+    pub fn decode(data: &[u8]) -> Option<Frame> {
+        if let Ok(frame) = serde_json::from_slice(data) {
+            return Some(frame);
+        }
+        // peers on older builds send `body,name`
+        let text = String::from_utf8_lossy(data);
+        let (body, name) = text.rsplit_once(',')?;
+        Some(Frame::Chat {
+            id: None,
+            name: name.to_string(),
+            body: body.to_string(),
+            reply_to: None,
+            seq: None,
+            sent_at: None,
+        })
+    }
+}
+
+/// Numbers the chat messages we publish, counting per topic so a peer that only joined some
+/// of our topics doesn't see gaps
+pub struct Sequencer {
+    session: u32,
+    sent: HashMap<String, u64>,
+}
+
+impl Default for Sequencer {
+    fn default() -> Sequencer {
+        Sequencer {
+            session: rand::random(),
+            sent: HashMap::new(),
+        }
+    }
+}
+
+impl Sequencer {
+    /// The number of the next message published to `topic`
+    pub fn next(&mut self, topic: &str) -> Seq {
+        let n = self.sent.entry(topic.to_string()).or_insert(0);
+        *n += 1;
+        Seq { session: self.session, n: *n }
+    }
+}
+
+/// Encode a chat message into payloads of at most `limit` bytes, splitting the body into chunks if needed
+pub fn encode_chat(message: &ChatMessage, limit: usize) -> Result<Vec<Vec<u8>>, String> {
+    let ChatMessage { id, sender: name, body, reply_to, seq, .. } = message;
+    let sent_at = Some(Utc::now().timestamp_millis());
+    let whole = Frame::Chat {
+        id: Some(*id),
+        name: name.to_string(),
+        body: body.to_string(),
+        reply_to: reply_to.clone(),
+        seq: *seq,
+        sent_at,
+    }
+    .encode();
+    if whole.len() <= limit {
+        return Ok(vec![whole]);
+    }
+
+    // size of a chunk without its part, with the widest possible numbers
+    let overhead = Frame::Chunk {
+        id: u64::MAX,
+        index: MAX_CHUNKS,
+        count: MAX_CHUNKS,
+        name: name.to_string(),
+        part: String::new(),
+        reply_to: reply_to.clone(),
+        seq: *seq,
+        sent_at: Some(i64::MAX),
+    }
+    .encode()
+    .len();
+    if overhead >= limit {
+        return Err(format!("a message chunk doesn't fit in {} bytes", limit));
+    }
+    let budget = limit - overhead;
+
+    let mut parts = Vec::new();
+    let mut part = String::new();
+    let mut size = 0;
+    for c in body.chars() {
+        let width = escaped_len(c);
+        if size + width > budget && !part.is_empty() {
+            parts.push(std::mem::take(&mut part));
+            size = 0;
+        }
+        part.push(c);
+        size += width;
+    }
+    if !part.is_empty() {
+        parts.push(part);
+    }
+    if parts.len() > MAX_CHUNKS as usize {
+        return Err(format!("message needs {} chunks, at most {} are allowed", parts.len(), MAX_CHUNKS));
+    }
+
+    let count = parts.len() as u32;
+    Ok(parts
+        .into_iter()
+        .enumerate()
+        .map(|(index, part)| {
+            Frame::Chunk {
+                id: *id,
+                index: index as u32,
+                count,
+                name: name.to_string(),
+                part,
+                reply_to: reply_to.clone(),
+                seq: *seq,
+                sent_at,
+            }
+            .encode()
+        })
+        .collect())
+}
+
+/// Length of a char once escaped inside a JSON string
+fn escaped_len(c: char) -> usize {
+    match c {
+        '"' | '\\' | '\n' | '\r' | '\t' | '\u{08}' | '\u{0c}' => 2,
+        c if (c as u32) < 0x20 => 6,
+        c => c.len_utf8(),
+    }
+}
+
+struct Partial {
+    name: String,
+    reply_to: Option<Quote>,
+    seq: Option<Seq>,
+    sent_at: Option<i64>,
+    parts: Vec<Option<String>>,
+    received: usize,
+    started: Instant,
+}
+
+/// Collects chunks per sender until a message is complete, in any order
+#[derive(Default)]
+pub struct Reassembler {
+    partials: HashMap<(Option<PeerId>, u64), Partial>,
+}
+
+impl Reassembler {
+    /// Feed a received frame, returns a chat frame once every chunk of it arrived
+    pub fn push(&mut self, source: Option<PeerId>, frame: Frame) -> Option<Frame> {
+        self.expire();
+
+        let (id, index, count, name, part, reply_to, seq, sent_at) = match frame {
+            Frame::Chunk {
+                id,
+                index,
+                count,
+                name,
+                part,
+                reply_to,
+                seq,
+                sent_at,
+            } => (id, index, count, name, part, reply_to, seq, sent_at),
+            frame => return Some(frame),
+        };
+        if count == 0 || count > MAX_CHUNKS || index >= count {
+            warn!("Ignoring malformed chunk {}/{} of message {}", index, count, id);
+            return None;
+        }
+
+        if !self.partials.contains_key(&(source, id)) {
+            self.make_room(source);
+        }
+        let partial = self.partials.entry((source, id)).or_insert_with(|| Partial {
+            name,
+            reply_to,
+            seq,
+            sent_at,
+            parts: vec![None; count as usize],
+            received: 0,
+            started: Instant::now(),
+        });
+        if partial.parts.len() != count as usize {
+            return None;
+        }
+        let slot = &mut partial.parts[index as usize];
+        if slot.is_none() {
+            *slot = Some(part);
+            partial.received += 1;
+        }
+        if partial.received < partial.parts.len() {
+            return None;
+        }
+
+        let partial = self.partials.remove(&(source, id))?;
+        Some(Frame::Chat {
+            id: Some(id),
+            name: partial.name,
+            body: partial.parts.into_iter().flatten().collect(),
+            reply_to: partial.reply_to,
+            seq: partial.seq,
+            sent_at: partial.sent_at,
+        })
+    }
+
+    /// Drop the oldest incomplete messages of `source` until another one fits under the cap
+    fn make_room(&mut self, source: Option<PeerId>) {
+        loop {
+            let open = self.partials.iter().filter(|((from, _), _)| *from == source);
+            if open.clone().count() < MAX_PARTIALS_PER_SOURCE {
+                return;
+            }
+            let oldest = match open.min_by_key(|(_, partial)| partial.started) {
+                Some((key, _)) => *key,
+                None => return,
+            };
+            if let Some(partial) = self.partials.remove(&oldest) {
+                warn!(
+                    "Discarding incomplete message {} from {:?}, too many open, got {}/{} chunks",
+                    oldest.1,
+                    source,
+                    partial.received,
+                    partial.parts.len()
+                );
+            }
+        }
+    }
+
+    fn expire(&mut self) {
+        self.partials.retain(|(source, id), partial| {
+            let live = partial.started.elapsed() < REASSEMBLY_TIMEOUT;
+            if !live {
+                warn!(
+                    "Discarding incomplete message {} from {:?}, got {}/{} chunks",
+                    id,
+                    source,
+                    partial.received,
+                    partial.parts.len()
+                );
+            }
+            live
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunks(body: &str, limit: usize) -> Vec<Frame> {
+        let message = ChatMessage::local("alice", body.to_string());
+        let payloads = encode_chat(&message, limit).unwrap();
+        payloads.iter().map(|payload| Frame::decode(payload).unwrap()).collect()
+    }
+
+    fn body(frame: Option<Frame>) -> Option<String> {
+        match frame? {
+            Frame::Chat { body, .. } => Some(body),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn numbers_messages_per_topic() {
+        let mut sequencer = Sequencer::default();
+        let (a1, a2, b1) = (sequencer.next("a"), sequencer.next("a"), sequencer.next("b"));
+        assert_eq!((a1.n, a2.n, b1.n), (1, 2, 1));
+        assert!(a1.session == a2.session && a2.session == b1.session);
+    }
+
+    #[test]
+    fn the_number_goes_out_with_the_message() {
+        let mut message = ChatMessage::local("alice", "hi".to_string());
+        message.seq = Some(Sequencer::default().next("a"));
+        let payloads = encode_chat(&message, 1024).unwrap();
+        match Frame::decode(&payloads[0]) {
+            Some(Frame::Chat { seq, .. }) => assert_eq!(seq, message.seq),
+            other => panic!("expected a chat frame, got {:?}", other),
+        }
+    }
+
+    fn chunk(id: u64, index: u32, count: u32) -> Frame {
+        Frame::Chunk {
+            id,
+            index,
+            count,
+            name: "alice".to_string(),
+            part: index.to_string(),
+            reply_to: None,
+            seq: None,
+            sent_at: None,
+        }
+    }
+
+    #[test]
+    fn keys_tell_frames_apart() {
+        let edit = |body: &str| Frame::Edit { id: 1, body: body.to_string() }.key();
+        assert_eq!(edit("fixed"), edit("fixed"));
+        assert_ne!(edit("fixed"), edit("fixed again"));
+        assert_ne!(chunk(1, 0, 2).key(), chunk(1, 1, 2).key());
+        assert_ne!(chunk(1, 0, 2).key(), Frame::Delete { id: 1 }.key());
+        let chat = |id| Frame::Chat {
+            id,
+            name: "alice".to_string(),
+            body: "hi".to_string(),
+            reply_to: None,
+            seq: None,
+            sent_at: None,
+        };
+        assert_eq!(chat(Some(7)).key(), Some(FrameKey::Chat(7)));
+        assert_eq!(chat(None).key(), None);
+    }
+
+    #[test]
+    fn reassembles_a_split_message() {
+        let text = "a long message \"quoted\" and\nsplit ".repeat(40);
+        let frames = chunks(&text, 400);
+        assert!(frames.len() > 1);
+        let mut reassembler = Reassembler::default();
+        let mut done = None;
+        for frame in frames {
+            assert!(done.is_none());
+            done = reassembler.push(None, frame);
+        }
+        assert_eq!(body(done), Some(text));
+    }
+
+    #[test]
+    fn reassembles_out_of_order() {
+        let text = "x".repeat(2000);
+        let mut frames = chunks(&text, 400);
+        frames.reverse();
+        let mut reassembler = Reassembler::default();
+        let last = frames.pop().unwrap();
+        for frame in frames {
+            assert_eq!(reassembler.push(None, frame), None);
+        }
+        assert_eq!(body(reassembler.push(None, last)), Some(text));
+    }
+
+    #[test]
+    fn duplicate_chunks_count_once() {
+        let mut reassembler = Reassembler::default();
+        assert_eq!(reassembler.push(None, chunk(1, 0, 2)), None);
+        assert_eq!(reassembler.push(None, chunk(1, 0, 2)), None);
+        assert_eq!(body(reassembler.push(None, chunk(1, 1, 2))), Some("01".to_string()));
+    }
+
+    #[test]
+    fn ignores_a_chunk_with_another_count() {
+        let mut reassembler = Reassembler::default();
+        assert_eq!(reassembler.push(None, chunk(1, 0, 3)), None);
+        assert_eq!(reassembler.push(None, chunk(1, 1, 2)), None);
+        assert_eq!(reassembler.push(None, chunk(1, 1, 3)), None);
+        assert_eq!(body(reassembler.push(None, chunk(1, 2, 3))), Some("012".to_string()));
+    }
+
+    #[test]
+    fn expires_incomplete_messages() {
+        let mut reassembler = Reassembler::default();
+        assert_eq!(reassembler.push(None, chunk(1, 0, 2)), None);
+        for partial in reassembler.partials.values_mut() {
+            partial.started = Instant::now().checked_sub(REASSEMBLY_TIMEOUT).unwrap();
+        }
+        // the first chunk is gone, so the second starts over
+        assert_eq!(reassembler.push(None, chunk(1, 1, 2)), None);
+        assert_eq!(reassembler.partials.len(), 1);
+    }
+
+    #[test]
+    fn caps_open_messages_per_source() {
+        let mut reassembler = Reassembler::default();
+        let spammer = Some(PeerId::random());
+        let other = Some(PeerId::random());
+        assert_eq!(reassembler.push(other, chunk(0, 0, 2)), None);
+        for id in 0..MAX_PARTIALS_PER_SOURCE as u64 + 3 {
+            assert_eq!(reassembler.push(spammer, chunk(id, 0, 2)), None);
+        }
+        let open = |source| reassembler.partials.keys().filter(|(from, _)| *from == source).count();
+        assert_eq!(open(spammer), MAX_PARTIALS_PER_SOURCE);
+        assert_eq!(open(other), 1);
+        // the oldest were dropped, the newest still complete
+        assert!(!reassembler.partials.contains_key(&(spammer, 0)));
+        let newest = MAX_PARTIALS_PER_SOURCE as u64 + 2;
+        assert_eq!(body(reassembler.push(spammer, chunk(newest, 1, 2))), Some("01".to_string()));
+    }
+}
@@ -0,0 +1,134 @@
+//! `tochat search`: finds messages in the history files of every topic, not only the ones a
+//! session has loaded. Files are read line by line, only the messages kept for context are held
+
+use std::borrow::Cow;
+use std::collections::VecDeque;
+use std::error::Error;
+use std::io::{self, Write};
+
+use chrono::NaiveDate;
+
+use crate::history;
+use crate::message::{ChatMessage, Kind};
+
+/// What `tochat search` looks for
+pub struct Search {
+    /// Each one has to appear in the body, a term with spaces is a phrase
+    terms: Vec<String>,
+    case_sensitive: bool,
+    since: Option<NaiveDate>,
+    until: Option<NaiveDate>,
+    /// Messages shown before and after each match
+    context: usize,
+}
+
+/// Parse `--since`/`--until`, days are in local time
+pub fn parse_day(s: &str) -> Result<NaiveDate, String> {
+    NaiveDate::parse_from_str(s.trim(), "%Y-%m-%d").map_err(|e| format!("`{}` is not a YYYY-MM-DD day: {}", s, e))
+}
+
+impl Search {
+    pub fn new(
+        terms: &[String],
+        case_sensitive: bool,
+        since: Option<NaiveDate>,
+        until: Option<NaiveDate>,
+        context: usize,
+    ) -> Result<Search, Box<dyn Error>> {
+        let terms: Vec<String> = terms
+            .iter()
+            .map(|term| term.trim().trim_matches('"').trim())
+            .filter(|term| !term.is_empty())
+            .map(|term| if case_sensitive { term.to_string() } else { term.to_lowercase() })
+            .collect();
+        if terms.is_empty() {
+            return Err("nothing to search for".into());
+        }
+        if let (Some(since), Some(until)) = (since, until) {
+            if since > until {
+                return Err("--since is after --until".into());
+            }
+        }
+        Ok(Search { terms, case_sensitive, since, until, context })
+    }
+
+    fn matches(&self, message: &ChatMessage) -> bool {
+        if message.kind == Kind::System || message.deleted {
+            return false;
+        }
+        let day = message.timestamp.naive_local().date();
+        if self.since.map_or(false, |since| day < since) || self.until.map_or(false, |until| day > until) {
+            return false;
+        }
+        let body = if self.case_sensitive { Cow::Borrowed(message.body.as_str()) } else { Cow::Owned(message.body.to_lowercase()) };
+        self.terms.iter().all(|term| body.contains(term.as_str()))
+    }
+
+    /// Search the history of `topic`, or of every topic, printing the matches to `out` grouped
+    /// by topic. Returns how many messages matched
+    pub fn run(&self, topic: Option<&str>, out: &mut impl Write) -> Result<usize, Box<dyn Error>> {
+        let files = history::files(topic)?;
+        if files.is_empty() {
+            return Err(match topic {
+                Some(topic) => format!("no history saved for {}", topic).into(),
+                None => "no history saved yet".into(),
+            });
+        }
+        let mut found = 0;
+        for (topic, path) in files {
+            found += self.search_file(&topic, history::read(&path)?, out)?;
+        }
+        Ok(found)
+    }
+
+    fn search_file(
+        &self,
+        topic: &str,
+        messages: impl Iterator<Item = io::Result<ChatMessage>>,
+        out: &mut impl Write,
+    ) -> io::Result<usize> {
+        let mut found = 0;
+        // the last messages not printed yet, shown before the next match
+        let mut before: VecDeque<ChatMessage> = VecDeque::with_capacity(self.context);
+        // messages still to print after the last match
+        let mut after = 0;
+        let mut last_printed: Option<usize> = None;
+        for (i, message) in messages.enumerate() {
+            let message = message?;
+            if self.matches(&message) {
+                match last_printed {
+                    None => writeln!(out, "== {}", topic)?,
+                    Some(last) if i - before.len() > last + 1 => writeln!(out, "--")?,
+                    _ => {}
+                }
+                for context in before.drain(..) {
+                    print(out, &context, false)?;
+                }
+                print(out, &message, true)?;
+                found += 1;
+                after = self.context;
+                last_printed = Some(i);
+            } else if after > 0 {
+                print(out, &message, false)?;
+                after -= 1;
+                last_printed = Some(i);
+            } else if self.context > 0 {
+                if before.len() == self.context {
+                    before.pop_front();
+                }
+                before.push_back(message);
+            }
+        }
+        if found > 0 {
+            writeln!(out)?;
+        }
+        Ok(found)
+    }
+}
+
+/// One message per line like a text export, matches marked with `>`
+fn print(out: &mut impl Write, message: &ChatMessage, hit: bool) -> io::Result<()> {
+    let mark = if hit { '>' } else { ' ' };
+    let body = message.body.replace('\n', " ⏎ ");
+    writeln!(out, "{} [{}] {}: {}", mark, message.timestamp.format("%Y-%m-%d %H:%M:%S"), message.sender, body)
+}
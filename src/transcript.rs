@@ -0,0 +1,133 @@
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use crate::message::{ChatMessage, Kind};
+
+/// Layout of an exported conversation, picked from the file extension
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    /// `[2022-10-01 12:00:00] alice: hi`
+    Text,
+    /// The sender and time in bold and italics above each body
+    Markdown,
+    /// A JSON array of the messages with every field
+    Json,
+}
+
+impl Format {
+    /// `.md` is markdown, `.json` is JSON, anything else plain text
+    pub fn from_path(path: &Path) -> Format {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("md") | Some("markdown") => Format::Markdown,
+            Some("json") => Format::Json,
+            _ => Format::Text,
+        }
+    }
+}
+
+/// Expand a leading `~/` the way a shell would
+pub fn expand_home(path: &str) -> PathBuf {
+    match (path.strip_prefix("~/"), env::var_os("HOME")) {
+        (Some(rest), Some(home)) => Path::new(&home).join(rest),
+        _ => PathBuf::from(path),
+    }
+}
+
+/// Write `messages` to `path` in the format its extension asks for
+pub fn export(messages: &[ChatMessage], path: &Path) -> io::Result<Format> {
+    let format = Format::from_path(path);
+    let mut out = BufWriter::new(File::create(path)?);
+    match format {
+        Format::Json => serde_json::to_writer_pretty(&mut out, messages)?,
+        Format::Text => {
+            for m in messages {
+                if let Some(quote) = &m.reply_to {
+                    writeln!(out, "    ↪ {}: {}", quote.sender, quote.snippet)?;
+                }
+                writeln!(out, "[{}] {}: {}", m.timestamp.format("%Y-%m-%d %H:%M:%S"), m.sender, m.body)?;
+            }
+        }
+        Format::Markdown => {
+            for m in messages {
+                writeln!(out, "**{}** _{}_\n", m.sender, m.timestamp.format("%Y-%m-%d %H:%M:%S"))?;
+                if let Some(quote) = &m.reply_to {
+                    writeln!(out, "> {}: {}\n", quote.sender, quote.snippet)?;
+                }
+                writeln!(out, "{}\n", m.body)?;
+            }
+        }
+    }
+    out.flush()?;
+    Ok(format)
+}
+
+/// `messages` quoted to paste into an issue or another chat, a `> HH:MM <name>: body` line
+/// each with the later lines of a body quoted too. Notices and deleted messages are left out,
+/// the count is of the messages quoted
+pub fn quote(messages: &[ChatMessage]) -> (String, usize) {
+    let mut quoted = String::new();
+    let mut count = 0;
+    for m in messages.iter().filter(|m| m.kind != Kind::System && !m.deleted) {
+        let mut lines = m.body.lines();
+        quoted.push_str(&format!("> {} <{}>: {}\n", m.timestamp.format("%H:%M"), m.sender, lines.next().unwrap_or_default()));
+        for line in lines {
+            quoted.push_str(&format!("> {}\n", line));
+        }
+        count += 1;
+    }
+    (quoted, count)
+}
+
+/// Read a transcript written by `/export` as JSON, or a history file with one message per line
+pub fn load(path: &Path) -> Result<Vec<ChatMessage>, String> {
+    let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    if text.trim_start().starts_with('[') {
+        return serde_json::from_str(&text)
+            .map_err(|e| format!("{} is not a valid transcript: {}", path.display(), e));
+    }
+    text.lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(i, line)| {
+            serde_json::from_str(line)
+                .map_err(|e| format!("{} line {} is not a valid message: {}", path.display(), i + 1, e))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{Local, NaiveDate, TimeZone};
+
+    fn at(mut message: ChatMessage, hour: u32, minute: u32) -> ChatMessage {
+        let at = NaiveDate::from_ymd_opt(2024, 3, 1).unwrap().and_hms_opt(hour, minute, 0).unwrap();
+        message.timestamp = Local.from_local_datetime(&at).earliest().unwrap();
+        message
+    }
+
+    #[test]
+    fn quote_formats_each_message_with_time_and_sender() {
+        let messages = vec![
+            at(ChatMessage::remote("alice", "hi\nhow are you".to_string()), 9, 5),
+            at(ChatMessage::local("bob", "fine".to_string()), 9, 6),
+        ];
+        let (quoted, count) = quote(&messages);
+        assert_eq!(quoted, "> 09:05 <alice>: hi\n> how are you\n> 09:06 <bob>: fine\n");
+        assert_eq!(count, 2);
+    }
+
+    #[test]
+    fn quote_skips_system_and_deleted_messages() {
+        let mut deleted = ChatMessage::remote("alice", String::new());
+        deleted.deleted = true;
+        let messages = vec![
+            ChatMessage::system("alice joined".to_string()),
+            deleted,
+            at(ChatMessage::remote("alice", "kept".to_string()), 23, 59),
+        ];
+        assert_eq!(quote(&messages), ("> 23:59 <alice>: kept\n".to_string(), 1));
+    }
+}
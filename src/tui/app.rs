@@ -1,92 +1,429 @@
 use crossterm::{
-    event::{self, Event, KeyCode, poll},
+    event::{self, Event, KeyCode, KeyEvent, KeyModifiers, poll},
+    execute,
+    terminal::SetTitle,
 };
 
 use instant::Duration;
-use tokio::sync::mpsc::{Sender, Receiver};
-use std::{io, sync::{Arc, Mutex}};
+use tokio::sync::mpsc::{error::{TryRecvError, TrySendError}, Sender, Receiver};
+use std::{io::{self, Write}, time::Instant};
 use tui::{
     backend::Backend,
     Terminal,
 };
-use super::{InputMode, ui::ui, App};
-
-use chrono::prelude::*;
-
+use super::{emoji, InputMode, ui::ui, App};
+use crate::config::EnterKey;
+use crate::message::{ChatMessage, Delivery, Quote};
+use crate::network::path::Preference;
+use crate::network::{Command, Update};
+use crate::transcript;
 
+/// Updates applied before drawing a frame, the rest wait for the next one so a burst
+/// of messages can't hold up rendering and key handling
+const MAX_UPDATES_PER_FRAME: usize = 256;
 
 pub async fn run_app<B: Backend>(
     terminal: &mut Terminal<B>,
-    app: App, 
-    tx1: Sender<String>,
-    mut rx2: Receiver<String>,
-    name: &String
-) -> io::Result<()> {
-
-    // crossed thread data
-    let app = Arc::new(Mutex::new(app));
-    let app_clone = app.clone();
+    mut app: App, 
+    tx1: Sender<Command>,
+    mut rx2: Receiver<Update>,
+    name: &String,
+    idle_timeout: Option<Duration>,
+) -> io::Result<App> {
 
+    let mut last_input = Instant::now();
+    let mut network_gone = false;
 
-    tokio::spawn(async move {
-        loop {
-            while let Some(msg) = rx2.recv().await {
-                let mut lock = app_clone.lock().unwrap();
-                (*lock).messages.items.push(msg);
-                let len = (*lock).messages.items.len() - 1;
-                (*lock).messages.state.select(Some(len));
+    // this loop owns the app, network updates are drained from the channel between frames
+    // instead of a second task locking the app while a frame is drawn
+    loop {
+        for _ in 0..MAX_UPDATES_PER_FRAME {
+            match rx2.try_recv() {
+                Ok(Update::Inject { .. }) if app.read_only => {
+                    app.push(ChatMessage::system("read-only, dropped a message from the inject socket".to_string()));
+                }
+                Ok(Update::Inject { body, reply_to }) => {
+                    let quote = reply_to.and_then(|id| app.quotable(id));
+                    send(&mut app, &tx1, name, body, quote);
+                }
+                Ok(update) => app.apply(update),
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    // the network task ended, nothing will arrive anymore
+                    if !std::mem::replace(&mut network_gone, true) {
+                        app.connection_lost(None);
+                    }
+                    break;
+                }
             }
         }
-    });
+        if app.announce_presence() && app.presence_bell && !app.muted() {
+            // the bell character doesn't move the cursor, tui's buffer stays in sync
+            let mut stdout = io::stdout();
+            stdout.write_all(b"\x07")?;
+            stdout.flush()?;
+        }
+        app.expire(chrono::Local::now());
+        app.poll_uploads();
+        terminal.draw(|f| ui(f, &mut app))?;
+        let unread = app.unread();
+        if app.title && app.titled != Some(unread) {
+            app.titled = Some(unread);
+            let title = if unread > 0 { format!("tochat ({})", unread) } else { "tochat".to_string() };
+            execute!(io::stdout(), SetTitle(title))?;
+        }
 
-    loop {
-        terminal.draw(|f| ui(f, &mut app.lock().unwrap()))?;
+        if let Some(timeout) = idle_timeout {
+            let went_away = !app.away && last_input.elapsed() >= timeout;
+            app.away |= went_away;
+            if went_away {
+                match tx1.try_send(Command::Away) {
+                    // not away after all, the next frame tries again
+                    Err(TrySendError::Full(_)) => app.away = false,
+                    Err(e) => app.command_refused(e, None),
+                    Ok(()) => {}
+                }
+            }
+        }
         
         // flush every 50 millis, avoid blocking
         if poll(Duration::from_millis(50))? {
-            if let Event::Key(key) = event::read()? {
-                let mut lock = app.lock().unwrap();
-                match (*lock).input_mode {
+            let event = event::read()?;
+            if let Event::Key(_) | Event::Paste(_) = event {
+                last_input = Instant::now();
+                let resumed = std::mem::replace(&mut app.away, false);
+                if resumed {
+                    match tx1.try_send(Command::Resume) {
+                        // still away, the next key tries again
+                        Err(TrySendError::Full(_)) => app.away = true,
+                        Err(e) => app.command_refused(e, None),
+                        Ok(()) => {}
+                    }
+                }
+            }
+            if let Event::Paste(text) = &event {
+                app.paste(text);
+            }
+            if let Event::Key(key) = event {
+                let key = match app.input_mode {
+                    InputMode::Editing => enter_key(app.enter, key),
+                    InputMode::Normal => key,
+                };
+                match app.input_mode {
+                    InputMode::Normal if app.show_help => match key.code {
+                        KeyCode::Char('?') | KeyCode::Esc => app.show_help = false,
+                        _ => {}
+                    },
+                    InputMode::Normal if app.show_events => match key.code {
+                        KeyCode::Char('l') | KeyCode::Esc => app.show_events = false,
+                        KeyCode::Up | KeyCode::Char('k') => app.scroll_events(1),
+                        KeyCode::Down | KeyCode::Char('j') => app.scroll_events(-1),
+                        KeyCode::PageUp => app.scroll_events(10),
+                        KeyCode::PageDown => app.scroll_events(-10),
+                        KeyCode::Home => app.scroll_events(isize::MAX),
+                        KeyCode::End => app.events_scroll = 0,
+                        _ => {}
+                    },
+                    InputMode::Normal if app.quitting => match key.code {
+                        KeyCode::Char('y') | KeyCode::Char('q') => return Ok(app),
+                        _ => app.quitting = false,
+                    },
+                    InputMode::Normal if !app.key_changes.is_empty() => match key.code {
+                        KeyCode::Char('y') => app.answer_key_change(true),
+                        KeyCode::Char('n') => app.answer_key_change(false),
+                        KeyCode::Char('q') => app.quitting = true,
+                        KeyCode::Char('?') => app.show_help = true,
+                        _ => {}
+                    },
+                    InputMode::Normal if !app.url_choices.is_empty() => match key.code {
+                        KeyCode::Char(c) if c.is_ascii_digit() => {
+                            app.pick_url(c.to_digit(10).unwrap() as usize);
+                        }
+                        _ => app.url_choices.clear(),
+                    },
                     InputMode::Normal => match key.code {
+                        KeyCode::Char('i') if app.read_only => {
+                            app.push(ChatMessage::system("read-only, sending is disabled".to_string()));
+                        }
                         KeyCode::Char('i') => {
-                            (*lock).input_mode = InputMode::Editing;
+                            app.input_mode = InputMode::Editing;
+                        }
+                        KeyCode::Char('q') if app.quit_confirm => {
+                            app.quitting = true;
                         }
                         KeyCode::Char('q') => {
-                            return Ok(());
-                        }
-                        KeyCode::Left => (*lock).messages.unselect(),
-                        KeyCode::Down => (*lock).messages.next(),
-                        KeyCode::Up => (*lock).messages.previous(),
-                        KeyCode::Char('j') => (*lock).messages.next(),
-                        KeyCode::Char('k') => (*lock).messages.previous(),
-                        KeyCode::Home => (*lock).messages.home(),
-                        KeyCode::End => (*lock).messages.end(),
+                            return Ok(app);
+                        }
+                        KeyCode::Left => app.messages.unselect(),
+                        KeyCode::Down if key.modifiers.contains(KeyModifiers::SHIFT) => app.messages.extend(true),
+                        KeyCode::Up if key.modifiers.contains(KeyModifiers::SHIFT) => app.messages.extend(false),
+                        KeyCode::Char('J') => app.messages.extend(true),
+                        KeyCode::Char('K') => app.messages.extend(false),
+                        KeyCode::Char('y') => app.copy_selected(),
+                        KeyCode::Esc => app.messages.collapse(),
+                        KeyCode::Down => app.messages.next(),
+                        KeyCode::Up => app.messages.previous(),
+                        KeyCode::Char('j') => app.messages.next(),
+                        KeyCode::Char('k') => app.messages.previous(),
+                        KeyCode::Char('o') => app.open_selected_urls(),
+                        KeyCode::Char('R') => app.reply_to_selected(),
+                        KeyCode::Char('e') => app.edit_last(),
+                        KeyCode::Char('f') => app.toggle_follow(),
+                        KeyCode::Char('c') => app.compact = !app.compact,
+                        KeyCode::Char('p') => app.show_peer_ids = !app.show_peer_ids,
+                        KeyCode::Char('h') => app.show_health = !app.show_health,
+                        KeyCode::Char('l') => {
+                            app.show_events = true;
+                            app.events_scroll = 0;
+                        }
+                        KeyCode::Char('?') => app.show_help = true,
+                        KeyCode::Char('+') => app.resize_input(5),
+                        KeyCode::Char('-') => app.resize_input(-5),
+                        KeyCode::Char('r') => {
+                            if let Some(message) = app.selected_failed() {
+                                let id = message.id;
+                                app.set_delivery(id, Delivery::Pending);
+                                if let Err(e) = tx1.try_send(Command::Publish(message)) {
+                                    app.command_refused(e, Some(id));
+                                }
+                            }
+                        }
+                        KeyCode::Tab => {
+                            let room = (app.active + 1) % app.rooms.len().max(1);
+                            switch_room(&mut app, &tx1, room);
+                        }
+                        KeyCode::BackTab => {
+                            let len = app.rooms.len().max(1);
+                            switch_room(&mut app, &tx1, (app.active + len - 1) % len);
+                        }
+                        KeyCode::Char(c @ '1'..='9') => switch_room(&mut app, &tx1, c as usize - '1' as usize),
+                        KeyCode::Home => app.messages.home(),
+                        KeyCode::End | KeyCode::Char('G') => app.messages.end(),
+                        KeyCode::Char('u') => app.first_unseen(),
                         _ => {}
                     },
                     InputMode::Editing => match key.code {
+                        KeyCode::Enter if app.input.trim().is_empty() => {
+                            app.input.clear();
+                            app.flash("nothing to send");
+                        }
+                        KeyCode::Enter if app.input.trim() == "/peers" => {
+                            app.input.clear();
+                            if let Err(e) = tx1.try_send(Command::Peers) {
+                                app.command_refused(e, None);
+                            }
+                        }
+                        KeyCode::Enter if app.input.trim() == "/address" => {
+                            app.input.clear();
+                            if let Err(e) = tx1.try_send(Command::Address) {
+                                app.command_refused(e, None);
+                            }
+                        }
+                        KeyCode::Enter if app.input.trim() == "/seen" => {
+                            app.input.clear();
+                            app.report_seen();
+                        }
+                        KeyCode::Enter if app.input.trim() == "/verify" || app.input.trim_start().starts_with("/verify ") => {
+                            let input: String = app.input.drain(..).collect();
+                            let name = input.trim().trim_start_matches("/verify").trim().to_string();
+                            app.verify(&name);
+                        }
+                        KeyCode::Enter if app.input.trim() == "/whois" => {
+                            app.input.clear();
+                            app.whois_selected();
+                        }
+                        KeyCode::Enter if matches!(app.input.trim(), "/mute" | "/unmute") => {
+                            let muted = app.input.trim() == "/mute";
+                            app.input.clear();
+                            app.set_muted(muted);
+                        }
+                        KeyCode::Enter if app.input.trim() == "/stats" => {
+                            app.input.clear();
+                            if let Err(e) = tx1.try_send(Command::Stats) {
+                                app.command_refused(e, None);
+                            }
+                        }
+                        KeyCode::Enter if app.input.trim() == "/export" || app.input.trim_start().starts_with("/export ") => {
+                            let input: String = app.input.drain(..).collect();
+                            match input.trim().strip_prefix("/export").map(str::trim) {
+                                Some(path) if !path.is_empty() => app.export(path),
+                                _ => app.push(ChatMessage::system(
+                                    "usage: /export <file>, .md for markdown, .json for JSON, plain text otherwise".to_string(),
+                                )),
+                            }
+                        }
+                        KeyCode::Enter if app.input.trim() == "/upload" || app.input.trim_start().starts_with("/upload ") => {
+                            let input: String = app.input.drain(..).collect();
+                            match input.trim().strip_prefix("/upload").map(str::trim) {
+                                Some(path) if !path.is_empty() => app.upload(transcript::expand_home(path)),
+                                _ => app.push(ChatMessage::system("usage: /upload <file>".to_string())),
+                            }
+                        }
+                        KeyCode::Enter if app.input.trim_start().starts_with("/msg ") => {
+                            let input: String = app.input.drain(..).collect();
+                            let mut args = input.trim_start()["/msg ".len()..].trim_start().splitn(2, ' ');
+                            let to = args.next().unwrap_or_default().to_string();
+                            let mut body = args.next().unwrap_or_default().trim().to_string();
+                            match app.peer_by_name(&to) {
+                                _ if body.is_empty() => app.push(ChatMessage::system("usage: /msg <name or peer id> <text>".to_string())),
+                                Ok(peer) => {
+                                    if app.expand_emoji {
+                                        body = emoji::expand(&body);
+                                    }
+                                    let mut message = ChatMessage::local(name, body);
+                                    message.private = true;
+                                    message.recipient = Some(to);
+                                    let id = message.id;
+                                    app.push(message.clone());
+                                    if let Err(e) = tx1.try_send(Command::Whisper(message, peer)) {
+                                        app.command_refused(e, Some(id));
+                                    }
+                                }
+                                Err(e) => app.push(ChatMessage::system(e)),
+                            }
+                        }
+                        KeyCode::Enter if matches!(app.input.trim(), "/direct" | "/relay" | "/auto") => {
+                            let preference = match app.input.trim() {
+                                "/direct" => Preference::Direct,
+                                "/relay" => Preference::Relay,
+                                _ => Preference::Auto,
+                            };
+                            app.input.clear();
+                            if let Err(e) = tx1.try_send(Command::Path(preference)) {
+                                app.command_refused(e, None);
+                            }
+                        }
+                        KeyCode::Enter if app.input.trim() == "/delete" => {
+                            app.input.clear();
+                            match app.selected_own() {
+                                Some(id) => {
+                                    app.apply_delete(id, None);
+                                    if let Err(e) = tx1.try_send(Command::Delete(id)) {
+                                        app.command_refused(e, None);
+                                    }
+                                }
+                                None => app.push(ChatMessage::system(
+                                    "select one of your messages to delete it".to_string(),
+                                )),
+                            }
+                        }
+                        KeyCode::Enter if app.amending.is_some() => {
+                            let id = app.amending.take().unwrap();
+                            let mut body: String = app.input.drain(..).collect();
+                            if app.expand_emoji {
+                                body = emoji::expand(&body);
+                            }
+                            if body.trim().is_empty() {
+                                app.push(ChatMessage::system("edit discarded, use /delete to remove a message".to_string()));
+                            } else {
+                                app.apply_edit(id, None, body.clone());
+                                if let Err(e) = tx1.try_send(Command::Edit(id, body)) {
+                                    app.command_refused(e, None);
+                                }
+                            }
+                        }
                         KeyCode::Enter => {
-                            tx1.send(format!("{},{}",  (*lock).input.clone(), name)).await.unwrap();
-                            let s = format!("{} {} - {}", 
-                                *name, 
-                                Local::now().format("%H:%M:%S").to_string(), 
-                                (*lock).input.drain(..).collect::<String>());
-                            (*lock).messages.items.push(s);
-                            let len = (*lock).messages.items.len() - 1;
-                            (*lock).messages.state.select(Some(len));
+                            let body: String = app.input.drain(..).collect();
+                            let quote = app.replying.take();
+                            send(&mut app, &tx1, name, body, quote);
+                        }
+                        KeyCode::Char('e') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                            app.input_echo = app.input_echo.next();
+                            app.flash(&format!("input echo {:?}", app.input_echo).to_lowercase());
                         }
+                        KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => app.discard_draft(),
                         KeyCode::Char(c) => {
-                            (*lock).input.push(c);
+                            app.input.push(c);
                         }
                         KeyCode::Backspace => {
-                            (*lock).input.pop();
+                            app.input.pop();
                         }
                         KeyCode::Esc => {
-                            (*lock).input_mode = InputMode::Normal;
+                            app.input_mode = InputMode::Normal;
                         }
                         _ => {}
                     },
                 }
+                app.mark_seen();
             }
         } 
     }
-}
\ No newline at end of file
+}
+
+/// With `--enter newline`, turn Enter into a line break and the send chords into the Enter
+/// the editing keys send on. Ctrl+Enter only arrives from terminals that report it apart from
+/// Enter, so Alt+Enter and Ctrl+S are the ones to rely on
+fn enter_key(enter: EnterKey, key: KeyEvent) -> KeyEvent {
+    let send = KeyEvent::new(KeyCode::Enter, KeyModifiers::NONE);
+    match (enter, key.code) {
+        (EnterKey::Send, _) => key,
+        (EnterKey::Newline, KeyCode::Enter) if key.modifiers.intersects(KeyModifiers::ALT | KeyModifiers::CONTROL) => send,
+        (EnterKey::Newline, KeyCode::Enter) => KeyEvent::new(KeyCode::Char('\n'), KeyModifiers::NONE),
+        (EnterKey::Newline, KeyCode::Char('s')) if key.modifiers.contains(KeyModifiers::CONTROL) => send,
+        _ => key,
+    }
+}
+
+/// Publish `body` as one of our messages, the way Enter does for the draft
+fn send(app: &mut App, tx1: &Sender<Command>, name: &str, mut body: String, reply_to: Option<Quote>) {
+    if app.expand_emoji {
+        body = emoji::expand(&body);
+    }
+    let mut message = ChatMessage::local(name, body);
+    message.reply_to = reply_to;
+    let id = message.id;
+    app.push(message.clone());
+    if let Err(e) = tx1.try_send(Command::Publish(message)) {
+        app.command_refused(e, Some(id));
+    }
+}
+
+/// Show another room once the network task knows to publish there, a busy or closed
+/// queue leaves the current one shown
+fn switch_room(app: &mut App, tx1: &Sender<Command>, room: usize) {
+    if room == app.active || room >= app.rooms.len() {
+        return;
+    }
+    match tx1.try_send(Command::Room(room)) {
+        Ok(()) => app.switch_room(room),
+        Err(e) => app.command_refused(e, None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(code: KeyCode, modifiers: KeyModifiers) -> KeyEvent {
+        KeyEvent::new(code, modifiers)
+    }
+
+    #[test]
+    fn enter_sends_by_default() {
+        for pressed in [
+            key(KeyCode::Enter, KeyModifiers::NONE),
+            key(KeyCode::Enter, KeyModifiers::ALT),
+            key(KeyCode::Char('s'), KeyModifiers::CONTROL),
+        ] {
+            assert_eq!(enter_key(EnterKey::Send, pressed), pressed);
+        }
+    }
+
+    #[test]
+    fn enter_starts_a_new_line_and_the_chords_send() {
+        let send = key(KeyCode::Enter, KeyModifiers::NONE);
+        let newline = key(KeyCode::Char('\n'), KeyModifiers::NONE);
+        assert_eq!(enter_key(EnterKey::Newline, key(KeyCode::Enter, KeyModifiers::NONE)), newline);
+        assert_eq!(enter_key(EnterKey::Newline, key(KeyCode::Enter, KeyModifiers::ALT)), send);
+        assert_eq!(enter_key(EnterKey::Newline, key(KeyCode::Enter, KeyModifiers::CONTROL)), send);
+        assert_eq!(enter_key(EnterKey::Newline, key(KeyCode::Char('s'), KeyModifiers::CONTROL)), send);
+    }
+
+    #[test]
+    fn other_keys_pass_through() {
+        let typed = key(KeyCode::Char('s'), KeyModifiers::NONE);
+        assert_eq!(enter_key(EnterKey::Newline, typed), typed);
+        let escape = key(KeyCode::Esc, KeyModifiers::NONE);
+        assert_eq!(enter_key(EnterKey::Newline, escape), escape);
+    }
+}
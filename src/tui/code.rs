@@ -0,0 +1,248 @@
+//! Fenced code blocks in message bodies: the lines between a line starting with ```` ``` ````,
+//! optionally followed by a language, and a line that is only ```` ``` ````. The body goes over
+//! the wire as typed, this only splits it for display
+
+/// A run of a message body, in order
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Block<'a> {
+    /// Lines rendered as usual, with inline markup
+    Text(&'a str),
+    /// The lines of a fenced block, without the fences
+    Code { language: Option<&'a str>, lines: Vec<&'a str> },
+}
+
+/// Split `body` into text and code blocks. A fence that is never closed stays text, so a stray
+/// ```` ``` ```` doesn't swallow the rest of the message
+pub fn blocks(body: &str) -> Vec<Block<'_>> {
+    // every line with its byte offset in `body`
+    let mut lines = Vec::new();
+    let mut start = 0;
+    for line in body.split('\n') {
+        lines.push((start, line));
+        start += line.len() + 1;
+    }
+    let text = |from: usize, to: usize| {
+        let (first, _) = lines[from];
+        let (last, line) = lines[to - 1];
+        Block::Text(&body[first..last + line.len()])
+    };
+
+    let mut blocks = Vec::new();
+    let mut text_from = 0;
+    let mut i = 0;
+    while i < lines.len() {
+        let opening = lines[i].1.trim_start().strip_prefix("```");
+        let closing = (i + 1..lines.len()).find(|&j| lines[j].1.trim() == "```");
+        match (opening, closing) {
+            (Some(info), Some(close)) => {
+                if text_from < i {
+                    blocks.push(text(text_from, i));
+                }
+                let language = info.split_whitespace().next().filter(|language| !language.contains('`'));
+                let code = lines[i + 1..close].iter().map(|(_, line)| line.trim_end_matches('\r')).collect();
+                blocks.push(Block::Code { language, lines: code });
+                i = close + 1;
+                text_from = i;
+            }
+            _ => i += 1,
+        }
+    }
+    if text_from < lines.len() {
+        blocks.push(text(text_from, lines.len()));
+    }
+    blocks
+}
+
+/// What a piece of a code line is, for highlighting
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Token {
+    Plain,
+    Keyword,
+    String,
+    Comment,
+}
+
+/// The keywords and line comment marker of a language tag, none for languages not known here
+fn syntax(language: &str) -> Option<(&'static [&'static str], Option<&'static str>)> {
+    Some(match language.to_ascii_lowercase().as_str() {
+        "rust" | "rs" => (
+            &[
+                "as", "async", "await", "break", "const", "continue", "crate", "dyn", "else", "enum", "false", "fn",
+                "for", "if", "impl", "in", "let", "loop", "match", "mod", "move", "mut", "pub", "ref", "return",
+                "self", "Self", "static", "struct", "super", "trait", "true", "type", "unsafe", "use", "where",
+                "while",
+            ],
+            Some("//"),
+        ),
+        "python" | "py" => (
+            &[
+                "and", "as", "async", "await", "break", "class", "continue", "def", "elif", "else", "except",
+                "False", "finally", "for", "from", "if", "import", "in", "is", "lambda", "None", "not", "or",
+                "pass", "raise", "return", "True", "try", "while", "with", "yield",
+            ],
+            Some("#"),
+        ),
+        "javascript" | "js" | "typescript" | "ts" => (
+            &[
+                "async", "await", "break", "case", "class", "const", "continue", "default", "else", "export",
+                "false", "for", "from", "function", "if", "import", "let", "new", "null", "return", "switch",
+                "this", "throw", "true", "try", "catch", "typeof", "undefined", "var", "while",
+            ],
+            Some("//"),
+        ),
+        "go" => (
+            &[
+                "break", "case", "chan", "const", "continue", "defer", "else", "false", "for", "func", "go", "if",
+                "import", "interface", "map", "nil", "package", "range", "return", "select", "struct", "switch",
+                "true", "type", "var",
+            ],
+            Some("//"),
+        ),
+        "c" | "cpp" | "c++" | "java" => (
+            &[
+                "break", "case", "char", "class", "const", "continue", "else", "false", "for", "if", "int", "long",
+                "new", "null", "nullptr", "private", "public", "return", "static", "struct", "switch", "true",
+                "void", "while",
+            ],
+            Some("//"),
+        ),
+        "sh" | "bash" | "shell" | "zsh" => (
+            &[
+                "case", "do", "done", "elif", "else", "esac", "export", "fi", "for", "function", "if", "in",
+                "local", "return", "then", "while",
+            ],
+            Some("#"),
+        ),
+        "toml" | "yaml" | "yml" => (&[], Some("#")),
+        "json" => (&["false", "null", "true"], None),
+        _ => return None,
+    })
+}
+
+/// Split a line of code in `language` into highlighted pieces: keywords, string literals and
+/// line comments. Comments spanning lines aren't followed. A language not known here, or none,
+/// leaves the line in one plain piece
+pub fn highlight<'a>(language: Option<&str>, line: &'a str) -> Vec<(Token, &'a str)> {
+    let (keywords, comment) = match language.and_then(syntax) {
+        Some(syntax) => syntax,
+        None => return vec![(Token::Plain, line)],
+    };
+    let mut pieces = Vec::new();
+    let mut plain_from = 0;
+    let mut chars = line.char_indices().peekable();
+    while let Some((at, c)) = chars.next() {
+        let (token, end) = if comment.map_or(false, |marker| line[at..].starts_with(marker)) {
+            (Token::Comment, line.len())
+        } else if c == '"' || (c == '\'' && comment == Some("#")) {
+            let mut end = line.len();
+            let mut escaped = false;
+            for (i, next) in line[at + 1..].char_indices() {
+                match next {
+                    '\\' if !escaped => escaped = true,
+                    next if next == c && !escaped => {
+                        end = at + 1 + i + 1;
+                        break;
+                    }
+                    _ => escaped = false,
+                }
+            }
+            (Token::String, end)
+        } else if c.is_alphabetic() || c == '_' {
+            let end = line[at..]
+                .find(|c: char| !c.is_alphanumeric() && c != '_')
+                .map_or(line.len(), |offset| at + offset);
+            let token = if keywords.contains(&&line[at..end]) { Token::Keyword } else { Token::Plain };
+            (token, end)
+        } else {
+            continue;
+        };
+        if token != Token::Plain {
+            if plain_from < at {
+                pieces.push((Token::Plain, &line[plain_from..at]));
+            }
+            pieces.push((token, &line[at..end]));
+            plain_from = end;
+        }
+        while chars.peek().map_or(false, |(i, _)| *i < end) {
+            chars.next();
+        }
+    }
+    if plain_from < line.len() {
+        pieces.push((Token::Plain, &line[plain_from..]));
+    }
+    pieces
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_text_and_code() {
+        let body = "look:\n```rust\nfn main() {}\n```\nneat";
+        assert_eq!(
+            blocks(body),
+            [
+                Block::Text("look:"),
+                Block::Code { language: Some("rust"), lines: vec!["fn main() {}"] },
+                Block::Text("neat"),
+            ]
+        );
+    }
+
+    #[test]
+    fn a_fence_without_language() {
+        assert_eq!(blocks("```\nls -la\r\n```"), [Block::Code { language: None, lines: vec!["ls -la"] }]);
+        assert_eq!(blocks("```\n```"), [Block::Code { language: None, lines: vec![] }]);
+    }
+
+    #[test]
+    fn an_unclosed_fence_stays_text() {
+        assert_eq!(blocks("a ``` b"), [Block::Text("a ``` b")]);
+        assert_eq!(blocks("```rust\nfn main() {}"), [Block::Text("```rust\nfn main() {}")]);
+        // inline code isn't a language
+        assert_eq!(blocks("```x```\ny\n```"), [Block::Code { language: None, lines: vec!["y"] }]);
+    }
+
+    #[test]
+    fn plain_text_is_one_block() {
+        assert_eq!(blocks("one\ntwo"), [Block::Text("one\ntwo")]);
+        assert_eq!(blocks(""), [Block::Text("")]);
+    }
+
+    #[test]
+    fn highlights_keywords_strings_and_comments() {
+        assert_eq!(
+            highlight(Some("rust"), r#"let s = "a \" b"; // done"#),
+            [
+                (Token::Keyword, "let"),
+                (Token::Plain, " s = "),
+                (Token::String, r#""a \" b""#),
+                (Token::Plain, "; "),
+                (Token::Comment, "// done"),
+            ]
+        );
+        // part of a longer word isn't a keyword
+        assert_eq!(highlight(Some("rust"), "letter"), [(Token::Plain, "letter")]);
+    }
+
+    #[test]
+    fn single_quotes_are_strings_where_hash_comments() {
+        assert_eq!(
+            highlight(Some("PY"), "x = 'a' # c"),
+            [(Token::Plain, "x = "), (Token::String, "'a'"), (Token::Plain, " "), (Token::Comment, "# c")]
+        );
+        assert_eq!(highlight(Some("rust"), "'a'"), [(Token::Plain, "'a'")]);
+    }
+
+    #[test]
+    fn an_unclosed_string_runs_to_the_end() {
+        assert_eq!(highlight(Some("js"), r#"f("oops"#), [(Token::Plain, "f("), (Token::String, r#""oops"#)]);
+    }
+
+    #[test]
+    fn unknown_languages_stay_plain() {
+        assert_eq!(highlight(Some("cobol"), "let x = 1"), [(Token::Plain, "let x = 1")]);
+        assert_eq!(highlight(None, "let x = 1"), [(Token::Plain, "let x = 1")]);
+    }
+}
@@ -0,0 +1,99 @@
+/// Shortcodes expanded on send, sorted by name for the binary search in `lookup`
+const SHORTCODES: &[(&str, &str)] = &[
+    ("+1", "👍"),
+    ("-1", "👎"),
+    ("100", "💯"),
+    ("angry", "😠"),
+    ("blush", "😊"),
+    ("broken_heart", "💔"),
+    ("bug", "🐛"),
+    ("check", "✅"),
+    ("clap", "👏"),
+    ("coffee", "☕"),
+    ("cool", "😎"),
+    ("cry", "😢"),
+    ("eyes", "👀"),
+    ("fire", "🔥"),
+    ("grin", "😁"),
+    ("heart", "❤️"),
+    ("joy", "😂"),
+    ("laughing", "😆"),
+    ("ok_hand", "👌"),
+    ("party", "🎉"),
+    ("pray", "🙏"),
+    ("rocket", "🚀"),
+    ("sad", "😞"),
+    ("see_no_evil", "🙈"),
+    ("shrug", "🤷"),
+    ("smile", "😄"),
+    ("sob", "😭"),
+    ("sparkles", "✨"),
+    ("star", "⭐"),
+    ("sunglasses", "😎"),
+    ("tada", "🎉"),
+    ("thinking", "🤔"),
+    ("thumbsdown", "👎"),
+    ("thumbsup", "👍"),
+    ("wave", "👋"),
+    ("wink", "😉"),
+    ("x", "❌"),
+];
+
+fn lookup(name: &str) -> Option<&'static str> {
+    SHORTCODES
+        .binary_search_by(|(code, _)| (*code).cmp(name))
+        .ok()
+        .map(|i| SHORTCODES[i].1)
+}
+
+/// Replace known `:shortcode:`s in `text` with their emoji, anything else is kept as typed
+pub fn expand(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find(':') {
+        out.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        let emoji = after.find(':').and_then(|end| Some((lookup(&after[..end])?, end)));
+        match emoji {
+            Some((emoji, end)) => {
+                out.push_str(emoji);
+                rest = &after[end + 1..];
+            }
+            // the closing colon may open the next shortcode, as in `10:30 :wave:`
+            None => {
+                out.push(':');
+                rest = after;
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shortcodes_are_sorted_for_lookup() {
+        assert!(SHORTCODES.windows(2).all(|pair| pair[0].0 < pair[1].0));
+        assert!(SHORTCODES.iter().all(|(code, emoji)| lookup(code) == Some(*emoji)));
+    }
+
+    #[test]
+    fn expands_known_shortcodes() {
+        assert_eq!(expand(":wave: hi :tada:"), "👋 hi 🎉");
+        assert_eq!(expand("ship it:rocket:"), "ship it🚀");
+    }
+
+    #[test]
+    fn keeps_anything_else_as_typed() {
+        assert_eq!(expand(":nope: and :wave"), ":nope: and :wave");
+        assert_eq!(expand("a: b"), "a: b");
+    }
+
+    #[test]
+    fn a_closing_colon_may_open_the_next_shortcode() {
+        assert_eq!(expand("10:30 :wave:"), "10:30 👋");
+    }
+}
@@ -0,0 +1,82 @@
+use std::ops::Range;
+
+/// Characters that end a sentence rather than a URL when they trail it
+const TRAILING: &[char] = &['.', ',', ';', ':', '!', '?', '\'', '"', ')', ']', '>'];
+
+/// Byte ranges of the http(s) URLs in `text`. Only whitespace separated words starting
+/// with a scheme count, and trailing punctuation is left out unless it closes a parenthesis
+/// opened inside the URL, as in `https://en.wikipedia.org/wiki/Rust_(programming_language)`
+pub fn find_urls(text: &str) -> Vec<Range<usize>> {
+    let mut urls = Vec::new();
+    let mut offset = 0;
+    for word in text.split_inclusive(char::is_whitespace) {
+        let start = offset;
+        offset += word.len();
+
+        let word = word.trim_end();
+        let scheme = if word.starts_with("https://") {
+            "https://".len()
+        } else if word.starts_with("http://") {
+            "http://".len()
+        } else {
+            continue;
+        };
+
+        let mut url = word;
+        while let Some(c) = url.chars().last() {
+            let balanced = c == ')' && url.matches('(').count() >= url.matches(')').count();
+            if !TRAILING.contains(&c) || balanced {
+                break;
+            }
+            url = &url[..url.len() - c.len_utf8()];
+        }
+        if url.len() > scheme {
+            urls.push(start..start + url.len());
+        }
+    }
+    urls
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn links(text: &str) -> Vec<&str> {
+        find_urls(text).into_iter().map(|range| &text[range]).collect()
+    }
+
+    #[test]
+    fn leaves_trailing_punctuation_out() {
+        assert_eq!(links("see https://example.com."), ["https://example.com"]);
+        assert_eq!(links("https://a.com, http://b.com/x?y=1!"), ["https://a.com", "http://b.com/x?y=1"]);
+        assert_eq!(links("(or https://example.com/docs)"), ["https://example.com/docs"]);
+        assert_eq!(links("https://example.com/path).\""), ["https://example.com/path"]);
+    }
+
+    #[test]
+    fn keeps_parentheses_opened_inside_the_url() {
+        let url = "https://en.wikipedia.org/wiki/Rust_(programming_language)";
+        assert_eq!(links(url), [url]);
+        assert_eq!(links(&format!("read {}.", url)), [url]);
+        assert_eq!(links(&format!("{})", url)), [url]);
+    }
+
+    #[test]
+    fn a_bare_scheme_is_no_link() {
+        assert!(links("https://").is_empty());
+        assert!(links("http://.").is_empty());
+        assert!(links("type https:// then the host").is_empty());
+    }
+
+    #[test]
+    fn needs_a_scheme_at_the_start_of_a_word() {
+        assert!(links("example.com www.example.com ftp://example.com").is_empty());
+        assert!(links("xhttps://example.com").is_empty());
+    }
+
+    #[test]
+    fn ranges_are_byte_offsets() {
+        let text = "héllo\twörld https://example.com/ü";
+        assert_eq!(find_urls(text), [text.find("https").unwrap()..text.len()]);
+    }
+}
@@ -0,0 +1,110 @@
+/// Inline formatting of a piece of message text
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    Plain,
+    /// `*bold*`
+    Bold,
+    /// `_italic_`
+    Italic,
+    /// `` `code` ``
+    Code,
+}
+
+fn format_of(marker: u8) -> Option<Format> {
+    match marker {
+        b'*' => Some(Format::Bold),
+        b'_' => Some(Format::Italic),
+        b'`' => Some(Format::Code),
+        _ => None,
+    }
+}
+
+/// Split `text` into formatted pieces, dropping the markers. A marker only opens at a word
+/// start before a non-space and only closes at a word end after one, so `2 * 3 * 4`,
+/// `snake_case_name` and a lone `*` stay plain. Code spans take their content literally
+pub fn parse(text: &str) -> Vec<(Format, &str)> {
+    let bytes = text.as_bytes();
+    let mut pieces = Vec::new();
+    let mut plain_start = 0;
+    let mut i = 0;
+    // markers are ASCII, so byte offsets at them are always char boundaries
+    while i < bytes.len() {
+        let format = match format_of(bytes[i]) {
+            Some(format) if opens(text, i) => format,
+            _ => {
+                i += 1;
+                continue;
+            }
+        };
+        match closing(text, i, format) {
+            Some(end) => {
+                if plain_start < i {
+                    pieces.push((Format::Plain, &text[plain_start..i]));
+                }
+                pieces.push((format, &text[i + 1..end]));
+                i = end + 1;
+                plain_start = i;
+            }
+            None => i += 1,
+        }
+    }
+    if plain_start < text.len() {
+        pieces.push((Format::Plain, &text[plain_start..]));
+    }
+    pieces
+}
+
+fn opens(text: &str, at: usize) -> bool {
+    let before = text[..at].chars().next_back();
+    let after = text[at + 1..].chars().next();
+    !before.map_or(false, char::is_alphanumeric)
+        && after.map_or(false, |c| !c.is_whitespace() && c != text.as_bytes()[at] as char)
+}
+
+fn closing(text: &str, open: usize, format: Format) -> Option<usize> {
+    let marker = text.as_bytes()[open];
+    let mut from = open + 1;
+    while let Some(offset) = text[from..].find(marker as char) {
+        let at = from + offset;
+        let before = text[..at].chars().next_back();
+        let after = text[at + 1..].chars().next();
+        let content = at > open + 1;
+        if content && format == Format::Code {
+            return Some(at);
+        }
+        if content
+            && !before.map_or(true, char::is_whitespace)
+            && !after.map_or(false, char::is_alphanumeric)
+        {
+            return Some(at);
+        }
+        from = at + 1;
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_marked_words() {
+        assert_eq!(parse("a *b* c"), [(Format::Plain, "a "), (Format::Bold, "b"), (Format::Plain, " c")]);
+        assert_eq!(
+            parse("_it_ and *bold text*"),
+            [(Format::Italic, "it"), (Format::Plain, " and "), (Format::Bold, "bold text")]
+        );
+    }
+
+    #[test]
+    fn code_is_taken_literally() {
+        assert_eq!(parse("`*not bold*`"), [(Format::Code, "*not bold*")]);
+    }
+
+    #[test]
+    fn stray_markers_stay_plain() {
+        for text in ["2 * 3 * 4", "snake_case_name", "*", "**", "*open"] {
+            assert_eq!(parse(text), [(Format::Plain, text)], "{}", text);
+        }
+    }
+}
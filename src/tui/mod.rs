@@ -1,19 +1,61 @@
 pub mod app;
+pub mod clipboard;
+pub mod code;
+pub mod emoji;
+pub mod links;
+pub mod markup;
+pub mod spell;
+pub mod theme;
 pub mod ui;
 
 use crossterm::{
-    event::{DisableMouseCapture, EnableMouseCapture},
+    event::{DisableBracketedPaste, DisableMouseCapture, EnableBracketedPaste, EnableMouseCapture},
     execute,
+    tty::IsTty,
     terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
 };
-use tokio::sync::mpsc::{Sender, Receiver};
-use std::{io, error::Error};
+use tokio::sync::mpsc::{self, error::TrySendError, Sender, Receiver};
+use crate::config::{Config, EnterKey, InputEcho, StartAt, DEFAULT_RELAY_NOTICE, INPUT_HEIGHT};
+use spell::Dictionary;
+use theme::Theme;
+use crate::history::{History, Window};
+use crate::transcript;
+use crate::upload::{self, UploadEvent, Uploader};
+use crate::webhook::Webhook;
+use crate::message::{ChatMessage, Delivery, Kind, Quote};
+use crate::network::path::{Path, PathStatus};
+use crate::network::health::Health;
+use crate::network::relay_link::RelayStatus;
+use crate::network::relay_pick;
+use crate::known_peers::Pin;
+use crate::network::secure::{generate_ed25519, Secret};
+use crate::network::{Command, Update};
+use chrono::{DateTime, Local};
+use libp2p::PeerId;
+use log::warn;
+use std::{io::{self, Write}, collections::{BTreeMap, HashMap, HashSet, VecDeque}, error::Error, ops::RangeInclusive, path::PathBuf, time::{Duration, Instant}};
 use tui::{
     backend::CrosstermBackend,
 
     Terminal, widgets::ListState,
 };
 
+/// Pastes above either limit get a notice asking to review the draft
+const PASTE_WARN_LINES: usize = 10;
+const PASTE_WARN_BYTES: usize = 2000;
+/// How long `App::flash` hints stay in the status line
+const FLASH_FOR: Duration = Duration::from_secs(3);
+/// How long a new path to the peer has to hold before it is announced, so a brief
+/// reconnection doesn't fill the list
+const PRESENCE_DEBOUNCE: Duration = Duration::from_secs(3);
+/// Connection events the `l` log keeps, the oldest go first
+const EVENT_LOG_LEN: usize = 500;
+
+/// Normal --i--> Editing: the draft in `App.input` is kept, the cursor goes to its end
+/// Editing --Esc--> Normal: the draft is kept for later
+/// Editing --Ctrl+C--> Editing: the draft is discarded
+/// Editing --Enter--> Editing: the draft is sent and cleared
+/// Normal or Editing --paste--> Editing: the pasted text is appended to the draft
 pub enum InputMode {
     Normal,
     Editing,
@@ -22,6 +64,8 @@ pub enum InputMode {
 pub struct StatefulList<T> {
     pub state: ListState,
     pub items: Vec<T>,
+    /// Where a range selected with Shift started, the selection is its other end
+    anchor: Option<usize>,
 }
 
 impl<T> StatefulList<T> {
@@ -29,7 +73,37 @@ impl<T> StatefulList<T> {
         StatefulList {
             state: ListState::default(),
             items,
+            anchor: None,
+        }
+    }
+
+    /// The selected items, from the anchor to the selection when a range is being selected
+    pub fn selection(&self) -> Option<RangeInclusive<usize>> {
+        let selected = self.state.selected().filter(|i| *i < self.items.len())?;
+        let anchor = self.anchor.filter(|i| *i < self.items.len()).unwrap_or(selected);
+        Some(anchor.min(selected)..=anchor.max(selected))
+    }
+
+    /// The range being selected, none while only one item is
+    pub fn range(&self) -> Option<RangeInclusive<usize>> {
+        self.anchor?;
+        self.selection()
+    }
+
+    /// Grow or shrink the range from the selection, one item down or up
+    fn extend(&mut self, down: bool) {
+        let anchor = self.anchor.or(self.state.selected());
+        if down {
+            self.next();
+        } else {
+            self.previous();
         }
+        self.anchor = anchor;
+    }
+
+    /// Back to selecting a single item
+    fn collapse(&mut self) {
+        self.anchor = None;
     }
 
     fn next(&mut self) {
@@ -43,6 +117,7 @@ impl<T> StatefulList<T> {
             }
             None => 0,
         };
+        self.anchor = None;
         self.state.select(Some(i));
     }
 
@@ -57,22 +132,62 @@ impl<T> StatefulList<T> {
             }
             None => 0,
         };
+        self.anchor = None;
         self.state.select(Some(i));
     }
 
     fn home(&mut self) {
+        self.anchor = None;
         self.state.select(Some(0));
     }
 
     fn end(&mut self) {
-        self.state.select(Some(self.items.len() - 1));
+        self.anchor = None;
+        self.state.select(self.items.len().checked_sub(1));
     }
 
     fn unselect(&mut self) {
+        self.anchor = None;
         self.state.select(None);
     }
 }
 
+/// A name that came with a key other than the one pinned for it, see `App::check_key`
+pub struct KeyChange {
+    pub name: String,
+    /// Base58 PeerIds, the pinned one and the one messages now come from
+    pub old: String,
+    pub new: String,
+}
+
+/// A topic of a channel session, shown as a tab. The messages of the active room live in
+/// `App` itself, its slot here holds them while another room is active
+pub struct Room {
+    pub topic: String,
+    /// Messages that arrived while another room was active
+    pub unread: usize,
+    /// Set with `/mute`, the room then counts no unread messages and rings no bell
+    pub muted: bool,
+    messages: StatefulList<ChatMessage>,
+    unseen: usize,
+    follow_tail: bool,
+    history: Option<History>,
+}
+
+impl Room {
+    fn new(topic: &str) -> Room {
+        Room {
+            topic: topic.to_string(),
+            unread: 0,
+            muted: false,
+            messages: StatefulList::with_items(Vec::new()),
+            unseen: 0,
+            follow_tail: true,
+            history: None,
+        }
+    }
+}
+
 /// App holds the state of the application
 pub struct App {
     /// Current value of the input box
@@ -80,9 +195,120 @@ pub struct App {
     /// Current input mode
     pub input_mode: InputMode,
     /// History of recorded messages
-    pub messages: StatefulList<String>,
+    pub messages: StatefulList<ChatMessage>,
     /// History of recorded messages
     pub remote_messages: String,
+    /// Where messages are persisted, if enabled
+    pub history: Option<History>,
+    /// The topics of the session, one tab each, empty when viewing a transcript
+    pub rooms: Vec<Room>,
+    /// Index of the room shown
+    pub active: usize,
+    /// Whether we left the topic after `--idle-timeout`
+    pub away: bool,
+    /// The message the draft answers, sent along with it
+    pub replying: Option<Quote>,
+    /// Id of our message the draft replaces, set by `e`
+    pub amending: Option<u64>,
+    /// How a dm session reaches its peer, unknown until the network task reports it
+    pub path: Option<PathStatus>,
+    /// Where a listening dm session is dialed, shown in the status line to copy
+    pub address: Option<String>,
+    /// The relay picked from `--relays` and how fast it answered, for the status line
+    pub relay_choice: Option<String>,
+    /// The connection to the relay, shown in the status line while it is down
+    pub relay: RelayStatus,
+    /// The last network health report, for the sidebar
+    pub health: Option<Health>,
+    /// The health sidebar is showing, switched with `h`
+    pub show_health: bool,
+    /// The newest `EVENT_LOG_LEN` connection events, oldest first
+    pub events: VecDeque<(DateTime<Local>, String)>,
+    /// The event log overlay is showing, switched with `l`
+    pub show_events: bool,
+    /// How many lines the event log is scrolled up from the newest event
+    pub events_scroll: usize,
+    /// Whether the peer connecting, switching path and disconnecting is noted in the list
+    pub presence: bool,
+    /// Whether those notes ring the terminal bell
+    pub presence_bell: bool,
+    /// The path the last note was about
+    announced: Option<Path>,
+    /// A path that differs from `announced` and since when, noted once it holds
+    pending: Option<(Option<Path>, Instant)>,
+    /// Our own name, a peer going by it too is told apart like any other collision
+    pub name: String,
+    /// When each peer, by the name shown, last sent a message
+    last_seen: HashMap<String, DateTime<Local>>,
+    /// When the dm peer's path last went away, shown while it is gone
+    pub peer_seen: Option<DateTime<Local>>,
+    /// Highest sequence number seen per room, author and sender session, to notice lost messages
+    sequences: HashMap<(usize, String, u32), u64>,
+    /// The PeerIds that sent messages under each name
+    roster: HashMap<String, HashSet<String>>,
+    /// The key pinned for each name, from `known_peers_file`
+    known_peers: BTreeMap<String, Pin>,
+    /// Where new pins are saved, `~/.tochat/known_peers.json`
+    known_peers_file: PathBuf,
+    /// Where `/mute` keeps the muted rooms, `~/.tochat/muted`
+    muted_file: PathBuf,
+    /// Names that came with a key other than the pinned one, waiting for the user to answer
+    pub key_changes: Vec<KeyChange>,
+    /// Messages from a changed key and their room, shown once it is accepted
+    held: Vec<(usize, ChatMessage)>,
+    /// Changed keys the user rejected, their messages are dropped
+    rejected: HashSet<String>,
+    /// Our own PeerId, for the safety numbers of `/verify`
+    pub local_peer: Option<PeerId>,
+    /// A short notice in the status line and when it was shown, see `flash`
+    pub flash: Option<(String, Instant)>,
+    /// Drafts longer than this many characters get a warning
+    pub warn_length: usize,
+    /// Underlines unknown words in the draft with `--spellcheck`
+    pub dictionary: Option<Dictionary>,
+    /// Gets the messages added to the list with `--webhook-url`
+    pub webhook: Option<Webhook>,
+    /// Uploads images with `--upload-url`, their links end up in the draft
+    pub uploader: Option<Uploader>,
+    /// Links of the selected message waiting for the user to pick one to open
+    pub url_choices: Vec<String>,
+    /// Whether `:shortcode:`s are turned into emoji on send
+    pub expand_emoji: bool,
+    /// Whether new messages get selected, scrolling the list to them
+    pub follow_tail: bool,
+    /// Messages that arrived below the selection while not following
+    pub unseen: usize,
+    /// Whether consecutive messages of a sender within a minute share one header
+    pub grouped: bool,
+    /// Each message on a single line, switched with `c`
+    pub compact: bool,
+    /// Whether senders' names are followed by their short PeerId, switched with `p`
+    pub show_peer_ids: bool,
+    /// Whether a message that arrives after later ones from its sender goes back among them
+    pub reorder: bool,
+    /// How the draft shows in the input box, switched with `Ctrl+E`
+    pub input_echo: InputEcho,
+    /// Whether Enter sends the draft or starts a new line in it
+    pub enter: EnterKey,
+    /// Spectator mode, `InputMode::Editing` is never entered
+    pub read_only: bool,
+    /// Messages older than this are removed by `expire`
+    pub message_ttl: Option<Duration>,
+    /// Whether the window title shows the unread count, see `title_supported`
+    pub title: bool,
+    /// The unread count last put in the title
+    pub titled: Option<usize>,
+    /// Whether `q` asks before quitting
+    pub quit_confirm: bool,
+    /// The quit prompt is showing
+    pub quitting: bool,
+    /// The `?` help overlay is showing
+    pub show_help: bool,
+    /// Input pane height in percent, resized with `+`/`-`
+    pub input_height: u16,
+    /// Status line height in percent of the area above the input
+    pub status_height: u16,
+    pub theme: Theme,
 }
 
 impl Default for App {
@@ -92,33 +318,1511 @@ impl Default for App {
             input_mode: InputMode::Normal,
             messages: StatefulList::with_items(Vec::new()),
             remote_messages: String::new(),
+            history: None,
+            rooms: Vec::new(),
+            active: 0,
+            away: false,
+            replying: None,
+            amending: None,
+            path: None,
+            address: None,
+            relay_choice: None,
+            relay: RelayStatus::Connected,
+            health: None,
+            show_health: false,
+            events: VecDeque::with_capacity(EVENT_LOG_LEN),
+            show_events: false,
+            events_scroll: 0,
+            presence: true,
+            presence_bell: false,
+            announced: None,
+            pending: None,
+            name: String::new(),
+            last_seen: HashMap::new(),
+            peer_seen: None,
+            sequences: HashMap::new(),
+            roster: HashMap::new(),
+            known_peers: BTreeMap::new(),
+            known_peers_file: crate::known_peers::path(),
+            muted_file: crate::muted::path(),
+            key_changes: Vec::new(),
+            held: Vec::new(),
+            rejected: HashSet::new(),
+            local_peer: None,
+            flash: None,
+            warn_length: 2000,
+            dictionary: None,
+            webhook: None,
+            uploader: None,
+            url_choices: Vec::new(),
+            expand_emoji: true,
+            follow_tail: true,
+            unseen: 0,
+            grouped: false,
+            compact: false,
+            show_peer_ids: false,
+            reorder: true,
+            input_echo: InputEcho::On,
+            enter: EnterKey::Send,
+            read_only: false,
+            message_ttl: None,
+            title: false,
+            titled: None,
+            quit_confirm: true,
+            quitting: false,
+            show_help: false,
+            input_height: 20,
+            status_height: 15,
+            theme: Theme::color(),
         }
     }
 }
 
-pub async fn bootstrap(tx1: Sender<String>, rx2: Receiver<String>, name: &String) -> Result<(), Box<dyn Error>> {
+impl App {
+    /// Append a message, select it when following the tail, persist it if history is enabled
+    /// and hand it to the webhook
+    pub fn push(&mut self, message: ChatMessage) {
+        self.record(&message);
+        self.messages.items.push(message);
+        if self.follow_tail {
+            let len = self.messages.items.len() - 1;
+            self.messages.collapse();
+            self.messages.state.select(Some(len));
+        } else {
+            self.unseen += 1;
+        }
+    }
+
+    /// Hand a message added to the list to the webhook and the history file
+    fn record(&self, message: &ChatMessage) {
+        if let Some(webhook) = &self.webhook {
+            let topic = self.rooms.get(self.active).map_or("", |room| room.topic.as_str());
+            webhook.notify(topic, message);
+        }
+        if let Some(history) = &self.history {
+            if message.kind != Kind::System {
+                if let Err(e) = history.append(message) {
+                    warn!("Failed to write history: {}", e);
+                }
+            }
+        }
+    }
+
+    /// Add a received message after its sender's earlier ones: before the first of the sender's
+    /// later ones when gossipsub delivered those first, at the end otherwise. A gap in the
+    /// sender's numbering gets a note, the missing messages may still turn up
+    fn insert_in_order(&mut self, message: ChatMessage) {
+        self.last_seen.insert(message.sender.clone(), Local::now());
+        let (author, seq) = match (&message.author, message.seq) {
+            (Some(author), Some(seq)) => (author.clone(), seq),
+            _ => return self.push(message),
+        };
+        let last = self.sequences.entry((self.active, author.clone(), seq.session)).or_insert(seq.n.saturating_sub(1));
+        let missing = seq.n.saturating_sub(last.saturating_add(1));
+        *last = (*last).max(seq.n);
+        if missing > 0 {
+            let notice = format!("{} earlier message(s) from {} may be missing", missing, message.sender);
+            self.push(ChatMessage::system(notice));
+        }
+
+        let later = self.messages.items.iter().position(|m| {
+            m.author.as_ref() == Some(&author) && m.seq.map_or(false, |s| s.session == seq.session && s.n > seq.n)
+        });
+        let at = match later {
+            Some(at) if self.reorder => at,
+            _ => return self.push(message),
+        };
+        self.record(&message);
+        if self.insert_at(at, message) && !self.follow_tail {
+            self.unseen += 1;
+        }
+    }
+
+    /// Insert into the list at `at`, keeping the selection on the message it was on. Returns
+    /// whether the message went below the selection
+    fn insert_at(&mut self, at: usize, message: ChatMessage) -> bool {
+        self.messages.items.insert(at, message);
+        if let Some(anchor) = self.messages.anchor.as_mut().filter(|anchor| **anchor >= at) {
+            *anchor += 1;
+        }
+        match self.messages.state.selected() {
+            Some(selected) if selected >= at => {
+                self.messages.state.select(Some(selected + 1));
+                false
+            }
+            _ => true,
+        }
+    }
+
+    /// Put the messages a peer replayed on joining among the listed ones by time, leaving out
+    /// those we have. The room saw them before us, so they count as neither unread nor unseen
+    /// and skip the webhook
+    fn replay(&mut self, messages: Vec<ChatMessage>) {
+        let mut added = 0;
+        for message in messages {
+            if self.messages.items.iter().any(|m| m.id == message.id) {
+                continue;
+            }
+            let at = self
+                .messages
+                .items
+                .iter()
+                .position(|m| m.timestamp > message.timestamp)
+                .unwrap_or(self.messages.items.len());
+            self.insert_at(at, message);
+            added += 1;
+        }
+        if added == 0 {
+            return;
+        }
+        if self.follow_tail {
+            self.messages.end();
+        }
+        // rewritten rather than appended, they go before messages already in the file
+        self.save_history();
+        self.push(ChatMessage::system(format!("{} earlier messages replayed by a peer in the room", added)));
+    }
+
+    /// Switch following the tail, turning it on jumps to the newest message
+    pub fn toggle_follow(&mut self) {
+        self.follow_tail = !self.follow_tail;
+        if self.follow_tail && !self.messages.items.is_empty() {
+            self.messages.end();
+        }
+        self.mark_seen();
+    }
+
+    /// Select where `--start-at` asks once the history loaded. Away from the bottom the list
+    /// stops following new messages, so the selection stays put until the user moves it
+    pub fn start_at(&mut self, start: StartAt, last_read: Option<DateTime<Local>>) {
+        let len = self.messages.items.len();
+        let selected = match start {
+            _ if len == 0 => None,
+            StartAt::Top => Some(0),
+            StartAt::Bottom => Some(len - 1),
+            // without a marker from an earlier session nothing counts as unread
+            StartAt::Unread => last_read
+                .and_then(|read| self.messages.items.iter().position(|m| m.kind == Kind::Remote && m.timestamp > read))
+                .or(Some(len - 1)),
+        };
+        self.messages.state.select(selected);
+        if let Some(i) = selected.filter(|i| *i < len - 1) {
+            self.follow_tail = false;
+            if start == StartAt::Unread {
+                self.unseen = len - 1 - i;
+            }
+        }
+    }
+
+    /// Received time of the newest message the user has seen, the ones counted in `unseen`
+    /// excluded. Saved on quit for `--start-at unread`
+    pub fn last_seen(&self) -> Option<DateTime<Local>> {
+        let seen = self.messages.items.len().checked_sub(self.unseen)?;
+        self.messages.items[..seen].last().map(|m| m.timestamp)
+    }
+
+    /// Scroll the event log up by `lines`, down when negative, stopping at either end
+    pub fn scroll_events(&mut self, lines: isize) {
+        let top = self.events.len().saturating_sub(1);
+        self.events_scroll = if lines < 0 {
+            self.events_scroll.saturating_sub(lines.unsigned_abs())
+        } else {
+            self.events_scroll.saturating_add(lines as usize).min(top)
+        };
+    }
+
+    /// Count the unseen messages down to the selected one as read, all of them once the newest
+    /// message is selected
+    pub fn mark_seen(&mut self) {
+        let len = self.messages.items.len();
+        match self.messages.state.selected() {
+            _ if self.follow_tail => self.unseen = 0,
+            Some(i) if i + self.unseen >= len => self.unseen = len.saturating_sub(i + 1),
+            _ => {}
+        }
+    }
+
+    /// Select the oldest of the messages that arrived unseen, reading down from there counts
+    /// them as read
+    pub fn first_unseen(&mut self) {
+        match self.messages.items.len().checked_sub(self.unseen) {
+            Some(first) if self.unseen > 0 => self.messages.state.select(Some(first)),
+            _ => self.flash("no unread messages"),
+        }
+    }
+
+    /// Copy the selected message, or range of messages, to the clipboard as a quote to paste
+    /// elsewhere
+    pub fn copy_selected(&mut self) {
+        let range = match self.messages.selection() {
+            Some(range) => range,
+            None => return self.flash("select a message to copy"),
+        };
+        let (quoted, count) = transcript::quote(&self.messages.items[range]);
+        if count == 0 {
+            return self.flash("nothing to copy, notices and deleted messages are left out");
+        }
+        match clipboard::copy(&quoted) {
+            Ok(()) if count == 1 => self.flash("copied the message as a quote"),
+            Ok(()) => self.flash(&format!("copied {} messages as a quote", count)),
+            Err(e) => self.push(ChatMessage::system(format!("copying failed: {}", e))),
+        }
+    }
+
+    /// Apply an update from the network task
+    pub fn apply(&mut self, update: Update) {
+        match update {
+            Update::Message(room, message) => {
+                self.in_room(room, |app| app.receive(message));
+                if room != self.active {
+                    if let Some(room) = self.rooms.get_mut(room).filter(|room| !room.muted) {
+                        room.unread += 1;
+                    }
+                }
+            }
+            Update::Replay(room, messages) => self.in_room(room, |app| app.replay(messages)),
+            Update::System(text) => self.push(ChatMessage::system(text)),
+            Update::Delivery(id, delivery) => self.in_room(self.room_of(id), |app| app.set_delivery(id, delivery)),
+            Update::Edit { id, author, body } => {
+                self.in_room(self.room_of(id), |app| app.apply_edit(id, Some(&author), body))
+            }
+            Update::Delete { id, author } => self.in_room(self.room_of(id), |app| app.apply_delete(id, Some(&author))),
+            Update::Address(address) => self.address = Some(address),
+            Update::Relay(status) => self.relay = status,
+            Update::Health(health) => self.health = Some(health),
+            Update::Event(at, line) => {
+                if self.events.len() == EVENT_LOG_LEN {
+                    self.events.pop_front();
+                }
+                self.events.push_back((at, line));
+            }
+            // published by `run_app`, which holds the command sender
+            Update::Inject { .. } => {}
+            Update::Path(status) => {
+                // the time freezes while the peer is gone
+                if status.path.is_none() && self.path.map_or(false, |old| old.path.is_some()) {
+                    self.peer_seen = Some(Local::now());
+                }
+                self.path = Some(status);
+                if status.path == self.announced {
+                    // back to what was announced before the change held, nothing to say
+                    self.pending = None;
+                } else if self.pending.map(|(path, _)| path) != Some(status.path) {
+                    self.pending = Some((status.path, Instant::now()));
+                }
+            }
+        }
+    }
+
+    /// Note a change of the path to the peer once it held for `PRESENCE_DEBOUNCE`, the peer
+    /// coming online is noted right away. Returns whether a note was added, for the bell
+    pub fn announce_presence(&mut self) -> bool {
+        let (path, since) = match self.pending {
+            Some(pending) => pending,
+            None => return false,
+        };
+        if self.announced.is_some() && since.elapsed() < PRESENCE_DEBOUNCE {
+            return false;
+        }
+        self.pending = None;
+        let notice = match (std::mem::replace(&mut self.announced, path), path) {
+            (None, Some(Path::Relayed)) => "peer joined the relay circuit",
+            (None, Some(Path::Direct)) => "peer connected, direct connection established",
+            (Some(Path::Relayed), Some(Path::Direct)) => "direct connection established",
+            (Some(Path::Direct), Some(Path::Relayed)) => "direct connection closed, still connected through the relay",
+            (_, None) => "peer disconnected",
+            _ => return false,
+        };
+        if !self.presence {
+            return false;
+        }
+        self.push(ChatMessage::system(notice.to_string()));
+        true
+    }
+
+    /// Trade the state of the active room in `App` with the one stored in `rooms[room]`.
+    /// Done twice with the same index it is undone
+    fn swap_room(&mut self, room: usize) {
+        let room = &mut self.rooms[room];
+        std::mem::swap(&mut self.messages, &mut room.messages);
+        std::mem::swap(&mut self.unseen, &mut room.unseen);
+        std::mem::swap(&mut self.follow_tail, &mut room.follow_tail);
+        std::mem::swap(&mut self.history, &mut room.history);
+    }
+
+    /// Run `f` with `room` as the active room, for updates to a room that isn't shown
+    fn in_room<T>(&mut self, room: usize, f: impl FnOnce(&mut App) -> T) -> T {
+        let active = self.active;
+        if room == active || room >= self.rooms.len() {
+            return f(self);
+        }
+        self.swap_room(active);
+        self.swap_room(room);
+        self.active = room;
+        let result = f(self);
+        self.swap_room(room);
+        self.swap_room(active);
+        self.active = active;
+        result
+    }
+
+    /// The room holding the message with this id, the active one when none does
+    fn room_of(&self, id: u64) -> usize {
+        if self.messages.items.iter().any(|m| m.id == id) {
+            return self.active;
+        }
+        self.rooms
+            .iter()
+            .position(|room| room.messages.items.iter().any(|m| m.id == id))
+            .unwrap_or(self.active)
+    }
+
+    /// Show another room. The network task has to be told first with `Command::Room`, so what
+    /// is sent from now on goes to its topic. The reply or edit in progress belonged to the old room
+    pub fn switch_room(&mut self, room: usize) {
+        if room == self.active || room >= self.rooms.len() {
+            return;
+        }
+        self.swap_room(self.active);
+        self.swap_room(room);
+        self.active = room;
+        self.rooms[room].unread = 0;
+        self.replying = None;
+        self.amending = None;
+        self.url_choices.clear();
+    }
+
+    /// Messages waiting to be read: the ones below the selection and those in other rooms,
+    /// muted rooms left out
+    pub fn unread(&self) -> usize {
+        let unseen = if self.muted() { 0 } else { self.unseen };
+        let others: usize = self
+            .rooms
+            .iter()
+            .enumerate()
+            .filter(|(i, room)| *i != self.active && !room.muted)
+            .map(|(_, room)| room.unread)
+            .sum();
+        unseen + others
+    }
+
+    /// `/seen`: when each peer that wrote last did, most recent first
+    pub fn report_seen(&mut self) {
+        let now = Local::now();
+        let mut seen: Vec<(&String, &DateTime<Local>)> = self.last_seen.iter().collect();
+        seen.sort_by(|a, b| b.1.cmp(a.1));
+        let notice = if seen.is_empty() {
+            "no peer wrote yet".to_string()
+        } else {
+            let peers: Vec<String> = seen.iter().map(|(name, at)| format!("{} {}", name, ago(**at, now))).collect();
+            format!("last seen: {}", peers.join(", "))
+        };
+        self.push(ChatMessage::system(notice));
+    }
+
+    /// `/whois`: the full PeerId that signed the selected message, to compare with the one the
+    /// sender sees out of band
+    pub fn whois_selected(&mut self) {
+        let message = match self.messages.state.selected().and_then(|i| self.messages.items.get(i)) {
+            Some(message) => message,
+            None => return self.flash("select a message first"),
+        };
+        let notice = match (message.kind, &message.author) {
+            (Kind::Local, _) => "that's your own message".to_string(),
+            (Kind::System, _) => "tochat's own notes have no sender".to_string(),
+            (Kind::Remote, Some(author)) => format!("{} is {}", message.sender, author),
+            (Kind::Remote, None) if message.replayed => {
+                format!("{} can't be verified, the peer that replayed it vouches for it", message.sender)
+            }
+            (Kind::Remote, None) => format!("no PeerId is known for this message from {}", message.sender),
+        };
+        self.push(ChatMessage::system(notice));
+    }
+
+    /// Whether the active room is muted
+    pub fn muted(&self) -> bool {
+        self.rooms.get(self.active).map_or(false, |room| room.muted)
+    }
+
+    /// `/mute` and `/unmute` the active room, remembered for later sessions
+    pub fn set_muted(&mut self, muted: bool) {
+        let room = match self.rooms.get_mut(self.active) {
+            Some(room) => room,
+            None => return self.flash("no room to mute"),
+        };
+        room.muted = muted;
+        room.unread = 0;
+        let topic = room.topic.clone();
+        if let Err(e) = crate::muted::set(&self.muted_file, &topic, muted) {
+            warn!("Failed to save the muted rooms: {}", e);
+        }
+        self.flash(&format!("{} {}", if muted { "muted" } else { "unmuted" }, topic));
+    }
+
+    /// Load the part of `history` in `window` into the active room and select where `start` asks
+    fn load_history(&mut self, mut history: History, window: &Window, start: StartAt) -> io::Result<()> {
+        self.messages.items = history.load(window)?;
+        self.start_at(start, history.last_read());
+        self.history = Some(history);
+        Ok(())
+    }
+
+    /// Take in a message from a peer. When several peers go by one name, their messages get a
+    /// suffix from their PeerId, so the same peer always shows as the same `name#suffix`
+    fn receive(&mut self, mut message: ChatMessage) {
+        let author = match message.author.clone() {
+            Some(author) => author,
+            None => return self.insert_in_order(message),
+        };
+        if self.rejected.contains(&author) {
+            return;
+        }
+        let name = message.sender.clone();
+        if !self.check_key(&name, &author) {
+            self.held.push((self.active, message));
+            return;
+        }
+        let authors = self.roster.entry(name.clone()).or_default();
+        let new_author = authors.insert(author.clone());
+        let others = authors.len() - 1;
+        if others == 0 && name != self.name {
+            return self.insert_in_order(message);
+        }
+
+        if new_author {
+            // the peers seen before under this name get their suffix too
+            let mut renamed = false;
+            for m in self.messages.items.iter_mut().filter(|m| m.kind == Kind::Remote && m.sender == name) {
+                if let Some(author) = &m.author {
+                    m.sender = disambiguated(&name, author);
+                    renamed = true;
+                }
+            }
+            if renamed {
+                self.save_history();
+            }
+            let notice = if others == 0 {
+                format!("another peer also goes by your name {}, showing it as {}", name, disambiguated(&name, &author))
+            } else {
+                format!("{} peers go by {}, showing the new one as {}", others + 1, name, disambiguated(&name, &author))
+            };
+            self.push(ChatMessage::system(notice));
+        }
+        message.sender = disambiguated(&name, &author);
+        self.insert_in_order(message);
+    }
+
+    /// Pin the key `name` first shows up with. Returns false when a name pinned to another key
+    /// comes with a new one: its messages are held back until the user accepts or rejects the
+    /// key. Several peers sharing a name in this session is what `receive` disambiguates, not a
+    /// changed key
+    fn check_key(&mut self, name: &str, author: &str) -> bool {
+        if self.local_peer.map_or(false, |local| local.to_base58() == author) {
+            return true;
+        }
+        if self.key_changes.iter().any(|change| change.new == author) {
+            return false;
+        }
+        match self.known_peers.get(name).map(|pin| pin.peer_id.clone()) {
+            Some(pinned) if pinned == author => true,
+            Some(pinned) if self.roster.get(name).map_or(false, |authors| authors.contains(&pinned)) => true,
+            Some(pinned) => {
+                self.push(ChatMessage::system(format!(
+                    "WARNING: the key of {} has changed since an earlier session, this may be someone else using the name. \
+                     Its messages are held back until you accept or reject the new key. Compare safety numbers out of band with /verify {} first",
+                    name, author
+                )));
+                self.key_changes.push(KeyChange { name: name.to_string(), old: pinned, new: author.to_string() });
+                // the prompt takes keys in normal mode, the draft stays
+                self.input_mode = InputMode::Normal;
+                false
+            }
+            None => {
+                self.pin_key(name, author);
+                true
+            }
+        }
+    }
+
+    fn pin_key(&mut self, name: &str, author: &str) {
+        match crate::known_peers::pin(&self.known_peers_file, name, author) {
+            Ok(pin) => {
+                self.known_peers.insert(name.to_string(), pin);
+            }
+            Err(e) => warn!("Failed to save the known peers: {}", e),
+        }
+    }
+
+    /// Answer the oldest key change prompt: accepting pins the new key and shows the messages
+    /// held back, rejecting drops them and the ones still to come from that key this session
+    pub fn answer_key_change(&mut self, accept: bool) {
+        if self.key_changes.is_empty() {
+            return;
+        }
+        let change = self.key_changes.remove(0);
+        let (held, rest): (Vec<_>, Vec<_>) =
+            self.held.drain(..).partition(|(_, m)| m.author.as_deref() == Some(change.new.as_str()));
+        self.held = rest;
+        if accept {
+            self.pin_key(&change.name, &change.new);
+            self.push(ChatMessage::system(format!("accepted the new key of {}", change.name)));
+            for (room, message) in held {
+                self.in_room(room, |app| app.receive(message));
+            }
+        } else {
+            self.rejected.insert(change.new.clone());
+            self.push(ChatMessage::system(format!(
+                "rejected the new key of {}, dropped {} messages from it and will drop the rest, {} stays pinned",
+                change.name,
+                held.len(),
+                change.old
+            )));
+        }
+    }
+
+    /// `/verify [name]`: the safety number of our key and the one of `name`, the selected
+    /// message's sender or the only peer that wrote, for both sides to compare out of band
+    pub fn verify(&mut self, name: &str) {
+        let local = match self.local_peer {
+            Some(local) => local,
+            None => return self.push(ChatMessage::system("cannot verify, your own key is unknown".to_string())),
+        };
+        let peer = if !name.is_empty() {
+            self.peer_by_name(name).map(|peer| (name.to_string(), peer))
+        } else {
+            self.verify_target()
+        };
+        let notice = match peer {
+            Ok((name, peer)) => format!(
+                "safety number with {} ({}): {}. It is the same on both sides, compare it with {} over a channel you trust",
+                name,
+                crate::network::short_peer_id(&peer),
+                crate::known_peers::safety_number(&local, &peer),
+                name
+            ),
+            Err(e) => e,
+        };
+        self.push(ChatMessage::system(notice));
+    }
+
+    /// Whom `/verify` without a name is about: the selected message's sender, otherwise the
+    /// only peer that wrote so far, as in a dm session
+    fn verify_target(&self) -> Result<(String, PeerId), String> {
+        let selected = self.messages.state.selected().and_then(|i| self.messages.items.get(i));
+        if let Some(message) = selected.filter(|m| m.kind == Kind::Remote) {
+            if let Some(peer) = message.author.as_ref().and_then(|author| author.parse().ok()) {
+                return Ok((message.sender.clone(), peer));
+            }
+        }
+        let mut authors = self.roster.iter().flat_map(|(name, authors)| authors.iter().map(move |author| (name, author)));
+        match (authors.next(), authors.next()) {
+            (Some((name, author)), None) => author
+                .parse()
+                .map(|peer| (name.clone(), peer))
+                .map_err(|_| format!("{} has an invalid peer id", name)),
+            (None, _) => Err("no peer wrote yet, usage: /verify <name or peer id>".to_string()),
+            _ => Err("select a message or name the peer, usage: /verify <name or peer id>".to_string()),
+        }
+    }
+
+    /// Start a draft answering the selected message, system messages can't be answered
+    pub fn reply_to_selected(&mut self) {
+        if self.read_only {
+            self.push(ChatMessage::system("read-only, sending is disabled".to_string()));
+            return;
+        }
+        match self.messages.state.selected().and_then(|i| self.messages.items.get(i)) {
+            // the quote would repeat the whisper in public
+            Some(message) if message.private => {
+                self.push(ChatMessage::system("private messages can't be quoted, answer with /msg".to_string()));
+            }
+            Some(message) if message.kind != Kind::System => {
+                self.replying = Some(Quote::of(message));
+                self.amending = None;
+                self.input_mode = InputMode::Editing;
+            }
+            _ => {}
+        }
+    }
+
+    /// Load our last message into the draft to correct it, Enter then publishes the edit
+    pub fn edit_last(&mut self) {
+        if self.read_only {
+            self.push(ChatMessage::system("read-only, sending is disabled".to_string()));
+            return;
+        }
+        // edits go out in the clear, so whispers are left alone
+        let last = self.messages.items.iter().rev().find(|m| m.kind == Kind::Local && !m.deleted && !m.private);
+        match last {
+            Some(message) => {
+                self.amending = Some(message.id);
+                self.input = message.body.clone();
+                self.replying = None;
+                self.input_mode = InputMode::Editing;
+            }
+            None => self.push(ChatMessage::system("no message of yours to edit".to_string())),
+        }
+    }
+
+    /// Ctrl+C while editing: throw the draft away along with the reply or edit it was for.
+    /// Esc leaves it for later instead
+    pub fn discard_draft(&mut self) {
+        self.input.clear();
+        self.replying = None;
+        self.amending = None;
+    }
+
+    /// The peer `/msg` sends to, given by peer id or by the name it sent messages under
+    pub fn peer_by_name(&self, name: &str) -> Result<PeerId, String> {
+        if let Ok(peer) = name.parse() {
+            return Ok(peer);
+        }
+        let mut peers: Vec<&String> = self
+            .messages
+            .items
+            .iter()
+            .filter(|m| m.kind == Kind::Remote && m.sender == name)
+            .filter_map(|m| m.author.as_ref())
+            .collect();
+        peers.sort();
+        peers.dedup();
+        match peers[..] {
+            [peer] => peer.parse().map_err(|_| format!("{} has an invalid peer id", name)),
+            [] => Err(format!("no messages from {} yet, use their peer id", name)),
+            _ => Err(format!("{} peers go by {}, use the peer id", peers.len(), name)),
+        }
+    }
+
+    /// The selected message if it is one of ours that can still be deleted
+    pub fn selected_own(&self) -> Option<u64> {
+        let message = self.messages.items.get(self.messages.state.selected()?)?;
+        (message.kind == Kind::Local && !message.deleted).then(|| message.id)
+    }
+
+    /// The message an edit or delete refers to: one of ours when `author` is `None`,
+    /// otherwise one received from `author`. Unknown ids find nothing
+    fn editable(&mut self, id: u64, author: Option<&PeerId>) -> Option<&mut ChatMessage> {
+        let author = author.map(PeerId::to_base58);
+        self.messages.items.iter_mut().find(|m| {
+            m.id == id
+                && !m.deleted
+                && match &author {
+                    None => m.kind == Kind::Local,
+                    Some(author) => m.kind == Kind::Remote && m.author.as_ref() == Some(author),
+                }
+        })
+    }
+
+    /// Replace the body of a message, see `editable` for which ones `author` may change
+    pub fn apply_edit(&mut self, id: u64, author: Option<&PeerId>, body: String) {
+        if let Some(message) = self.editable(id, author) {
+            message.body = body;
+            message.edited = true;
+            self.save_history();
+        }
+    }
+
+    /// Tombstone a message, see `editable` for which ones `author` may delete
+    pub fn apply_delete(&mut self, id: u64, author: Option<&PeerId>) {
+        if let Some(message) = self.editable(id, author) {
+            message.body.clear();
+            message.deleted = true;
+            self.save_history();
+        }
+    }
+
+    /// A quote of the message with this id for an answer to it, `reply_to_selected` by id
+    pub fn quotable(&self, id: u64) -> Option<Quote> {
+        let message = self.messages.items.iter().find(|m| m.id == id)?;
+        (message.kind != Kind::System && !message.private).then(|| Quote::of(message))
+    }
+
+    /// What `message` answers as it should be shown, taken from the original while it is
+    /// still in the list and from the quote it came with otherwise
+    pub fn quoted(&self, message: &ChatMessage) -> Option<Quote> {
+        let quote = message.reply_to.as_ref()?;
+        let original = self.messages.items.iter().find(|m| m.id == quote.id && m.kind != Kind::System);
+        Some(original.map_or_else(|| quote.clone(), Quote::of))
+    }
+
+    /// Open the link in the selected message, or let the user pick when there are several
+    pub fn open_selected_urls(&mut self) {
+        let message = match self.messages.state.selected().and_then(|i| self.messages.items.get(i)) {
+            Some(message) => message,
+            None => return,
+        };
+        let mut urls: Vec<String> = links::find_urls(&message.body)
+            .into_iter()
+            .map(|range| message.body[range].to_string())
+            .collect();
+        urls.truncate(9);
+        match urls.len() {
+            0 => {}
+            1 => self.open_url(&urls[0]),
+            _ => self.url_choices = urls,
+        }
+    }
+
+    /// Open the `n`th (from 1) of the links offered by `open_selected_urls`
+    pub fn pick_url(&mut self, n: usize) {
+        let choices = std::mem::take(&mut self.url_choices);
+        if let Some(url) = n.checked_sub(1).and_then(|i| choices.get(i)) {
+            self.open_url(url);
+        }
+    }
+
+    fn open_url(&mut self, url: &str) {
+        if let Err(e) = webbrowser::open(url) {
+            self.push(ChatMessage::system(format!("could not open {}: {}", url, e)));
+        }
+    }
+
+    /// Drop messages older than `message_ttl` in every room
+    pub fn expire(&mut self, now: DateTime<Local>) {
+        if self.message_ttl.is_none() {
+            return;
+        }
+        for room in 0..self.rooms.len().max(1) {
+            self.in_room(room, |app| app.expire_room(now));
+        }
+    }
+
+    /// Drop messages older than `message_ttl`, keeping the selection on the same message
+    /// or the next one left, and rewrite the history without them
+    fn expire_room(&mut self, now: DateTime<Local>) {
+        let ttl = match self.message_ttl.and_then(|ttl| chrono::Duration::from_std(ttl).ok()) {
+            Some(ttl) => ttl,
+            None => return,
+        };
+        let cutoff = now - ttl;
+        // `--since` and `--max-messages` leave messages in the file that aren't shown
+        let expired_unshown = self.history.as_mut().map_or(false, |history| history.expire(cutoff));
+        if !self.messages.items.iter().any(|m| m.timestamp < cutoff) {
+            if expired_unshown {
+                self.save_history();
+            }
+            return;
+        }
+
+        let selected = self.messages.state.selected();
+        let removed_before = selected.map_or(0, |i| {
+            self.messages.items[..i].iter().filter(|m| m.timestamp < cutoff).count()
+        });
+        self.messages.items.retain(|m| m.timestamp >= cutoff);
+        self.messages.collapse();
+
+        let len = self.messages.items.len();
+        self.unseen = self.unseen.min(len);
+        self.messages.state.select(match selected {
+            _ if len == 0 => None,
+            Some(i) => Some((i - removed_before).min(len - 1)),
+            None => None,
+        });
+        self.save_history();
+    }
+
+    /// The network task is gone and a command couldn't be handed to it, `unsent` is the message
+    /// that was to be published
+    pub fn connection_lost(&mut self, unsent: Option<u64>) {
+        let notice = match unsent {
+            Some(id) => {
+                self.set_delivery(id, Delivery::Failed);
+                "message not sent, connection lost"
+            }
+            None => "connection lost",
+        };
+        self.push(ChatMessage::system(notice.to_string()));
+    }
+
+    /// The network task didn't take a command. The TUI doesn't wait for it, so a full queue is
+    /// reported like a closed one; `unsent` is the message that was to be published and is marked
+    /// failed so `r` can retry it
+    pub fn command_refused(&mut self, error: TrySendError<Command>, unsent: Option<u64>) {
+        match error {
+            TrySendError::Closed(_) => self.connection_lost(unsent),
+            TrySendError::Full(_) => {
+                let notice = match unsent {
+                    Some(id) => {
+                        self.set_delivery(id, Delivery::Failed);
+                        "network busy, message not sent, press r to retry"
+                    }
+                    None => "network busy, try again",
+                };
+                self.push(ChatMessage::system(notice.to_string()));
+            }
+        }
+    }
+
+    /// The selected message if it is one of ours that failed to send, for retrying
+    pub fn selected_failed(&self) -> Option<ChatMessage> {
+        let message = self.messages.items.get(self.messages.state.selected()?)?;
+        (message.kind == Kind::Local && message.delivery == Delivery::Failed).then(|| message.clone())
+    }
+
+    /// Put pasted text into the draft as is, its newlines don't send anything. Large pastes
+    /// get a notice so they are reviewed before Enter sends them as one message
+    pub fn paste(&mut self, text: &str) {
+        if self.read_only {
+            self.push(ChatMessage::system("read-only, sending is disabled".to_string()));
+            return;
+        }
+        // dropping an image on the terminal pastes its path
+        if let Some(path) = self.pasted_image(text) {
+            self.upload(path);
+            return;
+        }
+        let text = text.replace("\r\n", "\n").replace('\r', "\n");
+        self.input_mode = InputMode::Editing;
+        self.input.push_str(&text);
+
+        let lines = text.lines().count();
+        if lines > PASTE_WARN_LINES || text.len() > PASTE_WARN_BYTES {
+            self.push(ChatMessage::system(format!(
+                "pasted {} lines ({} bytes) into the draft, review it before pressing Enter or discard it with Ctrl+C",
+                lines,
+                text.len()
+            )));
+        }
+    }
+
+    /// The image file a paste names, when there is an uploader to send it to
+    fn pasted_image(&self, text: &str) -> Option<PathBuf> {
+        self.uploader.as_ref()?;
+        let text = text.trim();
+        let text = text.strip_prefix("file://").unwrap_or(text);
+        let text = text.trim_matches(|c| c == '\'' || c == '"');
+        if text.is_empty() || text.contains('\n') {
+            return None;
+        }
+        let path = transcript::expand_home(text);
+        (upload::is_image(&path) && path.is_file()).then_some(path)
+    }
+
+    /// Upload `path` for `/upload` or a pasted image, its link goes into the draft once done
+    pub fn upload(&mut self, path: PathBuf) {
+        let uploader = match &self.uploader {
+            Some(uploader) => uploader,
+            None => {
+                let notice = "no upload host, start with --upload-url to upload files";
+                return self.push(ChatMessage::system(notice.to_string()));
+            }
+        };
+        if !path.is_file() {
+            return self.push(ChatMessage::system(format!("cannot upload {}, no such file", path.display())));
+        }
+        uploader.start(path.clone());
+        self.flash(&format!("uploading {}", path.display()));
+    }
+
+    /// Take in what running uploads reported since the last frame
+    pub fn poll_uploads(&mut self) {
+        while let Some(event) = self.uploader.as_mut().and_then(Uploader::poll) {
+            match event {
+                UploadEvent::Progress { name, sent, total } => {
+                    self.flash(&format!("uploading {} {}%", name, sent * 100 / total.max(1)));
+                }
+                UploadEvent::Done { name, url } => {
+                    if !self.input.is_empty() && !self.input.ends_with(char::is_whitespace) {
+                        self.input.push(' ');
+                    }
+                    self.input.push_str(&url);
+                    self.input_mode = InputMode::Editing;
+                    self.flash(&format!("uploaded {}, its link is in the draft", name));
+                }
+                UploadEvent::Failed { name, error } => {
+                    self.push(ChatMessage::system(format!("upload of {} failed: {}", name, error)));
+                }
+            }
+        }
+    }
+
+    /// Write the conversation to `path` for `/export`, reporting the outcome in the list
+    pub fn export(&mut self, path: &str) {
+        let path = transcript::expand_home(path);
+        let notice = match transcript::export(&self.messages.items, &path) {
+            Ok(format) => format!(
+                "exported {} messages to {} as {:?}",
+                self.messages.items.len(),
+                path.display(),
+                format
+            ),
+            Err(e) => format!("export to {} failed: {}", path.display(), e),
+        };
+        self.push(ChatMessage::system(notice));
+    }
+
+    /// Show `text` in the status line for `FLASH_FOR`, for hints not worth a place in the list
+    pub fn flash(&mut self, text: &str) {
+        self.flash = Some((text.to_string(), Instant::now()));
+    }
+
+    /// The flashed text while it is still showing
+    pub fn flashing(&self) -> Option<&str> {
+        match &self.flash {
+            Some((text, at)) if at.elapsed() < FLASH_FOR => Some(text),
+            _ => None,
+        }
+    }
+
+    /// Grow (positive) or shrink the input pane by `step` percent, within `INPUT_HEIGHT`
+    pub fn resize_input(&mut self, step: i16) {
+        let height = (self.input_height as i16 + step) as u16;
+        self.input_height = height.clamp(*INPUT_HEIGHT.start(), *INPUT_HEIGHT.end());
+    }
+
+    /// Keep where the user got to in each room for the next `--start-at unread`
+    fn remember_read(&mut self) {
+        for room in 0..self.rooms.len().max(1) {
+            self.in_room(room, |app| {
+                if let (Some(history), Some(at)) = (&app.history, app.last_seen()) {
+                    if let Err(e) = history.mark_read(at) {
+                        warn!("Failed to write the read marker: {}", e);
+                    }
+                }
+            });
+        }
+    }
+
+    /// Rewrite the history file after messages in it changed
+    fn save_history(&self) {
+        if let Some(history) = &self.history {
+            if let Err(e) = history.save(&self.messages.items) {
+                warn!("Failed to write history: {}", e);
+            }
+        }
+    }
+
+    /// Record the delivery outcome of one of our messages
+    pub fn set_delivery(&mut self, id: u64, delivery: Delivery) {
+        if let Some(message) = self.messages.items.iter_mut().find(|m| m.kind == Kind::Local && m.id == id) {
+            message.delivery = delivery;
+            self.save_history();
+        }
+    }
+}
+
+/// `name` with the last characters of the PeerId that used it, e.g. `alice#kx6nXT`
+fn disambiguated(name: &str, author: &str) -> String {
+    let suffix = &author[author.len().saturating_sub(6)..];
+    format!("{}#{}", name, suffix)
+}
+
+/// The Linux console and dumb terminals print title sequences or choke on them, most others
+/// show the title and keep a stack of earlier ones to restore
+fn title_supported() -> bool {
+    !matches!(std::env::var("TERM").as_deref(), Ok("dumb") | Ok("linux") | Err(_))
+}
+
+/// How long before `now` something happened, as `just now`, `5m ago`, `2h ago` or `3d ago`
+pub fn ago(then: DateTime<Local>, now: DateTime<Local>) -> String {
+    let secs = (now - then).num_seconds().max(0);
+    match secs {
+        0..=59 => "just now".to_string(),
+        60..=3599 => format!("{}m ago", secs / 60),
+        3600..=86399 => format!("{}h ago", secs / 3600),
+        _ => format!("{}d ago", secs / 86400),
+    }
+}
+
+/// Fail early when stdin or stdout isn't a terminal, raw mode and the alternate screen
+/// would otherwise error out or write escape codes into the redirected output
+pub fn ensure_terminal(command: &str, key_on_stdin: bool) -> Result<(), Box<dyn Error>> {
+    if !io::stdout().is_tty() {
+        return Err(format!("tochat {} requires a terminal, but stdout is redirected", command).into());
+    }
+    // with the key piped in, keys are read from the controlling terminal instead
+    if !io::stdin().is_tty() && !(key_on_stdin && std::fs::File::open("/dev/tty").is_ok()) {
+        return Err(format!("tochat {} requires a terminal, but stdin is redirected", command).into());
+    }
+    Ok(())
+}
+
+pub async fn bootstrap(tx1: Sender<Command>, rx2: Receiver<Update>, config: &Config, history: Option<History>) -> Result<(), Box<dyn Error>> {
+    let mut app = App::default();
+    app.expand_emoji = config.emoji;
+    app.grouped = config.grouped;
+    app.compact = config.compact;
+    app.input_echo = config.input_echo;
+    app.enter = config.enter;
+    app.show_peer_ids = config.show_peer_ids;
+    app.reorder = !config.arrival_order;
+    app.read_only = config.read_only;
+    app.message_ttl = config.message_ttl;
+    app.quit_confirm = config.quit_confirm;
+    app.input_height = config.input_height;
+    app.status_height = config.status_height;
+    app.theme = Theme::detect(config.terminal_colors);
+    app.theme.names = config.color_names.clone();
+    app.name = config.name.clone();
+    app.warn_length = config.warn_length;
+    app.presence = config.presence;
+    app.presence_bell = config.presence_bell;
+    app.title = config.title && title_supported();
+    app.rooms = config.rooms.iter().map(|topic| Room::new(topic)).collect();
+    app.known_peers = crate::known_peers::load(&app.known_peers_file).unwrap_or_else(|e| {
+        warn!("Failed to read the known peers: {}", e);
+        Default::default()
+    });
+    app.local_peer = config.secret().ok().map(|secret| PeerId::from(generate_ed25519(&secret).public()));
+    let muted = crate::muted::load(&app.muted_file).unwrap_or_else(|e| {
+        warn!("Failed to read the muted rooms: {}", e);
+        Default::default()
+    });
+    for room in &mut app.rooms {
+        room.muted = muted.contains(&room.topic) || config.mute.contains(&room.topic);
+    }
+    if let Some(history) = history {
+        app.load_history(history, &config.window, config.start_at)?;
+        // the other rooms keep their own history files, the first is opened by the caller
+        for (room, topic) in config.rooms.iter().enumerate().skip(1) {
+            let history = History::open(topic)?;
+            app.in_room(room, |app| app.load_history(history, &config.window, config.start_at))?;
+        }
+    }
+
+    // after the history, so a failure notice isn't replaced by it
+    if let Some(path) = &config.spellcheck {
+        match Dictionary::load(path) {
+            Ok(dictionary) => app.dictionary = Some(dictionary),
+            Err(e) => app.push(ChatMessage::system(format!("spell check off, cannot read {}: {}", path.display(), e))),
+        }
+    }
+
+    if let (Some(rtt), Some(relay)) = (config.relay_rtt, relay_pick::socket_address(&config.relay_address)) {
+        app.relay_choice = Some(format!("relay {}, {} ms", relay, rtt.as_millis()));
+    }
+    if config.default_relay() && !config.loopback {
+        app.push(ChatMessage::system(DEFAULT_RELAY_NOTICE.to_string()));
+    }
+
+    if let Some(url) = &config.webhook_url {
+        match Webhook::spawn(url.clone(), config.webhook_timeout, config.webhook_sent) {
+            Ok(webhook) => app.webhook = Some(webhook),
+            Err(e) => app.push(ChatMessage::system(format!("webhook off, cannot set it up: {}", e))),
+        }
+    }
+
+    if let Some(url) = &config.upload_url {
+        match Uploader::new(url.clone(), config.upload_token.clone().map(|Secret(token)| token), config.upload_field.clone()) {
+            Ok(uploader) => app.uploader = Some(uploader),
+            Err(e) => app.push(ChatMessage::system(format!("uploads off, cannot set them up: {}", e))),
+        }
+    }
+
+    run(app, tx1, rx2, &config.name, config.idle_timeout).await
+}
+
+/// Browse a transcript read-only, without any network. Nothing is written back to the file
+pub async fn view(messages: Vec<ChatMessage>) -> Result<(), Box<dyn Error>> {
+    let mut app = App::default();
+    app.read_only = true;
+    app.theme = Theme::detect(false);
+    app.messages.items = messages;
+    if !app.messages.items.is_empty() {
+        app.messages.end();
+    }
+
+    // no network task: commands fail right away, and keeping the sender means no update
+    // ever arrives without the channel counting as closed
+    let (tx1, _) = mpsc::channel(1);
+    let (_tx2, rx2) = mpsc::channel(1);
+    run(app, tx1, rx2, &"viewer".to_string(), None).await
+}
+
+async fn run(app: App, tx1: Sender<Command>, rx2: Receiver<Update>, name: &String, idle_timeout: Option<Duration>) -> Result<(), Box<dyn Error>> {
     // setup terminal
     enable_raw_mode()?;
     let mut stdout = io::stdout();
-    execute!(stdout, EnterAlternateScreen, EnableMouseCapture)?;
+    let title = app.title;
+    if title {
+        // push the current title, popped again on exit
+        write!(stdout, "\x1b[22;0t")?;
+    }
+    execute!(stdout, EnterAlternateScreen, EnableMouseCapture, EnableBracketedPaste)?;
     let backend = CrosstermBackend::new(stdout);
     let mut terminal = Terminal::new(backend)?;
 
     // create app and run it
-    let app = App::default();
-    let res = app::run_app(&mut terminal, app, tx1, rx2, name).await;
+    let mut res = app::run_app(&mut terminal, app, tx1, rx2, name, idle_timeout).await;
+    if let Ok(app) = &mut res {
+        app.remember_read();
+    }
 
     // restore terminal
     disable_raw_mode()?;
     execute!(
         terminal.backend_mut(),
         LeaveAlternateScreen,
-        DisableMouseCapture
+        DisableMouseCapture,
+        DisableBracketedPaste
     )?;
+    if title {
+        write!(terminal.backend_mut(), "\x1b[23;0t")?;
+    }
     terminal.show_cursor()?;
 
-    if let Err(err) = res {
-        println!("{:?}", err)
-    }
+    // main reports it, now that the terminal is back to normal
+    res?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn list(selected: usize) -> StatefulList<u8> {
+        let mut list = StatefulList::with_items((0..5).collect());
+        list.state.select(Some(selected));
+        list
+    }
+
+    #[test]
+    fn extend_past_the_anchor_and_back() {
+        let mut list = list(2);
+        list.extend(true);
+        list.extend(true);
+        assert_eq!(list.range(), Some(2..=4));
+        list.extend(false);
+        list.extend(false);
+        list.extend(false);
+        assert_eq!(list.range(), Some(1..=2));
+        list.extend(true);
+        list.extend(true);
+        assert_eq!(list.range(), Some(2..=3));
+    }
+
+    #[test]
+    fn shrink_to_a_single_item() {
+        let mut list = list(1);
+        list.extend(true);
+        assert_eq!(list.range(), Some(1..=2));
+        list.extend(false);
+        assert_eq!(list.selection(), Some(1..=1));
+        assert_eq!(list.state.selected(), Some(1));
+    }
+
+    #[test]
+    fn esc_collapses_the_range() {
+        let mut list = list(3);
+        list.extend(false);
+        assert_eq!(list.range(), Some(2..=3));
+        list.collapse();
+        assert_eq!(list.range(), None);
+        assert_eq!(list.selection(), Some(2..=2));
+    }
+
+    /// An app pinning keys in a fresh file under the temp dir
+    fn app_pinning_in(test: &str) -> App {
+        let dir = std::env::temp_dir().join(format!("tochat-{}-{}", test, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        App { known_peers_file: dir.join("known_peers.json"), ..App::default() }
+    }
+
+    const ALICE: &str = "12D3KooWDpJ7As7BWAwRMfu1VU2WCqNjvq387JEYKDBj4kx6nXTN";
+    const MALLORY: &str = "12D3KooWD3eckifWpRn9wQpMG9R9hX3sD158z7EqHWmweQAJU5SA";
+
+    fn pinned(app: &App, name: &str) -> Option<String> {
+        let pins = crate::known_peers::load(&app.known_peers_file).unwrap();
+        pins.get(name).map(|pin| pin.peer_id.clone())
+    }
+
+    #[test]
+    fn pins_the_first_key_seen() {
+        let mut app = app_pinning_in("first-seen");
+        assert!(app.check_key("alice", ALICE));
+        assert_eq!(pinned(&app, "alice").as_deref(), Some(ALICE));
+        assert!(app.check_key("alice", ALICE));
+        assert!(app.key_changes.is_empty());
+    }
+
+    #[test]
+    fn warns_when_the_key_changes() {
+        let mut app = app_pinning_in("key-change");
+        assert!(app.check_key("alice", ALICE));
+        // a later session reading the pin
+        let mut later = App { known_peers_file: app.known_peers_file.clone(), ..App::default() };
+        later.known_peers = crate::known_peers::load(&later.known_peers_file).unwrap();
+        assert!(!later.check_key("alice", MALLORY));
+        assert_eq!(later.key_changes.len(), 1);
+        assert!(later.messages.items.iter().any(|m| m.body.starts_with("WARNING: the key of alice has changed")));
+        // held back until answered, without asking twice
+        assert!(!later.check_key("alice", MALLORY));
+        assert_eq!(later.key_changes.len(), 1);
+        assert_eq!(pinned(&later, "alice").as_deref(), Some(ALICE));
+    }
+
+    #[test]
+    fn accepting_a_changed_key_pins_it() {
+        let mut app = app_pinning_in("re-pin");
+        assert!(app.check_key("alice", ALICE));
+        assert!(!app.check_key("alice", MALLORY));
+        app.answer_key_change(true);
+        assert!(app.key_changes.is_empty());
+        assert_eq!(pinned(&app, "alice").as_deref(), Some(MALLORY));
+        assert!(app.check_key("alice", MALLORY));
+    }
+
+    #[test]
+    fn ctrl_c_discards_the_draft_and_what_it_was_for() {
+        let mut app = App::default();
+        let mut own = ChatMessage::local("me", "helo".to_string());
+        own.id = 7;
+        app.push(own);
+        app.edit_last();
+        assert_eq!((app.input.as_str(), app.amending), ("helo", Some(7)));
+        app.discard_draft();
+        assert_eq!((app.input.as_str(), app.amending), ("", None));
+        assert!(matches!(app.input_mode, InputMode::Editing));
+
+        app.messages.state.select(Some(0));
+        app.reply_to_selected();
+        app.input = "sure".to_string();
+        app.discard_draft();
+        assert!(app.input.is_empty());
+        assert!(app.replying.is_none());
+    }
+
+    /// An app holding one of our messages, selected
+    fn app_sending(id: u64) -> App {
+        let mut app = App::default();
+        let mut own = ChatMessage::local("me", "hi".to_string());
+        own.id = id;
+        app.push(own);
+        app
+    }
+
+    fn last_notice(app: &App) -> &str {
+        &app.messages.items.last().unwrap().body
+    }
+
+    #[test]
+    fn a_lost_connection_fails_the_unsent_message() {
+        let mut app = app_sending(7);
+        app.connection_lost(Some(7));
+        assert_eq!(last_notice(&app), "message not sent, connection lost");
+        app.messages.state.select(Some(0));
+        assert_eq!(app.selected_failed().map(|m| m.id), Some(7));
+
+        app.connection_lost(None);
+        assert_eq!(last_notice(&app), "connection lost");
+    }
+
+    #[test]
+    fn a_full_queue_offers_a_retry() {
+        let mut app = app_sending(7);
+        app.command_refused(TrySendError::Full(Command::Room(0)), Some(7));
+        assert_eq!(last_notice(&app), "network busy, message not sent, press r to retry");
+        app.messages.state.select(Some(0));
+        assert!(app.selected_failed().is_some());
+
+        app.command_refused(TrySendError::Closed(Command::Room(0)), None);
+        assert_eq!(last_notice(&app), "connection lost");
+    }
+
+    #[test]
+    fn only_our_failed_messages_are_retried() {
+        let mut app = app_sending(7);
+        app.messages.state.select(Some(0));
+        assert!(app.selected_failed().is_none());
+        let mut remote = ChatMessage::remote("alice", "hi".to_string());
+        remote.delivery = Delivery::Failed;
+        app.push(remote);
+        assert!(app.selected_failed().is_none());
+    }
+
+    fn from(name: &str, author: &str) -> ChatMessage {
+        let mut message = ChatMessage::remote(name, "hi".to_string());
+        message.author = Some(author.to_string());
+        message
+    }
+
+    fn senders(app: &App) -> Vec<&str> {
+        app.messages.items.iter().filter(|m| m.kind == Kind::Remote).map(|m| m.sender.as_str()).collect()
+    }
+
+    #[test]
+    fn suffixes_the_last_characters_of_the_peer_id() {
+        assert_eq!(disambiguated("alice", ALICE), "alice#x6nXTN");
+        assert_eq!(disambiguated("alice", "abc"), "alice#abc");
+    }
+
+    #[test]
+    fn tells_apart_peers_sharing_a_name() {
+        let mut app = app_pinning_in("collide");
+        app.receive(from("alice", ALICE));
+        assert_eq!(senders(&app), ["alice"]);
+        app.receive(from("alice", MALLORY));
+        let (alice, mallory) = (disambiguated("alice", ALICE), disambiguated("alice", MALLORY));
+        assert_eq!(senders(&app), [&alice, &mallory]);
+        assert!(app.messages.items.iter().any(|m| m.body == format!("2 peers go by alice, showing the new one as {}", mallory)));
+        // the same peer keeps its suffix
+        app.receive(from("alice", ALICE));
+        assert_eq!(senders(&app), [&alice, &mallory, &alice]);
+    }
+
+    #[test]
+    fn tells_apart_a_peer_using_our_name() {
+        let mut app = App { name: "me".to_string(), ..app_pinning_in("our-name") };
+        app.receive(from("me", ALICE));
+        assert_eq!(senders(&app), [disambiguated("me", ALICE)]);
+        assert!(app.messages.items.iter().any(|m| m.body.starts_with("another peer also goes by your name me")));
+    }
+
+    /// An app with the history of four messages a minute apart, the third one ours
+    fn app_with_history(base: DateTime<Local>) -> App {
+        let mut app = App::default();
+        app.messages.items = (0..4)
+            .map(|i| {
+                let mut message = match i {
+                    2 => ChatMessage::local("me", format!("message {}", i)),
+                    _ => ChatMessage::remote("alice", format!("message {}", i)),
+                };
+                message.timestamp = base + chrono::Duration::minutes(i);
+                message
+            })
+            .collect();
+        app
+    }
+
+    #[test]
+    fn starts_at_the_top_or_the_bottom() {
+        let mut app = app_with_history(Local::now());
+        app.start_at(StartAt::Top, None);
+        assert_eq!(app.messages.state.selected(), Some(0));
+        assert!(!app.follow_tail);
+
+        let mut app = app_with_history(Local::now());
+        app.start_at(StartAt::Bottom, None);
+        assert_eq!(app.messages.state.selected(), Some(3));
+        assert!(app.follow_tail);
+    }
+
+    #[test]
+    fn starts_at_the_first_unread_message() {
+        let base = Local::now();
+        let mut app = app_with_history(base);
+        // our own message after the marker doesn't count as unread
+        app.start_at(StartAt::Unread, Some(base + chrono::Duration::minutes(1)));
+        assert_eq!(app.messages.state.selected(), Some(3));
+
+        let mut app = app_with_history(base);
+        app.start_at(StartAt::Unread, Some(base));
+        assert_eq!(app.messages.state.selected(), Some(1));
+        assert_eq!(app.unseen, 2);
+        assert!(!app.follow_tail);
+        assert_eq!(app.last_seen(), Some(base + chrono::Duration::minutes(1)));
+    }
+
+    #[test]
+    fn starts_at_the_bottom_without_a_read_marker() {
+        let mut app = app_with_history(Local::now());
+        app.start_at(StartAt::Unread, None);
+        assert_eq!(app.messages.state.selected(), Some(3));
+        assert_eq!(app.unseen, 0);
+
+        let mut empty = App::default();
+        empty.start_at(StartAt::Unread, Some(Local::now()));
+        assert_eq!(empty.messages.state.selected(), None);
+        assert_eq!(empty.last_seen(), None);
+    }
+
+    fn numbered(n: u64) -> ChatMessage {
+        let mut message = from("alice", ALICE);
+        message.body = format!("message {}", n);
+        message.seq = Some(crate::message::Seq { session: 1, n });
+        message
+    }
+
+    fn bodies(app: &App) -> Vec<&str> {
+        app.messages.items.iter().filter(|m| m.kind == Kind::Remote).map(|m| m.body.as_str()).collect()
+    }
+
+    #[test]
+    fn puts_late_messages_back_in_order() {
+        let mut app = App::default();
+        for n in [1, 3, 2] {
+            app.insert_in_order(numbered(n));
+        }
+        assert_eq!(bodies(&app), ["message 1", "message 2", "message 3"]);
+        // the gap before 3 was filled, not lost
+        assert!(app.messages.items.iter().any(|m| m.body == "1 earlier message(s) from alice may be missing"));
+    }
+
+    #[test]
+    fn keeps_the_arrival_order_when_asked() {
+        let mut app = App { reorder: false, ..App::default() };
+        for n in [1, 3, 2] {
+            app.insert_in_order(numbered(n));
+        }
+        assert_eq!(bodies(&app), ["message 1", "message 3", "message 2"]);
+    }
+
+    #[test]
+    fn a_new_session_starts_the_numbering_over() {
+        let mut app = App::default();
+        app.insert_in_order(numbered(5));
+        let mut restarted = numbered(1);
+        restarted.seq = Some(crate::message::Seq { session: 2, n: 1 });
+        app.insert_in_order(restarted);
+        assert_eq!(bodies(&app), ["message 5", "message 1"]);
+        assert!(!app.messages.items.iter().any(|m| m.kind == Kind::System));
+    }
+
+    #[test]
+    fn a_muted_room_counts_nothing_unread() {
+        let dir = std::env::temp_dir().join(format!("tochat-mute-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let mut app = App { muted_file: dir.join("muted"), ..App::default() };
+        app.rooms = vec![Room::new("ops"), Room::new("random")];
+        app.switch_room(1);
+        app.set_muted(true);
+        assert!(app.muted());
+        app.switch_room(0);
+        assert!(!app.muted());
+
+        app.apply(Update::Message(1, ChatMessage::remote("alice", "hi".to_string())));
+        assert_eq!(app.rooms[1].unread, 0);
+        assert_eq!(crate::muted::load(&app.muted_file).unwrap().into_iter().collect::<Vec<_>>(), ["random"]);
+
+        app.switch_room(1);
+        app.set_muted(false);
+        app.switch_room(0);
+        app.apply(Update::Message(1, ChatMessage::remote("alice", "hi".to_string())));
+        assert_eq!(app.rooms[1].unread, 1);
+        assert!(crate::muted::load(&app.muted_file).unwrap().is_empty());
+    }
+
+    #[test]
+    fn ago_rounds_down_to_the_largest_unit() {
+        let now = Local::now();
+        let before = |secs| now - chrono::Duration::seconds(secs);
+        assert_eq!(ago(before(59), now), "just now");
+        assert_eq!(ago(before(60), now), "1m ago");
+        assert_eq!(ago(before(3599), now), "59m ago");
+        assert_eq!(ago(before(3600), now), "1h ago");
+        assert_eq!(ago(before(86400 * 3 + 5), now), "3d ago");
+        // a clock that went back
+        assert_eq!(ago(now + chrono::Duration::seconds(30), now), "just now");
+    }
+
+    #[test]
+    fn seen_lists_the_most_recent_first() {
+        let mut app = App::default();
+        app.report_seen();
+        assert_eq!(last_notice(&app), "no peer wrote yet");
+
+        app.last_seen.insert("bob".to_string(), Local::now() - chrono::Duration::hours(2));
+        app.apply(Update::Message(0, ChatMessage::remote("alice", "hi".to_string())));
+        app.report_seen();
+        assert_eq!(last_notice(&app), "last seen: alice just now, bob 2h ago");
+    }
+
+    #[test]
+    fn a_plain_move_drops_the_anchor() {
+        let mut list = list(0);
+        list.extend(true);
+        list.next();
+        assert_eq!(list.range(), None);
+        assert_eq!(list.selection(), Some(2..=2));
+    }
+}
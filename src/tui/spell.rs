@@ -0,0 +1,109 @@
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::ops::Range;
+use std::path::Path;
+
+use super::links;
+
+/// Word list for `--spellcheck`, one word per line as in `/usr/share/dict/words`
+pub struct Dictionary {
+    words: HashSet<String>,
+}
+
+impl Dictionary {
+    pub fn load(path: &Path) -> io::Result<Dictionary> {
+        let words = fs::read_to_string(path)?
+            .lines()
+            .map(|word| word.trim().to_lowercase())
+            .filter(|word| !word.is_empty())
+            .collect();
+        Ok(Dictionary { words })
+    }
+
+    fn knows(&self, word: &str) -> bool {
+        let lower = word.to_lowercase();
+        // `don't` and `peer's` are usually listed without the ending
+        self.words.contains(&lower)
+            || lower.strip_suffix("'s").map_or(false, |stem| self.words.contains(stem))
+    }
+
+    /// Byte ranges of the words in `text` that aren't in the list. Links, `/commands`,
+    /// `:shortcodes:` and words with digits are left alone
+    pub fn unknown_words(&self, text: &str) -> Vec<Range<usize>> {
+        let urls = links::find_urls(text);
+        let mut unknown = Vec::new();
+        let mut offset = 0;
+        for token in text.split_inclusive(char::is_whitespace) {
+            let start = offset;
+            offset += token.len();
+            let token = token.trim_end();
+            if token.starts_with('/') || (token.starts_with(':') && token.ends_with(':')) {
+                continue;
+            }
+            if urls.iter().any(|url| url.contains(&start)) {
+                continue;
+            }
+            for (at, word) in words(token) {
+                if !word.chars().any(|c| c.is_ascii_digit()) && !self.knows(word) {
+                    unknown.push(start + at..start + at + word.len());
+                }
+            }
+        }
+        unknown
+    }
+}
+
+/// Runs of letters and inner apostrophes in `token` with their byte offsets
+fn words(token: &str) -> Vec<(usize, &str)> {
+    let mut words = Vec::new();
+    let mut start = None;
+    for (i, c) in token.char_indices().chain([(token.len(), ' ')]) {
+        let inside = c.is_alphanumeric() || (c == '\'' && start.is_some());
+        match (start, inside) {
+            (None, true) => start = Some(i),
+            (Some(s), false) => {
+                let word = token[s..i].trim_end_matches('\'');
+                words.push((s, word));
+                start = None;
+            }
+            _ => {}
+        }
+    }
+    words
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dictionary() -> Dictionary {
+        let words = ["hello", "world", "don't", "peer"].iter().map(|word| word.to_string()).collect();
+        Dictionary { words }
+    }
+
+    #[test]
+    fn finds_the_unknown_words() {
+        assert_eq!(dictionary().unknown_words("hello wrold"), [6..11]);
+        assert!(dictionary().unknown_words("Hello, World!").is_empty());
+    }
+
+    #[test]
+    fn knows_listed_contractions_and_possessives() {
+        assert!(dictionary().unknown_words("don't peer's").is_empty());
+        assert_eq!(dictionary().unknown_words("peers'"), [0..5]);
+    }
+
+    #[test]
+    fn leaves_commands_shortcodes_numbers_and_links_alone() {
+        assert_eq!(dictionary().unknown_words("/msg bob"), [5..8]);
+        assert!(dictionary().unknown_words(":wave: 2nd https://exmaple.com/xyz").is_empty());
+    }
+
+    #[test]
+    fn splits_words_at_punctuation() {
+        assert_eq!(words("rock'n'roll,"), [(0, "rock'n'roll")]);
+        assert_eq!(words("'tis (so)"), [(1, "tis"), (6, "so")]);
+        assert_eq!(words("it's'"), [(0, "it's")]);
+    }
+}
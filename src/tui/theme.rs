@@ -0,0 +1,219 @@
+use std::env;
+
+use tui::style::{Color, Modifier, Style};
+
+/// Sender colors of `--color-names` without `--name-colors`: light ANSI colors that read well
+/// on a dark background and the terminal maps to its own scheme
+pub const NAME_COLORS: &[Color] = &[
+    Color::LightCyan,
+    Color::LightGreen,
+    Color::LightMagenta,
+    Color::LightBlue,
+    Color::LightRed,
+    Color::Yellow,
+    Color::Cyan,
+    Color::Green,
+    Color::Magenta,
+];
+
+/// Styles the UI is drawn with, `monochrome` is for terminals without color support
+pub struct Theme {
+    /// Whether cues normally carried by color are shown as text markers instead
+    pub monochrome: bool,
+    pub header: Style,
+    pub body: Style,
+    pub link: Style,
+    pub code: Style,
+    /// In fenced code blocks: their frame, and the highlighted keywords, strings and comments
+    pub code_border: Style,
+    pub keyword: Style,
+    pub string: Style,
+    pub comment: Style,
+    /// The answered message above a reply
+    pub quote: Style,
+    /// Headers of messages a peer replayed when we joined
+    pub replayed: Style,
+    /// Words `--spellcheck` doesn't know, in the input
+    pub misspelled: Style,
+    /// Status line flags like `[away]`
+    pub flag: Style,
+    pub unseen: Style,
+    pub prompt: Style,
+    /// The Normal mode help line
+    pub hint: Style,
+    pub highlight: Style,
+    pub editing: Style,
+    /// Sender colors with `--color-names`, none leaves every name in `header`
+    pub names: Vec<Color>,
+}
+
+impl Theme {
+    pub fn color() -> Theme {
+        Theme {
+            monochrome: false,
+            header: Style::default().fg(Color::White),
+            body: Style::default().fg(Color::LightYellow),
+            link: Style::default().fg(Color::LightBlue).add_modifier(Modifier::UNDERLINED),
+            code: Style::default().fg(Color::Cyan).bg(Color::DarkGray),
+            code_border: Style::default().fg(Color::DarkGray),
+            keyword: Style::default().fg(Color::LightMagenta).add_modifier(Modifier::BOLD),
+            string: Style::default().fg(Color::LightGreen),
+            comment: Style::default().fg(Color::Gray).add_modifier(Modifier::ITALIC),
+            quote: Style::default().fg(Color::Gray).add_modifier(Modifier::ITALIC),
+            replayed: Style::default().fg(Color::DarkGray),
+            misspelled: Style::default().fg(Color::LightRed).add_modifier(Modifier::UNDERLINED),
+            flag: Style::default().fg(Color::DarkGray),
+            unseen: Style::default().fg(Color::LightGreen),
+            prompt: Style::default().fg(Color::LightRed),
+            hint: Style::default().add_modifier(Modifier::RAPID_BLINK),
+            highlight: Style::default().bg(Color::Rgb(40, 40, 40)),
+            editing: Style::default().fg(Color::Yellow),
+            names: Vec::new(),
+        }
+    }
+
+    /// Only the 16 ANSI colors, which the terminal maps to its own color scheme
+    pub fn terminal() -> Theme {
+        Theme {
+            highlight: Style::default().bg(Color::DarkGray),
+            ..Theme::color()
+        }
+    }
+
+    /// Only bold, underline and reverse video, which even basic terminals render
+    pub fn monochrome() -> Theme {
+        Theme {
+            monochrome: true,
+            header: Style::default().add_modifier(Modifier::BOLD),
+            body: Style::default(),
+            link: Style::default().add_modifier(Modifier::UNDERLINED),
+            code: Style::default().add_modifier(Modifier::REVERSED),
+            code_border: Style::default(),
+            keyword: Style::default().add_modifier(Modifier::BOLD),
+            string: Style::default(),
+            comment: Style::default(),
+            quote: Style::default(),
+            replayed: Style::default(),
+            misspelled: Style::default().add_modifier(Modifier::UNDERLINED),
+            flag: Style::default(),
+            unseen: Style::default().add_modifier(Modifier::BOLD),
+            prompt: Style::default().add_modifier(Modifier::BOLD),
+            hint: Style::default(),
+            highlight: Style::default().add_modifier(Modifier::REVERSED),
+            editing: Style::default(),
+            names: Vec::new(),
+        }
+    }
+
+    /// The header of a message signed by `author`, a base58 PeerId. Each PeerId gets the same
+    /// color of `names` every time, so a sender keeps its color however it is named
+    pub fn sender(&self, author: Option<&str>) -> Style {
+        match author {
+            Some(author) if !self.monochrome && !self.names.is_empty() => {
+                self.header.fg(self.names[palette_index(author, self.names.len())])
+            }
+            _ => self.header,
+        }
+    }
+
+    /// Monochrome when `NO_COLOR` is set to anything (https://no-color.org) or `TERM` is `dumb`,
+    /// otherwise the terminal's palette with `--terminal-colors`
+    pub fn detect(terminal_colors: bool) -> Theme {
+        let no_color = env::var_os("NO_COLOR").map_or(false, |v| !v.is_empty());
+        let dumb = env::var("TERM").map_or(false, |term| term == "dumb");
+        if no_color || dumb {
+            Theme::monochrome()
+        } else if terminal_colors {
+            Theme::terminal()
+        } else {
+            Theme::color()
+        }
+    }
+}
+
+/// FNV-1a of `author` into `0..len`, unlike std's hasher it is fixed across builds and platforms
+fn palette_index(author: &str, len: usize) -> usize {
+    let hash = author.bytes().fold(0xcbf29ce484222325u64, |hash, b| (hash ^ b as u64).wrapping_mul(0x100000001b3));
+    (hash % len as u64) as usize
+}
+
+/// Parse a `--name-colors` entry: an ANSI color name like `lightcyan` or `#rrggbb`. Colors
+/// too dark to read on the background are refused
+pub fn parse_name_color(s: &str) -> Result<Color, String> {
+    let name = s.trim().to_ascii_lowercase().replace(['-', '_'], "");
+    let color = match name.as_str() {
+        "red" => Color::Red,
+        "green" => Color::Green,
+        "yellow" => Color::Yellow,
+        "blue" => Color::Blue,
+        "magenta" => Color::Magenta,
+        "cyan" => Color::Cyan,
+        "gray" | "grey" => Color::Gray,
+        "lightred" => Color::LightRed,
+        "lightgreen" => Color::LightGreen,
+        "lightyellow" => Color::LightYellow,
+        "lightblue" => Color::LightBlue,
+        "lightmagenta" => Color::LightMagenta,
+        "lightcyan" => Color::LightCyan,
+        "white" => Color::White,
+        "black" | "darkgray" | "darkgrey" => return Err(format!("color `{}` is too dark to read on the background", s)),
+        hex => {
+            let rgb = hex
+                .strip_prefix('#')
+                .filter(|hex| hex.len() == 6)
+                .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+                .ok_or_else(|| format!("unknown color `{}`, use an ANSI color name like lightcyan or #rrggbb", s))?;
+            let (r, g, b) = ((rgb >> 16) as u8, (rgb >> 8) as u8, rgb as u8);
+            // perceived brightness, the message list is drawn on a dark background
+            if 299 * r as u32 + 587 * g as u32 + 114 * b as u32 < 90_000 {
+                return Err(format!("color `{}` is too dark to read on the background", s));
+            }
+            Color::Rgb(r, g, b)
+        }
+    };
+    Ok(color)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALICE: &str = "12D3KooWDpJ7As7BWAwRMfu1VU2WCqNjvq387JEYKDBj4kx6nXTN";
+    const BOB: &str = "12D3KooWD3eckifWpRn9wQpMG9R9hX3sD158z7EqHWmweQAJU5SA";
+
+    #[test]
+    fn a_peer_id_always_gets_the_same_color() {
+        // golden values, a change here recolors every sender
+        assert_eq!(palette_index(ALICE, 9), 6);
+        assert_eq!(palette_index(BOB, 9), 7);
+        assert_eq!(palette_index(ALICE, 1000), 450);
+    }
+
+    #[test]
+    fn names_are_colored_only_with_a_palette_and_colors() {
+        let mut theme = Theme::color();
+        assert_eq!(theme.sender(Some(ALICE)), theme.header);
+        theme.names = NAME_COLORS.to_vec();
+        assert_eq!(theme.sender(Some(ALICE)), theme.header.fg(Color::Cyan));
+        assert_eq!(theme.sender(None), theme.header);
+
+        let mut monochrome = Theme::monochrome();
+        monochrome.names = NAME_COLORS.to_vec();
+        assert_eq!(monochrome.sender(Some(ALICE)), monochrome.header);
+    }
+
+    #[test]
+    fn parses_color_names_and_hex() {
+        assert_eq!(parse_name_color("LightCyan"), Ok(Color::LightCyan));
+        assert_eq!(parse_name_color(" light-cyan "), Ok(Color::LightCyan));
+        assert_eq!(parse_name_color("#ff8800"), Ok(Color::Rgb(255, 136, 0)));
+    }
+
+    #[test]
+    fn refuses_unknown_and_dark_colors() {
+        assert!(parse_name_color("black").unwrap_err().contains("too dark"));
+        assert!(parse_name_color("#333333").unwrap_err().contains("too dark"));
+        assert!(parse_name_color("#fff").unwrap_err().contains("unknown color"));
+        assert!(parse_name_color("purple").unwrap_err().contains("unknown color"));
+    }
+}
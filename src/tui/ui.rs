@@ -1,85 +1,647 @@
 use tui::{
     backend::Backend,
-    layout::{Constraint, Direction, Layout},
-    style::{Color, Modifier, Style},
+    layout::{Constraint, Direction, Layout, Rect},
+    style::{Modifier, Style},
     text::{Span, Spans, Text},
-    widgets::{Block, Borders, List, ListItem, Paragraph},
+    widgets::{Block, Borders, Clear, List, ListItem, Paragraph},
     Frame,
 };
 
-use super::{InputMode, App};
+use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
 
+use super::code::{self, Token};
+use super::markup::{self, Format};
+use super::theme::Theme;
+use super::spell::Dictionary;
+use super::{ago, links, InputMode, App};
+use crate::config::{EnterKey, InputEcho, INPUT_HEIGHT};
+use crate::message::{ChatMessage, Delivery, Kind, Quote};
+use crate::network::health::{Health, RESERVATION_TTL};
+use crate::network::relay_link::RelayStatus;
+use crate::network::short_peer_id;
+use libp2p::PeerId;
+use chrono::Local;
+use std::time::Duration;
+
+/// Suffix for the header of our own messages showing whether they went out
+fn delivery_marker(message: &ChatMessage) -> &'static str {
+    match (message.kind, message.delivery) {
+        (Kind::Local, Delivery::Pending) => " …",
+        (Kind::Local, Delivery::Delivered) => " ✓",
+        (Kind::Local, Delivery::Failed) => " ✗ not sent",
+        (Kind::Local, Delivery::Unconfirmed) => " ? unconfirmed",
+        _ => "",
+    }
+}
+
+/// Key bindings and commands listed by the `?` overlay, keep in sync with `app::run_app`
+const HELP: &[(&str, &str)] = &[
+    ("Normal mode", ""),
+    ("i", "start editing"),
+    ("j / Down", "select the next message"),
+    ("k / Up", "select the previous message"),
+    ("Home / End, G", "select the first / last message"),
+    ("u", "select the first unread message"),
+    ("J / K", "select a range of messages, as Shift+Down / Up"),
+    ("Esc", "back to a single selected message"),
+    ("Left", "clear the selection"),
+    ("y", "copy the selected messages as a quote"),
+    ("o", "open the links in the selected message"),
+    ("f", "toggle following new messages"),
+    ("h", "show / hide the network health sidebar"),
+    ("l", "show / hide the connection event log, j / k scroll it"),
+    ("p", "show / hide the short PeerId next to senders' names"),
+    ("c", "toggle the compact one line display"),
+    ("r", "resend the selected message if it failed"),
+    ("R", "reply to the selected message"),
+    ("e", "edit your last message"),
+    ("+ / -", "grow / shrink the input pane"),
+    ("Tab / Shift+Tab", "channels with --join, the next / previous room"),
+    ("1 - 9", "channels with --join, the room with this number"),
+    ("?", "show / hide this help"),
+    ("q", "quit"),
+    ("", ""),
+    ("Editing mode", ""),
+    ("Enter", "send the message, a new line with --enter newline"),
+    ("Alt+Enter, Ctrl+S", "send the message with --enter newline"),
+    ("Esc", "back to normal mode, keeping the draft"),
+    ("Ctrl+C", "discard the draft, the reply or the edit"),
+    ("Ctrl+E", "show the draft as typed, as dots or not at all"),
+    ("", ""),
+    ("Commands", ""),
+    ("/peers", "show connected peers and the topic mesh"),
+    ("/stats", "show uptime, message counts, traffic and round trip times"),
+    ("/address", "show the address peers reach you at through the relay"),
+    ("/export <file>", "save the conversation, .md or .json picks the format"),
+    ("/seen", "show when each peer last wrote"),
+    ("/whois", "show the full PeerId of the selected message's sender"),
+    ("/verify [name]", "show the safety number to compare with a peer out of band"),
+    ("/upload <file>", "upload to --upload-url, the link goes into the draft"),
+    ("/delete", "delete the selected message if it is yours"),
+    ("/mute, /unmute", "stop / start counting unread messages and ringing the bell for this room"),
+    ("/msg <name>", "followed by text, send a message only that peer can read"),
+    ("/direct", "dm sessions, use the direct path to the peer"),
+    ("/relay", "dm sessions, use the relay"),
+    ("/auto", "dm sessions, direct unless it degrades"),
+];
+
+/// A `percent_x` by `percent_y` rectangle centered in `area`
+fn centered_rect(percent_x: u16, percent_y: u16, area: Rect) -> Rect {
+    let vertical = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([
+            Constraint::Percentage((100 - percent_y) / 2),
+            Constraint::Percentage(percent_y),
+            Constraint::Percentage((100 - percent_y) / 2),
+        ])
+        .split(area);
+    Layout::default()
+        .direction(Direction::Horizontal)
+        .constraints([
+            Constraint::Percentage((100 - percent_x) / 2),
+            Constraint::Percentage(percent_x),
+            Constraint::Percentage((100 - percent_x) / 2),
+        ])
+        .split(vertical[1])[1]
+}
+
+fn help_overlay<B: Backend>(f: &mut Frame<B>) {
+    let lines: Vec<Spans> = HELP
+        .iter()
+        .map(|(keys, action)| match *action {
+            "" => Spans::from(Span::styled(*keys, Style::default().add_modifier(Modifier::UNDERLINED))),
+            _ => Spans::from(vec![
+                Span::styled(format!("  {:<12}", keys), Style::default().add_modifier(Modifier::BOLD)),
+                Span::raw(*action),
+            ]),
+        })
+        .collect();
+    let area = centered_rect(60, 70, f.size());
+    let help = Paragraph::new(lines)
+        .block(Block::default().borders(Borders::ALL).title("Help, ? or Esc to close"));
+    f.render_widget(Clear, area);
+    f.render_widget(help, area);
+}
+
+/// The `l` overlay: the connection events, newest at the bottom, `events_scroll` lines up
+fn events_overlay<B: Backend>(f: &mut Frame<B>, app: &App) {
+    let area = centered_rect(80, 70, f.size());
+    let height = area.height.saturating_sub(2) as usize;
+    let end = app.events.len() - app.events_scroll.min(app.events.len());
+    let lines: Vec<Spans> = app
+        .events
+        .iter()
+        .take(end)
+        .skip(end.saturating_sub(height))
+        .map(|(at, line)| {
+            let at = Span::styled(format!("{} ", at.format("%H:%M:%S")), Style::default().add_modifier(Modifier::DIM));
+            Spans::from(vec![at, Span::raw(line.clone())])
+        })
+        .collect();
+    let title = match app.events_scroll {
+        0 => format!("Events ({}), j/k to scroll, l or Esc to close", app.events.len()),
+        up => format!("Events ({}, {} newer below), j/k to scroll, l or Esc to close", app.events.len(), up),
+    };
+    let log = if lines.is_empty() { Paragraph::new("no events yet") } else { Paragraph::new(lines) };
+    f.render_widget(Clear, area);
+    f.render_widget(log.block(Block::default().borders(Borders::ALL).title(title)), area);
+}
+
+/// Columns of the health sidebar, borders included
+const HEALTH_WIDTH: u16 = 34;
+
+/// The `h` sidebar: the relay, the reservation, the mesh and each connected peer
+fn health_panel(health: Option<&Health>, theme: &Theme) -> Paragraph<'static> {
+    let block = Block::default().borders(Borders::ALL).title("Health, h to close");
+    let health = match health {
+        Some(health) => health,
+        None => return Paragraph::new("no network").block(block),
+    };
+    let rtt = |rtt: Option<Duration>| rtt.map_or("-".to_string(), |rtt| format!("{}ms", rtt.as_millis()));
+    let mut lines = vec![Spans::from(Span::styled("Relay", theme.header.add_modifier(Modifier::BOLD)))];
+    if let Some(relay) = &health.relay {
+        lines.push(Spans::from(format!(" {}", short_peer_id(relay))));
+    }
+    lines.push(Spans::from(format!(" {}, rtt {}", health.relay_status, rtt(health.relay_rtt))));
+    if let Some(agent) = &health.relay_agent {
+        lines.push(Spans::from(Span::styled(format!(" {}", agent), theme.flag)));
+    }
+    let reservation = match health.reserved_at {
+        Some(at) => {
+            let left = RESERVATION_TTL.saturating_sub(at.elapsed());
+            format!(" renewed {}m ago, ~{}m left", at.elapsed().as_secs() / 60, left.as_secs() / 60)
+        }
+        None => " none".to_string(),
+    };
+    lines.push(Spans::from(Span::styled("Reservation", theme.header.add_modifier(Modifier::BOLD))));
+    lines.push(Spans::from(reservation));
+    lines.push(Spans::from(Span::styled("Mesh", theme.header.add_modifier(Modifier::BOLD))));
+    lines.push(Spans::from(format!(" {} peers", health.mesh)));
+    lines.push(Spans::from(Span::styled(
+        format!("Peers ({})", health.peers.len()),
+        theme.header.add_modifier(Modifier::BOLD),
+    )));
+    for peer in &health.peers {
+        let link = if peer.direct { "direct" } else { "relayed" };
+        lines.push(Spans::from(format!(" {} {} {}", short_peer_id(&peer.peer), link, rtt(peer.rtt))));
+    }
+    Paragraph::new(lines).block(block)
+}
+
+/// Title of the message list: the tab bar when the session joined several rooms, with
+/// the number to switch to each and the count of messages it got while in the background
+fn room_tabs(app: &App) -> Spans<'static> {
+    if app.rooms.len() < 2 {
+        return Spans::from("Messages");
+    }
+    let mut tabs = Vec::new();
+    for (i, room) in app.rooms.iter().enumerate() {
+        if i > 0 {
+            tabs.push(Span::raw(" │"));
+        }
+        let label = format!(" {} {} ", i + 1, room.topic);
+        if i == app.active {
+            tabs.push(Span::styled(label, Style::default().add_modifier(Modifier::REVERSED | Modifier::BOLD)));
+        } else {
+            tabs.push(Span::styled(label, app.theme.header));
+        }
+        if room.muted {
+            tabs.push(Span::styled("(muted) ", app.theme.flag));
+        } else if room.unread > 0 {
+            tabs.push(Span::styled(format!("({}) ", room.unread), app.theme.unseen));
+        }
+    }
+    Spans::from(tabs)
+}
+
+/// Without colors, our own and tochat's messages are told apart by a prefix
+fn kind_prefix(message: &ChatMessage, theme: &Theme) -> &'static str {
+    match message.kind {
+        Kind::Local if theme.monochrome => "> ",
+        Kind::System if theme.monochrome => "* ",
+        _ => "",
+    }
+}
+
+/// `--compact`: the message on one line as `HH:MM <name> body`, the body cut to what is
+/// left of `width` so the line never wraps. Replies get a `↪` instead of the quote
+fn compact_line(message: &ChatMessage, width: usize, show_peer_ids: bool, theme: &Theme) -> Spans<'static> {
+    let private = if message.private { " (private)" } else { "" };
+    let replayed = if message.replayed { " (replayed)" } else { "" };
+    let head = format!(
+        "{} {}<{}{}>{}{} ",
+        message.timestamp.format("%H:%M"),
+        kind_prefix(message, theme),
+        message.sender,
+        peer_tag(message, show_peer_ids),
+        private,
+        replayed
+    );
+    let marker = format!("{}{}", if message.edited { " (edited)" } else { "" }, delivery_marker(message));
+    let (body, style) = if message.deleted {
+        ("message deleted".to_string(), theme.quote)
+    } else {
+        let reply = if message.reply_to.is_some() { "↪ " } else { "" };
+        (format!("{}{}", reply, message.body.replace('\n', " ")), theme.body)
+    };
+    let room = width.saturating_sub(head.width() + marker.width());
+    Spans::from(vec![
+        Span::styled(head, header_style(message, theme)),
+        Span::styled(ellipsize(&body, room), style),
+        Span::styled(marker, theme.header),
+    ])
+}
+
+/// With `--show-peerids`, the short PeerId that signed a received message, e.g. ` [12D3Ko…kx6nXTN]`.
+/// `/whois` shows it in full
+fn peer_tag(message: &ChatMessage, show_peer_ids: bool) -> String {
+    match &message.author {
+        Some(author) if show_peer_ids && message.kind == Kind::Remote => {
+            let short = author.parse::<PeerId>().map_or_else(|_| author.clone(), |peer| short_peer_id(&peer));
+            format!(" [{}]", short)
+        }
+        _ => String::new(),
+    }
+}
+
+/// Replayed messages get a dimmer header, the marker says it without colors. With
+/// `--color-names` the others are in their sender's color
+fn header_style(message: &ChatMessage, theme: &Theme) -> Style {
+    if message.replayed {
+        theme.replayed
+    } else {
+        theme.sender(message.author.as_deref())
+    }
+}
+
+/// `text` cut to at most `width` columns, ending in `…` when something was cut
+fn ellipsize(text: &str, width: usize) -> String {
+    if text.width() <= width {
+        return text.to_string();
+    }
+    let mut cut = String::new();
+    let mut used = 0;
+    for c in text.chars() {
+        let w = c.width().unwrap_or(0);
+        // one column stays free for the ellipsis
+        if used + w + 1 > width {
+            break;
+        }
+        cut.push(c);
+        used += w;
+    }
+    if width > 0 {
+        cut.push('…');
+    }
+    cut
+}
+
+/// The line shown above a reply, and in the input title while writing one
+fn quote_line(quote: &Quote) -> String {
+    format!(" ↪ {}: {}", quote.sender, quote.snippet)
+}
+
+/// Whether `message` goes under the header of `previous` in the grouped display
+fn continues(previous: &ChatMessage, message: &ChatMessage) -> bool {
+    previous.kind == message.kind
+        && previous.sender == message.sender
+        && previous.private == message.private
+        && previous.replayed == message.replayed
+        && previous.recipient == message.recipient
+        && previous.timestamp.timestamp() / 60 == message.timestamp.timestamp() / 60
+}
+
+/// The message body with its links underlined and `*bold*`, `_italic_` and `` `code` `` rendered.
+/// Links are kept literal so markers inside them don't get styled
+fn body_spans<'a>(body: &'a str, theme: &Theme) -> Spans<'a> {
+    let mut spans = vec![Span::styled(" ", theme.body)];
+    let mut last = 0;
+    for url in links::find_urls(body) {
+        push_formatted(&mut spans, &body[last..url.start], theme);
+        spans.push(Span::styled(&body[url.clone()], theme.link));
+        last = url.end;
+    }
+    push_formatted(&mut spans, &body[last..], theme);
+    Spans::from(spans)
+}
+
+/// The lines of a message body: text lines as `body_spans` renders them, fenced code blocks
+/// framed on the left, top and bottom with their lines kept as sent and highlighted when they
+/// name a language tochat knows. The frame fits the longest line, up to `width`
+fn body_lines<'a>(body: &'a str, width: usize, theme: &Theme) -> Vec<Spans<'a>> {
+    let mut lines = Vec::new();
+    for block in code::blocks(body) {
+        match block {
+            code::Block::Text(text) => lines.extend(text.split('\n').map(|line| body_spans(line, theme))),
+            code::Block::Code { language, lines: code } => {
+                // tabs are zero width in the terminal, so they're expanded
+                let code: Vec<String> = code.iter().map(|line| line.replace('\t', "    ")).collect();
+                let widest = code.iter().map(|line| line.width()).max().unwrap_or(0);
+                let frame = (widest + 3).min(width.saturating_sub(2)).max(4);
+                let title = language.map(|language| format!("─ {} ", language)).unwrap_or_default();
+                let fill = "─".repeat(frame.saturating_sub(title.width() + 1));
+                lines.push(Spans::from(Span::styled(format!(" ┌{}{}", title, fill), theme.code_border)));
+                for line in &code {
+                    let mut spans = vec![Span::styled(" │ ", theme.code_border)];
+                    for (token, piece) in code::highlight(language, line) {
+                        let style = match token {
+                            Token::Plain => theme.code,
+                            Token::Keyword => theme.code.patch(theme.keyword),
+                            Token::String => theme.code.patch(theme.string),
+                            Token::Comment => theme.code.patch(theme.comment),
+                        };
+                        spans.push(Span::styled(piece.to_string(), style));
+                    }
+                    lines.push(Spans::from(spans));
+                }
+                lines.push(Spans::from(Span::styled(format!(" └{}", "─".repeat(frame - 1)), theme.code_border)));
+            }
+        }
+    }
+    lines
+}
+
+fn push_formatted<'a>(spans: &mut Vec<Span<'a>>, text: &'a str, theme: &Theme) {
+    for (format, piece) in markup::parse(text) {
+        let style = match format {
+            Format::Plain => theme.body,
+            Format::Bold => theme.body.add_modifier(Modifier::BOLD),
+            Format::Italic => theme.body.add_modifier(Modifier::ITALIC),
+            Format::Code => theme.code,
+        };
+        spans.push(Span::styled(piece, style));
+    }
+}
+
+/// The draft as `--input-echo` shows it: as typed, a dot per character keeping the line
+/// breaks, or nothing
+fn echoed(input: &str, echo: InputEcho) -> String {
+    match echo {
+        InputEcho::On => input.to_string(),
+        InputEcho::Dots => input.chars().map(|c| if c == '\n' { '\n' } else { '•' }).collect(),
+        InputEcho::Off => String::new(),
+    }
+}
+
+/// The draft, with the words `--spellcheck` doesn't know underlined
+fn input_text<'a>(input: &'a str, dictionary: Option<&Dictionary>, theme: &Theme) -> Text<'a> {
+    let dictionary = match dictionary {
+        Some(dictionary) => dictionary,
+        None => return Text::raw(input),
+    };
+    let unknown = dictionary.unknown_words(input);
+    let mut lines = Vec::new();
+    let mut offset = 0;
+    for line in input.split('\n') {
+        let (start, end) = (offset, offset + line.len());
+        offset = end + 1;
+        let mut spans = Vec::new();
+        let mut last = start;
+        for word in unknown.iter().filter(|word| word.start >= start && word.end <= end) {
+            spans.push(Span::raw(&input[last..word.start]));
+            spans.push(Span::styled(&input[word.clone()], theme.misspelled));
+            last = word.end;
+        }
+        spans.push(Span::raw(&input[last..end]));
+        lines.push(Spans::from(spans));
+    }
+    Text::from(lines)
+}
+
+/// The room left for the messages above the input box in a terminal `height` rows high. When
+/// composing, the input grows past its share to show every line of the draft, up to the largest
+/// share `+` would give it
+fn messages_area(height: u16, input_height: u16, enter: EnterKey, draft: &str) -> Constraint {
+    let inside = height.saturating_sub(4);
+    let share = |percent: u16| (inside as u32 * percent as u32 / 100) as u16;
+    let drafted = draft.matches('\n').count() as u16 + 3;
+    if enter == EnterKey::Newline && drafted > share(input_height) {
+        Constraint::Length(inside - drafted.min(share(*INPUT_HEIGHT.end())))
+    } else {
+        Constraint::Percentage(100 - input_height)
+    }
+}
 
 pub fn ui<B: Backend>(f: &mut Frame<B>, app: &mut App) {
+    let messages = messages_area(f.size().height, app.input_height, app.enter, &app.input);
     let chunks = Layout::default()
         .direction(Direction::Vertical)
         .margin(2)
-        .constraints([Constraint::Percentage(80), Constraint::Percentage(20)].as_ref())
+        // the input keeps its border and one line however small the terminal gets
+        .constraints([messages, Constraint::Min(3)].as_ref())
         .split(f.size());
 
     let top_chunks = Layout::default()
         .direction(Direction::Vertical)
-        .constraints([Constraint::Percentage(15), Constraint::Percentage(85)].as_ref())
+        .constraints([Constraint::Percentage(app.status_height), Constraint::Min(3)].as_ref())
         .split(chunks[0]);
 
-    let (msg, style) = match app.input_mode {
+    let theme = &app.theme;
+    let (mut msg, style) = match app.input_mode {
+        InputMode::Normal if app.quitting => (
+            vec![
+                Span::raw("Quit tochat? "),
+                Span::styled("y", Style::default().add_modifier(Modifier::BOLD)),
+                Span::raw(" or "),
+                Span::styled("q", Style::default().add_modifier(Modifier::BOLD)),
+                Span::raw(" to quit, any other key to stay"),
+            ],
+            theme.prompt,
+        ),
+        InputMode::Normal if !app.key_changes.is_empty() => {
+            let change = &app.key_changes[0];
+            (
+                vec![
+                    Span::raw(format!("The key of {} changed to {}. Accept it? ", change.name, change.new)),
+                    Span::styled("y", Style::default().add_modifier(Modifier::BOLD)),
+                    Span::raw(" to accept and show its messages, "),
+                    Span::styled("n", Style::default().add_modifier(Modifier::BOLD)),
+                    Span::raw(" to reject and drop them"),
+                ],
+                theme.prompt,
+            )
+        }
+        InputMode::Normal if !app.url_choices.is_empty() => {
+            let mut msg = vec![Span::raw("Open which link? ")];
+            for (i, url) in app.url_choices.iter().enumerate() {
+                msg.push(Span::styled(format!("{}", i + 1), Style::default().add_modifier(Modifier::BOLD)));
+                msg.push(Span::raw(format!(" {}  ", url)));
+            }
+            msg.push(Span::raw("any other key to cancel"));
+            (msg, Style::default())
+        }
         InputMode::Normal => (
             vec![
                 Span::raw("Press "),
                 Span::styled("q", Style::default().add_modifier(Modifier::BOLD)),
                 Span::raw(" to exit, "),
                 Span::styled("i", Style::default().add_modifier(Modifier::BOLD)),
-                Span::raw(" to start editing."),
+                Span::raw(if app.read_only { " (disabled, read-only), " } else { " to start editing, " }),
+                Span::styled("o", Style::default().add_modifier(Modifier::BOLD)),
+                Span::raw(" to open links, "),
+                Span::styled("f", Style::default().add_modifier(Modifier::BOLD)),
+                Span::raw(" to toggle following new messages, "),
+                Span::styled("?", Style::default().add_modifier(Modifier::BOLD)),
+                Span::raw(" for help."),
             ],
-            Style::default().add_modifier(Modifier::RAPID_BLINK),
+            theme.hint,
         ),
         InputMode::Editing => (
             vec![
                 Span::raw("Press "),
                 Span::styled("Esc", Style::default().add_modifier(Modifier::BOLD)),
                 Span::raw(" to stop editing, "),
-                Span::styled("Enter", Style::default().add_modifier(Modifier::BOLD)),
-                Span::raw(" to record the message"),
+                Span::styled("Ctrl+C", Style::default().add_modifier(Modifier::BOLD)),
+                Span::raw(" to discard the draft, "),
+                Span::styled(
+                    if app.enter == EnterKey::Newline { "Alt+Enter" } else { "Enter" },
+                    Style::default().add_modifier(Modifier::BOLD),
+                ),
+                Span::raw(if app.enter == EnterKey::Newline {
+                    " to record the message, Enter for a new line"
+                } else {
+                    " to record the message"
+                }),
             ],
             Style::default(),
         ),
     };
+    if let Some(text) = app.flashing() {
+        msg.push(Span::styled(format!("  {}", text), theme.prompt));
+    }
+    if let Some(path) = &app.path {
+        msg.push(Span::styled(format!("  [{}]", path), theme.flag));
+        if let (None, Some(seen)) = (path.path, app.peer_seen) {
+            msg.push(Span::styled(format!("  [peer last seen {}]", ago(seen, Local::now())), theme.flag));
+        }
+    }
+    if let Some(address) = &app.address {
+        msg.push(Span::styled(format!("  [at {}]", address), theme.flag));
+    }
+    if let Some(choice) = &app.relay_choice {
+        msg.push(Span::styled(format!("  [{}]", choice), theme.flag));
+    }
+    if app.relay != RelayStatus::Connected {
+        msg.push(Span::styled(format!("  [{}]", app.relay), theme.flag));
+    }
+    if app.read_only {
+        msg.push(Span::styled("  [read-only]", theme.flag));
+    }
+    if app.away {
+        msg.push(Span::styled("  [away]", theme.flag));
+    }
+    if app.muted() {
+        msg.push(Span::styled("  [muted]", theme.flag));
+    }
+    if !app.follow_tail {
+        msg.push(Span::styled("  [paused]", theme.flag));
+    }
+    if app.unseen > 0 {
+        msg.push(Span::styled(format!("  ▼ {} new", app.unseen), theme.unseen));
+    }
     let mut text = Text::from(Spans::from(msg));
     text.patch_style(style);
     let help_message = Paragraph::new(text).block(Block::default());
     f.render_widget(help_message, top_chunks[0]);
 
-    // messages display area
-    let messages: Vec<ListItem> = app
-        .messages
-        .items
+    // the health sidebar takes the right of the message list
+    let list_area = if app.show_health {
+        let columns = Layout::default()
+            .direction(Direction::Horizontal)
+            .constraints([Constraint::Min(20), Constraint::Length(HEALTH_WIDTH)].as_ref())
+            .split(top_chunks[1]);
+        f.render_widget(health_panel(app.health.as_ref(), theme), columns[1]);
+        columns[0]
+    } else {
+        top_chunks[1]
+    };
+
+    // messages display area, one item per message so the selection maps to messages
+    let items = &app.messages.items;
+    // inside the borders
+    let width = list_area.width.saturating_sub(2) as usize;
+    let messages: Vec<ListItem> = items
         .iter()
         .enumerate()
-        .map(|(_, m)| {
-            let c: Vec<_> = m.split("-").collect();
-            let content = vec![
-                Spans::from(Span::styled(format!("{}", c[0]), Style::default().fg(Color::White))),
-                Spans::from(Span::styled(format!("{}", c[1]), Style::default().fg(Color::LightYellow))),
-            ];
-            ListItem::new(content)
+        .map(|(i, m)| {
+            if app.compact {
+                return ListItem::new(compact_line(m, width, app.show_peer_ids, theme));
+            }
+            let mut body = if m.deleted {
+                vec![Spans::from(Span::styled(" message deleted", theme.quote))]
+            } else {
+                body_lines(&m.body, width, theme)
+            };
+            let edited = if m.edited { " (edited)" } else { "" };
+            let private = match (m.private, &m.recipient) {
+                (true, Some(recipient)) => format!(" (private to {})", recipient),
+                (true, None) => " (private)".to_string(),
+                _ => String::new(),
+            };
+            let quote = app.quoted(m).map(|q| Spans::from(Span::styled(quote_line(&q), theme.quote)));
+            if app.grouped && i > 0 && continues(&items[i - 1], m) {
+                // the collapsed header would have carried these markers
+                if let Some(last) = body.last_mut() {
+                    last.0.push(Span::styled(format!("{}{}", edited, delivery_marker(m)), theme.header));
+                }
+                return ListItem::new(quote.into_iter().chain(body).collect::<Vec<_>>());
+            }
+            let header = format!(
+                "{}{}{} {}{}{}{}{}",
+                kind_prefix(m, theme),
+                m.sender,
+                peer_tag(m, app.show_peer_ids),
+                m.timestamp.format("%H:%M:%S"),
+                private,
+                if m.replayed { " (replayed)" } else { "" },
+                edited,
+                delivery_marker(m)
+            );
+            let header = Spans::from(Span::styled(header, header_style(m, theme)));
+            ListItem::new([header].into_iter().chain(quote).chain(body).collect::<Vec<_>>())
+        })
+        .collect();
+    // the rest of a range selected with Shift, the selection itself is highlighted by the list
+    let range = app.messages.range();
+    let messages: Vec<ListItem> = messages
+        .into_iter()
+        .enumerate()
+        .map(|(i, item)| match &range {
+            Some(range) if range.contains(&i) => item.style(theme.highlight),
+            _ => item,
         })
         .collect();
     let messages =
         List::new(messages)
-            .block(Block::default().borders(Borders::ALL).title("Messages"))
-            .highlight_style(
-                Style::default()
-                .bg(Color::Rgb(40, 40, 40)),
-            );
-        f.render_stateful_widget(messages, top_chunks[1], &mut app.messages.state);
+            .block(Block::default().borders(Borders::ALL).title(room_tabs(app)))
+            .highlight_style(theme.highlight);
+        f.render_stateful_widget(messages, list_area, &mut app.messages.state);
 
     // input area
-    let input = Paragraph::new(app.input.as_ref())
+    let mut title = match (&app.replying, app.amending) {
+        (Some(quote), _) => format!("Input, {}", quote_line(quote).trim_start()),
+        (None, Some(_)) => "Input, editing your last message".to_string(),
+        (None, None) => "Input".to_string(),
+    };
+    let length = app.input.chars().count();
+    if length > app.warn_length {
+        title.push_str(&format!(" (long message, {} characters)", length));
+    }
+    if app.input_echo != InputEcho::On {
+        title.push_str(" (hidden)");
+    }
+    let shown = echoed(&app.input, app.input_echo);
+    let text = match app.input_echo {
+        InputEcho::On => input_text(&app.input, app.dictionary.as_ref(), theme),
+        // underlining unknown words would give them away
+        _ => Text::raw(shown.as_str()),
+    };
+    let input = Paragraph::new(text)
         .style(match app.input_mode {
             InputMode::Normal => Style::default(),
-            InputMode::Editing => Style::default().fg(Color::Yellow),
+            InputMode::Editing => theme.editing,
         })
-        .block(Block::default().borders(Borders::ALL).title("Input"));
+        .block(Block::default().borders(Borders::ALL).title(title));
     f.render_widget(input, chunks[1]);
     match app.input_mode {
         InputMode::Normal =>
@@ -88,12 +650,84 @@ pub fn ui<B: Backend>(f: &mut Frame<B>, app: &mut App) {
 
         InputMode::Editing => {
             // Make the cursor visible and ask tui-rs to put it at the specified coordinates after rendering
+            // a pasted draft can span lines, the cursor goes after the last one
+            let last_line = shown.rsplit('\n').next().unwrap_or_default();
+            let line = shown.matches('\n').count() as u16;
             f.set_cursor(
                 // Put cursor past the end of the input text
-                chunks[1].x + app.input.len() as u16 + 1,
-                // Move one line down, from the border to the input line
-                chunks[1].y + 1,
+                chunks[1].x + last_line.width() as u16 + 1,
+                // Move down from the border to the input line, staying inside the box
+                chunks[1].y + 1 + line.min(chunks[1].height.saturating_sub(3)),
             )
         }
     }
+
+    if app.show_events {
+        events_overlay(f, app);
+    }
+    if app.show_help {
+        help_overlay(f);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ellipsize_cuts_to_the_width() {
+        assert_eq!(ellipsize("hello", 10), "hello");
+        assert_eq!(ellipsize("hello", 5), "hello");
+        assert_eq!(ellipsize("hello world", 6), "hello…");
+        assert_eq!(ellipsize("hello", 0), "");
+    }
+
+    #[test]
+    fn ellipsize_counts_wide_characters() {
+        assert_eq!(ellipsize("日本語", 4), "日…");
+        assert!(ellipsize("日本語です", 7).width() <= 7);
+    }
+
+    #[test]
+    fn echo_masks_the_draft_but_keeps_its_lines() {
+        assert_eq!(echoed("hi\nthere", InputEcho::On), "hi\nthere");
+        assert_eq!(echoed("hi\nthere", InputEcho::Dots), "••\n•••••");
+        assert_eq!(echoed("héllo", InputEcho::Dots), "•••••");
+        assert_eq!(echoed("hi", InputEcho::Off), "");
+    }
+
+    #[test]
+    fn compact_lines_never_wrap() {
+        let theme = Theme::color();
+        let message = ChatMessage::remote("alice", "a long\nmessage ".repeat(20));
+        let line = compact_line(&message, 40, false, &theme);
+        assert_eq!(line.width(), 40);
+        assert!(line.0.iter().all(|span| !span.content.contains('\n')));
+        assert!(line.0[1].content.ends_with('…'));
+    }
+
+    #[test]
+    fn compact_lines_mark_replies_and_deletes() {
+        let theme = Theme::monochrome();
+        let mut message = ChatMessage::local("me", "sure".to_string());
+        message.reply_to = Some(Quote::of(&ChatMessage::remote("alice", "lunch?".to_string())));
+        let line = compact_line(&message, 80, false, &theme);
+        assert!(line.0[0].content.ends_with("> <me> "));
+        assert_eq!(line.0[1].content, "↪ sure");
+
+        message.deleted = true;
+        let line = compact_line(&message, 80, false, &theme);
+        assert_eq!(line.0[1].content, "message deleted");
+    }
+
+    #[test]
+    fn the_input_grows_with_a_multi_line_draft() {
+        // 100 rows leave 96 inside the margins, of which the input gets 20% or at most 80%
+        let draft = "line\n".repeat(30);
+        assert_eq!(messages_area(100, 20, EnterKey::Send, &draft), Constraint::Percentage(80));
+        assert_eq!(messages_area(100, 20, EnterKey::Newline, "one\ntwo"), Constraint::Percentage(80));
+        assert_eq!(messages_area(100, 20, EnterKey::Newline, &draft), Constraint::Length(96 - 33));
+        let draft = "line\n".repeat(80);
+        assert_eq!(messages_area(100, 20, EnterKey::Newline, &draft), Constraint::Length(96 - 76));
+    }
 }
@@ -0,0 +1,137 @@
+//! `--upload-url`: images are posted to a file host instead of sent over the network, and the
+//! link it answers with goes into the draft. Hosts like 0x0.st take a multipart form with the
+//! file in one field and answer with the URL as plain text
+
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use bytes::Bytes;
+use futures::stream;
+use reqwest::multipart::{Form, Part};
+use reqwest::{Body, Client, Url};
+use tokio::sync::mpsc::{self, Receiver, Sender};
+
+/// Largest file uploaded, it is read into memory first
+const MAX_UPLOAD: u64 = 64 * 1024 * 1024;
+/// Bytes between two progress reports
+const CHUNK: usize = 64 * 1024;
+/// Uploads may take a while on a slow link, but not forever
+const TIMEOUT: Duration = Duration::from_secs(300);
+
+/// What an upload reports back to the TUI, which polls for it between frames
+#[derive(Debug)]
+pub enum UploadEvent {
+    Progress { name: String, sent: u64, total: u64 },
+    Done { name: String, url: String },
+    Failed { name: String, error: String },
+}
+
+/// Runs uploads in tasks of their own, so the TUI never waits for the host
+pub struct Uploader {
+    client: Client,
+    url: Url,
+    token: Option<String>,
+    field: String,
+    tx: Sender<UploadEvent>,
+    rx: Receiver<UploadEvent>,
+}
+
+impl Uploader {
+    pub fn new(url: Url, token: Option<String>, field: String) -> reqwest::Result<Uploader> {
+        let client = Client::builder().timeout(TIMEOUT).build()?;
+        let (tx, rx) = mpsc::channel(64);
+        Ok(Uploader { client, url, token, field, tx, rx })
+    }
+
+    /// Start uploading `path`, its outcome arrives through `poll`
+    pub fn start(&self, path: PathBuf) {
+        let (client, url, token, field, tx) =
+            (self.client.clone(), self.url.clone(), self.token.clone(), self.field.clone(), self.tx.clone());
+        let name = file_name(&path);
+        tokio::spawn(async move {
+            let event = match upload(&client, url, token, field, &path, &name, &tx).await {
+                Ok(url) => UploadEvent::Done { name, url },
+                Err(error) => UploadEvent::Failed { name, error },
+            };
+            let _ = tx.send(event).await;
+        });
+    }
+
+    /// The next event of a running upload, without waiting
+    pub fn poll(&mut self) -> Option<UploadEvent> {
+        self.rx.try_recv().ok()
+    }
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name().map_or_else(|| path.display().to_string(), |name| name.to_string_lossy().into_owned())
+}
+
+/// Whether `path` names an image file by its extension, a pasted path to one is uploaded
+pub fn is_image(path: &Path) -> bool {
+    mime(path).starts_with("image/")
+}
+
+fn mime(path: &Path) -> &'static str {
+    let extension = path.extension().map(|e| e.to_string_lossy().to_ascii_lowercase());
+    match extension.as_deref() {
+        Some("png") => "image/png",
+        Some("jpg") | Some("jpeg") => "image/jpeg",
+        Some("gif") => "image/gif",
+        Some("webp") => "image/webp",
+        Some("bmp") => "image/bmp",
+        _ => "application/octet-stream",
+    }
+}
+
+async fn upload(
+    client: &Client,
+    url: Url,
+    token: Option<String>,
+    field: String,
+    path: &Path,
+    name: &str,
+    progress: &Sender<UploadEvent>,
+) -> Result<String, String> {
+    let size = tokio::fs::metadata(path).await.map_err(|e| format!("cannot read {}: {}", path.display(), e))?.len();
+    if size > MAX_UPLOAD {
+        return Err(format!("{} is larger than {} MiB", name, MAX_UPLOAD / 1024 / 1024));
+    }
+    let data = tokio::fs::read(path).await.map_err(|e| format!("cannot read {}: {}", path.display(), e))?;
+
+    // reported as the body is streamed to the host
+    let chunks: Vec<Bytes> = data.chunks(CHUNK).map(Bytes::copy_from_slice).collect();
+    let (tx, total, shown) = (progress.clone(), size, name.to_string());
+    let mut sent = 0;
+    let body = stream::iter(chunks.into_iter().map(move |chunk| {
+        sent += chunk.len() as u64;
+        let _ = tx.try_send(UploadEvent::Progress { name: shown.clone(), sent, total });
+        Ok::<_, std::io::Error>(chunk)
+    }));
+    let part = Part::stream_with_length(Body::wrap_stream(body), size)
+        .file_name(name.to_string())
+        .mime_str(mime(path))
+        .map_err(|e| e.to_string())?;
+    let mut request = client.post(url).multipart(Form::new().part(field, part));
+    if let Some(token) = token {
+        request = request.bearer_auth(token);
+    }
+
+    let response = request.send().await.map_err(|e| {
+        if e.is_connect() || e.is_timeout() {
+            format!("the upload host can't be reached: {}", e)
+        } else {
+            e.to_string()
+        }
+    })?;
+    let status = response.status();
+    let text = response.text().await.map_err(|e| e.to_string())?;
+    if !status.is_success() {
+        return Err(format!("the upload host answered {}", status));
+    }
+    let link = text.trim();
+    match Url::parse(link) {
+        Ok(link) if matches!(link.scheme(), "http" | "https") => Ok(link.to_string()),
+        _ => Err("the upload host didn't answer with a link".to_string()),
+    }
+}
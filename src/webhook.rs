@@ -0,0 +1,113 @@
+use std::time::Duration;
+
+use chrono::{DateTime, Local};
+use log::{debug, warn};
+use reqwest::{Client, StatusCode, Url};
+use serde::Serialize;
+use tokio::sync::mpsc::{self, error::TrySendError, Sender};
+
+use crate::message::{ChatMessage, Kind};
+use crate::network::backoff_delay;
+
+/// Messages waiting to be posted, more are dropped rather than slowing down the TUI
+const QUEUE: usize = 256;
+/// Tries per message, the first one included
+const ATTEMPTS: u32 = 4;
+
+/// What `--webhook-url` receives for each message, as the JSON body of a POST
+#[derive(Serialize)]
+struct Payload<'a> {
+    /// `received` or `sent`
+    event: &'static str,
+    topic: &'a str,
+    id: u64,
+    /// As shown in the list, with the `#suffix` when several peers share the name
+    sender: &'a str,
+    /// PeerId that signed a received message, `null` for sent ones
+    peer_id: Option<&'a str>,
+    /// `null` for whispers, they were sealed for us and stay off the wire
+    body: Option<&'a str>,
+    /// Id of the message this one answers
+    reply_to: Option<u64>,
+    private: bool,
+    timestamp: DateTime<Local>,
+}
+
+/// Posts messages to `--webhook-url` from a task of its own, so a slow endpoint never
+/// holds up the TUI
+pub struct Webhook {
+    queue: Sender<String>,
+    sent: bool,
+}
+
+impl Webhook {
+    /// Start the posting task. `sent` posts our own messages too
+    pub fn spawn(url: Url, timeout: Duration, sent: bool) -> reqwest::Result<Webhook> {
+        let client = Client::builder().timeout(timeout).build()?;
+        let (queue, mut rx) = mpsc::channel::<String>(QUEUE);
+        tokio::spawn(async move {
+            while let Some(json) = rx.recv().await {
+                post(&client, &url, json).await;
+            }
+        });
+        Ok(Webhook { queue, sent })
+    }
+
+    /// Queue `message`, seen in `topic`, when it is one the hook is for, without waiting
+    pub fn notify(&self, topic: &str, message: &ChatMessage) {
+        let event = match message.kind {
+            Kind::Remote => "received",
+            Kind::Local if self.sent => "sent",
+            _ => return,
+        };
+        let payload = Payload {
+            event,
+            topic,
+            id: message.id,
+            sender: &message.sender,
+            peer_id: message.author.as_deref(),
+            body: (!message.private).then(|| message.body.as_str()),
+            reply_to: message.reply_to.as_ref().map(|quote| quote.id),
+            private: message.private,
+            timestamp: message.timestamp,
+        };
+        let json = match serde_json::to_string(&payload) {
+            Ok(json) => json,
+            Err(e) => return warn!("Webhook payload for message {} failed: {}", message.id, e),
+        };
+        match self.queue.try_send(json) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => warn!("Webhook queue full, dropping message {}", message.id),
+            Err(TrySendError::Closed(_)) => warn!("Webhook task gone, dropping message {}", message.id),
+        }
+    }
+}
+
+/// POST `json`, retrying with backoff while the failure looks transient: no connection,
+/// a timeout, a 5xx or a 429
+async fn post(client: &Client, url: &Url, json: String) {
+    for attempt in 1..=ATTEMPTS {
+        let result = client
+            .post(url.clone())
+            .header("Content-Type", "application/json")
+            .body(json.clone())
+            .send()
+            .await;
+        let transient = match result {
+            Ok(response) if response.status().is_success() => return debug!("Webhook accepted a message"),
+            Ok(response) => {
+                let status = response.status();
+                warn!("Webhook answered {} (attempt {}/{})", status, attempt, ATTEMPTS);
+                status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS
+            }
+            Err(e) => {
+                warn!("Webhook post failed (attempt {}/{}): {}", attempt, ATTEMPTS, e);
+                e.is_timeout() || e.is_connect() || e.is_request()
+            }
+        };
+        if !transient || attempt == ATTEMPTS {
+            return warn!("Giving up on a webhook message");
+        }
+        tokio::time::sleep(backoff_delay(attempt)).await;
+    }
+}